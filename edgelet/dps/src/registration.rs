@@ -16,8 +16,7 @@ use tokio::timer::Interval;
 use url::form_urlencoded::Serializer as UrlSerializer;
 
 use edgelet_core::crypto::{Activate, KeyIdentity, KeyStore, Sign, Signature, SignatureAlgorithm};
-use edgelet_http::client::{Client, ClientImpl, TokenSource};
-use edgelet_http::ErrorKind as HttpErrorKind;
+use edgelet_http::client::{Client, ClientImpl, Condition, TokenSource};
 
 use crate::error::{Error, ErrorKind};
 use crate::model::{
@@ -179,7 +178,7 @@ where
                 &format!("{}/registrations/{}/register", scope_id, registration_id),
                 None,
                 Some(registration.clone()),
-                false,
+                Condition::None,
             )
             .map_err(|err| Error::from(err.context(ErrorKind::GetOperationId)));
         Box::new(future)
@@ -210,7 +209,7 @@ where
                 ),
                 None,
                 None,
-                false,
+                Condition::None,
             ).map_err(|err| Error::from(err.context(ErrorKind::GetOperationStatus)))
             .map(
                 |operation_status: Option<RegistrationOperationStatus>| ->
@@ -314,7 +313,7 @@ where
                 &uri_path,
                 None,
                 Some(registration),
-                false,
+                Condition::None,
             )
             .map_err(|err| Error::from(err.context(ErrorKind::RegisterWithX509IdentityCertificate)))
             .map(
@@ -351,7 +350,7 @@ where
                         &format!("{}/registrations/{}/register", scope_id, registration_id),
                         None,
                         Some(registration),
-                        false,
+                        Condition::None,
                     )
                     .map_err(|err| {
                         Error::from(err.context(ErrorKind::RegisterWithSymmetricChallengeKey))
@@ -389,7 +388,7 @@ where
                 &format!("{}/registrations/{}/register", scope_id, registration_id),
                 None,
                 Some(registration.clone()),
-                false,
+                Condition::None,
             )
             .then(move |result| {
                 match result {
@@ -399,23 +398,29 @@ where
                     Err(err) => {
                         // If request is returned with status unauthorized, extract the tpm
                         // challenge from the payload, generate a signature and re-issue the
-                        // request
-                        let body = if let HttpErrorKind::HttpWithErrorResponse(status, body) =
-                            err.kind()
-                        {
-                            if *status == StatusCode::UNAUTHORIZED {
+                        // request. The status is taken from the service error rather than the
+                        // error kind, which names some 401s more specifically.
+                        let body = match err.service_error() {
+                            Some(service_error)
+                                if service_error.status_code() == StatusCode::UNAUTHORIZED =>
+                            {
                                 debug!(
                                     "Registration unauthorized, checking response for challenge {}",
-                                    status,
+                                    service_error.status_code(),
+                                );
+                                Some(service_error.message().to_string())
+                            }
+                            Some(service_error) => {
+                                debug!(
+                                    "Unexpected registration status, {}",
+                                    service_error.status_code()
                                 );
-                                Some(body.clone())
-                            } else {
-                                debug!("Unexpected registration status, {}", status);
                                 None
                             }
-                        } else {
-                            debug!("Response error {:?}", err);
-                            None
+                            None => {
+                                debug!("Response error {:?}", err);
+                                None
+                            }
                         };
 
                         body.map_or_else(
@@ -0,0 +1,171 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, ErrorKind};
+
+/// Fails requests fast once the service has been unreachable for a number of
+/// consecutive attempts. After the cooldown a single trial request is let
+/// through, and the circuit closes again if it reaches the service.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    Trial,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+        }
+    }
+
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if Instant::now() < until => CircuitState::Open,
+            State::Open { .. } | State::Trial => CircuitState::HalfOpen,
+        }
+    }
+
+    pub(crate) fn try_acquire(&self) -> Result<Attempt, Error> {
+        let mut state = self.lock();
+
+        match *state {
+            State::Closed { .. } => (),
+            State::Open { until } if Instant::now() >= until => *state = State::Trial,
+            State::Open { .. } | State::Trial => return Err(Error::from(ErrorKind::CircuitOpen)),
+        }
+
+        Ok(Attempt {
+            breaker: self.clone(),
+            recorded: false,
+        })
+    }
+
+    fn record(&self, reached_service: bool) {
+        let mut state = self.lock();
+
+        *state = match *state {
+            _ if reached_service => State::Closed { failures: 0 },
+            State::Closed { failures } if failures + 1 < self.failure_threshold => State::Closed {
+                failures: failures + 1,
+            },
+            _ => State::Open {
+                until: Instant::now() + self.cooldown,
+            },
+        };
+    }
+
+    fn abandon(&self) {
+        // a trial request that was dropped before completing lets the next
+        // request try again
+        let mut state = self.lock();
+        if let State::Trial = *state {
+            *state = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("Unable to lock the circuit breaker mutex")
+    }
+}
+
+pub(crate) struct Attempt {
+    breaker: CircuitBreaker,
+    recorded: bool,
+}
+
+impl Attempt {
+    pub(crate) fn record(mut self, reached_service: bool) {
+        self.recorded = true;
+        self.breaker.record(reached_service);
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.abandon();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+
+    use std::time::Duration;
+
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(3600));
+
+        breaker.try_acquire().unwrap().record(false);
+        breaker.try_acquire().unwrap().record(true);
+        breaker.try_acquire().unwrap().record(false);
+        assert_eq!(CircuitState::Closed, breaker.state());
+
+        breaker.try_acquire().unwrap().record(false);
+        assert_eq!(CircuitState::Open, breaker.state());
+        match breaker.try_acquire() {
+            Err(ref err) if *err.kind() == ErrorKind::CircuitOpen => (),
+            _ => panic!("Expected the open circuit to fail fast"),
+        }
+    }
+
+    #[test]
+    fn trial_request_after_cooldown_closes_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(0));
+
+        breaker.try_acquire().unwrap().record(false);
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+
+        let trial = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_err());
+        trial.record(true);
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[test]
+    fn dropped_trial_request_allows_another_trial() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(0));
+
+        breaker.try_acquire().unwrap().record(false);
+        drop(breaker.try_acquire().unwrap());
+
+        breaker.try_acquire().unwrap().record(false);
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use hyper::StatusCode;
+
+use crate::error::{Error, ErrorKind};
+
+const DEFAULT_LATENCY_THRESHOLD_SECS: u64 = 1;
+
+/// Limits the number of requests a `Client` has in flight, adjusting the
+/// limit using an AIMD scheme. The limit grows by one after a full window of
+/// fast, successful responses and is halved whenever the service throttles
+/// or responds slower than the latency threshold. Responses to requests sent
+/// before the last decrease don't halve it again, so a burst of throttled
+/// requests only halves it once.
+#[derive(Clone)]
+pub struct AdaptiveConcurrency {
+    min_limit: usize,
+    max_limit: usize,
+    latency_threshold: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    limit: usize,
+    successes: usize,
+    in_flight: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+    // the number of permits issued so far, and how many had been issued
+    // when the limit was last decreased
+    issued: u64,
+    decreased_at: u64,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new(initial_limit: usize, max_limit: usize) -> Self {
+        let max_limit = max_limit.max(1);
+
+        AdaptiveConcurrency {
+            min_limit: 1,
+            max_limit,
+            latency_threshold: Duration::from_secs(DEFAULT_LATENCY_THRESHOLD_SECS),
+            state: Arc::new(Mutex::new(State {
+                limit: initial_limit.max(1).min(max_limit),
+                successes: 0,
+                in_flight: 0,
+                waiters: VecDeque::new(),
+                issued: 0,
+                decreased_at: 0,
+            })),
+        }
+    }
+
+    pub fn with_min_limit(mut self, min_limit: usize) -> Self {
+        self.min_limit = min_limit.max(1).min(self.max_limit);
+        self
+    }
+
+    pub fn with_latency_threshold(mut self, latency_threshold: Duration) -> Self {
+        self.latency_threshold = latency_threshold;
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.lock().limit
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    pub(crate) fn acquire(&self) -> impl Future<Item = Permit, Error = Error> {
+        let mut state = self.lock();
+
+        if state.in_flight < state.limit {
+            state.in_flight += 1;
+            Either::A(future::ok(self.issue(&mut state)))
+        } else {
+            // the slot is handed over as a permit by whichever request
+            // releases it next, so a waiter dropped before it is polled
+            // releases the slot with the permit
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+
+            Either::B(rx.map_err(|_| Error::from(ErrorKind::Http)))
+        }
+    }
+
+    // Adjusts the limit for a completed request, whose permit was issued
+    // after `issued` others.
+    fn complete(&self, issued: u64, latency: Duration, status: Option<StatusCode>) {
+        let throttled = status.map_or(false, |status| {
+            status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+        });
+
+        {
+            let mut state = self.lock();
+            if throttled || latency > self.latency_threshold {
+                // the decrease already made up for requests sent before it
+                if issued >= state.decreased_at {
+                    state.limit = (state.limit / 2).max(self.min_limit);
+                    state.successes = 0;
+                    state.decreased_at = state.issued;
+                }
+            } else {
+                state.successes += 1;
+                if state.successes >= state.limit {
+                    state.limit = (state.limit + 1).min(self.max_limit);
+                    state.successes = 0;
+                }
+            }
+        }
+
+        // a raised limit has room for requests that are already queued
+        self.wake_waiters();
+    }
+
+    fn release(&self) {
+        self.lock().in_flight -= 1;
+        self.wake_waiters();
+    }
+
+    // Hands free slots to queued requests. The lock isn't held while a permit
+    // is sent, since a permit that can't be delivered because its request was
+    // dropped releases the slot again when it is dropped in turn.
+    fn wake_waiters(&self) {
+        loop {
+            let (waiter, permit) = {
+                let mut state = self.lock();
+                if state.in_flight >= state.limit {
+                    break;
+                }
+                match state.waiters.pop_front() {
+                    Some(waiter) => {
+                        state.in_flight += 1;
+                        (waiter, self.issue(&mut state))
+                    }
+                    None => break,
+                }
+            };
+
+            let _ = waiter.send(permit);
+        }
+    }
+
+    // The permit for a slot already counted as in flight.
+    fn issue(&self, state: &mut State) -> Permit {
+        let permit = Permit::new(self.clone(), state.issued);
+        state.issued += 1;
+        permit
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("Unable to lock the adaptive concurrency mutex")
+    }
+}
+
+pub(crate) struct Permit {
+    controller: AdaptiveConcurrency,
+    issued: u64,
+    started: Instant,
+}
+
+impl Permit {
+    fn new(controller: AdaptiveConcurrency, issued: u64) -> Self {
+        Permit {
+            controller,
+            issued,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn complete(self, status: Option<StatusCode>) {
+        self.controller
+            .complete(self.issued, self.started.elapsed(), status);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.controller.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdaptiveConcurrency;
+
+    use std::time::Duration;
+
+    use futures::{future, Async, Future};
+    use hyper::StatusCode;
+
+    #[test]
+    fn limit_grows_after_a_window_of_fast_responses() {
+        let controller = AdaptiveConcurrency::new(2, 3);
+
+        for _ in 0..2 {
+            let permit = controller.acquire().wait().unwrap();
+            permit.complete(Some(StatusCode::OK));
+        }
+
+        assert_eq!(3, controller.limit());
+        assert_eq!(0, controller.in_flight());
+    }
+
+    #[test]
+    fn limit_is_halved_on_slow_responses() {
+        let controller =
+            AdaptiveConcurrency::new(8, 8).with_latency_threshold(Duration::from_secs(0));
+
+        let permit = controller.acquire().wait().unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        permit.complete(Some(StatusCode::OK));
+
+        assert_eq!(4, controller.limit());
+    }
+
+    #[test]
+    fn limit_does_not_drop_below_min() {
+        let controller = AdaptiveConcurrency::new(4, 8).with_min_limit(3);
+
+        let permit = controller.acquire().wait().unwrap();
+        permit.complete(Some(StatusCode::TOO_MANY_REQUESTS));
+
+        assert_eq!(3, controller.limit());
+    }
+
+    #[test]
+    fn concurrent_throttled_responses_halve_limit_once() {
+        let controller = AdaptiveConcurrency::new(8, 8);
+
+        let permits: Vec<_> = (0..4)
+            .map(|_| controller.acquire().wait().unwrap())
+            .collect();
+        for permit in permits {
+            permit.complete(Some(StatusCode::TOO_MANY_REQUESTS));
+        }
+        assert_eq!(4, controller.limit());
+
+        // a request sent after the decrease can decrease it again
+        let permit = controller.acquire().wait().unwrap();
+        permit.complete(Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(2, controller.limit());
+    }
+
+    #[test]
+    fn released_slot_is_handed_to_waiter() {
+        let controller = AdaptiveConcurrency::new(1, 1);
+
+        future::lazy(move || {
+            let permit = controller.acquire().wait().unwrap();
+            let mut waiter = controller.acquire();
+            assert!(!waiter.poll().unwrap().is_ready());
+
+            drop(permit);
+            match waiter.poll().unwrap() {
+                Async::Ready(_permit) => assert_eq!(1, controller.in_flight()),
+                Async::NotReady => panic!("Expected the waiter to be handed the released slot"),
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn waiter_dropped_after_handoff_releases_slot() {
+        let controller = AdaptiveConcurrency::new(1, 1);
+
+        let permit = controller.acquire().wait().unwrap();
+        let waiter = controller.acquire();
+
+        drop(permit);
+        assert_eq!(1, controller.in_flight());
+        drop(waiter);
+        assert_eq!(0, controller.in_flight());
+    }
+
+    #[test]
+    fn raised_limit_wakes_waiters() {
+        let controller = AdaptiveConcurrency::new(1, 2);
+
+        future::lazy(move || {
+            let _permit = controller.acquire().wait().unwrap();
+            let mut waiter = controller.acquire();
+            assert!(!waiter.poll().unwrap().is_ready());
+
+            controller.complete(0, Duration::from_millis(0), Some(StatusCode::OK));
+            assert_eq!(2, controller.limit());
+            match waiter.poll().unwrap() {
+                Async::Ready(_permit) => assert_eq!(2, controller.in_flight()),
+                Async::NotReady => panic!("Expected the waiter to be woken by the raised limit"),
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use failure::ResultExt;
+use hyper::header::{HeaderName, HeaderValue, IF_MATCH, IF_NONE_MATCH};
+
+use crate::error::{Error, ErrorKind};
+
+/// The condition a request is made on, by the ETag of the resource it acts on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// The request is unconditional.
+    None,
+    /// The request applies to any existing version of the resource, with
+    /// `If-Match: *`.
+    Any,
+    /// The request applies only if the resource still has this ETag.
+    IfMatch(String),
+    /// The request applies only if the resource doesn't have this ETag.
+    IfNoneMatch(String),
+}
+
+impl Condition {
+    /// `IfMatch` with `etag`, or `fallback` if there is none.
+    pub fn if_match_or(etag: Option<&str>, fallback: Condition) -> Self {
+        etag.map_or(fallback, |etag| Condition::IfMatch(etag.to_string()))
+    }
+
+    /// The header expressing the condition, if it needs one.
+    pub(crate) fn into_header(self) -> Result<Option<(HeaderName, HeaderValue)>, Error> {
+        let (name, value) = match self {
+            Condition::None => return Ok(None),
+            Condition::Any => (IF_MATCH, HeaderValue::from_static("*")),
+            Condition::IfMatch(etag) => (IF_MATCH, etag_value(&etag)?),
+            Condition::IfNoneMatch(etag) => (IF_NONE_MATCH, etag_value(&etag)?),
+        };
+        Ok(Some((name, value)))
+    }
+}
+
+impl Default for Condition {
+    fn default() -> Self {
+        Condition::None
+    }
+}
+
+// ETags are sent as quoted strings in conditional headers, but IoT Hub
+// returns them unquoted in response bodies.
+fn etag_value(etag: &str) -> Result<HeaderValue, Error> {
+    let value = if etag.starts_with('"') {
+        HeaderValue::from_str(etag)
+    } else {
+        HeaderValue::from_str(&format!("\"{}\"", etag))
+    };
+    value.context(ErrorKind::Http).map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{HeaderName, IF_MATCH, IF_NONE_MATCH};
+
+    use super::Condition;
+    use crate::error::ErrorKind;
+
+    fn header(condition: Condition) -> Option<(HeaderName, String)> {
+        condition
+            .into_header()
+            .unwrap()
+            .map(|(name, value)| (name, value.to_str().unwrap().to_string()))
+    }
+
+    #[test]
+    fn none_sets_no_header() {
+        assert_eq!(None, header(Condition::None));
+    }
+
+    #[test]
+    fn any_matches_any_etag() {
+        assert_eq!(Some((IF_MATCH, "*".to_string())), header(Condition::Any));
+    }
+
+    #[test]
+    fn if_match_quotes_etag() {
+        assert_eq!(
+            Some((IF_MATCH, "\"AAAA\"".to_string())),
+            header(Condition::IfMatch("AAAA".to_string()))
+        );
+        assert_eq!(
+            Some((IF_MATCH, "\"AAAA\"".to_string())),
+            header(Condition::IfMatch("\"AAAA\"".to_string()))
+        );
+    }
+
+    #[test]
+    fn if_none_match_quotes_etag() {
+        assert_eq!(
+            Some((IF_NONE_MATCH, "\"AAAA\"".to_string())),
+            header(Condition::IfNoneMatch("AAAA".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_etag_fails() {
+        let err = Condition::IfMatch("AA\nAA".to_string())
+            .into_header()
+            .unwrap_err();
+        assert_eq!(&ErrorKind::Http, err.kind());
+    }
+
+    #[test]
+    fn if_match_or_falls_back_without_etag() {
+        assert_eq!(
+            Condition::IfMatch("AAAA".to_string()),
+            Condition::if_match_or(Some("AAAA"), Condition::Any)
+        );
+        assert_eq!(Condition::Any, Condition::if_match_or(None, Condition::Any));
+    }
+}
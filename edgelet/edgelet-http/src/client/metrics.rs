@@ -0,0 +1,150 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::sync::Arc;
+
+use futures::{Async, Poll, Stream};
+use hyper::{Body, Chunk, Method, StatusCode};
+
+/// Receives a `RequestMetrics` for every request a `Client` sends, including
+/// each retry. A request that got a response is recorded once the body of
+/// the response has been read, or dropped before it was.
+pub trait MetricsHook: Send + Sync {
+    fn record(&self, metrics: &RequestMetrics);
+}
+
+impl<F> MetricsHook for F
+where
+    F: Fn(&RequestMetrics) + Send + Sync,
+{
+    fn record(&self, metrics: &RequestMetrics) {
+        (self)(metrics)
+    }
+}
+
+/// A request a `Client` sent, the status of its response if it got one, and
+/// the sizes of their bodies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestMetrics {
+    method: Method,
+    path: String,
+    status: Option<StatusCode>,
+    attribution: Option<String>,
+    request_bytes: Option<u64>,
+    response_bytes: Option<u64>,
+}
+
+impl RequestMetrics {
+    pub(crate) fn new(method: Method, path: String, attribution: Option<String>) -> Self {
+        RequestMetrics {
+            method,
+            path,
+            status: None,
+            attribution,
+            request_bytes: None,
+            response_bytes: None,
+        }
+    }
+
+    pub(crate) fn with_status(mut self, status: Option<StatusCode>) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(crate) fn with_request_bytes(mut self, request_bytes: Option<u64>) -> Self {
+        self.request_bytes = request_bytes;
+        self
+    }
+
+    pub(crate) fn with_response_bytes(mut self, response_bytes: Option<u64>) -> Self {
+        self.response_bytes = response_bytes;
+        self
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The status of the response, or `None` if the request failed before
+    /// one was received.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// The label the client was given with `Client::with_attribution`.
+    pub fn attribution(&self) -> Option<&str> {
+        self.attribution.as_ref().map(AsRef::as_ref)
+    }
+
+    /// The size of the serialized request body, or `None` if it was
+    /// streamed without a known length.
+    pub fn request_bytes(&self) -> Option<u64> {
+        self.request_bytes
+    }
+
+    /// The size of the response body, or `None` if there was no response or
+    /// its body was dropped before it was read to the end.
+    pub fn response_bytes(&self) -> Option<u64> {
+        self.response_bytes
+    }
+}
+
+// A response body counting the bytes read from it, which passes the metrics
+// of its request to the hook once it is read to the end or dropped.
+struct MeteredBody {
+    body: Body,
+    metrics_hook: Arc<dyn MetricsHook>,
+    metrics: Option<RequestMetrics>,
+    bytes: u64,
+}
+
+impl MeteredBody {
+    fn finish(&mut self, response_bytes: Option<u64>) {
+        if let Some(metrics) = self.metrics.take() {
+            self.metrics_hook
+                .record(&metrics.with_response_bytes(response_bytes));
+        }
+    }
+}
+
+impl Stream for MeteredBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let chunk = self.body.poll()?;
+        match chunk {
+            Async::Ready(Some(ref chunk)) => self.bytes += chunk.len() as u64,
+            Async::Ready(None) => {
+                let bytes = self.bytes;
+                self.finish(Some(bytes));
+            }
+            Async::NotReady => (),
+        }
+        Ok(chunk)
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+/// Wraps the body of the response to a request so that `metrics` are
+/// recorded with its size once it has been read.
+pub(crate) fn metered_body(
+    body: Body,
+    metrics_hook: Arc<dyn MetricsHook>,
+    metrics: RequestMetrics,
+) -> Body {
+    Body::wrap_stream(MeteredBody {
+        body,
+        metrics_hook,
+        metrics: Some(metrics),
+        bytes: 0,
+    })
+}
@@ -0,0 +1,3791 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use failure::{Fail, ResultExt};
+use futures::future::{self, Either, Loop};
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{self, Body, Chunk, Method, Request, Response, StatusCode, Uri};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio_timer::timer::Handle as TimerHandle;
+use tokio_timer::Delay;
+use typed_headers::{http, mime, ContentLength, ContentType, HeaderMapExt};
+use url::form_urlencoded::Serializer as UrlSerializer;
+use url::Url;
+
+use edgelet_utils::ensure_not_empty_with_context;
+
+use crate::error::{Error, ErrorKind, ServiceError};
+
+mod circuit;
+mod concurrency;
+mod condition;
+mod metrics;
+mod rate_limit;
+mod recording;
+mod retry;
+mod token_cache;
+
+pub use self::circuit::{CircuitBreaker, CircuitState};
+pub use self::concurrency::AdaptiveConcurrency;
+pub use self::condition::Condition;
+pub use self::metrics::{MetricsHook, RequestMetrics};
+pub use self::rate_limit::{OperationClass, RateLimiter};
+pub use self::recording::{Exchange, RecordingService, ReplayService};
+pub use self::retry::{RetryBudget, RetryPolicy};
+pub use self::token_cache::CachedTokenSource;
+
+use self::metrics::metered_body;
+use self::retry::Retries;
+
+const IOTHUB_ERROR_CODE_HEADER: &str = "iothub-errorcode";
+const ON_BEHALF_OF_HEADER: &str = "x-ms-edge-moduleId";
+const ATTRIBUTION_HEADER: &str = "x-ms-client-attribution";
+const REQUEST_ID_HEADER: &str = "x-ms-request-id";
+
+// an API version no service supports, for `Client::supported_api_versions`
+const PROBE_API_VERSION: &str = "0000-00-00";
+
+// a cheap authenticated request, for `Client::warmup`
+const WARMUP_PATH: &str = "/statistics/service";
+
+// builds the error for a response with the given status, error code and body
+type ErrorResponse = fn(StatusCode, Option<&str>, &[u8]) -> Error;
+
+pub trait TokenSource {
+    type Error;
+    fn get(&self, expiry: &DateTime<Utc>) -> Result<String, Self::Error>;
+
+    /// Gets a new token even if the source would otherwise reuse one, for
+    /// when the service rejected the one it had.
+    fn get_uncached(&self, expiry: &DateTime<Utc>) -> Result<String, Self::Error> {
+        self.get(expiry)
+    }
+
+    /// Gets a new token scoped to `host` instead of the host the source
+    /// issues tokens for, to sign a request redirected there. `None` if the
+    /// source can't scope its tokens to another host.
+    fn get_for_host(
+        &self,
+        _host: &str,
+        _expiry: &DateTime<Utc>,
+    ) -> Result<Option<String>, Self::Error> {
+        Ok(None)
+    }
+}
+
+pub trait ClientImpl: Send + Sync {
+    type Response: Future<Item = Response<Body>, Error = hyper::Error> + Send;
+
+    fn call(&self, req: Request<Body>) -> Self::Response;
+
+    /// Whether connections opened by one call are kept for later calls, which
+    /// is what makes `Client::warmup` worth doing.
+    fn pools_connections(&self) -> bool {
+        false
+    }
+}
+
+impl<C> ClientImpl for hyper::Client<C, Body>
+where
+    C: hyper::client::connect::Connect + Sync + 'static,
+    <C as hyper::client::connect::Connect>::Transport: 'static,
+    <C as hyper::client::connect::Connect>::Future: 'static,
+{
+    type Response = hyper::client::ResponseFuture;
+
+    fn call(&self, req: Request<Body>) -> Self::Response {
+        self.request(req)
+    }
+
+    fn pools_connections(&self) -> bool {
+        true
+    }
+}
+
+impl<F, R> ClientImpl for F
+where
+    F: Fn(Request<Body>) -> R + Send + Sync,
+    R: IntoFuture<Item = Response<Body>, Error = hyper::Error>,
+    <R as IntoFuture>::Future: Send,
+{
+    type Response = <R as IntoFuture>::Future;
+
+    fn call(&self, req: Request<Body>) -> Self::Response {
+        (self)(req).into_future()
+    }
+}
+
+/// A client of an IoT Hub style REST API.
+///
+/// Every method takes `&self` and the state shared between requests, that of
+/// the circuit breaker, rate limiter, concurrency limit, retry budget and
+/// metrics hook, is kept behind `Arc<Mutex<_>>` or atomics. So a `Client`
+/// is `Send` and `Sync` whenever its token source is, and can be shared
+/// across tasks and threads through an `Arc`. Clones are cheap and share all
+/// of that state too.
+pub struct Client<C, T> {
+    inner: Arc<C>,
+    token_source: Option<T>,
+    api_version: String,
+    host_name: Url,
+    host_override: Option<Url>,
+    gateway_host: Option<Url>,
+    user_agent: Option<String>,
+    on_behalf_of: Option<String>,
+    attribution: Option<String>,
+    attribution_header: bool,
+    metrics_hook: Option<Arc<dyn MetricsHook>>,
+    base_path: Option<String>,
+    concurrency: Option<AdaptiveConcurrency>,
+    circuit_breaker: Option<CircuitBreaker>,
+    rate_limiter: Option<RateLimiter>,
+    retry_policy: Option<RetryPolicy>,
+    retry_budget: Option<RetryBudget>,
+    refresh_expired_tokens: bool,
+    conditional_writes_required: bool,
+    max_redirects: u32,
+    timer: Option<TimerHandle>,
+    timeout: Option<StdDuration>,
+    body_read_timeout: Option<StdDuration>,
+}
+
+impl<C, T> Client<C, T>
+where
+    C: ClientImpl,
+    T: TokenSource + Clone,
+    T::Error: Fail,
+{
+    pub fn new(
+        inner: C,
+        token_source: Option<T>,
+        api_version: String,
+        host_name: Url,
+    ) -> Result<Self, Error> {
+        ensure_not_empty_with_context(&api_version, || {
+            ErrorKind::InvalidApiVersion(api_version.clone())
+        })?;
+
+        let client = Client {
+            inner: Arc::new(inner),
+            token_source,
+            api_version,
+            host_name,
+            host_override: None,
+            gateway_host: None,
+            user_agent: None,
+            on_behalf_of: None,
+            attribution: None,
+            attribution_header: false,
+            metrics_hook: None,
+            base_path: None,
+            concurrency: None,
+            circuit_breaker: None,
+            rate_limiter: None,
+            retry_policy: None,
+            retry_budget: None,
+            refresh_expired_tokens: false,
+            conditional_writes_required: false,
+            max_redirects: 0,
+            timer: None,
+            timeout: None,
+            body_read_timeout: None,
+        };
+
+        Ok(client)
+    }
+
+    pub fn with_token_source(mut self, source: T) -> Self {
+        self.token_source = Some(source);
+        self
+    }
+
+    /// Sends requests to `connect_to` instead of the host name, while still
+    /// naming the host name in their `Host` header, for a server that
+    /// impersonates the service. SAS tokens are unaffected as their resource
+    /// URI comes from the token source.
+    pub fn with_host_override(mut self, connect_to: Url) -> Self {
+        self.host_override = Some(connect_to);
+        self
+    }
+
+    /// Sends requests to `gateway_host`, such as the parent edge a nested
+    /// edge device reaches IoT Hub through, instead of the host name. Unlike
+    /// with a host override the requests name the gateway in their `Host`
+    /// header. The host name remains the hub the device belongs to, which
+    /// SAS tokens are scoped to.
+    pub fn with_gateway_host(mut self, gateway_host: Url) -> Self {
+        self.gateway_host = Some(gateway_host);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Names `module_id`, a module of a child device in a nested edge, as the
+    /// identity every request acts on behalf of. The requests are still
+    /// authenticated with this client's token source, which for nested edge
+    /// is the parent edge's credentials.
+    pub fn with_on_behalf_of(mut self, module_id: &str) -> Self {
+        self.on_behalf_of = Some(module_id.to_string());
+        self
+    }
+
+    /// Labels every request with `attribution`, such as the tenant it is made
+    /// for, in what is passed to the metrics hook. Clones of a client are
+    /// cheap, so a clone can be labeled for each tenant.
+    pub fn with_attribution(mut self, attribution: &str) -> Self {
+        self.attribution = Some(attribution.to_string());
+        self
+    }
+
+    /// Also sends the attribution label of every request in an
+    /// `x-ms-client-attribution` header, which IoT Hub ignores.
+    pub fn with_attribution_header(mut self, attribution_header: bool) -> Self {
+        self.attribution_header = attribution_header;
+        self
+    }
+
+    /// Passes `metrics_hook` the outcome of every request sent, including
+    /// each retry.
+    pub fn with_metrics_hook<H>(mut self, metrics_hook: H) -> Self
+    where
+        H: 'static + MetricsHook,
+    {
+        self.metrics_hook = Some(Arc::new(metrics_hook));
+        self
+    }
+
+    /// Prefixes the path of every request with `base_path`, for services
+    /// served under a path behind a gateway.
+    pub fn with_base_path(mut self, base_path: &str) -> Self {
+        let base_path = base_path.trim_matches('/');
+        self.base_path = if base_path.is_empty() {
+            None
+        } else {
+            Some(format!("/{}", base_path))
+        };
+        self
+    }
+
+    pub fn with_adaptive_concurrency(mut self, concurrency: AdaptiveConcurrency) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Delays requests to keep every `OperationClass` within the rate given
+    /// to `rate_limiter`. Every resent request is counted again.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Resends requests that failed according to `retry_policy`. Requests
+    /// with a streamed body are never resent.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Caps the retries `retry_policy` allows across all requests of this
+    /// client and its clones, and any other client given the same budget.
+    /// Once the budget is exhausted failed requests aren't retried until it
+    /// refills.
+    pub fn with_retry_budget(mut self, retry_budget: RetryBudget) -> Self {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+
+    /// Regenerates the SAS token and resends a request once if IoT Hub
+    /// rejects its token as expired. The new token bypasses any token cache
+    /// and expires relative to the `Date` of the service's response rather
+    /// than the device's clock. If it is rejected again the request fails
+    /// with `ErrorKind::ClockSkew`.
+    pub fn with_refresh_expired_tokens(mut self, refresh_expired_tokens: bool) -> Self {
+        self.refresh_expired_tokens = refresh_expired_tokens;
+        self
+    }
+
+    /// Rejects requests that would be sent with `If-Match: *` with
+    /// `ErrorKind::MissingEtag` instead, so that every write has to be
+    /// conditional on a real ETag.
+    pub fn with_conditional_writes_required(mut self, conditional_writes_required: bool) -> Self {
+        self.conditional_writes_required = conditional_writes_required;
+        self
+    }
+
+    /// Follows up to `max_redirects` consecutive 301, 302, 307 and 308
+    /// redirects, resending the request as it was to the `Location` of the
+    /// response. A redirect to another origin drops the `Authorization` and
+    /// `Host` headers and is signed again with a token the token source
+    /// scopes to the new host, or sent unsigned if it can't, so the token for
+    /// the hub is never sent to another host. Requests with a streamed body
+    /// are never redirected.
+    pub fn with_max_redirects(mut self, max_redirects: u32) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Runs the timers of request timeouts, retry backoffs and the rate
+    /// limiter on `timer` instead of the timer of the runtime the request is
+    /// polled on. This lets a client be embedded in an application that
+    /// drives it from outside a Tokio runtime, where it would otherwise fail
+    /// for want of a timer.
+    pub fn with_timer(mut self, timer: TimerHandle) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Fails every attempt at a request with `ErrorKind::Timeout` if its
+    /// response hasn't arrived within `timeout`. Timed out requests aren't
+    /// retried.
+    pub fn with_timeout(mut self, timeout: StdDuration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails every attempt at a request with `ErrorKind::BodyReadTimeout` if
+    /// the body of its response hasn't been read in full within
+    /// `body_read_timeout` of its headers arriving, so that a body trickling
+    /// in can't hold a request up indefinitely. Timed out requests aren't
+    /// retried.
+    pub fn with_body_read_timeout(mut self, body_read_timeout: StdDuration) -> Self {
+        self.body_read_timeout = Some(body_read_timeout);
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn on_behalf_of(&self) -> Option<&str> {
+        self.on_behalf_of.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn attribution(&self) -> Option<&str> {
+        self.attribution.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn attribution_header(&self) -> bool {
+        self.attribution_header
+    }
+
+    pub fn metrics_hook(&self) -> Option<&dyn MetricsHook> {
+        self.metrics_hook.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn host_name(&self) -> &Url {
+        &self.host_name
+    }
+
+    pub fn host_override(&self) -> Option<&Url> {
+        self.host_override.as_ref()
+    }
+
+    pub fn gateway_host(&self) -> Option<&Url> {
+        self.gateway_host.as_ref()
+    }
+
+    pub fn adaptive_concurrency(&self) -> Option<&AdaptiveConcurrency> {
+        self.concurrency.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.retry_budget.as_ref()
+    }
+
+    pub fn refresh_expired_tokens(&self) -> bool {
+        self.refresh_expired_tokens
+    }
+
+    pub fn conditional_writes_required(&self) -> bool {
+        self.conditional_writes_required
+    }
+
+    pub fn max_redirects(&self) -> u32 {
+        self.max_redirects
+    }
+
+    pub fn timer(&self) -> Option<&TimerHandle> {
+        self.timer.as_ref()
+    }
+
+    pub fn timeout(&self) -> Option<StdDuration> {
+        self.timeout
+    }
+
+    pub fn body_read_timeout(&self) -> Option<StdDuration> {
+        self.body_read_timeout
+    }
+
+    fn add_sas_token<B>(&self, req: &mut Request<B>, path: &str) -> Result<(), Error> {
+        if let Some(ref source) = self.token_source {
+            let token_duration = Duration::hours(1);
+            let expiry = Utc::now() + token_duration;
+            let token = source.get(&expiry).context(ErrorKind::TokenSource)?;
+            debug!(
+                "Success generating token for request {} {}",
+                req.method(),
+                path,
+            );
+            set_sas_token(req, &token);
+        } else {
+            debug!("Empty token source for request {} {}", req.method(), path);
+        }
+
+        Ok(())
+    }
+
+    pub fn request<BodyT, ResponseT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> impl Future<Item = Option<ResponseT>, Error = Error>
+    where
+        BodyT: Serialize,
+        ResponseT: 'static + DeserializeOwned,
+    {
+        self.json_request(method, path, query, body, condition)
+            .map(|req| {
+                self.send_with_retries(req, Error::service_error_response)
+                    .map(|(response, _)| response)
+            })
+            .into_future()
+            .flatten()
+    }
+
+    /// Like `request`, but an error response whose body deserializes into `E`
+    /// fails with `ErrorKind::TypedService`. `Error::typed_service_error`
+    /// returns the deserialized body.
+    pub fn request_typed_error<BodyT, ResponseT, E>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> impl Future<Item = Option<ResponseT>, Error = Error>
+    where
+        BodyT: Serialize,
+        ResponseT: 'static + DeserializeOwned,
+        E: 'static + DeserializeOwned + Send + Sync,
+    {
+        self.json_request(method, path, query, body, condition)
+            .map(|req| {
+                self.send_with_retries(req, Error::typed_service_error_response::<E>)
+                    .map(|(response, _)| response)
+            })
+            .into_future()
+            .flatten()
+    }
+
+    /// Like `request`, but also sends the given `headers` and returns the
+    /// headers of the response alongside its body.
+    pub fn request_with_meta<BodyT, ResponseT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        headers: HeaderMap,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> impl Future<Item = (Option<ResponseT>, HeaderMap), Error = Error>
+    where
+        BodyT: Serialize,
+        ResponseT: 'static + DeserializeOwned,
+    {
+        self.json_request(method, path, query, body, condition)
+            .map(|mut req| {
+                req.headers_mut().extend(headers);
+                self.send_with_retries(req, Error::service_error_response)
+            })
+            .into_future()
+            .flatten()
+    }
+
+    /// Like `request`, but returns the response as soon as its head arrives,
+    /// for its body to be read as a stream, such as to parse a large listing
+    /// without buffering all of it. The request is retried like any other
+    /// until a successful response arrives. If a body read timeout is set,
+    /// the body of that response fails with `ErrorKind::BodyReadTimeout` if
+    /// it hasn't been read in full within the timeout of its headers
+    /// arriving.
+    pub fn request_streaming<BodyT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> impl Future<Item = Response<Body>, Error = Error>
+    where
+        BodyT: Serialize,
+    {
+        let timer = self.timer.clone();
+        let body_read_timeout = self.body_read_timeout;
+
+        self.json_request(method, path, query, body, condition)
+            .map(|req| {
+                self.retrying(req, |client, req| {
+                    client.send_streaming(req, Error::service_error_response)
+                })
+            })
+            .into_future()
+            .flatten()
+            .map(move |resp| match body_read_timeout {
+                Some(timeout) => resp.map(|body| {
+                    let deadline = delay(timer.as_ref(), Instant::now() + timeout);
+                    Body::wrap_stream(DeadlineBody { body, deadline })
+                }),
+                None => resp,
+            })
+    }
+
+    /// Like `request`, but sends `content_type` as the `Content-Type` of the
+    /// request instead of `application/json`, for endpoints that expect
+    /// another media type. The body is still serialized as JSON.
+    pub fn request_with_content_type<BodyT, ResponseT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        content_type: HeaderValue,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> impl Future<Item = Option<ResponseT>, Error = Error>
+    where
+        BodyT: Serialize,
+        ResponseT: 'static + DeserializeOwned,
+    {
+        self.json_request(method, path, query, body, condition)
+            .map(|mut req| {
+                req.headers_mut()
+                    .insert(http::header::CONTENT_TYPE, content_type);
+                self.send_with_retries(req, Error::service_error_response)
+                    .map(|(response, _)| response)
+            })
+            .into_future()
+            .flatten()
+    }
+
+    /// Asks the service which API versions it supports, on a best-effort
+    /// basis. IoT Hub has no endpoint listing them, so this probes for them
+    /// instead: it sends a cheap `GET /statistics/service` with an API version
+    /// no service supports, and parses the versions IoT Hub lists in the error
+    /// it rejects the request with. If the service answers any other way this
+    /// fails with `ErrorKind::SupportedApiVersions`.
+    pub fn supported_api_versions(&self) -> impl Future<Item = Vec<String>, Error = Error> {
+        let mut probe = self.clone();
+        probe.api_version = PROBE_API_VERSION.to_string();
+
+        probe
+            .request::<(), Value>(
+                Method::GET,
+                "/statistics/service",
+                None,
+                None,
+                Condition::None,
+            )
+            .then(|result| {
+                let err = match result {
+                    Ok(_) => return Err(Error::from(ErrorKind::SupportedApiVersions)),
+                    Err(err) => err,
+                };
+                let versions = err
+                    .service_error()
+                    .map(|service_error| listed_api_versions(service_error.message()))
+                    .unwrap_or_default();
+                if versions.is_empty() {
+                    Err(Error::from(err.context(ErrorKind::SupportedApiVersions)))
+                } else {
+                    Ok(versions)
+                }
+            })
+    }
+
+    /// Opens a connection to the service ahead of the first real request, so
+    /// that request doesn't pay for the TCP and TLS handshakes. This sends a
+    /// cheap authenticated `GET /statistics/service` and succeeds whatever
+    /// the service answers, since any answer leaves the connection pooled. It
+    /// doesn't go through the retry policy, circuit breaker, rate limiter or
+    /// concurrency limit, and does nothing if the underlying client doesn't
+    /// pool connections. Like any other request it fails with
+    /// `ErrorKind::Timeout` if there's no answer within the client's timeout.
+    pub fn warmup(&self) -> impl Future<Item = (), Error = Error> {
+        if !self.inner.pools_connections() {
+            return Either::B(future::ok(()));
+        }
+
+        let req =
+            match self.json_request::<()>(Method::GET, WARMUP_PATH, None, None, Condition::None) {
+                Ok(req) => req.map(Body::from),
+                Err(err) => return Either::B(future::err(err)),
+            };
+
+        let res = self
+            .inner
+            .call(req)
+            .and_then(|resp| resp.into_body().concat2())
+            .then(|res| {
+                res.context(ErrorKind::Warmup)?;
+                Ok(())
+            });
+
+        Either::A(within(
+            res,
+            self.timer.as_ref(),
+            self.timeout,
+            ErrorKind::Timeout,
+        ))
+    }
+
+    fn json_request<BodyT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        body: Option<BodyT>,
+        condition: Condition,
+    ) -> Result<Request<Bytes>, Error>
+    where
+        BodyT: Serialize,
+    {
+        let mut req = self.request_builder(method, path, query, condition)?;
+
+        // add request body if there is any
+        let mut req = if let Some(body) = body {
+            let serialized = serde_json::to_string(&body).context(ErrorKind::Http)?;
+            let serialized_len = serialized.len();
+            let mut req = req.body(Bytes::from(serialized)).context(ErrorKind::Http)?;
+            req.headers_mut()
+                .typed_insert(&ContentType(mime::APPLICATION_JSON));
+            req.headers_mut()
+                .typed_insert(&ContentLength(serialized_len as u64));
+            req
+        } else {
+            req.body(Bytes::new()).context(ErrorKind::Http)?
+        };
+
+        // add sas token
+        self.add_sas_token(&mut req, path)?;
+
+        Ok(req)
+    }
+
+    /// Like `request`, but sends `body` as the stream yields it. Its length
+    /// isn't known upfront, so hyper sends it with chunked transfer encoding.
+    ///
+    /// A streamed body can't be sent again, so the request is sent once: it
+    /// isn't retried, redirected, or resent with a refreshed token, and fails
+    /// with the first error whatever the retry policy.
+    pub fn request_stream<S, ResponseT>(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        body: S,
+        condition: Condition,
+    ) -> impl Future<Item = Option<ResponseT>, Error = Error>
+    where
+        S: 'static + Stream + Send,
+        S::Error: Into<Box<dyn StdError + Send + Sync>>,
+        Chunk: From<S::Item>,
+        ResponseT: 'static + DeserializeOwned,
+    {
+        self.request_builder(method, path, query, condition)
+            .and_then(|mut req| {
+                let mut req = req.body(Body::wrap_stream(body)).context(ErrorKind::Http)?;
+                req.headers_mut()
+                    .typed_insert(&ContentType(mime::APPLICATION_JSON));
+
+                // add sas token
+                self.add_sas_token(&mut req, path)?;
+
+                Ok(req)
+            })
+            .map(|req| {
+                self.send(req, Error::service_error_response)
+                    .map(|(response, _)| response)
+            })
+            .into_future()
+            .flatten()
+    }
+
+    fn request_builder(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<HashMap<&str, &str>>,
+        condition: Condition,
+    ) -> Result<http::request::Builder, Error> {
+        // append api-version to the query string and url encode it
+        let query = query
+            .unwrap_or_else(HashMap::new)
+            .iter()
+            .fold(
+                UrlSerializer::new(String::new()).append_pair("api-version", &self.api_version),
+                |ser, (key, val)| ser.append_pair(key, val),
+            )
+            .finish();
+
+        // build the full url
+        let path_query = if let Some(ref base_path) = self.base_path {
+            format!("{}/{}?{}", base_path, path.trim_start_matches('/'), query)
+        } else {
+            format!("{}?{}", path, query)
+        };
+        let target = self.gateway_host.as_ref().unwrap_or(&self.host_name);
+        let base = self.host_override.as_ref().unwrap_or(target);
+        let url = base
+            .join(&path_query)
+            .with_context(|_| ErrorKind::UrlJoin(base.clone(), path_query))
+            .context(ErrorKind::Http)?;
+
+        let mut req = Request::builder();
+        req.method(method).uri(url.as_str());
+
+        // name the host being impersonated rather than the one connected to
+        if self.host_override.is_some() {
+            if let Some(host) = target.host_str() {
+                match target.port() {
+                    Some(port) => req.header(http::header::HOST, format!("{}:{}", host, port)),
+                    None => req.header(http::header::HOST, host),
+                };
+            }
+        }
+
+        // add user agent header
+        if let Some(ref user_agent) = self.user_agent {
+            req.header(http::header::USER_AGENT, &**user_agent);
+        }
+
+        // name the identity the request acts on behalf of
+        if let Some(ref on_behalf_of) = self.on_behalf_of {
+            req.header(ON_BEHALF_OF_HEADER, &**on_behalf_of);
+        }
+
+        // label the request for attribution only if asked to
+        if self.attribution_header {
+            if let Some(ref attribution) = self.attribution {
+                req.header(ATTRIBUTION_HEADER, &**attribution);
+            }
+        }
+
+        // make the request conditional if we've been asked to
+        if condition == Condition::Any && self.conditional_writes_required {
+            return Err(Error::from(ErrorKind::MissingEtag));
+        }
+        if let Some((name, value)) = condition.into_header()? {
+            req.header(name, value);
+        }
+
+        Ok(req)
+    }
+
+    fn send_with_retries<ResponseT>(
+        &self,
+        req: Request<Bytes>,
+        error_response: ErrorResponse,
+    ) -> impl Future<Item = (Option<ResponseT>, HeaderMap), Error = Error>
+    where
+        ResponseT: 'static + DeserializeOwned,
+    {
+        self.retrying(req, move |client, req| client.send(req, error_response))
+    }
+
+    // Sends `req` with `send`, following redirects and retrying it according
+    // to the retry policy until it succeeds or the policy gives up.
+    fn retrying<S, F>(
+        &self,
+        req: Request<Bytes>,
+        send: S,
+    ) -> impl Future<Item = F::Item, Error = Error>
+    where
+        S: Fn(&Self, Request<Body>) -> F,
+        F: Future<Error = Error>,
+    {
+        let policy = self.retry_policy.unwrap_or_default();
+        let client = self.clone();
+
+        future::loop_fn((req, Retries::default()), move |(mut req, retries)| {
+            let client = client.clone();
+            send(&client, copy_request(&req)).then(move |res| match res {
+                Ok(res) => Either::A(future::ok(Loop::Break(res))),
+                Err(err) => {
+                    if let ErrorKind::Redirect(_, location) = err.kind() {
+                        if retries.redirects() < client.max_redirects {
+                            return Either::A(
+                                client
+                                    .redirect(&mut req, location)
+                                    .map(|()| Loop::Continue((req, retries.with_redirect())))
+                                    .into_future(),
+                            );
+                        }
+                    }
+                    match client.next_retry(&err, &mut req, &policy, retries) {
+                        Ok(Some(retries)) => {
+                            debug!("Retrying failed request: {}", err);
+                            Either::B(Either::A(
+                                policy
+                                    .wait(&err, client.timer.as_ref())
+                                    .map(move |()| Loop::Continue((req, retries))),
+                            ))
+                        }
+                        Ok(None) => Either::B(Either::B(future::err(err))),
+                        Err(err) => Either::B(Either::B(future::err(err))),
+                    }
+                }
+            })
+        })
+    }
+
+    // Points `req` at the `location` it was redirected to. Headers naming or
+    // authenticating the original host aren't sent to another origin, which
+    // the request is signed for again instead.
+    fn redirect(&self, req: &mut Request<Bytes>, location: &str) -> Result<(), Error> {
+        let current = Url::parse(&req.uri().to_string())
+            .with_context(|_| ErrorKind::InvalidUrl(req.uri().to_string()))?;
+        let target = current
+            .join(location)
+            .with_context(|_| ErrorKind::InvalidUrl(location.to_string()))?;
+        debug!("Following redirect from {} to {}", current, target);
+
+        *req.uri_mut() = target
+            .as_str()
+            .parse::<Uri>()
+            .with_context(|_| ErrorKind::InvalidUrl(location.to_string()))?;
+        if target.origin() != current.origin() {
+            req.headers_mut().remove(http::header::AUTHORIZATION);
+            req.headers_mut().remove(http::header::HOST);
+            let expiry = Utc::now() + Duration::hours(1);
+            if let Some(token) = self.token_for(&target, &expiry, false)? {
+                set_sas_token(req, &token);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The token to sign a request to `url` with: one from the token source
+    // if `url` is on the host the client sends its requests to, otherwise
+    // one the source scopes to the host of `url`, if it can.
+    fn token_for(
+        &self,
+        url: &Url,
+        expiry: &DateTime<Utc>,
+        uncached: bool,
+    ) -> Result<Option<String>, Error> {
+        let source = match self.token_source {
+            Some(ref source) => source,
+            None => return Ok(None),
+        };
+        let target = self.gateway_host.as_ref().unwrap_or(&self.host_name);
+        let base = self.host_override.as_ref().unwrap_or(target);
+
+        let token = if url.origin() == base.origin() {
+            if uncached {
+                source.get_uncached(expiry).map(Some)
+            } else {
+                source.get(expiry).map(Some)
+            }
+        } else {
+            url.host_str()
+                .map_or(Ok(None), |host| source.get_for_host(host, expiry))
+        };
+        Ok(token.context(ErrorKind::TokenSource)?)
+    }
+
+    // Decides whether a request that failed with `err` is sent again. If
+    // it failed because its SAS token was rejected as expired the token is
+    // regenerated first, and if its connection was reset it is resent once
+    // whatever the retry policy. Other retries must also fit in the retry
+    // budget.
+    fn next_retry(
+        &self,
+        err: &Error,
+        req: &mut Request<Bytes>,
+        policy: &RetryPolicy,
+        retries: Retries,
+    ) -> Result<Option<Retries>, Error> {
+        if let ErrorKind::ClockSkew(_) = err.kind() {
+            if self.refresh_expired_tokens && !retries.token_refreshed() {
+                // the token a cache still holds would be rejected again, as
+                // would one expiring relative to the skewed clock
+                let now = err
+                    .service_error()
+                    .and_then(ServiceError::date)
+                    .unwrap_or_else(Utc::now);
+                let url = Url::parse(&req.uri().to_string())
+                    .with_context(|_| ErrorKind::InvalidUrl(req.uri().to_string()))?;
+                if let Some(token) = self.token_for(&url, &(now + Duration::hours(1)), true)? {
+                    debug!("SAS token was rejected as expired, retrying with a new token");
+                    set_sas_token(req, &token);
+                }
+                return Ok(Some(retries.with_token_refreshed()));
+            }
+        }
+
+        // usually a pooled connection the service closed while it was idle,
+        // which the request is resent on a new connection to get past
+        if err.is_connection_reset() && !retries.reset_retried() {
+            debug!("Connection was reset, retrying request: {}", err);
+            return Ok(Some(retries.with_reset_retried()));
+        }
+
+        let retries = policy.next(err, retries);
+        if retries.is_some()
+            && !self
+                .retry_budget
+                .as_ref()
+                .map_or(true, RetryBudget::try_acquire)
+        {
+            debug!(
+                "Retry budget exhausted, not retrying failed request: {}",
+                err
+            );
+            return Ok(None);
+        }
+        Ok(retries)
+    }
+
+    fn send<ResponseT>(
+        &self,
+        req: Request<Body>,
+        error_response: ErrorResponse,
+    ) -> impl Future<Item = (Option<ResponseT>, HeaderMap), Error = Error>
+    where
+        ResponseT: 'static + DeserializeOwned,
+    {
+        let timer = self.timer.clone();
+        let body_read_timeout = self.body_read_timeout;
+
+        self.send_streaming(req, error_response)
+            .and_then(move |resp| {
+                let (
+                    http::response::Parts {
+                        status, headers, ..
+                    },
+                    body,
+                ) = resp.into_parts();
+                let body = body
+                    .concat2()
+                    .map_err(|err| Error::from(err.context(ErrorKind::Http)));
+                within(
+                    body,
+                    timer.as_ref(),
+                    body_read_timeout,
+                    ErrorKind::BodyReadTimeout,
+                )
+                .map(move |body| (status, body, headers))
+            })
+            .and_then(|(status, body, headers)| {
+                // a 204 has no content even if a proxy sent some anyway, and
+                // some proxies send whitespace for an empty body
+                if status == StatusCode::NO_CONTENT || body.iter().all(u8::is_ascii_whitespace) {
+                    Ok((None, headers))
+                } else {
+                    Ok((Some(parse_json::<ResponseT>(&body)?), headers))
+                }
+            })
+    }
+
+    // Sends `req` and returns its response once a successful one arrives,
+    // without reading its body. The body of an error response is read to
+    // build the error from.
+    fn send_streaming(
+        &self,
+        req: Request<Body>,
+        error_response: ErrorResponse,
+    ) -> impl Future<Item = Response<Body>, Error = Error> {
+        // Nothing is taken from the rate limiter, the concurrency limit or
+        // the circuit breaker until the request is first polled, so a request
+        // that is built but never sent holds up no other.
+        let class = OperationClass::of(req.method(), req.uri().path());
+        let rate_limiter = self.rate_limiter.clone();
+        let rate_limit_timer = self.timer.clone();
+        let concurrency = self.concurrency.clone();
+        let circuit_breaker = self.circuit_breaker.clone();
+        let permit = future::lazy(move || {
+            // fail fast while the service is known to be unreachable
+            if circuit_breaker.as_ref().map(CircuitBreaker::state) == Some(CircuitState::Open) {
+                return Either::B(future::err(Error::from(ErrorKind::CircuitOpen)));
+            }
+
+            // wait for the rate of this class of operation to allow the request
+            let rate_limit = match rate_limiter {
+                Some(rate_limiter) => {
+                    Either::A(rate_limiter.acquire_on(class, rate_limit_timer.as_ref()))
+                }
+                None => Either::B(future::ok(())),
+            };
+
+            // wait for a slot if the number of requests in flight is limited
+            let permit = rate_limit.and_then(move |()| match concurrency {
+                Some(concurrency) => Either::A(concurrency.acquire().map(Some)),
+                None => Either::B(future::ok(None)),
+            });
+
+            // the attempt is only taken once the request is about to be sent,
+            // so that a trial request doesn't keep the circuit from letting
+            // others through while it waits its turn
+            Either::A(permit.and_then(move |permit| {
+                let attempt = match circuit_breaker.as_ref().map(CircuitBreaker::try_acquire) {
+                    Some(Ok(attempt)) => Some(attempt),
+                    Some(Err(err)) => return Err(err),
+                    None => None,
+                };
+                Ok((permit, attempt))
+            }))
+        });
+
+        let metrics = self.metrics_hook.clone().map(|metrics_hook| {
+            let metrics = RequestMetrics::new(
+                req.method().clone(),
+                req.uri().path().to_string(),
+                self.attribution.clone(),
+            )
+            .with_request_bytes(req.body().content_length());
+            (metrics_hook, metrics)
+        });
+
+        let follow_redirects = self.max_redirects > 0;
+        let inner = self.inner.clone();
+        let timer = self.timer.clone();
+        let body_timer = self.timer.clone();
+        let timeout = self.timeout;
+        let body_read_timeout = self.body_read_timeout;
+        permit
+            .and_then(move |(permit, attempt)| {
+                let resp = inner
+                    .call(req)
+                    .map_err(|err| Error::from(err.context(ErrorKind::Http)));
+                within(resp, timer.as_ref(), timeout, ErrorKind::Timeout).then(move |resp| {
+                    if let Some(attempt) = attempt {
+                        attempt.record(resp.is_ok());
+                    }
+                    if let Some(permit) = permit {
+                        permit.complete(resp.as_ref().ok().map(Response::status));
+                    }
+                    match (resp, metrics) {
+                        (Ok(resp), Some((metrics_hook, metrics))) => {
+                            let metrics = metrics.with_status(Some(resp.status()));
+                            Ok(resp.map(|body| metered_body(body, metrics_hook, metrics)))
+                        }
+                        (Err(err), Some((metrics_hook, metrics))) => {
+                            metrics_hook.record(&metrics);
+                            Err(err)
+                        }
+                        (resp, None) => resp,
+                    }
+                })
+            })
+            .and_then(move |resp| {
+                if resp.status().is_success() {
+                    return Either::A(future::ok(resp));
+                }
+
+                let (
+                    http::response::Parts {
+                        status, headers, ..
+                    },
+                    body,
+                ) = resp.into_parts();
+                let error_code = headers
+                    .get(IOTHUB_ERROR_CODE_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(ToString::to_string);
+                let body = body
+                    .concat2()
+                    .map_err(|err| Error::from(err.context(ErrorKind::Http)));
+                let err = within(
+                    body,
+                    body_timer.as_ref(),
+                    body_read_timeout,
+                    ErrorKind::BodyReadTimeout,
+                )
+                .and_then(move |body| -> Result<Response<Body>, Error> {
+                    if let Some(location) =
+                        redirect_location(status, &headers).filter(|_| follow_redirects)
+                    {
+                        return Err(Error::from(ErrorKind::Redirect(status, location)));
+                    }
+
+                    let err =
+                        error_response(status, error_code.as_ref().map(AsRef::as_ref), &*body);
+                    let err = match retry_after(&headers) {
+                        Some(retry_after) => err.with_retry_after(retry_after),
+                        None => err,
+                    };
+                    let err = match server_date(&headers) {
+                        Some(date) => err.with_date(date),
+                        None => err,
+                    };
+                    Err(match request_id(&headers) {
+                        Some(request_id) => err.with_request_id(request_id.to_string()),
+                        None => err,
+                    })
+                });
+                Either::B(err)
+            })
+    }
+}
+
+// IoT Hub rejects an unsupported API version with a message like "Invalid
+// protocol version: 0000-00-00. Supported API versions: 2016-02-03,
+// 2018-06-30", so the versions are the dates following "supported".
+fn listed_api_versions(message: &str) -> Vec<String> {
+    let start = match message.to_ascii_lowercase().find("supported") {
+        Some(start) => start,
+        None => return vec![],
+    };
+
+    let mut versions: Vec<String> = vec![];
+    for word in message[start..].split(|c: char| !c.is_ascii_alphanumeric() && c != '-') {
+        if is_api_version(word) && !versions.iter().any(|version| version == word) {
+            versions.push(word.to_string());
+        }
+    }
+    versions
+}
+
+// API versions are dates like `2018-06-30`, optionally suffixed like
+// `2015-08-15-preview`.
+fn is_api_version(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+        && (bytes.len() == 10 || bytes[10] == b'-')
+}
+
+// Response bodies are parsed with `simd-json` when that feature is enabled,
+// which is faster than `serde_json` on large listings.
+#[cfg(feature = "simd-json")]
+fn parse_json<T>(body: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    // simd-json parses in place, so it needs its own copy of the body
+    let mut body = body.to_vec();
+    simd_json::serde::from_slice(&mut body)
+        .context(ErrorKind::Http)
+        .map_err(Error::from)
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse_json<T>(body: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_slice(body)
+        .context(ErrorKind::Http)
+        .map_err(Error::from)
+}
+
+// A timer firing at `deadline`, on `timer` if there is one and otherwise on
+// the timer of the runtime it is polled on.
+fn delay(timer: Option<&TimerHandle>, deadline: Instant) -> Delay {
+    match timer {
+        Some(timer) => timer.delay(deadline),
+        None => Delay::new(deadline),
+    }
+}
+
+// Fails with `elapsed` if `future` hasn't completed within `timeout`.
+fn within<F>(
+    future: F,
+    timer: Option<&TimerHandle>,
+    timeout: Option<StdDuration>,
+    elapsed: ErrorKind,
+) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    match timeout {
+        Some(timeout) => {
+            let deadline = delay(timer, Instant::now() + timeout)
+                .map_err(|err| Error::from(err.context(ErrorKind::Http)));
+            Either::A(future.select2(deadline).then(|res| match res {
+                Ok(Either::A((item, _))) => Ok(item),
+                Ok(Either::B(((), _))) => Err(Error::from(elapsed)),
+                Err(Either::A((err, _))) | Err(Either::B((err, _))) => Err(err),
+            }))
+        }
+        None => Either::B(future),
+    }
+}
+
+// A streamed response body that fails with `ErrorKind::BodyReadTimeout` if
+// it hasn't ended by the time `deadline` fires.
+struct DeadlineBody {
+    body: Body,
+    deadline: Delay,
+}
+
+impl Stream for DeadlineBody {
+    type Item = Chunk;
+    type Error = Box<dyn StdError + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        if let Async::Ready(chunk) = self.body.poll()? {
+            return Ok(Async::Ready(chunk));
+        }
+
+        match self.deadline.poll()? {
+            Async::Ready(()) => Err(Box::new(Error::from(ErrorKind::BodyReadTimeout).compat())),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+fn copy_request(req: &Request<Bytes>) -> Request<Body> {
+    let mut copy = Request::new(Body::from(req.body().clone()));
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+// the location of a redirect that resends the request as it was
+fn redirect_location(status: StatusCode, headers: &HeaderMap) -> Option<String> {
+    match status {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => headers
+            .get(http::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string),
+        _ => None,
+    }
+}
+
+/// The ID the service gave a request in the `x-ms-request-id` header of its
+/// response, as returned by `Client::request_with_meta`. Azure support asks
+/// for it to investigate a request.
+pub fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn set_sas_token<B>(req: &mut Request<B>, token: &str) {
+    req.headers_mut().insert(
+        http::header::AUTHORIZATION,
+        format!("SharedAccessSignature {}", token).parse().unwrap(),
+    );
+}
+
+fn server_date(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    let value = headers.get(http::header::DATE)?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+// `Retry-After` is either a number of seconds or the date to retry after
+fn retry_after(headers: &HeaderMap) -> Option<StdDuration> {
+    let value = headers.get(http::header::RETRY_AFTER)?.to_str().ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(StdDuration::from_secs(seconds)),
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()?
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .ok(),
+    }
+}
+
+impl<C, T> Clone for Client<C, T>
+where
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        Client {
+            inner: self.inner.clone(),
+            token_source: self.token_source.clone(),
+            api_version: self.api_version.clone(),
+            host_name: self.host_name.clone(),
+            host_override: self.host_override.clone(),
+            gateway_host: self.gateway_host.clone(),
+            user_agent: self.user_agent.clone(),
+            on_behalf_of: self.on_behalf_of.clone(),
+            attribution: self.attribution.clone(),
+            attribution_header: self.attribution_header,
+            metrics_hook: self.metrics_hook.clone(),
+            base_path: self.base_path.clone(),
+            concurrency: self.concurrency.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            retry_policy: self.retry_policy,
+            retry_budget: self.retry_budget.clone(),
+            refresh_expired_tokens: self.refresh_expired_tokens,
+            conditional_writes_required: self.conditional_writes_required,
+            max_redirects: self.max_redirects,
+            timer: self.timer.clone(),
+            timeout: self.timeout,
+            body_read_timeout: self.body_read_timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hyper, request_id, AdaptiveConcurrency, Body, CachedTokenSource, Chunk, CircuitBreaker,
+        CircuitState, Client, Condition, Either, Error, Future, HeaderMap, HeaderMapExt,
+        HeaderValue, Method, OperationClass, RateLimiter, RequestMetrics, RetryBudget, RetryPolicy,
+        Stream, TimerHandle, TokenSource, Url,
+    };
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::str;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration as StdDuration, Instant};
+
+    use chrono::{DateTime, Duration, Utc};
+    use futures::{future, stream, task, Async, Poll};
+    use hyper::body::Payload;
+    use hyper::client::connect::{Connect, Connected, Destination};
+    use hyper::client::HttpConnector;
+    use hyper::service::{service_fn, service_fn_ok};
+    use hyper::{Client as HyperClient, Request, Response, Server, StatusCode};
+    use typed_headers::{mime, ContentType};
+    use url::form_urlencoded::parse as parse_query;
+
+    use crate::error::ErrorKind;
+
+    struct StaticTokenSource {
+        token: String,
+    }
+
+    impl StaticTokenSource {
+        pub fn new(token: String) -> Self {
+            StaticTokenSource { token }
+        }
+    }
+
+    impl TokenSource for StaticTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok(self.token.clone())
+        }
+    }
+
+    impl Clone for StaticTokenSource {
+        fn clone(&self) -> Self {
+            StaticTokenSource {
+                token: self.token.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_api_version_fails() {
+        let hyper_client = HyperClient::new();
+        let token_source: Option<StaticTokenSource> = None;
+        let api_version = "".to_string();
+        let client = Client::new(
+            hyper_client,
+            token_source,
+            api_version.clone(),
+            Url::parse("http://localhost").unwrap(),
+        );
+        match client {
+            Ok(_) => panic!("Expected error but got a result."),
+            Err(err) => {
+                if let ErrorKind::InvalidApiVersion(s) = err.kind() {
+                    assert_eq!(s, &api_version);
+                } else {
+                    panic!(
+                        "Wrong error kind. Expected `InvalidApiVersion` found {:?}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn white_space_api_version_fails() {
+        let hyper_client = HyperClient::new();
+        let token_source: Option<StaticTokenSource> = None;
+        let api_version = "      ".to_string();
+        let client = Client::new(
+            hyper_client,
+            token_source,
+            api_version.clone(),
+            Url::parse("http://localhost").unwrap(),
+        );
+        match client {
+            Ok(_) => panic!("Expected error but got a result."),
+            Err(err) => {
+                if let ErrorKind::InvalidApiVersion(s) = err.kind() {
+                    assert_eq!(s, &api_version);
+                } else {
+                    panic!(
+                        "Wrong error kind. Expected `InvalidApiVersion` found {:?}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn request_adds_api_version() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let api_version2 = api_version.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/boo");
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            // check that the query has api version_
+            let query_map: HashMap<String, String> =
+                parse_query(req.uri().query().unwrap().as_bytes())
+                    .into_owned()
+                    .collect();
+            assert_eq!(query_map.get("api-version"), Some(&api_version2));
+
+            future::ok(Response::new(response.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task =
+            client.request::<String, String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let _result: Option<String> = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn request_adds_api_version_with_other_query_params() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let api_version2 = api_version.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/boo");
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            // check that the query has api version
+            let query_map: HashMap<String, String> =
+                parse_query(req.uri().query().unwrap().as_bytes())
+                    .into_owned()
+                    .collect();
+            assert_eq!(query_map.get("api-version"), Some(&api_version2));
+            assert_eq!(query_map.get("k1"), Some(&"v1".to_string()));
+            assert_eq!(
+                query_map.get("k2"),
+                Some(&"this value has spaces and \u{1f42e}\u{1f42e}\u{1f42e}".to_string())
+            );
+
+            Ok(Response::new(response.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let mut query = HashMap::new();
+        query.insert("k1", "v1");
+        query.insert(
+            "k2",
+            "this value has spaces and \u{1f42e}\u{1f42e}\u{1f42e}",
+        );
+
+        let task = client.request::<String, String>(
+            Method::GET,
+            "/boo",
+            Some(query),
+            None,
+            Condition::None,
+        );
+
+        let _result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn request_adds_user_agent() {
+        let api_version = "2018-04-10".to_string();
+        let user_agent = "edgelet/request/test";
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(
+                user_agent,
+                &*req.headers().get(hyper::header::USER_AGENT).unwrap(),
+            );
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            Ok(Response::new(response.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_user_agent(user_agent);
+
+        let task =
+            client.request::<String, String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let _result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn request_adds_sas_token() {
+        let api_version = "2018-04-10".to_string();
+        let sas_token = "super_secret_password_y'all";
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+
+        let handler = move |req: Request<Body>| {
+            let sas_header = req.headers().get(hyper::header::AUTHORIZATION).unwrap();
+            let expected_sas = format!("SharedAccessSignature {}", sas_token);
+            assert_eq!(expected_sas, *sas_header);
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            Ok(Response::new(response.into()))
+        };
+        let token_source: Option<StaticTokenSource> =
+            Some(StaticTokenSource::new(sas_token.to_string()));
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task =
+            client.request::<String, String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let _result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn request_adds_if_match_header() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(
+                Some("*").map(AsRef::as_ref),
+                req.headers()
+                    .get(hyper::header::IF_MATCH)
+                    .map(AsRef::as_ref)
+            );
+
+            let mut response = Response::new(response.into());
+            response
+                .headers_mut()
+                .typed_insert(&ContentType(mime::APPLICATION_JSON));
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<String, _>(Method::GET, "/boo", None, None, Condition::Any);
+
+        let _result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn request_id_is_kept_on_success_and_failure() {
+        let handler = |req: Request<Body>| {
+            let status = if req.uri().path() == "/fails" {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::OK
+            };
+            Ok(Response::builder()
+                .status(status)
+                .header("x-ms-request-id", format!("id{}", req.uri().path()))
+                .body(Body::empty())
+                .unwrap())
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            handler,
+            token_source,
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let (_, headers) = runtime
+            .block_on(client.request_with_meta::<(), String>(
+                Method::GET,
+                "/succeeds",
+                None,
+                HeaderMap::new(),
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("id/succeeds"), request_id(&headers));
+
+        let err = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/fails",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(Some("id/fails"), err.request_id());
+    }
+
+    #[test]
+    fn error_response_keeps_retry_after() {
+        let handler = |_req: Request<Body>| {
+            Ok(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(hyper::header::RETRY_AFTER, "30")
+                .body(Body::empty())
+                .unwrap())
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            handler,
+            token_source,
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert!(err.is_throttled());
+        assert_eq!(Some(StdDuration::from_secs(30)), err.retry_after());
+    }
+
+    #[test]
+    fn request_adds_etag_condition_header() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(
+                Some("\"AAAA\""),
+                req.headers()
+                    .get(hyper::header::IF_MATCH)
+                    .map(|value| value.to_str().unwrap())
+            );
+            Ok(Response::new(r#""response""#.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_conditional_writes_required(true);
+
+        let task = client.request::<(), String>(
+            Method::DELETE,
+            "/boo",
+            None,
+            None,
+            Condition::IfMatch("AAAA".to_string()),
+        );
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn request_without_etag_fails_when_conditional_writes_are_required() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("An unconditional write should not be sent")
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_conditional_writes_required(true);
+
+        let task = client.request::<(), String>(Method::DELETE, "/boo", None, None, Condition::Any);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap_err();
+        assert_eq!(&ErrorKind::MissingEtag, err.kind());
+    }
+
+    #[test]
+    fn request_adds_body() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            req.into_body()
+                .concat2()
+                .and_then(|req_body| {
+                    str::from_utf8(&req_body)
+                        .map(move |req_body| {
+                            assert_eq!("\"Here be dragons\"".to_string(), req_body)
+                        })
+                        .map_err(|e| panic!("Error: {:?}", e))
+                })
+                .and_then(move |_| Ok(Response::new(response.into())))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<String, String>(
+            Method::POST,
+            "/boo",
+            None,
+            Some("Here be dragons".to_string()),
+            Condition::None,
+        );
+
+        let _result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn request_stream_sends_chunked_body() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            // hyper chunks a body of unknown length itself
+            assert_eq!(None, req.headers().get(hyper::header::CONTENT_LENGTH));
+            assert_eq!(None, req.headers().get(hyper::header::TRANSFER_ENCODING));
+            assert_eq!(None, req.body().content_length());
+
+            req.into_body()
+                .fold(Vec::new(), |mut chunks, chunk| {
+                    chunks.push(chunk.to_vec());
+                    Ok::<_, hyper::Error>(chunks)
+                })
+                .and_then(move |chunks| {
+                    assert_eq!(vec![b"[1".to_vec(), b",2".to_vec(), b"]".to_vec()], chunks);
+                    Ok(Response::new(response.into()))
+                })
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let body =
+            stream::iter_ok::<_, io::Error>(vec![b"[1".to_vec(), b",2".to_vec(), b"]".to_vec()]);
+        let task =
+            client.request_stream::<_, String>(Method::POST, "/boo", None, body, Condition::None);
+
+        let result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, "response");
+    }
+
+    #[test]
+    fn request_can_return_empty_response() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            req.into_body()
+                .concat2()
+                .and_then(|req_body| {
+                    str::from_utf8(&req_body)
+                        .map(move |req_body| {
+                            assert_eq!("\"Here be dragons\"".to_string(), req_body)
+                        })
+                        .map_err(|e| panic!("Error: {:?}", e))
+                })
+                .and_then(|_| Ok(Response::new(Body::empty())))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<String, _>(
+            Method::POST,
+            "/boo",
+            None,
+            Some("Here be dragons".to_string()),
+            Condition::None,
+        );
+
+        let result: Option<String> = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn request_returns_response() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            Ok(Response::new(response.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task =
+            client.request::<String, String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let result: String = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, "response");
+    }
+
+    #[test]
+    fn request_with_meta_exchanges_headers() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let response = r#""response""#;
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(
+                "token1",
+                req.headers()
+                    .get("x-ms-continuation")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            );
+
+            let response = Response::builder()
+                .header("x-ms-continuation", "token2")
+                .body(response.into())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-continuation", HeaderValue::from_static("token1"));
+        let task = client.request_with_meta::<(), String>(
+            Method::GET,
+            "/boo",
+            None,
+            headers,
+            None,
+            Condition::None,
+        );
+
+        let (result, headers) = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+        assert_eq!(Some("response".to_string()), result);
+        assert_eq!(
+            "token2",
+            headers.get("x-ms-continuation").unwrap().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn request_with_content_type_overrides_json() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(
+                "application/json-patch+json",
+                req.headers()[hyper::header::CONTENT_TYPE]
+            );
+
+            Ok(Response::new(r#""response""#.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request_with_content_type::<_, String>(
+            Method::PATCH,
+            "/boo",
+            None,
+            HeaderValue::from_static("application/json-patch+json"),
+            Some(vec!["op"]),
+            Condition::None,
+        );
+
+        let result = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+        assert_eq!(Some("response".to_string()), result);
+    }
+
+    #[test]
+    fn request_quota_exceeded_is_not_retryable() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(
+                    r#"{"Message":"ErrorCode:IotHubQuotaExceeded;Total number of messages on IotHub exceeded the allocated quota"}"#
+                        .into(),
+                )
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<(), String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::QuotaExceeded(StatusCode::TOO_MANY_REQUESTS, _) => (),
+            _ => panic!("Wrong error kind. Expected `QuotaExceeded` found {:?}", err),
+        }
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn supported_api_versions_parses_unsupported_version_error() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!("/statistics/service", req.uri().path());
+            let query_map: HashMap<String, String> =
+                parse_query(req.uri().query().unwrap().as_bytes())
+                    .into_owned()
+                    .collect();
+            assert_eq!(
+                Some("0000-00-00"),
+                query_map.get("api-version").map(AsRef::as_ref)
+            );
+
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(
+                    r#"{"Message":"ErrorCode:InvalidProtocolVersion;Invalid protocol version: 0000-00-00. Supported API versions: 2015-08-15-preview, 2016-02-03, 2018-06-30","ExceptionMessage":"Tracking ID:1234-G:0-TimeStamp:10/15/2026 10:00:00"}"#
+                        .into(),
+                )
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let versions = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.supported_api_versions())
+            .unwrap();
+        assert_eq!(
+            vec!["2015-08-15-preview", "2016-02-03", "2018-06-30"],
+            versions
+        );
+        assert_eq!("2018-04-10", client.api_version());
+    }
+
+    #[test]
+    fn supported_api_versions_fails_without_listed_versions() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(r#"{"Message":"ErrorCode:ArgumentInvalid;Bad request"}"#.into())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.supported_api_versions())
+            .unwrap_err();
+        assert_eq!(&ErrorKind::SupportedApiVersions, err.kind());
+    }
+
+    #[test]
+    fn request_rate_limited_is_retryable() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(
+                    r#"{"errorCode":429001,"message":"Throttling exception has occurred"}"#.into(),
+                )
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<(), String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::HttpWithErrorResponse(StatusCode::TOO_MANY_REQUESTS, _) => (),
+            _ => panic!(
+                "Wrong error kind. Expected `HttpWithErrorResponse` found {:?}",
+                err
+            ),
+        }
+        assert!(err.is_retryable());
+    }
+
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Item = ();
+        type Error = hyper::Error;
+
+        fn poll(&mut self) -> Poll<(), hyper::Error> {
+            if self.0 {
+                Ok(Async::Ready(()))
+            } else {
+                self.0 = true;
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_concurrency_backs_off_when_throttled() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight_copy = max_in_flight.clone();
+        let handler = move |_req: Request<Body>| {
+            let in_flight = in_flight.clone();
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight_copy.fetch_max(current, Ordering::SeqCst);
+
+            // let the other requests reach the handler before responding
+            YieldOnce(false).map(move |()| {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let status = if current > 2 {
+                    StatusCode::TOO_MANY_REQUESTS
+                } else {
+                    StatusCode::OK
+                };
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .expect("could not build hyper::Response")
+            })
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_adaptive_concurrency(AdaptiveConcurrency::new(4, 8));
+
+        let requests: Vec<_> = (0..4)
+            .map(|_| {
+                client
+                    .request::<(), String>(Method::GET, "/boo", None, None, Condition::None)
+                    .then(Ok::<_, Error>)
+            })
+            .collect();
+
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(future::join_all(requests))
+            .unwrap();
+
+        assert_eq!(4, max_in_flight.load(Ordering::SeqCst));
+        assert_eq!(2, results.iter().filter(|result| result.is_err()).count());
+        // both throttled requests were sent before the limit was halved
+        assert_eq!(2, client.adaptive_concurrency().unwrap().limit());
+        assert_eq!(0, client.adaptive_concurrency().unwrap().in_flight());
+    }
+
+    #[test]
+    fn adaptive_concurrency_limits_requests_in_flight() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight_copy = max_in_flight.clone();
+        let handler = move |_req: Request<Body>| {
+            let in_flight = in_flight.clone();
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight_copy.fetch_max(current, Ordering::SeqCst);
+
+            YieldOnce(false).map(move |()| {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .expect("could not build hyper::Response")
+            })
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_adaptive_concurrency(AdaptiveConcurrency::new(2, 2));
+
+        let requests: Vec<_> = (0..6)
+            .map(|_| {
+                client
+                    .request::<(), String>(Method::GET, "/boo", None, None, Condition::None)
+                    .then(Ok::<_, Error>)
+            })
+            .collect();
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(future::join_all(requests))
+            .unwrap();
+
+        assert_eq!(2, max_in_flight.load(Ordering::SeqCst));
+        assert_eq!(1, client.adaptive_concurrency().unwrap().limit());
+    }
+
+    #[test]
+    fn circuit_breaker_fails_fast_after_transport_failures() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let calls_copy = calls.clone();
+        let handler = move |_req: Request<Body>| {
+            calls_copy.fetch_add(1, Ordering::SeqCst);
+
+            // a body that fails to stream is the simplest way to produce a
+            // `hyper::Error` like the connector would
+            Body::wrap_stream(stream::once::<Chunk, _>(Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "connection refused",
+            ))))
+            .concat2()
+            .map(|_| Response::new(Body::empty()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_circuit_breaker(CircuitBreaker::new(3, StdDuration::from_secs(3600)));
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        for _ in 0..3 {
+            let err = runtime
+                .block_on(client.request::<(), String>(
+                    Method::GET,
+                    "/boo",
+                    None,
+                    None,
+                    Condition::None,
+                ))
+                .unwrap_err();
+            assert_eq!(&ErrorKind::Http, err.kind());
+        }
+        assert_eq!(
+            CircuitState::Open,
+            client.circuit_breaker().unwrap().state()
+        );
+
+        let err = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(&ErrorKind::CircuitOpen, err.kind());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn request_reads_error_code_header() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header("iothub-errorcode", "IotHubQuotaExceeded")
+                .body(Body::empty())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<(), String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::QuotaExceeded(StatusCode::FORBIDDEN, _) => (),
+            _ => panic!("Wrong error kind. Expected `QuotaExceeded` found {:?}", err),
+        }
+
+        let service_error = err.service_error().unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, service_error.status_code());
+        assert_eq!(Some("IotHubQuotaExceeded"), service_error.error_code());
+        assert_eq!("", service_error.message());
+    }
+
+    #[test]
+    fn request_prefers_error_code_header_over_body() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("iothub-errorcode", "ThrottlingException")
+                .body(r#"{"errorCode":403002,"message":"quota exceeded"}"#.into())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let task = client.request::<(), String>(Method::GET, "/boo", None, None, Condition::None);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::HttpWithErrorResponse(StatusCode::TOO_MANY_REQUESTS, _) => (),
+            _ => panic!(
+                "Wrong error kind. Expected `HttpWithErrorResponse` found {:?}",
+                err
+            ),
+        }
+        assert_eq!(
+            Some("ThrottlingException"),
+            err.service_error().unwrap().error_code()
+        );
+    }
+
+    struct FlakyConnector {
+        attempts: Arc<AtomicUsize>,
+        inner: HttpConnector,
+    }
+
+    impl Connect for FlakyConnector {
+        type Transport = <HttpConnector as Connect>::Transport;
+        type Error = io::Error;
+        type Future =
+            Box<dyn Future<Item = (Self::Transport, Connected), Error = Self::Error> + Send>;
+
+        fn connect(&self, dst: Destination) -> Self::Future {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::new(future::err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "connection refused",
+                )))
+            } else {
+                Box::new(self.inner.connect(dst))
+            }
+        }
+    }
+
+    struct CountingConnector {
+        connections: Arc<AtomicUsize>,
+        inner: HttpConnector,
+    }
+
+    impl Connect for CountingConnector {
+        type Transport = <HttpConnector as Connect>::Transport;
+        type Error = io::Error;
+        type Future = <HttpConnector as Connect>::Future;
+
+        fn connect(&self, dst: Destination) -> Self::Future {
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            self.inner.connect(dst)
+        }
+    }
+
+    #[test]
+    fn warmup_primes_connection_pool() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_copy = requests.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+            let requests = requests_copy.clone();
+            service_fn_ok(move |_req: Request<Body>| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                Response::new(Body::from(r#""pong""#))
+            })
+        });
+        let host_name = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        runtime.spawn(server.map_err(|err| panic!("{}", err)));
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connector = CountingConnector {
+            connections: connections.clone(),
+            inner: HttpConnector::new(1),
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::builder().build::<_, Body>(connector),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap();
+
+        runtime.block_on(client.warmup()).unwrap();
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+
+        let response = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("pong".to_string()), response);
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stalled_warmup_times_out() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(|| {
+            service_fn(|_req: Request<Body>| future::empty::<Response<Body>, hyper::Error>())
+        });
+        let host_name = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        runtime.spawn(server.map_err(|err| panic!("{}", err)));
+
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::builder().build::<_, Body>(HttpConnector::new(1)),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap()
+        .with_timeout(StdDuration::from_millis(50));
+
+        let err = runtime.block_on(client.warmup()).unwrap_err();
+        assert_eq!(&ErrorKind::Timeout, err.kind());
+    }
+
+    #[test]
+    fn warmup_does_nothing_without_connection_pooling() {
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("warmup should not send a request")
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            handler,
+            token_source,
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.warmup())
+            .unwrap();
+    }
+
+    #[test]
+    fn on_behalf_of_header_is_set_when_configured() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(
+                "child/$edgeHub",
+                req.headers()["x-ms-edge-moduleId"].to_str().unwrap()
+            );
+            Ok(Response::new(Body::from(r#""pong""#)))
+        };
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_on_behalf_of("child/$edgeHub");
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("pong".to_string()), response);
+    }
+
+    #[test]
+    fn on_behalf_of_header_is_absent_by_default() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(None, req.headers().get("x-ms-edge-moduleId"));
+            Ok(Response::new(Body::from(r#""pong""#)))
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            handler,
+            token_source,
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn shared_client_sends_concurrent_requests() {
+        fn assert_send_sync<S: Send + Sync>(_: &S) {}
+
+        let handler = |_req: Request<Body>| Ok(Response::new(Body::from(r#""pong""#)));
+        let recorded = Arc::new(AtomicUsize::new(0));
+        let recorded_copy = recorded.clone();
+        let client = Arc::new(
+            Client::new(
+                handler,
+                Some(StaticTokenSource::new("token".to_string())),
+                "2018-04-10".to_string(),
+                Url::parse("http://localhost").unwrap(),
+            )
+            .unwrap()
+            .with_metrics_hook(move |_metrics: &RequestMetrics| {
+                recorded_copy.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_adaptive_concurrency(AdaptiveConcurrency::new(2, 4)),
+        );
+        assert_send_sync(&client);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let requests = (0..25).map(|_| {
+                        client.request::<(), String>(
+                            Method::GET,
+                            "/ping",
+                            None,
+                            None,
+                            Condition::None,
+                        )
+                    });
+                    tokio::runtime::current_thread::Runtime::new()
+                        .unwrap()
+                        .block_on(future::join_all(requests))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            let responses = thread.join().unwrap();
+            assert!(responses
+                .iter()
+                .all(|response| response.as_ref().map(AsRef::as_ref) == Some("pong")));
+        }
+
+        assert_eq!(100, recorded.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn metrics_hook_receives_attribution() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(None, req.headers().get("x-ms-client-attribution"));
+            Ok(Response::new(Body::from(r#""pong""#)))
+        };
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let recorded_copy = recorded.clone();
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_metrics_hook(move |metrics: &RequestMetrics| {
+            recorded_copy.lock().unwrap().push(metrics.clone());
+        })
+        .with_attribution("tenant1");
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(1, recorded.len());
+        assert_eq!(&Method::GET, recorded[0].method());
+        assert_eq!("/ping", recorded[0].path());
+        assert_eq!(Some(StatusCode::OK), recorded[0].status());
+        assert_eq!(Some("tenant1"), recorded[0].attribution());
+    }
+
+    #[test]
+    fn metrics_hook_receives_body_sizes() {
+        let handler = |req: Request<Body>| {
+            let body = req.into_body().concat2().wait().unwrap();
+            assert_eq!(r#""ping""#, str::from_utf8(&body).unwrap());
+            Ok(Response::new(Body::from(r#""pong!""#)))
+        };
+        // bytes sent and received
+        let counters = Arc::new(Mutex::new((0, 0)));
+        let counters_copy = counters.clone();
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_metrics_hook(move |metrics: &RequestMetrics| {
+            let mut counters = counters_copy.lock().unwrap();
+            counters.0 += metrics.request_bytes().unwrap();
+            counters.1 += metrics.response_bytes().unwrap();
+        });
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        for _ in 0..2 {
+            runtime
+                .block_on(client.request::<String, String>(
+                    Method::POST,
+                    "/ping",
+                    None,
+                    Some("ping".to_string()),
+                    Condition::None,
+                ))
+                .unwrap();
+        }
+
+        // `"ping"` and `"pong!"` twice
+        assert_eq!((12, 14), *counters.lock().unwrap());
+    }
+
+    #[test]
+    fn attribution_header_is_set_when_enabled() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(
+                "tenant1",
+                req.headers()["x-ms-client-attribution"].to_str().unwrap()
+            );
+            Ok(Response::new(Body::from(r#""pong""#)))
+        };
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_attribution("tenant1")
+        .with_attribution_header(true);
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn host_override_keeps_logical_host_header() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(|| {
+            service_fn_ok(|req: Request<Body>| {
+                let host = req.headers()[hyper::header::HOST].to_str().unwrap();
+                Response::new(Body::from(format!("\"{}\"", host)))
+            })
+        });
+        let connect_to = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        runtime.spawn(server.map_err(|err| panic!("{}", err)));
+
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::new(),
+            token_source,
+            "2018-04-10".to_string(),
+            Url::parse("https://myhub.azure-devices.net").unwrap(),
+        )
+        .unwrap()
+        .with_host_override(connect_to);
+
+        let host = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("myhub.azure-devices.net".to_string()), host);
+    }
+
+    // Serves `"pong"`, except that the first `resets` connections are closed
+    // once the request has been read, without a response.
+    fn resetting_server(resets: usize) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host_name = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connections_copy = connections.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+
+                if connections_copy.fetch_add(1, Ordering::SeqCst) >= resets {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\n\r\n\"pong\"")
+                        .unwrap();
+                }
+            }
+        });
+
+        (host_name, connections)
+    }
+
+    #[test]
+    fn connection_reset_is_retried_once() {
+        let (host_name, connections) = resetting_server(1);
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::new(),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap();
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("pong".to_string()), response);
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn connection_reset_twice_fails() {
+        let (host_name, connections) = resetting_server(2);
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::new(),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert!(err.is_connection_reset());
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn connection_closed_by_server_is_not_reused() {
+        // answers every request with `Connection: close` but leaves the
+        // connection open, so a client that reused it would be served on it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host_name = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connections_copy = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                connections_copy.fetch_add(1, Ordering::SeqCst);
+                let mut stream = stream.unwrap();
+                thread::spawn(move || loop {
+                    let mut request = vec![];
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 6\r\n\r\n\"pong\"",
+                        )
+                        .unwrap();
+                });
+            }
+        });
+
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::new(),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap();
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        for _ in 0..2 {
+            let response = runtime
+                .block_on(client.request::<(), String>(
+                    Method::GET,
+                    "/ping",
+                    None,
+                    None,
+                    Condition::None,
+                ))
+                .unwrap();
+            assert_eq!(Some("pong".to_string()), response);
+        }
+        // hyper doesn't return a connection to its pool once the response on
+        // it said `Connection: close`, so the second request opened a new one
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn connect_failures_are_retried_separately() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(|| service_fn_ok(|_req: Request<Body>| Response::new(Body::from(r#""pong""#))));
+        let host_name = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        runtime.spawn(server.map_err(|err| panic!("{}", err)));
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connector = FlakyConnector {
+            attempts: attempts.clone(),
+            inner: HttpConnector::new(1),
+        };
+        let token_source: Option<StaticTokenSource> = None;
+        let client = Client::new(
+            HyperClient::builder().build::<_, Body>(connector),
+            token_source,
+            "2018-04-10".to_string(),
+            host_name,
+        )
+        .unwrap()
+        .with_retry_policy(RetryPolicy::new().with_connect_retries(1));
+
+        let response = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("pong".to_string()), response);
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn retryable_responses_resend_the_request() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let calls_copy = calls.clone();
+        let handler = move |req: Request<Body>| {
+            let body = req.into_body().concat2().wait().unwrap();
+            assert_eq!(r#""ping""#, str::from_utf8(&body).unwrap());
+
+            let status = if calls_copy.fetch_add(1, Ordering::SeqCst) == 0 {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            let mut response = Response::new(Body::from(r#""pong""#));
+            *response.status_mut() = status;
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new().with_request_retries(1));
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<String, String>(
+                Method::POST,
+                "/ping",
+                None,
+                Some("ping".to_string()),
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("pong".to_string()), response);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn retry_after_delay_lets_other_futures_run() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let calls_copy = calls.clone();
+        let handler = move |_req: Request<Body>| {
+            if calls_copy.fetch_add(1, Ordering::SeqCst) == 0 {
+                Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(hyper::header::RETRY_AFTER, "1")
+                    .body(Body::empty())
+                    .unwrap())
+            } else {
+                Ok(Response::new(Body::from(r#""pong""#)))
+            }
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new().with_request_retries(1));
+
+        // a timer firing early in the retry delay, which only fires on time
+        // if the delay doesn't block the thread
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let start = Instant::now();
+        let fired = Arc::new(Mutex::new(None));
+        let fired_copy = fired.clone();
+        runtime.spawn(
+            tokio::timer::Delay::new(start + StdDuration::from_millis(200))
+                .map(move |()| *fired_copy.lock().unwrap() = Some(Instant::now()))
+                .map_err(|_| ()),
+        );
+
+        let response = runtime
+            .block_on(client.request::<String, String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        let done = Instant::now();
+        assert_eq!(Some("pong".to_string()), response);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        assert!(done - start >= StdDuration::from_secs(1));
+
+        let fired = fired.lock().unwrap().expect("timer didn't fire");
+        assert!(done - fired >= StdDuration::from_millis(500));
+    }
+
+    #[test]
+    fn exhausted_retry_budget_fails_fast() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let calls_copy = calls.clone();
+        let handler = move |_req: Request<Body>| {
+            calls_copy.fetch_add(1, Ordering::SeqCst);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            Ok(response)
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new().with_request_retries(3))
+            .with_retry_budget(RetryBudget::new(1, StdDuration::from_secs(3600)));
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        // the first request is retried once, which uses up the budget
+        runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        let err = runtime
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/ping",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[derive(Debug, serde_derive::Deserialize, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct ListedModule {
+        module_id: String,
+        device_id: String,
+        etag: Option<String>,
+        cloud_to_device_message_count: usize,
+        authentication: serde_json::Value,
+    }
+
+    // Checks the response parsing backend, which is `simd-json` when that
+    // feature is enabled, against `serde_json` on a large module listing.
+    #[test]
+    fn request_parses_large_listing_like_serde_json() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let modules: Vec<_> = (0..5000)
+            .map(|i| {
+                serde_json::json!({
+                    "moduleId": format!("module-\u{e9}-{}", i),
+                    "deviceId": "d1",
+                    "etag": if i % 2 == 0 { Some(format!("\"AAAA{}\"", i)) } else { None },
+                    "cloudToDeviceMessageCount": i,
+                    "authentication": {
+                        "type": "sas",
+                        "symmetricKey": { "primaryKey": "a2V5\n", "secondaryKey": null },
+                    },
+                })
+            })
+            .collect();
+        let body = serde_json::to_string(&modules).unwrap();
+        let expected: Vec<ListedModule> = serde_json::from_str(&body).unwrap();
+
+        let handler = move |_req: Request<Body>| Ok(Response::new(Body::from(body.clone())));
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let listed = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), Vec<ListedModule>>(
+                Method::GET,
+                "/devices/d1/modules",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, listed);
+    }
+
+    #[derive(Debug, serde_derive::Deserialize, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct ValidationError {
+        field: String,
+        reason: String,
+    }
+
+    #[test]
+    fn request_typed_error_parses_error_body() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| {
+            Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(r#"{"field":"moduleId","reason":"too long"}"#.into())
+                .unwrap())
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request_typed_error::<(), String, ValidationError>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::TypedService(StatusCode::BAD_REQUEST, _) => (),
+            _ => panic!("Expected a typed service error, found {:?}", err),
+        }
+        assert_eq!(
+            Some(&ValidationError {
+                field: "moduleId".to_string(),
+                reason: "too long".to_string(),
+            }),
+            err.typed_service_error::<ValidationError>()
+        );
+    }
+
+    #[test]
+    fn with_base_path_prefixes_request_path() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |req: Request<Body>| {
+            assert_eq!("/iothub/devices/d1", req.uri().path());
+            Ok(Response::new(r#""response""#.into()))
+        };
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_base_path("/iothub/");
+        assert_eq!(Some("/iothub"), client.base_path());
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/devices/d1",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("response".to_string()), response);
+    }
+
+    #[derive(Clone)]
+    struct CountingTokenSource {
+        count: Arc<AtomicUsize>,
+    }
+
+    impl TokenSource for CountingTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok(format!("t{}", self.count.fetch_add(1, Ordering::SeqCst)))
+        }
+
+        fn get_for_host(
+            &self,
+            host: &str,
+            _expiry: &DateTime<Utc>,
+        ) -> Result<Option<String>, Error> {
+            let count = self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(format!("{} t{}", host, count)))
+        }
+    }
+
+    fn expired_first_token(
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        |req: Request<Body>| {
+            let token = req.headers().get(hyper::header::AUTHORIZATION).unwrap();
+            let response = if token == "SharedAccessSignature t0" {
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(hyper::header::DATE, "Tue, 15 Nov 1994 08:12:31 GMT")
+                    .body(r#"{"Message":"ErrorCode:IotHubUnauthorized;Unauthorized"}"#.into())
+                    .unwrap()
+            } else {
+                Response::new(r#""response""#.into())
+            };
+            Ok(response)
+        }
+    }
+
+    // Redirects from localhost to other.example, and then `hops` times
+    // within other.example, where requests must be signed with
+    // `other_host_token`.
+    fn redirecting_handler(
+        hops: usize,
+        hub_token: &'static str,
+        other_host_token: Option<&'static str>,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            let token = req
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .map(|token| token.to_str().unwrap().to_string());
+            let response = match req.uri().host() {
+                Some("localhost") => {
+                    assert_eq!(Some(hub_token), token.as_ref().map(AsRef::as_ref));
+                    Response::builder()
+                        .status(StatusCode::TEMPORARY_REDIRECT)
+                        .header(
+                            hyper::header::LOCATION,
+                            "http://other.example/hop1?api-version=2018-04-10",
+                        )
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                Some("other.example") => {
+                    // the hub's token is never sent to another host
+                    assert_eq!(other_host_token, token.as_ref().map(AsRef::as_ref));
+                    let hop: usize = req.uri().path()["/hop".len()..].parse().unwrap();
+                    if hop < hops {
+                        Response::builder()
+                            .status(StatusCode::PERMANENT_REDIRECT)
+                            .header(hyper::header::LOCATION, format!("/hop{}", hop + 1))
+                            .body(Body::empty())
+                            .unwrap()
+                    } else {
+                        Response::new(r#""redirected""#.into())
+                    }
+                }
+                host => panic!("unexpected host {:?}", host),
+            };
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn redirect_to_another_host_is_signed_for_it() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let token_source = CountingTokenSource {
+            count: count.clone(),
+        };
+        let client = Client::new(
+            redirecting_handler(
+                2,
+                "SharedAccessSignature t0",
+                Some("SharedAccessSignature other.example t1"),
+            ),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_max_redirects(3);
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("redirected".to_string()), response);
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn redirect_to_another_host_is_sent_unsigned_without_a_token_for_it() {
+        let client = Client::new(
+            redirecting_handler(2, "SharedAccessSignature hub", None),
+            Some(StaticTokenSource::new("hub".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_max_redirects(3);
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("redirected".to_string()), response);
+    }
+
+    #[test]
+    fn redirects_are_bounded() {
+        let token_source = CountingTokenSource {
+            count: Arc::new(AtomicUsize::new(0)),
+        };
+        let client = Client::new(
+            redirecting_handler(
+                5,
+                "SharedAccessSignature t0",
+                Some("SharedAccessSignature other.example t1"),
+            ),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_max_redirects(3);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::Redirect(StatusCode::PERMANENT_REDIRECT, "/hop4".to_string()),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn redirects_are_not_followed_by_default() {
+        let token_source = CountingTokenSource {
+            count: Arc::new(AtomicUsize::new(0)),
+        };
+        let client = Client::new(
+            redirecting_handler(0, "SharedAccessSignature t0", None),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::HttpWithErrorResponse(StatusCode::TEMPORARY_REDIRECT, String::new()),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn expired_token_is_reported_as_clock_skew() {
+        let token_source = CountingTokenSource {
+            count: Arc::new(AtomicUsize::new(0)),
+        };
+        let client = Client::new(
+            expired_first_token(),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::ClockSkew(_) => (),
+            _ => panic!("Expected a clock skew error, found {:?}", err),
+        }
+    }
+
+    #[test]
+    fn expired_token_is_refreshed_once() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let token_source = CountingTokenSource {
+            count: count.clone(),
+        };
+        let client = Client::new(
+            expired_first_token(),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_refresh_expired_tokens(true);
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("response".to_string()), response);
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+
+    #[derive(Clone)]
+    struct ExpiryTokenSource {
+        expiries: Arc<Mutex<Vec<DateTime<Utc>>>>,
+    }
+
+    impl TokenSource for ExpiryTokenSource {
+        type Error = Error;
+        fn get(&self, expiry: &DateTime<Utc>) -> Result<String, Error> {
+            let mut expiries = self.expiries.lock().unwrap();
+            expiries.push(*expiry);
+            Ok(format!("t{}", expiries.len() - 1))
+        }
+    }
+
+    #[test]
+    fn expired_token_is_refreshed_past_cache_for_server_clock() {
+        let expiries = Arc::new(Mutex::new(vec![]));
+        let token_source = CachedTokenSource::new(
+            ExpiryTokenSource {
+                expiries: expiries.clone(),
+            },
+            Duration::hours(1),
+            Duration::minutes(5),
+        );
+        let client = Client::new(
+            expired_first_token(),
+            Some(token_source),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_refresh_expired_tokens(true);
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::GET,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(Some("response".to_string()), response);
+
+        let server_date = DateTime::parse_from_rfc2822("Tue, 15 Nov 1994 08:12:31 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let expiries = expiries.lock().unwrap();
+        assert_eq!(2, expiries.len());
+        assert_eq!(server_date + Duration::hours(1), expiries[1]);
+    }
+
+    #[test]
+    fn request_treats_no_content_as_empty() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| {
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body("\r\n".into())
+                .unwrap())
+        };
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::DELETE,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(None, response);
+    }
+
+    #[test]
+    fn request_treats_whitespace_body_as_empty() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| Ok(Response::new(" \n".into()));
+        let client = Client::new(handler, token_source, api_version, host_name).unwrap();
+
+        let response = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), String>(
+                Method::PUT,
+                "/boo",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        assert_eq!(None, response);
+    }
+
+    #[test]
+    fn rate_limits_apply_per_operation_class() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| Ok(Response::new(Body::empty()));
+        let rate_limiter = RateLimiter::new().with_limit(
+            OperationClass::RegistryWrite,
+            1,
+            StdDuration::from_secs(3600),
+        );
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_rate_limiter(rate_limiter);
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime
+            .block_on(client.request::<(), ()>(
+                Method::PUT,
+                "/devices/d1",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+
+        // the write bucket is empty, but reads still go through
+        runtime
+            .block_on(client.request::<(), ()>(
+                Method::GET,
+                "/devices/d1",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+
+        let err = runtime
+            .block_on(tokio::timer::Timeout::new(
+                client.request::<(), ()>(Method::PUT, "/devices/d1", None, None, Condition::None),
+                StdDuration::from_millis(50),
+            ))
+            .unwrap_err();
+        assert!(err.is_elapsed());
+    }
+
+    #[test]
+    fn unsent_request_takes_no_rate_limit_token() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |_req: Request<Body>| Ok(Response::new(Body::empty()));
+        let rate_limiter = RateLimiter::new().with_limit(
+            OperationClass::RegistryWrite,
+            1,
+            StdDuration::from_secs(3600),
+        );
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_rate_limiter(rate_limiter);
+
+        let unsent =
+            client.request::<(), ()>(Method::PUT, "/devices/d1", None, None, Condition::None);
+
+        // the only write the bucket allows goes to the request that is sent
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(tokio::timer::Timeout::new(
+                client.request::<(), ()>(Method::PUT, "/devices/d1", None, None, Condition::None),
+                StdDuration::from_secs(5),
+            ))
+            .unwrap();
+        drop(unsent);
+    }
+
+    #[test]
+    fn trial_request_waiting_on_rate_limit_leaves_circuit_to_others() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let token_source: Option<StaticTokenSource> = None;
+
+        let handler = |req: Request<Body>| {
+            if req.uri().path() == "/unreachable" {
+                Either::A(
+                    Body::wrap_stream(stream::once::<Chunk, _>(Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "connection refused",
+                    ))))
+                    .concat2()
+                    .map(|_| Response::new(Body::empty())),
+                )
+            } else {
+                Either::B(future::ok(Response::new(Body::empty())))
+            }
+        };
+        let rate_limiter = RateLimiter::new().with_limit(
+            OperationClass::RegistryWrite,
+            1,
+            StdDuration::from_secs(3600),
+        );
+        let client = Client::new(handler, token_source, api_version, host_name)
+            .unwrap()
+            .with_circuit_breaker(CircuitBreaker::new(1, StdDuration::from_secs(0)))
+            .with_rate_limiter(rate_limiter.clone());
+
+        // the circuit opens, with its cooldown already over, and the write
+        // bucket is empty
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime
+            .block_on(client.request::<(), ()>(
+                Method::GET,
+                "/unreachable",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        runtime
+            .block_on(rate_limiter.acquire(OperationClass::RegistryWrite))
+            .unwrap();
+
+        // the write waits for the bucket without taking the trial, which
+        // goes to the read instead
+        let write =
+            client.request::<(), ()>(Method::PUT, "/devices/d1", None, None, Condition::None);
+        let read =
+            client.request::<(), ()>(Method::GET, "/devices/d1", None, None, Condition::None);
+        match runtime.block_on(write.select2(read)) {
+            Ok(Either::B(_)) => (),
+            _ => panic!("the read should be sent while the write waits"),
+        }
+        assert_eq!(
+            CircuitState::Closed,
+            client.circuit_breaker().unwrap().state()
+        );
+    }
+
+    #[test]
+    fn explicit_timer_times_out_request_outside_runtime() {
+        // the timer of a runtime the request isn't polled on
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let timer = runtime
+            .block_on(future::lazy(|| Ok::<_, ()>(TimerHandle::current())))
+            .unwrap();
+
+        let handler = |_req: Request<Body>| future::empty::<Response<Body>, hyper::Error>();
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_timer(timer)
+        .with_timeout(StdDuration::from_millis(50));
+        assert_eq!(Some(StdDuration::from_millis(50)), client.timeout());
+
+        let err = client
+            .request::<(), ()>(Method::GET, "/devices/d1", None, None, Condition::None)
+            .wait()
+            .unwrap_err();
+        assert_eq!(&ErrorKind::Timeout, err.kind());
+        assert!(err.is_timeout());
+
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn stalled_streamed_body_times_out() {
+        let handler = |_req: Request<Body>| {
+            let body = stream::once::<_, hyper::Error>(Ok(Chunk::from("[")))
+                .chain(stream::poll_fn(|| Ok(Async::NotReady)));
+            Ok::<_, hyper::Error>(Response::new(Body::wrap_stream(body)))
+        };
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_body_read_timeout(StdDuration::from_millis(50));
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let resp = runtime
+            .block_on(client.request_streaming::<()>(
+                Method::GET,
+                "/devices",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap();
+        let err = runtime.block_on(resp.into_body().concat2()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Response body did not arrive in time"));
+    }
+
+    #[test]
+    fn stalled_body_times_out() {
+        // the headers arrive at once, then the body stalls after its first chunk
+        let handler = |_req: Request<Body>| {
+            let body = stream::once::<_, hyper::Error>(Ok(Chunk::from("[")))
+                .chain(stream::poll_fn(|| Ok(Async::NotReady)));
+            Ok::<_, hyper::Error>(Response::new(Body::wrap_stream(body)))
+        };
+        let client = Client::new(
+            handler,
+            Some(StaticTokenSource::new("token".to_string())),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+        .with_timeout(StdDuration::from_secs(5))
+        .with_body_read_timeout(StdDuration::from_millis(50));
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), Vec<String>>(
+                Method::GET,
+                "/devices",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap_err();
+        assert_eq!(&ErrorKind::BodyReadTimeout, err.kind());
+        assert!(err.is_timeout());
+    }
+}
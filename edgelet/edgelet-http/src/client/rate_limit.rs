@@ -0,0 +1,179 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::future::{self, Either};
+use futures::Future;
+use hyper::Method;
+use tokio_timer::timer::Handle as TimerHandle;
+
+use super::delay;
+use crate::error::{Error, ErrorKind};
+
+/// The classes of operations that IoT Hub throttles separately.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OperationClass {
+    RegistryRead,
+    RegistryWrite,
+    Twin,
+    Method,
+}
+
+impl OperationClass {
+    pub fn of(method: &Method, path: &str) -> Self {
+        if path.contains("/methods") {
+            OperationClass::Method
+        } else if path.contains("/twins/") {
+            OperationClass::Twin
+        } else if *method == Method::GET || path.ends_with("/query") {
+            OperationClass::RegistryRead
+        } else {
+            OperationClass::RegistryWrite
+        }
+    }
+}
+
+/// Delays requests so that each class of operation stays within its own
+/// rate. Every class is a token bucket holding up to the number of requests
+/// allowed per period, and classes without a limit are never delayed.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<OperationClass, Bucket>>>,
+}
+
+struct Bucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    /// Allows `requests` operations of `class` every `period`, in bursts of
+    /// up to `requests`.
+    pub fn with_limit(self, class: OperationClass, requests: u32, period: Duration) -> Self {
+        let capacity = f64::from(requests.max(1));
+        self.lock().insert(
+            class,
+            Bucket {
+                capacity,
+                per_second: capacity / period.as_secs_f64(),
+                tokens: capacity,
+                updated: Instant::now(),
+            },
+        );
+        self
+    }
+
+    /// Waits until a request of `class` may be sent.
+    pub fn acquire(&self, class: OperationClass) -> impl Future<Item = (), Error = Error> {
+        self.acquire_on(class, None)
+    }
+
+    // Like `acquire`, but waits on `timer` if there is one.
+    pub(crate) fn acquire_on(
+        &self,
+        class: OperationClass,
+        timer: Option<&TimerHandle>,
+    ) -> impl Future<Item = (), Error = Error> {
+        let wait = self.reserve(class);
+        if wait == Duration::from_secs(0) {
+            Either::A(future::ok(()))
+        } else {
+            Either::B(
+                delay(timer, Instant::now() + wait)
+                    .map_err(|err| Error::from(err.context(ErrorKind::Http))),
+            )
+        }
+    }
+
+    // Takes a token from the bucket of `class`, going into debt if it is
+    // empty, and returns how long to wait until the debt is repaid.
+    fn reserve(&self, class: OperationClass) -> Duration {
+        self.lock()
+            .get_mut(&class)
+            .map_or_else(|| Duration::from_secs(0), Bucket::reserve)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<OperationClass, Bucket>> {
+        self.buckets
+            .lock()
+            .expect("Unable to lock the rate limiter mutex")
+    }
+}
+
+impl Bucket {
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.per_second;
+        self.updated = now;
+        self.tokens = (self.tokens + refill).min(self.capacity) - 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OperationClass, RateLimiter};
+
+    use std::time::Duration;
+
+    use hyper::Method;
+
+    #[test]
+    fn operations_are_classified_by_method_and_path() {
+        assert_eq!(
+            OperationClass::RegistryRead,
+            OperationClass::of(&Method::GET, "/devices/d1/modules")
+        );
+        assert_eq!(
+            OperationClass::RegistryRead,
+            OperationClass::of(&Method::POST, "/devices/query")
+        );
+        assert_eq!(
+            OperationClass::RegistryWrite,
+            OperationClass::of(&Method::PUT, "/devices/d1/modules/m1")
+        );
+        assert_eq!(
+            OperationClass::Twin,
+            OperationClass::of(&Method::PATCH, "/twins/d1/modules/m1")
+        );
+        assert_eq!(
+            OperationClass::Method,
+            OperationClass::of(&Method::POST, "/twins/d1/methods")
+        );
+    }
+
+    #[test]
+    fn buckets_are_independent() {
+        let limiter = RateLimiter::new()
+            .with_limit(OperationClass::RegistryWrite, 1, Duration::from_secs(3600))
+            .with_limit(OperationClass::RegistryRead, 1, Duration::from_secs(3600));
+
+        assert_eq!(
+            Duration::from_secs(0),
+            limiter.reserve(OperationClass::RegistryWrite)
+        );
+        assert!(limiter.reserve(OperationClass::RegistryWrite) > Duration::from_secs(3000));
+        assert_eq!(
+            Duration::from_secs(0),
+            limiter.reserve(OperationClass::RegistryRead)
+        );
+        assert_eq!(
+            Duration::from_secs(0),
+            limiter.reserve(OperationClass::Twin)
+        );
+    }
+}
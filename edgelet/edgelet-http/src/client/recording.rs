@@ -0,0 +1,400 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use failure::ResultExt;
+use futures::{future, Future, Stream};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::{self, Body, Request, Response, StatusCode};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::ClientImpl;
+use crate::error::{Error, ErrorKind};
+
+const REDACTED: &str = "***";
+
+// JSON body fields that carry credentials
+const SECRET_FIELDS: &[&str] = &[
+    "connectionString",
+    "primaryKey",
+    "primaryThumbprint",
+    "secondaryKey",
+    "secondaryThumbprint",
+];
+
+// headers describing a body that redaction may have changed
+const BODY_FRAMING_HEADERS: &[&str] = &["content-length", "transfer-encoding"];
+
+/// A request a `RecordingService` sent and the response it got, with
+/// credentials redacted.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Exchange {
+    method: String,
+    uri: String,
+    request_headers: Vec<(String, String)>,
+    request_body: String,
+    status: u16,
+    response_headers: Vec<(String, String)>,
+    response_body: String,
+}
+
+impl Exchange {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The path and query of the request.
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn request_headers(&self) -> &[(String, String)] {
+        &self.request_headers
+    }
+
+    pub fn request_body(&self) -> &str {
+        &self.request_body
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn response_headers(&self) -> &[(String, String)] {
+        &self.response_headers
+    }
+
+    pub fn response_body(&self) -> &str {
+        &self.response_body
+    }
+
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        self.method == req.method().as_str() && self.uri == path_and_query(req)
+    }
+
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.response_body.clone()));
+        *response.status_mut() =
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        for (name, value) in &self.response_headers {
+            if BODY_FRAMING_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Wraps a `ClientImpl` to write every request it sends and the response it
+/// gets to `writer`, one JSON `Exchange` per line, for `ReplayService` to
+/// serve later. The `Authorization` header and the keys, thumbprints and
+/// connection strings in JSON bodies are redacted. Bodies are buffered in
+/// full, so streamed requests are only sent once their stream has ended.
+pub struct RecordingService<C> {
+    inner: Arc<C>,
+    writer: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl<C> RecordingService<C> {
+    pub fn new<W>(inner: C, writer: W) -> Self
+    where
+        W: 'static + Write + Send,
+    {
+        RecordingService {
+            inner: Arc::new(inner),
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> ClientImpl for RecordingService<C>
+where
+    C: 'static + ClientImpl,
+{
+    type Response = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+    fn call(&self, req: Request<Body>) -> Self::Response {
+        let inner = self.inner.clone();
+        let writer = self.writer.clone();
+
+        let (parts, body) = req.into_parts();
+        let res = body.concat2().and_then(move |request_body| {
+            let request_body = request_body.into_bytes();
+            let req = Request::from_parts(parts, Body::from(request_body.clone()));
+            let method = req.method().to_string();
+            let uri = path_and_query(&req);
+            let request_headers = redacted_headers(req.headers());
+
+            inner.call(req).and_then(move |resp| {
+                let (parts, body) = resp.into_parts();
+                body.concat2().map(move |response_body| {
+                    let exchange = Exchange {
+                        method,
+                        uri,
+                        request_headers,
+                        request_body: redacted_body(&request_body),
+                        status: parts.status.as_u16(),
+                        response_headers: redacted_headers(&parts.headers),
+                        response_body: redacted_body(&response_body),
+                    };
+                    record(&writer, &exchange);
+
+                    Response::from_parts(parts, Body::from(response_body))
+                })
+            })
+        });
+
+        Box::new(res)
+    }
+}
+
+/// Serves the responses of recorded `Exchange`s, so that code can be run
+/// against what a service answered without reaching it. A request is
+/// answered with the first exchange with the same method, path and query
+/// that hasn't been served yet, or with the last such exchange once all have
+/// been. A request that was never recorded is answered with a 404.
+pub struct ReplayService {
+    exchanges: Mutex<Vec<(Exchange, bool)>>,
+}
+
+impl ReplayService {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        ReplayService {
+            exchanges: Mutex::new(
+                exchanges
+                    .into_iter()
+                    .map(|exchange| (exchange, false))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Reads exchanges in the format `RecordingService` writes them.
+    pub fn from_reader<R>(reader: R) -> Result<Self, Error>
+    where
+        R: BufRead,
+    {
+        let mut exchanges = Vec::new();
+        for line in reader.lines() {
+            let line = line.context(ErrorKind::Recording)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            exchanges.push(serde_json::from_str(&line).context(ErrorKind::Recording)?);
+        }
+        Ok(ReplayService::new(exchanges))
+    }
+
+    fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        let mut exchanges = self
+            .exchanges
+            .lock()
+            .expect("Unable to lock the replayed exchanges mutex");
+
+        let next = exchanges
+            .iter()
+            .position(|(exchange, served)| !served && exchange.matches(req))
+            .or_else(|| {
+                exchanges
+                    .iter()
+                    .rposition(|(exchange, _)| exchange.matches(req))
+            });
+        if let Some(index) = next {
+            exchanges[index].1 = true;
+            exchanges[index].0.to_response()
+        } else {
+            let mut response = Response::new(Body::from(format!(
+                "No recorded response for {} {}",
+                req.method(),
+                path_and_query(req)
+            )));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+impl ClientImpl for ReplayService {
+    type Response = future::FutureResult<Response<Body>, hyper::Error>;
+
+    fn call(&self, req: Request<Body>) -> Self::Response {
+        future::ok(self.respond(&req))
+    }
+}
+
+fn path_and_query<B>(req: &Request<B>) -> String {
+    req.uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path().to_string(), ToString::to_string)
+}
+
+fn record(writer: &Mutex<dyn Write + Send>, exchange: &Exchange) {
+    let mut writer = writer
+        .lock()
+        .expect("Unable to lock the recording writer mutex");
+    let res = serde_json::to_writer(&mut *writer, exchange)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writer.write_all(b"\n"))
+        .and_then(|()| writer.flush());
+    if let Err(err) = res {
+        warn!("Could not record exchange: {}", err);
+    }
+}
+
+fn redacted_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let authorization = AUTHORIZATION;
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if *name == authorization {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn redacted_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact_fields(&mut value);
+            value.to_string()
+        }
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn redact_fields(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&name.as_str()) && !field.is_null() {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact_fields(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_fields),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordingService, ReplayService};
+
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use hyper::{Body, Method, Request, Response};
+    use serde_json::{json, Value};
+    use url::Url;
+
+    use crate::client::{Client, ClientImpl, Condition, TokenSource};
+    use crate::error::Error;
+
+    #[derive(Clone)]
+    struct StaticTokenSource;
+
+    impl TokenSource for StaticTokenSource {
+        type Error = Error;
+
+        fn get(&self, _expiry: &chrono::DateTime<chrono::Utc>) -> Result<String, Error> {
+            Ok("secret-token".to_string())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn get_module<C>(inner: C) -> Value
+    where
+        C: ClientImpl,
+    {
+        let client = Client::new(
+            inner,
+            Some(StaticTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(client.request::<(), Value>(
+                Method::GET,
+                "/devices/d1/modules/m1",
+                None,
+                None,
+                Condition::None,
+            ))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn recorded_exchange_replays_same_result() {
+        let handler = |_req: Request<Body>| {
+            Ok::<_, hyper::Error>(Response::new(Body::from(
+                json!({
+                    "moduleId": "m1",
+                    "authentication": { "symmetricKey": { "primaryKey": "secret-key" } },
+                })
+                .to_string(),
+            )))
+        };
+        let recording = SharedBuffer::default();
+
+        let recorded = get_module(RecordingService::new(handler, recording.clone()));
+
+        let recording = recording.0.lock().unwrap().clone();
+        let text = String::from_utf8(recording.clone()).unwrap();
+        assert!(!text.contains("secret-token"));
+        assert!(!text.contains("secret-key"));
+
+        let replayed = get_module(ReplayService::from_reader(&recording[..]).unwrap());
+        assert_eq!("m1", replayed["moduleId"]);
+        assert_eq!(recorded["moduleId"], replayed["moduleId"]);
+        assert_eq!(
+            "***",
+            replayed["authentication"]["symmetricKey"]["primaryKey"]
+        );
+    }
+
+    #[test]
+    fn unrecorded_request_is_not_found() {
+        let replay = ReplayService::new(Vec::new());
+        let response = replay.respond(
+            &Request::get("/devices/d1?api-version=2018-04-10")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::future::{self, Either};
+use futures::Future;
+use log::debug;
+use tokio_timer::timer::Handle as TimerHandle;
+
+use super::delay;
+use crate::error::{Error, ErrorKind};
+
+const DEFAULT_MAX_DELAY_SECS: u64 = 300;
+
+/// How often a `Client` resends a request that failed. Failures to resolve
+/// or connect to the server happen before anything was sent, so they are
+/// counted separately from requests that reached the service and failed
+/// with a retryable status.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    connect_retries: u32,
+    request_retries: u32,
+    backoff: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            connect_retries: 0,
+            request_retries: 0,
+            backoff: Duration::from_secs(0),
+            max_delay: Duration::from_secs(DEFAULT_MAX_DELAY_SECS),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Retries {
+    connect: u32,
+    request: u32,
+    token_refreshed: bool,
+    reset_retried: bool,
+    redirects: u32,
+}
+
+impl Retries {
+    pub(crate) fn token_refreshed(self) -> bool {
+        self.token_refreshed
+    }
+
+    pub(crate) fn with_token_refreshed(self) -> Self {
+        Retries {
+            token_refreshed: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn reset_retried(self) -> bool {
+        self.reset_retried
+    }
+
+    pub(crate) fn with_reset_retried(self) -> Self {
+        Retries {
+            reset_retried: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn redirects(self) -> u32 {
+        self.redirects
+    }
+
+    pub(crate) fn with_redirect(self) -> Self {
+        Retries {
+            redirects: self.redirects + 1,
+            ..self
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        RetryPolicy::default()
+    }
+
+    pub fn with_connect_retries(mut self, connect_retries: u32) -> Self {
+        self.connect_retries = connect_retries;
+        self
+    }
+
+    pub fn with_request_retries(mut self, request_retries: u32) -> Self {
+        self.request_retries = request_retries;
+        self
+    }
+
+    /// Time to wait before every retry, unless the failed response asks
+    /// for a longer wait with `Retry-After`.
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The longest wait before a retry. A request whose failed response asks
+    /// for a longer wait with `Retry-After` fails instead of being retried.
+    /// Defaults to 5 minutes.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn connect_retries(&self) -> u32 {
+        self.connect_retries
+    }
+
+    pub fn request_retries(&self) -> u32 {
+        self.request_retries
+    }
+
+    pub fn backoff(&self) -> Duration {
+        self.backoff
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// Returns the retries made once `err` is retried, or `None` if it
+    /// shouldn't be.
+    pub(crate) fn next(&self, err: &Error, retries: Retries) -> Option<Retries> {
+        let retries = if err.is_connect() && retries.connect < self.connect_retries {
+            Retries {
+                connect: retries.connect + 1,
+                ..retries
+            }
+        } else if !err.is_connect() && err.is_retryable() && retries.request < self.request_retries
+        {
+            Retries {
+                request: retries.request + 1,
+                ..retries
+            }
+        } else {
+            return None;
+        };
+
+        let delay = self.delay(err);
+        if delay > self.max_delay {
+            debug!(
+                "Not retrying request that asked for a wait of {:?}, longer than {:?}",
+                delay, self.max_delay
+            );
+            return None;
+        }
+
+        Some(retries)
+    }
+
+    // Waits before retrying a request that failed with `err`. The wait is
+    // a timer on the reactor rather than a sleep, so the other futures on
+    // the thread keep running meanwhile.
+    pub(crate) fn wait(
+        &self,
+        err: &Error,
+        timer: Option<&TimerHandle>,
+    ) -> impl Future<Item = (), Error = Error> {
+        let wait = self.delay(err);
+        if wait == Duration::from_secs(0) {
+            Either::A(future::ok(()))
+        } else {
+            Either::B(
+                delay(timer, Instant::now() + wait)
+                    .map_err(|err| Error::from(err.context(ErrorKind::Http))),
+            )
+        }
+    }
+
+    fn delay(&self, err: &Error) -> Duration {
+        err.retry_after().map_or(self.backoff, |retry_after| {
+            cmp::max(self.backoff, retry_after)
+        })
+    }
+}
+
+/// Caps the retries of every request sharing it, so that many requests
+/// failing at once don't resend in a storm against a recovering service. It
+/// is a token bucket holding up to the number of retries allowed per period,
+/// and a request whose retry finds it empty fails instead. Clones share the
+/// same bucket.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+    bucket: Arc<Mutex<BudgetBucket>>,
+}
+
+#[derive(Debug)]
+struct BudgetBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RetryBudget {
+    /// Allows `retries` retries every `period`, in bursts of up to
+    /// `retries`.
+    pub fn new(retries: u32, period: Duration) -> Self {
+        let capacity = f64::from(retries);
+        RetryBudget {
+            bucket: Arc::new(Mutex::new(BudgetBucket {
+                capacity,
+                per_second: capacity / period.as_secs_f64(),
+                tokens: capacity,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a retry from the budget, or returns `false` if it is exhausted.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut bucket = self
+            .bucket
+            .lock()
+            .expect("Unable to lock the retry budget mutex");
+
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * bucket.per_second;
+        bucket.updated = now;
+        bucket.tokens = (bucket.tokens + refill).min(bucket.capacity);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retries, RetryBudget, RetryPolicy};
+
+    use std::time::Duration;
+
+    use hyper::StatusCode;
+
+    use crate::error::Error;
+
+    #[test]
+    fn request_retries_are_limited() {
+        let policy = RetryPolicy::new()
+            .with_connect_retries(3)
+            .with_request_retries(1);
+        let err = Error::http_with_error_response(StatusCode::SERVICE_UNAVAILABLE, &b""[..]);
+
+        let retries = policy.next(&err, Retries::default()).unwrap();
+        assert!(policy.next(&err, retries).is_none());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let policy = RetryPolicy::new().with_request_retries(1);
+        let err = Error::http_with_error_response(StatusCode::BAD_REQUEST, &b""[..]);
+
+        assert!(policy.next(&err, Retries::default()).is_none());
+    }
+
+    #[test]
+    fn waits_longer_than_max_delay_are_not_retried() {
+        let policy = RetryPolicy::new()
+            .with_request_retries(1)
+            .with_max_delay(Duration::from_secs(60));
+        let err = Error::http_with_error_response(StatusCode::TOO_MANY_REQUESTS, &b""[..]);
+
+        let within = err.with_retry_after(Duration::from_secs(60));
+        assert!(policy.next(&within, Retries::default()).is_some());
+
+        let beyond = Error::http_with_error_response(StatusCode::TOO_MANY_REQUESTS, &b""[..])
+            .with_retry_after(Duration::from_secs(3600));
+        assert!(policy.next(&beyond, Retries::default()).is_none());
+        assert_eq!(Duration::from_secs(300), RetryPolicy::new().max_delay());
+    }
+
+    #[test]
+    fn retry_budget_is_shared_between_clones() {
+        let budget = RetryBudget::new(2, Duration::from_secs(3600));
+        let clone = budget.clone();
+
+        assert!(budget.try_acquire());
+        assert!(clone.try_acquire());
+        assert!(!budget.try_acquire());
+        assert!(!clone.try_acquire());
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::TokenSource;
+
+// A token handed out and the time it expires at.
+type CachedToken = (String, DateTime<Utc>);
+
+/// Caches the tokens of a `TokenSource` that is expensive to call, such as
+/// one acquiring tokens from an identity provider. Tokens are requested to
+/// last `lifetime`, whatever expiry the client asks for, and are reused until
+/// they are within `refresh_margin` of expiring. Clones share the cache.
+///
+/// The cache stays locked while a token is refreshed, so concurrent requests
+/// that find the token expiring wait for that one refresh instead of each
+/// calling the source.
+#[derive(Clone)]
+pub struct CachedTokenSource<T> {
+    source: T,
+    lifetime: Duration,
+    refresh_margin: Duration,
+    cached: Arc<Mutex<Option<CachedToken>>>,
+}
+
+impl<T> CachedTokenSource<T> {
+    pub fn new(source: T, lifetime: Duration, refresh_margin: Duration) -> Self {
+        CachedTokenSource {
+            source,
+            lifetime,
+            refresh_margin,
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn source(&self) -> &T {
+        &self.source
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn refresh_margin(&self) -> Duration {
+        self.refresh_margin
+    }
+}
+
+impl<T> TokenSource for CachedTokenSource<T>
+where
+    T: TokenSource,
+{
+    type Error = T::Error;
+
+    fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Self::Error> {
+        let mut cached = self
+            .cached
+            .lock()
+            .expect("Unable to lock the token cache mutex");
+
+        if let Some((ref token, expiry)) = *cached {
+            if Utc::now() + self.refresh_margin < expiry {
+                return Ok(token.clone());
+            }
+        }
+
+        let expiry = Utc::now() + self.lifetime;
+        let token = self.source.get(&expiry)?;
+        *cached = Some((token.clone(), expiry));
+        Ok(token)
+    }
+
+    // The token replaces none in the cache, since it was asked for with a
+    // particular expiry rather than the cache's lifetime.
+    fn get_uncached(&self, expiry: &DateTime<Utc>) -> Result<String, Self::Error> {
+        self.source.get_uncached(expiry)
+    }
+
+    // Tokens for other hosts are rare enough not to be worth caching.
+    fn get_for_host(
+        &self,
+        host: &str,
+        expiry: &DateTime<Utc>,
+    ) -> Result<Option<String>, Self::Error> {
+        self.source.get_for_host(host, expiry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CachedTokenSource;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration as StdDuration;
+
+    use chrono::{DateTime, Duration, Utc};
+
+    use crate::client::TokenSource;
+    use crate::error::Error;
+
+    #[derive(Clone)]
+    struct CountingTokenSource {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TokenSource for CountingTokenSource {
+        type Error = Error;
+
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            // slow enough for concurrent callers to pile up on a refresh
+            thread::sleep(StdDuration::from_millis(20));
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(format!("token{}", calls))
+        }
+    }
+
+    fn counting_source() -> (CountingTokenSource, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source = CountingTokenSource {
+            calls: calls.clone(),
+        };
+        (source, calls)
+    }
+
+    #[test]
+    fn concurrent_requests_share_one_refresh() {
+        let (source, calls) = counting_source();
+        let source = CachedTokenSource::new(source, Duration::hours(1), Duration::minutes(5));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let source = source.clone();
+                thread::spawn(move || source.get(&Utc::now()).unwrap())
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!("token1", thread.join().unwrap());
+        }
+
+        assert_eq!("token1", source.get(&Utc::now()).unwrap());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn expired_token_is_refreshed() {
+        let (source, calls) = counting_source();
+        let source = CachedTokenSource::new(source, Duration::milliseconds(200), Duration::zero());
+
+        assert_eq!("token1", source.get(&Utc::now()).unwrap());
+        assert_eq!("token1", source.get(&Utc::now()).unwrap());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        thread::sleep(StdDuration::from_millis(250));
+        assert_eq!("token2", source.get(&Utc::now()).unwrap());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+}
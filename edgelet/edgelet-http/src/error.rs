@@ -1,21 +1,65 @@
 // Copyright (c) Microsoft. All rights reserved.
 
+use std::any::Any;
+use std::error::Error as StdError;
 use std::fmt::{self, Display};
+use std::io;
 use std::net::SocketAddr;
 use std::str;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use failure::{Backtrace, Compat, Context, Fail};
 use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
 use hyper::{Body, Response, StatusCode, Uri};
-use serde_json::json;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use systemd::Fd;
 use url::Url;
 
 use crate::IntoResponse;
 
+// how hyper describes a connection closed before the response was complete
+const INCOMPLETE_MESSAGE: &str = "connection closed before message completed";
+
+// IoT Hub error codes of failures that clear by themselves
+const TRANSIENT_ERROR_CODES: &[&str] = &[
+    "GatewayTimeout",
+    "InternalServerError",
+    "ServerBusy",
+    "ServerError",
+    "ServiceUnavailable",
+    "ThrottleBacklogLimitExceeded",
+    "ThrottlingBacklogTimeout",
+    "ThrottlingException",
+];
+
+// IoT Hub error codes of failures that retrying the same request won't fix
+const PERMANENT_ERROR_CODES: &[&str] = &[
+    "ArgumentInvalid",
+    "ArgumentNull",
+    "BadRequest",
+    "DeviceAlreadyExists",
+    "DeviceNotFound",
+    "IotHubNotFound",
+    "IotHubQuotaExceeded",
+    "ModuleAlreadyExistsOnDevice",
+    "ModuleNotFound",
+    "PreconditionFailed",
+];
+
+// IoT Hub error codes of a rejected SAS token, which was rejected as expired
+// only if the message says so or the clocks disagree
+const TOKEN_EXPIRED_ERROR_CODES: &[&str] = &["IotHubUnauthorized", "401003"];
+
+// how far, in seconds, the service's clock may be from ours before a rejected
+// token is taken to have expired by its clock
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 5 * 60;
+
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
+    service_error: Option<ServiceError>,
 }
 
 #[derive(Debug, Fail, PartialEq)]
@@ -26,6 +70,9 @@ pub enum ErrorKind {
     #[fail(display = "An error occurred while binding a listener to {}", _0)]
     BindListener(BindListenerType),
 
+    #[fail(display = "Response body did not arrive in time")]
+    BodyReadTimeout,
+
     #[fail(display = "Unable to delete a TLS certificate")]
     CertificateDeletionError,
 
@@ -44,6 +91,15 @@ pub enum ErrorKind {
     #[fail(display = "A valid certificate was not found")]
     CertificateNotFound,
 
+    #[fail(display = "The circuit breaker is open, so the request was not sent")]
+    CircuitOpen,
+
+    #[fail(
+        display = "IoT Hub rejected the SAS token as expired. This usually means the device clock is wrong, so check that it is synchronized: {}",
+        _0
+    )]
+    ClockSkew(String),
+
     #[fail(display = "Could not perform HTTP request")]
     Http,
 
@@ -81,12 +137,18 @@ pub enum ErrorKind {
         path: String,
     },
 
+    #[fail(display = "Conditional writes are required, but the request has no ETag to match")]
+    MissingEtag,
+
     #[fail(display = "Module not found")]
     ModuleNotFound(String),
 
     #[fail(display = "An error occurred for path {}", _0)]
     Path(String),
 
+    #[fail(display = "IoT Hub quota exceeded: [{}] {}", _0, _1)]
+    QuotaExceeded(StatusCode, String),
+
     #[fail(display = "An error occurred with the proxy {}", _0)]
     Proxy(Uri),
 
@@ -96,9 +158,21 @@ pub enum ErrorKind {
     )]
     PKCS12Identity(String),
 
+    #[fail(display = "Could not read request recording")]
+    Recording,
+
+    #[fail(display = "Request was redirected with status {} to {}", _0, _1)]
+    Redirect(StatusCode, String),
+
     #[fail(display = "An error occurred in the service")]
     ServiceError,
 
+    #[fail(display = "Could not determine the API versions the service supports")]
+    SupportedApiVersions,
+
+    #[fail(display = "Request timed out")]
+    Timeout,
+
     #[fail(display = "An error occurred configuring the TLS stack")]
     TlsBootstrapError,
 
@@ -111,11 +185,17 @@ pub enum ErrorKind {
     #[fail(display = "Could not parse trust bundle")]
     TrustBundle,
 
+    #[fail(display = "HTTP request failed: [{}] {}", _0, _1)]
+    TypedService(StatusCode, TypedServiceError),
+
     #[fail(
         display = "Could not form well-formed URL by joining {:?} with {:?}",
         _0, _1
     )]
     UrlJoin(Url, String),
+
+    #[fail(display = "Could not connect to the service ahead of requests")]
+    Warmup,
 }
 
 impl Fail for Error {
@@ -140,29 +220,268 @@ impl Error {
     }
 
     pub fn http_with_error_response(status_code: StatusCode, body: &[u8]) -> Self {
-        let kind = match str::from_utf8(body) {
-            Ok(body) => ErrorKind::HttpWithErrorResponse(status_code, body.to_string()),
-            Err(_) => ErrorKind::HttpWithErrorResponse(
+        Error::service_error_response(status_code, None, body)
+    }
+
+    /// Builds the error for a failed response. The `error_code` reported in
+    /// the `iothub-errorcode` response header, if any, takes precedence over
+    /// the one parsed from the body.
+    pub fn service_error_response(
+        status_code: StatusCode,
+        error_code: Option<&str>,
+        body: &[u8],
+    ) -> Self {
+        let message = match str::from_utf8(body) {
+            Ok(body) => body.to_string(),
+            Err(_) => "<could not parse response body as utf-8>".to_string(),
+        };
+        let error_code = error_code
+            .map(ToString::to_string)
+            .or_else(|| service_error_code(&message));
+        let service_error = ServiceError {
+            status_code,
+            error_code,
+            message: message.clone(),
+            retry_after: None,
+            request_id: None,
+            date: None,
+        };
+
+        let kind = if service_error.is_quota_exceeded() {
+            ErrorKind::QuotaExceeded(status_code, message)
+        } else if service_error.is_token_expired() {
+            ErrorKind::ClockSkew(message)
+        } else {
+            ErrorKind::HttpWithErrorResponse(status_code, message)
+        };
+
+        Error {
+            inner: Context::new(kind),
+            service_error: Some(service_error),
+        }
+    }
+
+    /// Like `service_error_response`, but if the body deserializes into `E`
+    /// the error is `ErrorKind::TypedService` and carries the deserialized
+    /// body.
+    pub fn typed_service_error_response<E>(
+        status_code: StatusCode,
+        error_code: Option<&str>,
+        body: &[u8],
+    ) -> Self
+    where
+        E: 'static + DeserializeOwned + Send + Sync,
+    {
+        let mut err = Error::service_error_response(status_code, error_code, body);
+        if let Ok(value) = serde_json::from_slice::<E>(body) {
+            let message = err
+                .service_error
+                .as_ref()
+                .map_or_else(String::new, |service_error| service_error.message.clone());
+            err.inner = Context::new(ErrorKind::TypedService(
                 status_code,
-                "<could not parse response body as utf-8>".to_string(),
+                TypedServiceError {
+                    message,
+                    value: Box::new(value),
+                },
+            ));
+        }
+        err
+    }
+
+    /// The body of an `ErrorKind::TypedService` error, if it is an `E`.
+    pub fn typed_service_error<E>(&self) -> Option<&E>
+    where
+        E: 'static,
+    {
+        match self.kind() {
+            ErrorKind::TypedService(_, typed) => typed.value.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn service_error(&self) -> Option<&ServiceError> {
+        self.service_error.as_ref()
+    }
+
+    pub(crate) fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        if let Some(ref mut service_error) = self.service_error {
+            service_error.retry_after = Some(retry_after);
+        }
+        self
+    }
+
+    pub(crate) fn with_request_id(mut self, request_id: String) -> Self {
+        if let Some(ref mut service_error) = self.service_error {
+            service_error.request_id = Some(request_id);
+        }
+        self
+    }
+
+    // Also reports a rejected token as clock skew if the service's clock is
+    // too far from ours, whatever the message.
+    pub(crate) fn with_date(mut self, date: DateTime<Utc>) -> Self {
+        if let Some(ref mut service_error) = self.service_error {
+            service_error.date = Some(date);
+            if let ErrorKind::HttpWithErrorResponse(_, message) = self.inner.get_context() {
+                if service_error.is_token_expired() {
+                    self.inner = Context::new(ErrorKind::ClockSkew(message.clone()));
+                }
+            }
+        }
+        self
+    }
+
+    /// The status of the error response the service sent, if it sent one.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        self.service_error.as_ref().map(ServiceError::status_code)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status_code() == Some(StatusCode::NOT_FOUND)
+    }
+
+    /// Whether the service throttled the request, which unlike an exhausted
+    /// quota clears after a while.
+    pub fn is_throttled(&self) -> bool {
+        self.status_code() == Some(StatusCode::TOO_MANY_REQUESTS) && !self.is_quota_exceeded()
+    }
+
+    /// Whether the service rejected the request for an exhausted quota.
+    /// This is decided by the service error rather than the kind, so an
+    /// `ErrorKind::TypedService` error is recognized too.
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.service_error
+            .as_ref()
+            .map_or(false, ServiceError::is_quota_exceeded)
+    }
+
+    /// Whether the request conflicted with the current state of the
+    /// resource, including a precondition like `If-Match` that failed.
+    pub fn is_conflict(&self) -> bool {
+        self.status_code() == Some(StatusCode::CONFLICT)
+            || self.status_code() == Some(StatusCode::PRECONDITION_FAILED)
+    }
+
+    /// Whether the service or a gateway in front of it timed out on the
+    /// request, or the client gave up waiting for its response.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Timeout | ErrorKind::BodyReadTimeout)
+            || self.status_code() == Some(StatusCode::REQUEST_TIMEOUT)
+            || self.status_code() == Some(StatusCode::GATEWAY_TIMEOUT)
+    }
+
+    /// How long the service asked to wait before retrying, from the
+    /// `Retry-After` header of its error response.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.service_error
+            .as_ref()
+            .and_then(ServiceError::retry_after)
+    }
+
+    /// The ID the service gave the request in the `x-ms-request-id` header of
+    /// its error response, which Azure support asks for to investigate it.
+    pub fn request_id(&self) -> Option<&str> {
+        self.service_error
+            .as_ref()
+            .and_then(ServiceError::request_id)
+    }
+
+    /// Whether the request that produced this error may succeed if retried,
+    /// which is whether its service error is transient. An exhausted daily
+    /// quota won't clear until it is reset.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind() {
+            ErrorKind::HttpWithErrorResponse(status_code, _)
+            | ErrorKind::TypedService(status_code, _) => self.service_error.as_ref().map_or_else(
+                || is_transient_status(*status_code),
+                ServiceError::is_transient,
             ),
-        };
+            _ => false,
+        }
+    }
+
+    /// Whether the request failed while resolving or connecting to the
+    /// server, before any of it was sent.
+    pub fn is_connect(&self) -> bool {
+        Fail::iter_chain(self).any(|cause| {
+            cause
+                .downcast_ref::<hyper::Error>()
+                .map_or(false, hyper::Error::is_connect)
+        })
+    }
 
-        kind.into()
+    /// Whether the connection was closed or reset by the server before the
+    /// response to the request was received, as happens when the server
+    /// drops an idle pooled connection just as it is reused.
+    pub fn is_connection_reset(&self) -> bool {
+        !self.is_connect()
+            && Fail::iter_chain(self).any(|cause| {
+                cause
+                    .downcast_ref::<hyper::Error>()
+                    .map_or(false, is_hyper_connection_reset)
+            })
     }
 }
 
+fn is_transient_status(status_code: StatusCode) -> bool {
+    status_code == StatusCode::TOO_MANY_REQUESTS || status_code.is_server_error()
+}
+
+fn is_hyper_connection_reset(err: &hyper::Error) -> bool {
+    // hyper has no accessor for an incomplete message, only its description
+    if err.to_string() == INCOMPLETE_MESSAGE {
+        return true;
+    }
+
+    err.source()
+        .and_then(|cause| cause.downcast_ref::<io::Error>())
+        .map_or(false, |err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+}
+
+// IoT Hub reports the error code either as a numeric `errorCode` field or as
+// an `ErrorCode:<code>;` prefix of the message, depending on the API version.
+fn service_error_code(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+
+    if let Some(code) = body.get("errorCode") {
+        return match code {
+            Value::Number(code) => Some(code.to_string()),
+            Value::String(code) => Some(code.clone()),
+            _ => None,
+        };
+    }
+
+    body.get("Message")
+        .and_then(Value::as_str)
+        .and_then(|message| message.split(';').next())
+        .filter(|code| code.starts_with("ErrorCode:"))
+        .map(|code| code.trim_start_matches("ErrorCode:").to_string())
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
         Error {
             inner: Context::new(kind),
+            service_error: None,
         }
     }
 }
 
 impl From<Context<ErrorKind>> for Error {
     fn from(inner: Context<ErrorKind>) -> Self {
-        Error { inner }
+        Error {
+            inner,
+            service_error: None,
+        }
     }
 }
 
@@ -201,6 +520,133 @@ impl IntoResponse for Compat<Error> {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceError {
+    status_code: StatusCode,
+    error_code: Option<String>,
+    message: String,
+    retry_after: Option<Duration>,
+    request_id: Option<String>,
+    date: Option<DateTime<Utc>>,
+}
+
+impl ServiceError {
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn error_code(&self) -> Option<&str> {
+        self.error_code.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(AsRef::as_ref)
+    }
+
+    /// The service's clock when it sent the response, from its `Date`
+    /// header.
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        self.date
+    }
+
+    /// Whether the failure is expected to clear by itself, like throttling
+    /// or a busy or failing server, rather than being a problem with the
+    /// request, like a missing or already existing resource. Known IoT Hub
+    /// error codes are classified by name. A numeric code starts with the
+    /// status it is reported with, which classifies it as does the status of
+    /// an error without a known code: throttling and server errors are
+    /// transient.
+    pub fn is_transient(&self) -> bool {
+        if self.is_quota_exceeded() {
+            return false;
+        }
+
+        match self.error_code() {
+            Some(code) if TRANSIENT_ERROR_CODES.contains(&code) => true,
+            Some(code) if PERMANENT_ERROR_CODES.contains(&code) => false,
+            Some(code) if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) => code[..3]
+                .parse()
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .map_or_else(
+                    || is_transient_status(self.status_code),
+                    is_transient_status,
+                ),
+            _ => is_transient_status(self.status_code),
+        }
+    }
+
+    /// Whether IoT Hub reported its daily quota as exhausted, which it does
+    /// with a 429 or a 403.
+    pub fn is_quota_exceeded(&self) -> bool {
+        (self.status_code == StatusCode::TOO_MANY_REQUESTS
+            || self.status_code == StatusCode::FORBIDDEN)
+            && self.error_code().map_or(false, |code| {
+                code == "IotHubQuotaExceeded" || code == "403002"
+            })
+    }
+
+    // IoT Hub reports an expired token and an invalid signature with the
+    // same codes, so only a message about expiry or a clock too far from
+    // ours tells them apart.
+    fn is_token_expired(&self) -> bool {
+        if self.status_code != StatusCode::UNAUTHORIZED
+            || !self
+                .error_code()
+                .map_or(false, |code| TOKEN_EXPIRED_ERROR_CODES.contains(&code))
+        {
+            return false;
+        }
+
+        let message = self.message.to_ascii_lowercase();
+        (message.contains("token") && message.contains("expired"))
+            || self.date.map_or(false, |date| {
+                (date - Utc::now()).num_seconds().abs() > CLOCK_SKEW_TOLERANCE_SECS
+            })
+    }
+}
+
+/// An error response body deserialized into a type chosen by the caller of
+/// `Client::request_typed_error`.
+pub struct TypedServiceError {
+    message: String,
+    value: Box<dyn Any + Send + Sync>,
+}
+
+impl TypedServiceError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Debug for TypedServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedServiceError")
+            .field("message", &self.message)
+            .finish()
+    }
+}
+
+impl Display for TypedServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl PartialEq for TypedServiceError {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BindListenerType {
     Address(SocketAddr),
@@ -246,3 +692,188 @@ impl Display for InvalidUrlReason {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use hyper::StatusCode;
+    use serde_json::Value;
+
+    use super::{Error, ErrorKind, ServiceError};
+
+    #[test]
+    fn not_found_is_recognized() {
+        assert!(Error::http_with_error_response(StatusCode::NOT_FOUND, &b""[..]).is_not_found());
+        assert!(!Error::http_with_error_response(StatusCode::GONE, &b""[..]).is_not_found());
+        assert!(!Error::from(ErrorKind::ModuleNotFound("m1".to_string())).is_not_found());
+    }
+
+    #[test]
+    fn throttled_is_recognized_apart_from_quota_exceeded() {
+        let throttled = Error::http_with_error_response(StatusCode::TOO_MANY_REQUESTS, &b""[..]);
+        assert!(throttled.is_throttled());
+        assert!(!throttled.is_quota_exceeded());
+
+        let quota_exceeded = Error::service_error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("IotHubQuotaExceeded"),
+            &b""[..],
+        );
+        assert!(!quota_exceeded.is_throttled());
+        assert!(quota_exceeded.is_quota_exceeded());
+
+        let typed_quota_exceeded = Error::typed_service_error_response::<Value>(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("IotHubQuotaExceeded"),
+            &br#"{"Message":"Total number of messages on IotHub exceeded the allocated quota"}"#[..],
+        );
+        assert!(typed_quota_exceeded.is_quota_exceeded());
+        assert!(!typed_quota_exceeded.is_retryable());
+    }
+
+    #[test]
+    fn conflict_is_recognized() {
+        assert!(Error::http_with_error_response(StatusCode::CONFLICT, &b""[..]).is_conflict());
+        assert!(
+            Error::http_with_error_response(StatusCode::PRECONDITION_FAILED, &b""[..])
+                .is_conflict()
+        );
+        assert!(!Error::http_with_error_response(StatusCode::BAD_REQUEST, &b""[..]).is_conflict());
+    }
+
+    #[test]
+    fn timeout_is_recognized() {
+        assert!(
+            Error::http_with_error_response(StatusCode::REQUEST_TIMEOUT, &b""[..]).is_timeout()
+        );
+        assert!(
+            Error::http_with_error_response(StatusCode::GATEWAY_TIMEOUT, &b""[..]).is_timeout()
+        );
+        assert!(
+            !Error::http_with_error_response(StatusCode::SERVICE_UNAVAILABLE, &b""[..])
+                .is_timeout()
+        );
+    }
+
+    #[test]
+    fn retry_after_is_kept_with_service_error() {
+        let err = Error::http_with_error_response(StatusCode::TOO_MANY_REQUESTS, &b""[..]);
+        assert_eq!(None, err.retry_after());
+
+        let err = err.with_retry_after(Duration::from_secs(5));
+        assert_eq!(Some(Duration::from_secs(5)), err.retry_after());
+        assert_eq!(Some(StatusCode::TOO_MANY_REQUESTS), err.status_code());
+
+        let err = Error::from(ErrorKind::Http).with_retry_after(Duration::from_secs(5));
+        assert_eq!(None, err.retry_after());
+        assert_eq!(None, err.status_code());
+    }
+
+    #[test]
+    fn clock_skew_is_recognized_by_error_code() {
+        let err = Error::service_error_response(
+            StatusCode::UNAUTHORIZED,
+            Some("401003"),
+            &b"Token expired"[..],
+        );
+        assert!(matches!(err.kind(), ErrorKind::ClockSkew(_)));
+
+        let err = Error::service_error_response(
+            StatusCode::UNAUTHORIZED,
+            None,
+            &br#"{"Message":"ErrorCode:IotHubUnauthorized;SAS token has expired"}"#[..],
+        );
+        assert!(matches!(err.kind(), ErrorKind::ClockSkew(_)));
+
+        // the same code without a message about expiry is only clock skew if
+        // the service's clock is too far from ours
+        let err = Error::service_error_response(
+            StatusCode::UNAUTHORIZED,
+            None,
+            &br#"{"Message":"ErrorCode:IotHubUnauthorized;Unauthorized"}"#[..],
+        )
+        .with_date(Utc::now() - chrono::Duration::hours(1));
+        assert!(matches!(err.kind(), ErrorKind::ClockSkew(_)));
+
+        // a message that happens to mention expiry doesn't make it clock skew
+        let err = Error::service_error_response(
+            StatusCode::UNAUTHORIZED,
+            None,
+            &br#"{"Message":"ErrorCode:IotHubUnauthorizedAccess;The device key has expired"}"#[..],
+        );
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::HttpWithErrorResponse(StatusCode::UNAUTHORIZED, _)
+        ));
+    }
+
+    #[test]
+    fn invalid_signature_is_not_clock_skew() {
+        let err = Error::service_error_response(
+            StatusCode::UNAUTHORIZED,
+            Some("401003"),
+            &br#"{"Message":"ErrorCode:IotHubUnauthorized;Invalid signature"}"#[..],
+        )
+        .with_date(Utc::now());
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::HttpWithErrorResponse(StatusCode::UNAUTHORIZED, _)
+        ));
+    }
+
+    #[test]
+    fn service_error_codes_are_classified() {
+        let is_transient = |status_code, error_code| {
+            Error::service_error_response(status_code, error_code, &b""[..])
+                .service_error()
+                .map_or(false, ServiceError::is_transient)
+        };
+
+        assert!(is_transient(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("ThrottlingException")
+        ));
+        assert!(is_transient(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("ServerBusy")
+        ));
+        assert!(is_transient(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("InternalServerError")
+        ));
+        assert!(is_transient(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("500001")
+        ));
+        assert!(is_transient(StatusCode::BAD_GATEWAY, None));
+
+        assert!(!is_transient(StatusCode::NOT_FOUND, Some("ModuleNotFound")));
+        assert!(!is_transient(StatusCode::NOT_FOUND, Some("404001")));
+        assert!(!is_transient(
+            StatusCode::CONFLICT,
+            Some("ModuleAlreadyExistsOnDevice")
+        ));
+        assert!(!is_transient(
+            StatusCode::BAD_REQUEST,
+            Some("ArgumentInvalid")
+        ));
+        assert!(!is_transient(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("IotHubQuotaExceeded")
+        ));
+
+        // the code takes precedence over the status it is reported with
+        assert!(!is_transient(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("DeviceNotFound")
+        ));
+        let err = Error::service_error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("DeviceNotFound"),
+            &b""[..],
+        );
+        assert!(!err.is_retryable());
+    }
+}
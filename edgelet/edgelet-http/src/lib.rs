@@ -4,6 +4,7 @@
 #![deny(clippy::all, clippy::pedantic)]
 #![allow(
     clippy::default_trait_access,
+    clippy::doc_markdown, // clippy wants the "IoT" of "IoT Hub" in a code fence
     clippy::missing_errors_doc,
     clippy::module_name_repetitions,
     clippy::must_use_candidate,
@@ -62,7 +63,7 @@ mod util;
 mod version;
 
 pub use certificate_manager::CertificateManager;
-pub use error::{BindListenerType, Error, ErrorKind, InvalidUrlReason};
+pub use error::{BindListenerType, Error, ErrorKind, InvalidUrlReason, ServiceError};
 pub use pid::Pid;
 pub use util::proxy::MaybeProxyClient;
 pub use util::UrlConnector;
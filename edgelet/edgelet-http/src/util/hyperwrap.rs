@@ -1,11 +1,12 @@
 // Copyright (c) Microsoft. All rights reserved.
 
 use failure::ResultExt;
-use futures::future;
+use futures::{future, Future};
+use hyper::client::connect::{Connect, Connected, Destination};
 use hyper::client::HttpConnector;
 use hyper::{Body, Client as HyperClient, Error as HyperError, Request, Response, StatusCode, Uri};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
-use hyper_tls::HttpsConnector;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
 use native_tls::{Certificate as TlsCertificate, TlsConnector};
 use openssl::x509::X509;
 use typed_headers::Credentials;
@@ -17,11 +18,14 @@ use crate::error::{Error, ErrorKind, InvalidUrlReason};
 use crate::PemCertificate;
 
 const DNS_WORKER_THREADS: usize = 4;
+const ALPN_H2: &str = "h2";
+const ALPN_HTTP1: &str = "http/1.1";
 
 #[derive(Clone, Debug)]
 pub struct Config {
     proxy_uri: Option<Uri>,
     null: bool,
+    http2: bool,
     identity_certificate: Option<PemCertificate>,
     trust_bundle: Option<PemCertificate>,
 }
@@ -47,6 +51,18 @@ impl Config {
         self
     }
 
+    /// Offers HTTP/2 during the TLS handshake. Connections to servers that
+    /// accept it multiplex requests over HTTP/2, and all others fall back to
+    /// HTTP/1.1. Connections through a proxy always use HTTP/1.1.
+    ///
+    /// Since multiplexed requests share a connection, an `AdaptiveConcurrency`
+    /// limit on the `Client` bounds the concurrent streams on it rather than
+    /// the number of connections open to the server.
+    pub fn http2(&mut self) -> &mut Config {
+        self.http2 = true;
+        self
+    }
+
     pub fn build(&self) -> Result<Client, Error> {
         if self.null {
             Ok(Client::Null)
@@ -71,6 +87,9 @@ impl Config {
                 let identity = id.get_identity().context(ErrorKind::Initialization)?;
                 builder.identity(identity);
             }
+            if self.http2 && self.proxy_uri.is_none() {
+                builder.request_alpns(&[ALPN_H2, ALPN_HTTP1]);
+            }
 
             let connector = builder.build().context(ErrorKind::Initialization)?;
             let mut http = HttpConnector::new(DNS_WORKER_THREADS);
@@ -78,9 +97,11 @@ impl Config {
             let https_connector = HttpsConnector::from((http, connector));
 
             match &self.proxy_uri {
-                None => Ok(Client::NoProxy(
-                    HyperClient::builder().build(https_connector),
-                )),
+                None => Ok(Client::NoProxy(HyperClient::builder().build(
+                    AlpnConnector {
+                        inner: https_connector,
+                    },
+                ))),
                 Some(uri) => {
                     let proxy = uri_to_proxy(uri.clone())?;
                     let conn = ProxyConnector::from_proxy(https_connector, proxy)
@@ -93,6 +114,40 @@ impl Config {
     }
 }
 
+/// Tells hyper which protocol the server selected with ALPN, so that
+/// connections where it agreed to `h2` are driven as HTTP/2. Without ALPN
+/// nothing is negotiated and connections stay on HTTP/1.1.
+#[derive(Clone, Debug)]
+pub struct AlpnConnector {
+    inner: HttpsConnector<HttpConnector>,
+}
+
+impl Connect for AlpnConnector {
+    type Transport = <HttpsConnector<HttpConnector> as Connect>::Transport;
+    type Error = <HttpsConnector<HttpConnector> as Connect>::Error;
+    type Future = Box<dyn Future<Item = (Self::Transport, Connected), Error = Self::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        Box::new(self.inner.connect(dst).map(|(stream, connected)| {
+            let negotiated_h2 = match stream {
+                MaybeHttpsStream::Https(ref tls) => tls
+                    .get_ref()
+                    .negotiated_alpn()
+                    .ok()
+                    .and_then(|protocol| protocol)
+                    .map_or(false, |protocol| protocol == ALPN_H2.as_bytes()),
+                MaybeHttpsStream::Http(_) => false,
+            };
+
+            if negotiated_h2 {
+                (stream, connected.negotiated_h2())
+            } else {
+                (stream, connected)
+            }
+        }))
+    }
+}
+
 fn uri_to_proxy(uri: Uri) -> Result<Proxy, Error> {
     let url = Url::parse(&uri.to_string()).with_context(|_| ErrorKind::Proxy(uri.clone()))?;
     let mut proxy = Proxy::new(Intercept::All, uri.clone());
@@ -149,7 +204,7 @@ fn uri_to_proxy(uri: Uri) -> Result<Proxy, Error> {
 
 #[derive(Clone, Debug)]
 pub enum Client {
-    NoProxy(HyperClient<HttpsConnector<HttpConnector>>),
+    NoProxy(HyperClient<AlpnConnector>),
     Proxy(HyperClient<ProxyConnector<HttpsConnector<HttpConnector>>>),
     Null,
 }
@@ -159,6 +214,7 @@ impl Client {
         Config {
             proxy_uri: None,
             null: false,
+            http2: false,
             identity_certificate: None,
             trust_bundle: None,
         }
@@ -192,6 +248,10 @@ impl ClientImpl for Client {
             )),
         }
     }
+
+    fn pools_connections(&self) -> bool {
+        !matches!(*self, Client::Null)
+    }
 }
 
 #[cfg(test)]
@@ -228,6 +288,19 @@ mod tests {
         assert!(client.has_proxy());
     }
 
+    #[test]
+    fn can_create_http2_client() {
+        let client = Client::configure().http2().build().unwrap();
+        assert!(!client.has_proxy() && !client.is_null());
+    }
+
+    #[test]
+    fn can_create_http2_client_with_proxy() {
+        let uri = "http://example.com".parse::<Uri>().unwrap();
+        let client = Client::configure().http2().proxy(uri).build().unwrap();
+        assert!(client.has_proxy());
+    }
+
     #[test]
     fn proxy_no_username() {
         let uri = "http://example.com".parse().unwrap();
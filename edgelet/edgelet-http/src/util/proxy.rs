@@ -18,11 +18,22 @@ impl MaybeProxyClient {
         identity_certificate: Option<PemCertificate>,
         trust_bundle: Option<PemCertificate>,
     ) -> Result<Self, Error> {
-        MaybeProxyClient::new_inner(false, proxy_uri, identity_certificate, trust_bundle)
+        MaybeProxyClient::new_inner(false, false, proxy_uri, identity_certificate, trust_bundle)
+    }
+
+    /// Like `new`, but negotiates HTTP/2 with servers that support it. See
+    /// `Config::http2`.
+    pub fn new_http2(
+        proxy_uri: Option<Uri>,
+        identity_certificate: Option<PemCertificate>,
+        trust_bundle: Option<PemCertificate>,
+    ) -> Result<Self, Error> {
+        MaybeProxyClient::new_inner(false, true, proxy_uri, identity_certificate, trust_bundle)
     }
 
     fn new_inner(
         null: bool,
+        http2: bool,
         proxy_uri: Option<Uri>,
         identity_certificate: Option<PemCertificate>,
         trust_bundle: Option<PemCertificate>,
@@ -31,6 +42,9 @@ impl MaybeProxyClient {
         if null {
             config.null();
         }
+        if http2 {
+            config.http2();
+        }
         if let Some(uri) = proxy_uri {
             config.proxy(uri);
         }
@@ -47,7 +61,7 @@ impl MaybeProxyClient {
 
     #[cfg(test)]
     pub fn new_null() -> Result<Self, Error> {
-        MaybeProxyClient::new_inner(true, None, None, None)
+        MaybeProxyClient::new_inner(true, false, None, None, None)
     }
 
     #[cfg(test)]
@@ -67,6 +81,10 @@ impl ClientImpl for MaybeProxyClient {
     fn call(&self, req: Request<Body>) -> Self::Response {
         self.client.call(req)
     }
+
+    fn pools_connections(&self) -> bool {
+        self.client.pools_connections()
+    }
 }
 
 #[cfg(test)]
@@ -89,6 +107,12 @@ mod tests {
         assert!(client.has_proxy() && !client.is_null());
     }
 
+    #[test]
+    fn can_create_http2_client() {
+        let client = MaybeProxyClient::new_http2(None, None, None).unwrap();
+        assert!(!client.has_proxy() && !client.is_null());
+    }
+
     #[test]
     fn client_calls_underlying_service() {
         let client = MaybeProxyClient::new_null().unwrap();
@@ -20,15 +20,14 @@ use chrono::{DateTime, Utc};
 use failure::{Fail, ResultExt};
 use futures::future::{self, Either};
 use futures::Future;
-use percent_encoding::{define_encode_set, percent_encode, PATH_SEGMENT_ENCODE_SET};
 use url::form_urlencoded::Serializer as UrlSerializer;
 
 use edgelet_core::crypto::{KeyIdentity, KeyStore, Sign, Signature, SignatureAlgorithm};
 use edgelet_core::{AuthType, Identity, IdentityManager, IdentityOperation, IdentitySpec};
 use edgelet_http::client::{ClientImpl, TokenSource};
 use iothubservice::{
-    AuthMechanism, AuthType as HubAuthType, DeviceClient, ErrorKind as HubErrorKind, Module,
-    ModuleOperationReason as HubReason, SymmetricKey,
+    resource_uri, AuthMechanism, AuthType as HubAuthType, DeviceClient, ErrorKind as HubErrorKind,
+    Module, ModuleOperationReason as HubReason, SymmetricKey,
 };
 
 pub use crate::error::{Error, ErrorKind, IdentityOperationReason};
@@ -36,10 +35,6 @@ pub use crate::error::{Error, ErrorKind, IdentityOperationReason};
 const KEY_PRIMARY: &str = "primary";
 const KEY_SECONDARY: &str = "secondary";
 
-define_encode_set! {
-    pub IOTHUB_ENCODE_SET = [PATH_SEGMENT_ENCODE_SET] | { '=' }
-}
-
 #[derive(Debug, PartialEq, serde_derive::Serialize)]
 pub struct HubIdentity {
     hub_module: Module,
@@ -115,20 +110,12 @@ where
             key,
         }
     }
-}
 
-impl<K> TokenSource for SasTokenSource<K>
-where
-    K: Sign + Clone,
-{
-    type Error = Error;
-
-    fn get(&self, expiry: &DateTime<Utc>) -> Result<String, Error> {
+    // Signs a token for the device on `host`, which is the hub unless a
+    // request was redirected to another host.
+    fn token(&self, host: &str, expiry: &DateTime<Utc>) -> Result<String, Error> {
         let expiry = expiry.timestamp().to_string();
-        let audience = format!("{}/devices/{}", self.hub_id, self.device_id);
-
-        let resource_uri =
-            percent_encode(audience.to_lowercase().as_bytes(), IOTHUB_ENCODE_SET).to_string();
+        let resource_uri = resource_uri(host, Some(&self.device_id), None);
         let sig_data = format!("{}\n{}", &resource_uri, expiry);
 
         let signature = self
@@ -145,6 +132,21 @@ where
     }
 }
 
+impl<K> TokenSource for SasTokenSource<K>
+where
+    K: Sign + Clone,
+{
+    type Error = Error;
+
+    fn get(&self, expiry: &DateTime<Utc>) -> Result<String, Error> {
+        self.token(&self.hub_id, expiry)
+    }
+
+    fn get_for_host(&self, host: &str, expiry: &DateTime<Utc>) -> Result<Option<String>, Error> {
+        self.token(host, expiry).map(Some)
+    }
+}
+
 impl<K> Clone for SasTokenSource<K>
 where
     K: Sign + Clone,
@@ -527,12 +529,12 @@ mod tests {
         key_store.insert(
             &KeyIdentity::Module("m1".to_string()),
             &format!("{}{}", KEY_PRIMARY, "g1"),
-            MemoryKey::new("pkey"),
+            MemoryKey::new("pkey-0123456789abcdef"),
         );
         key_store.insert(
             &KeyIdentity::Module("m1".to_string()),
             &format!("{}{}", KEY_SECONDARY, "g1"),
-            MemoryKey::new("skey"),
+            MemoryKey::new("skey-0123456789abcdef"),
         );
 
         let api_version = "2018-04-10".to_string();
@@ -546,8 +548,12 @@ mod tests {
                 .with_type(HubAuthType::Sas)
                 .with_symmetric_key(
                     SymmetricKey::default()
-                        .with_primary_key(base64::encode(MemoryKey::new("pkey").as_ref()))
-                        .with_secondary_key(base64::encode(MemoryKey::new("skey").as_ref())),
+                        .with_primary_key(base64::encode(
+                            MemoryKey::new("pkey-0123456789abcdef").as_ref(),
+                        ))
+                        .with_secondary_key(base64::encode(
+                            MemoryKey::new("skey-0123456789abcdef").as_ref(),
+                        )),
                 ),
         );
         let expected_module_result = expected_module2
@@ -918,4 +924,30 @@ mod tests {
         );
         assert_eq!(expected, token);
     }
+
+    #[test]
+    fn token_source_scopes_token_to_another_host() {
+        // arrange
+        let hub_id = "Miyagley-Edge.azure-devices.net".to_string();
+        let device_id = "miYagley1".to_string();
+        let key = MemoryKey::new(base64::decode("key").unwrap());
+        let token_source = SasTokenSource::new(hub_id, device_id, key);
+        let expiry = Utc.ymd(2018, 4, 26).and_hms(20, 54, 15);
+
+        // act
+        let hub_token = token_source
+            .get_for_host("Miyagley-Edge.azure-devices.net", &expiry)
+            .unwrap();
+        let other_token = token_source
+            .get_for_host("Other-Edge.azure-devices.net", &expiry)
+            .unwrap()
+            .unwrap();
+
+        // assert
+        assert_eq!(Some(token_source.get(&expiry).unwrap()), hub_token);
+        assert!(
+            other_token.starts_with("sr=other-edge.azure-devices.net%2Fdevices%2Fmiyagley1&sig=")
+        );
+        assert!(other_token.ends_with("&se=1524776055"));
+    }
 }
@@ -12,6 +12,7 @@ use edgelet_http::ErrorKind as HttpErrorKind;
 use iothubservice::Error as HubServiceError;
 
 use failure::{Backtrace, Context, Fail};
+use hyper::StatusCode;
 #[cfg(windows)]
 use windows_service::Error as WindowsServiceError;
 
@@ -98,15 +99,15 @@ impl From<CoreError> for Error {
 
                 for cause in hub_failure.iter_causes() {
                     if let Some(err) = cause.downcast_ref::<HttpError>() {
+                        // the status is matched rather than the kind, which
+                        // names some error responses more specifically
                         match HttpError::kind(err) {
                             HttpErrorKind::Http => {
                                 error_kind =
                                     ErrorKind::Initialize(InitializeErrorReason::InvalidHubConfig);
                             }
-                            HttpErrorKind::HttpWithErrorResponse(code, _message) => {
-                                if code.as_u16() == 401 {
-                                    error_kind = ErrorKind::InvalidSignedToken;
-                                }
+                            _ if err.status_code() == Some(StatusCode::UNAUTHORIZED) => {
+                                error_kind = ErrorKind::InvalidSignedToken;
                             }
                             _ => {}
                         };
@@ -1453,8 +1453,10 @@ where
             .context(ErrorKind::Initialize(InitializeErrorReason::HttpClient))?,
     )
     .context(ErrorKind::Initialize(InitializeErrorReason::HttpClient))?;
+    // the runtime manages the identities of its own `$edgeAgent` and `$edgeHub`
     let device_client = DeviceClient::new(http_client, device_id.clone())
-        .context(ErrorKind::Initialize(InitializeErrorReason::DeviceClient))?;
+        .context(ErrorKind::Initialize(InitializeErrorReason::DeviceClient))?
+        .with_system_modules_allowed(true);
     let id_man = HubIdentityManager::new(key_store.clone(), device_client);
 
     let (mgmt_tx, mgmt_rx) = oneshot::channel();
@@ -86,7 +86,7 @@ fn list_modules<C, T>(
     device_client: &DeviceClient<C, T>,
 ) where
     C: ClientImpl + 'static,
-    T: TokenSource + Clone + 'static,
+    T: TokenSource + Clone + Send + 'static,
     T::Error: Fail,
 {
     let response = tokio_runtime
@@ -101,7 +101,7 @@ fn get_module<C, T>(
     module_id: String,
 ) where
     C: ClientImpl + 'static,
-    T: TokenSource + Clone + 'static,
+    T: TokenSource + Clone + Send + 'static,
     T::Error: Fail,
 {
     let response = tokio_runtime
@@ -116,7 +116,7 @@ fn create_module<C, T>(
     module_id: String,
 ) where
     C: ClientImpl + 'static,
-    T: TokenSource + Clone + 'static,
+    T: TokenSource + Clone + Send + 'static,
     T::Error: Fail,
 {
     let response = tokio_runtime
@@ -131,7 +131,7 @@ fn delete_module<C, T>(
     module_id: &str,
 ) where
     C: ClientImpl + 'static,
-    T: TokenSource + Clone + 'static,
+    T: TokenSource + Clone + Send + 'static,
     T::Error: Fail,
 {
     tokio_runtime
@@ -0,0 +1,64 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::model::Module;
+
+/// Caches the module listing of a device for a fixed TTL. Every invalidation
+/// bumps a generation counter so that a listing which was already in flight
+/// when the cache got invalidated doesn't repopulate it with stale modules.
+#[derive(Clone)]
+pub(crate) struct ModuleCache {
+    ttl: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    generation: u64,
+    entry: Option<(Instant, Vec<Module>)>,
+}
+
+impl ModuleCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        ModuleCache {
+            ttl,
+            state: Arc::new(Mutex::new(State {
+                generation: 0,
+                entry: None,
+            })),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<Vec<Module>> {
+        let state = self.lock();
+        state
+            .entry
+            .as_ref()
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, modules)| modules.clone())
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    pub(crate) fn set(&self, generation: u64, modules: Vec<Module>) {
+        let mut state = self.lock();
+        if state.generation == generation {
+            state.entry = Some((Instant::now(), modules));
+        }
+    }
+
+    pub(crate) fn invalidate(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.entry = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("Unable to lock the module cache mutex")
+    }
+}
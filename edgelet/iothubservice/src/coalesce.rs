@@ -0,0 +1,130 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use failure::Fail;
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::Future;
+
+use crate::error::{Error, ErrorKind};
+
+// the module ID, ETag and whether a missing module counts as deleted
+pub(crate) type DeleteKey = (String, Option<String>, bool);
+
+type Waiters = Vec<oneshot::Sender<Result<(), Arc<Error>>>>;
+
+/// Shares one delete request between identical deletes made while it is in
+/// flight. The first delete of a module sends the request, and the others
+/// wait for its result instead of sending their own and failing with a 404.
+/// Only the first delete builds its request. Clones share the in-flight
+/// deletes.
+#[derive(Clone, Default)]
+pub(crate) struct DeleteCoalescer {
+    in_flight: Arc<Mutex<HashMap<DeleteKey, Waiters>>>,
+}
+
+impl DeleteCoalescer {
+    pub(crate) fn coalesce<D, F>(
+        &self,
+        key: DeleteKey,
+        delete: D,
+    ) -> impl Future<Item = (), Error = Error>
+    where
+        D: FnOnce() -> F,
+        F: Future<Item = (), Error = Error>,
+    {
+        let mut in_flight = self.lock();
+        if let Some(waiters) = in_flight.get_mut(&key) {
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(sender);
+            return Either::B(receiver.then(|result| match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(err)) => Err(shared_error(err)),
+                // the delete being waited for was dropped before it completed
+                Err(oneshot::Canceled) => Err(Error::from(ErrorKind::DeleteModule)),
+            }));
+        }
+        in_flight.insert(key.clone(), Waiters::new());
+        drop(in_flight);
+
+        let mut leader = Leader {
+            coalescer: self.clone(),
+            key: Some(key),
+        };
+        Either::A(delete().then(move |result| {
+            let waiters = leader.finish();
+            if waiters.is_empty() {
+                return result;
+            }
+
+            match result {
+                Ok(()) => {
+                    for waiter in waiters {
+                        let _ = waiter.send(Ok(()));
+                    }
+                    Ok(())
+                }
+                Err(err) => {
+                    let err = Arc::new(err);
+                    for waiter in waiters {
+                        let _ = waiter.send(Err(err.clone()));
+                    }
+                    Err(Arc::try_unwrap(err).unwrap_or_else(shared_error))
+                }
+            }
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<DeleteKey, Waiters>> {
+        self.in_flight
+            .lock()
+            .expect("Unable to lock the in-flight deletes mutex")
+    }
+}
+
+// Removes the in-flight delete it leads once it completes, or once it is
+// dropped so that the deletes waiting for it don't wait forever.
+struct Leader {
+    coalescer: DeleteCoalescer,
+    key: Option<DeleteKey>,
+}
+
+impl Leader {
+    fn finish(&mut self) -> Waiters {
+        self.key
+            .take()
+            .and_then(|key| self.coalescer.lock().remove(&key))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// A delete error for each of the deletes that share `err`.
+pub(crate) fn shared_error(err: Arc<Error>) -> Error {
+    Error::from(SharedFailure(err).context(ErrorKind::DeleteModule))
+}
+
+// The error of a delete shared with others, which keeps its causes so that
+// `Error::http_error` still finds the failed request.
+#[derive(Debug)]
+struct SharedFailure(Arc<Error>);
+
+impl fmt::Display for SharedFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Fail for SharedFailure {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(&*self.0)
+    }
+}
@@ -1,17 +1,49 @@
 // Copyright (c) Microsoft. All rights reserved.
 
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use failure::{Fail, ResultExt};
-use futures::future::{self, Either};
-use futures::Future;
+use futures::future::{self, Either, Loop};
+use futures::{stream, Future, Stream};
+use hyper::header::{HeaderMap, ETAG, LOCATION};
 use hyper::{Method, StatusCode};
 use percent_encoding::{define_encode_set, percent_encode, PercentEncode, PATH_SEGMENT_ENCODE_SET};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::timer::Delay;
+use url::Url;
 
-use edgelet_http::client::{Client, ClientImpl, TokenSource};
-use edgelet_http::error::ErrorKind as HttpErrorKind;
+use edgelet_http::client::{Client, ClientImpl, Condition, TokenSource};
 use edgelet_utils::ensure_not_empty_with_context;
 
-use crate::error::{Error, ErrorKind, ModuleOperationReason};
-use crate::model::{AuthMechanism, Module};
+use crate::cache::ModuleCache;
+use crate::coalesce::{shared_error, DeleteCoalescer};
+use crate::error::{Error, ErrorKind, ModuleOperationReason, PartialListError};
+use crate::json_stream::JsonArrayStream;
+use crate::metadata::AppMetadata;
+use crate::model::timestamp;
+use crate::model::{
+    AuthMechanism, AuthType, BulkRegistryOperationResult, Device, DeviceExport, ImportMode,
+    MethodResult, MethodTimeouts, Module, ModuleOperation, Properties, PurgeMessageQueueResult,
+    SymmetricKey, Timestamp, Twin, TwinMetadata,
+};
+use crate::paginator::Paginator;
+use crate::sas::resource_uri;
+
+const RESERVED_MODULE_ID_PREFIX: char = '$';
+const MODULE_QUERY_API_VERSION: &str = "2018-06-30";
+const STATUS_DISABLED: &str = "disabled";
+const MAX_TWIN_PATCH_SIZE: usize = 32 * 1024;
+const MAX_MODULE_ID_LENGTH: usize = 128;
+// The most operations IoT Hub accepts in one bulk registry request.
+const MAX_BULK_OPERATIONS: usize = 100;
+
+// the characters IoT Hub allows in module IDs besides ASCII letters and digits
+const MODULE_ID_SYMBOLS: &str = "-.+%_#*?!(),:=@$'";
 
 define_encode_set! {
     pub IOTHUB_ENCODE_SET = [PATH_SEGMENT_ENCODE_SET] | { '=' }
@@ -20,6 +52,131 @@ define_encode_set! {
 pub struct DeviceClient<C, T> {
     client: Client<C, T>,
     device_id: String,
+    cache: Option<ModuleCache>,
+    etag_fallback: EtagFallback,
+    system_modules_allowed: bool,
+    split_twin_patches: bool,
+    module_id_matching: ModuleIdMatching,
+    deletes: Option<DeleteCoalescer>,
+    required_fields: bool,
+}
+
+/// What mutations like `DeviceClient::update_module` and
+/// `DeviceClient::delete` do when they aren't given an ETag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EtagFallback {
+    /// Apply the operation unconditionally with `If-Match: *`, which a
+    /// client requiring conditional writes rejects.
+    MatchAny,
+    /// Send the request without an `If-Match` header, leaving IoT Hub to
+    /// decide what an unconditional write does.
+    Omit,
+    /// Fail the operation with `ModuleOperationReason::MissingEtag`.
+    Fail,
+}
+
+/// How module IDs are compared locally, when looking for a module among
+/// those listed or deciding whether two modules are the same. Requests always
+/// use the ID exactly as the caller gave it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModuleIdMatching {
+    Exact,
+    /// IDs differing only in the case of ASCII letters are the same.
+    CaseInsensitive,
+}
+
+impl ModuleIdMatching {
+    pub fn matches(self, module_id: &str, other: &str) -> bool {
+        match self {
+            ModuleIdMatching::Exact => module_id == other,
+            ModuleIdMatching::CaseInsensitive => module_id.eq_ignore_ascii_case(other),
+        }
+    }
+}
+
+impl Default for ModuleIdMatching {
+    fn default() -> Self {
+        ModuleIdMatching::Exact
+    }
+}
+
+/// What `DeviceClient::ensure_module` did to converge the module.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EnsureOutcome {
+    Created(Module),
+    Updated(Module),
+    Unchanged(Module),
+}
+
+impl EnsureOutcome {
+    pub fn module(&self) -> &Module {
+        match self {
+            EnsureOutcome::Created(module)
+            | EnsureOutcome::Updated(module)
+            | EnsureOutcome::Unchanged(module) => module,
+        }
+    }
+
+    pub fn into_module(self) -> Module {
+        match self {
+            EnsureOutcome::Created(module)
+            | EnsureOutcome::Updated(module)
+            | EnsureOutcome::Unchanged(module) => module,
+        }
+    }
+}
+
+/// A rough estimate of the size of the device's modules serialized as JSON,
+/// from `DeviceClient::estimate_modules_size`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeEstimate {
+    bytes: usize,
+    module_count: usize,
+    sample_size: usize,
+}
+
+impl SizeEstimate {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn module_count(&self) -> usize {
+        self.module_count
+    }
+
+    /// The number of modules whose size was measured. The estimate is exact
+    /// when it is the module count.
+    pub fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.sample_size == self.module_count
+    }
+}
+
+/// The IDs of the modules `DeviceClient::delete_all_modules` has deleted so
+/// far. Clones share the same list, so a clone kept by the caller still
+/// reports what was deleted after the future is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct DeletedModules {
+    module_ids: Arc<Mutex<Vec<String>>>,
+}
+
+impl DeletedModules {
+    pub fn module_ids(&self) -> Vec<String> {
+        self.module_ids
+            .lock()
+            .expect("Unable to lock the deleted modules mutex")
+            .clone()
+    }
+
+    fn push(&self, module_id: String) {
+        self.module_ids
+            .lock()
+            .expect("Unable to lock the deleted modules mutex")
+            .push(module_id);
+    }
 }
 
 impl<C, T> DeviceClient<C, T>
@@ -33,29 +190,411 @@ where
             ErrorKind::InvalidDeviceId(device_id.clone())
         })?;
 
-        Ok(DeviceClient { client, device_id })
+        Ok(DeviceClient {
+            client,
+            device_id,
+            cache: None,
+            etag_fallback: EtagFallback::MatchAny,
+            system_modules_allowed: false,
+            split_twin_patches: false,
+            module_id_matching: ModuleIdMatching::Exact,
+            deletes: None,
+            required_fields: false,
+        })
+    }
+
+    /// Serves `list_modules` from memory for `ttl` after each listing. The
+    /// cache is invalidated whenever this client modifies a module.
+    pub fn with_module_cache(mut self, ttl: Duration) -> Self {
+        self.cache = Some(ModuleCache::new(ttl));
+        self
+    }
+
+    pub fn with_etag_fallback(mut self, etag_fallback: EtagFallback) -> Self {
+        self.etag_fallback = etag_fallback;
+        self
+    }
+
+    /// Issues this client's module operations on behalf of `module_id`, for a
+    /// parent edge managing the modules of a child device in a nested edge
+    /// with its own credentials. See `Client::with_on_behalf_of`.
+    pub fn with_on_behalf_of(mut self, module_id: &str) -> Self {
+        self.client = self.client.with_on_behalf_of(module_id);
+        self
+    }
+
+    /// Sends this client's requests to `gateway_host`, the parent edge a leaf
+    /// device in a nested edge reaches IoT Hub through, while SAS tokens stay
+    /// scoped to the hub. See `Client::with_gateway_host`.
+    pub fn with_gateway_host(mut self, gateway_host: Url) -> Self {
+        self.client = self.client.with_gateway_host(gateway_host);
+        self
+    }
+
+    /// Lets this client create and update modules with IDs starting with `$`,
+    /// which IoT Edge reserves for its system modules like `$edgeAgent`. Only
+    /// the runtime itself should manage those.
+    pub fn with_system_modules_allowed(mut self, system_modules_allowed: bool) -> Self {
+        self.system_modules_allowed = system_modules_allowed;
+        self
+    }
+
+    /// Lets `update_module_twin` split a patch over IoT Hub's size limit into
+    /// several patches of its top-level desired properties instead of
+    /// rejecting it. The patches are applied one after another, so the split
+    /// update isn't atomic: a failure part way leaves the earlier patches
+    /// applied, and readers can see the twin between patches.
+    pub fn with_split_twin_patches(mut self, split_twin_patches: bool) -> Self {
+        self.split_twin_patches = split_twin_patches;
+        self
+    }
+
+    /// Compares module IDs with `module_id_matching` when `ensure_module`
+    /// and `EdgeDeviceClient::ensure_system_modules` look for an existing
+    /// module, so that a module whose ID differs only in case isn't created
+    /// again. With anything but `Exact` they look among the listed modules
+    /// instead of getting the module by its ID.
+    pub fn with_module_id_matching(mut self, module_id_matching: ModuleIdMatching) -> Self {
+        self.module_id_matching = module_id_matching;
+        self
+    }
+
+    /// Lets identical deletes made while one is in flight share its request
+    /// and result rather than each send their own, which would fail with a
+    /// 404 for all but the first. Deletes are identical when they are for the
+    /// same module with the same ETag, and either all or none ignore a
+    /// missing module. Clones of this client share their deletes.
+    pub fn with_delete_coalescing(mut self, delete_coalescing: bool) -> Self {
+        self.deletes = if delete_coalescing {
+            Some(DeleteCoalescer::default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Fails reads and writes of modules with `ErrorKind::MissingField` when
+    /// IoT Hub returns a module without one of the fields it always sets,
+    /// instead of returning the module with the field absent. See
+    /// `Module::require_fields`.
+    pub fn with_required_fields(mut self, required_fields: bool) -> Self {
+        self.required_fields = required_fields;
+        self
     }
 
     pub fn device_id(&self) -> &str {
         self.device_id.as_ref()
     }
 
+    /// The resource URI of this device on the hub, which SAS tokens for its
+    /// requests are scoped to even when they are sent through a gateway.
+    pub fn resource_uri(&self) -> String {
+        resource_uri(
+            self.client.host_name().host_str().unwrap_or_default(),
+            Some(&self.device_id),
+            None,
+        )
+    }
+
+    pub fn etag_fallback(&self) -> EtagFallback {
+        self.etag_fallback
+    }
+
+    pub fn system_modules_allowed(&self) -> bool {
+        self.system_modules_allowed
+    }
+
+    pub fn split_twin_patches(&self) -> bool {
+        self.split_twin_patches
+    }
+
+    pub fn module_id_matching(&self) -> ModuleIdMatching {
+        self.module_id_matching
+    }
+
+    pub fn delete_coalescing(&self) -> bool {
+        self.deletes.is_some()
+    }
+
+    pub fn required_fields(&self) -> bool {
+        self.required_fields
+    }
+
+    /// The condition a mutation is made on given the ETag it was given, or
+    /// `None` if it must fail for lack of one.
+    fn condition(&self, etag: Option<&str>) -> Option<Condition> {
+        match (etag, self.etag_fallback) {
+            (Some(etag), _) => Some(Condition::IfMatch(etag.to_string())),
+            (None, EtagFallback::MatchAny) => Some(Condition::Any),
+            (None, EtagFallback::Omit) => Some(Condition::None),
+            (None, EtagFallback::Fail) => None,
+        }
+    }
+
+    pub fn invalidate(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.invalidate();
+        }
+    }
+
+    fn invalidate_on_completion<F>(&self, f: F) -> impl Future<Item = F::Item, Error = F::Error>
+    where
+        F: Future,
+    {
+        let cache = self.cache.clone();
+        f.then(move |result| {
+            if let Some(cache) = cache {
+                cache.invalidate();
+            }
+            result
+        })
+    }
+
+    /// Creates a module. When `authentication` is `None` the request leaves
+    /// it out entirely, and IoT Hub defaults the module to SAS authentication
+    /// with keys it generates. Those keys are returned in the created module.
     pub fn create_module(
         &self,
         module_id: String,
         authentication: Option<AuthMechanism>,
         managed_by: Option<&str>,
     ) -> impl Future<Item = Module, Error = Error> {
-        self.upsert_module(module_id, authentication, managed_by, false)
+        self.upsert_module(module_id, authentication, managed_by, Condition::None)
+    }
+
+    /// Like `create_module`, but also returns the `Location` header of the
+    /// response, which points at the created module if IoT Hub sent it.
+    pub fn create_module_with_location(
+        &self,
+        module_id: String,
+        authentication: Option<AuthMechanism>,
+        managed_by: Option<&str>,
+    ) -> impl Future<Item = (Module, Option<String>), Error = Error> {
+        self.upsert_module_with_headers(
+            module_id,
+            authentication,
+            managed_by,
+            HeaderMap::new(),
+            Condition::None,
+        )
+        .map(|(module, headers)| {
+            let location = headers
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string);
+            (module, location)
+        })
+    }
+
+    /// Like `create_module`, but sends `metadata` as `iothub-app-*` headers
+    /// and returns the app metadata IoT Hub echoes back.
+    pub fn create_module_with_app_metadata(
+        &self,
+        module_id: String,
+        authentication: Option<AuthMechanism>,
+        managed_by: Option<&str>,
+        metadata: &AppMetadata,
+    ) -> impl Future<Item = (Module, AppMetadata), Error = Error> {
+        self.upsert_module_with_headers(
+            module_id,
+            authentication,
+            managed_by,
+            metadata.to_headers(),
+            Condition::None,
+        )
+        .map(|(module, headers)| (module, AppMetadata::from_headers(&headers)))
+    }
+
+    /// Creates a module then patches its twin with the initial `desired`
+    /// properties, which a registry create can't carry. If the patch fails
+    /// the module is deleted again, provided it hasn't changed since it was
+    /// created, and this fails with `CreateModuleWithTwin`. If it can't be
+    /// deleted this fails with `CreateModuleWithTwinPartial` instead, and the
+    /// module is left without its initial twin.
+    pub fn create_module_with_twin(
+        &self,
+        module_id: String,
+        authentication: Option<AuthMechanism>,
+        desired: Value,
+    ) -> impl Future<Item = (Module, Twin), Error = Error> {
+        let client = self.clone();
+
+        self.create_module(module_id.clone(), authentication, None)
+            .and_then(move |module| {
+                client.update_module_twin(&module_id, desired, None).then(
+                    move |result| match result {
+                        Ok(twin) => Either::A(future::ok((module, twin))),
+                        Err(err) => Either::B(client.delete(&module).then(move |deleted| {
+                            let kind = match deleted {
+                                Ok(()) => ErrorKind::CreateModuleWithTwin(module_id),
+                                Err(_) => ErrorKind::CreateModuleWithTwinPartial(module_id),
+                            };
+                            Err(Error::from(err.context(kind)))
+                        })),
+                    },
+                )
+            })
+    }
+
+    /// Creates `modules`, each an ID with the authentication given to
+    /// `create_module`, with at most `max_concurrent` creates in flight, and
+    /// returns the result of each in the order given. A failed create
+    /// doesn't stop the others unless `fail_fast` is set. Then the first
+    /// failure fails the whole operation as soon as it happens, the creates
+    /// in flight are dropped and no further ones are sent. IoT Hub may still
+    /// carry out a create that was in flight.
+    pub fn create_modules(
+        &self,
+        modules: Vec<(String, Option<AuthMechanism>)>,
+        max_concurrent: usize,
+        fail_fast: bool,
+    ) -> impl Future<Item = Vec<(String, Result<Module, Error>)>, Error = Error> {
+        let client = self.clone();
+
+        stream::iter_ok(modules)
+            .map(move |(module_id, authentication)| {
+                client
+                    .create_module(module_id.clone(), authentication, None)
+                    .then(move |result| match result {
+                        Err(err) if fail_fast => Err(err),
+                        result => Ok((module_id, result)),
+                    })
+            })
+            .buffered(max_concurrent.max(1))
+            .collect()
     }
 
+    /// Updates a module. The update is made conditional according to the
+    /// client's `EtagFallback`.
     pub fn update_module(
         &self,
         module_id: String,
         authentication: Option<AuthMechanism>,
         managed_by: Option<&str>,
     ) -> impl Future<Item = Module, Error = Error> {
-        self.upsert_module(module_id, authentication, managed_by, true)
+        match self.condition(None) {
+            Some(condition) => {
+                Either::A(self.upsert_module(module_id, authentication, managed_by, condition))
+            }
+            None => Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                module_id,
+                ModuleOperationReason::MissingEtag,
+            )))),
+        }
+    }
+
+    /// Like `update_module`, but sends `metadata` as `iothub-app-*` headers
+    /// and returns the app metadata IoT Hub echoes back.
+    pub fn update_module_with_app_metadata(
+        &self,
+        module_id: String,
+        authentication: Option<AuthMechanism>,
+        managed_by: Option<&str>,
+        metadata: &AppMetadata,
+    ) -> impl Future<Item = (Module, AppMetadata), Error = Error> {
+        match self.condition(None) {
+            Some(condition) => Either::A(
+                self.upsert_module_with_headers(
+                    module_id,
+                    authentication,
+                    managed_by,
+                    metadata.to_headers(),
+                    condition,
+                )
+                .map(|(module, headers)| (module, AppMetadata::from_headers(&headers))),
+            ),
+            None => Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                module_id,
+                ModuleOperationReason::MissingEtag,
+            )))),
+        }
+    }
+
+    /// Replaces the keys of a module with new ones generated by IoT Hub,
+    /// switching it to SAS authentication if it used anything else. The
+    /// update is made conditional according to the client's `EtagFallback`.
+    pub fn rotate_module_to_hub_generated(
+        &self,
+        module_id: &str,
+    ) -> impl Future<Item = SymmetricKey, Error = Error> {
+        self.rotate_module_keys(module_id.to_string(), None)
+    }
+
+    /// Replaces the keys of every module of the device that uses SAS
+    /// authentication with new ones generated by IoT Hub, rotating at most
+    /// `max_concurrent` modules at a time. Each update is conditional on the
+    /// ETag the module was listed with, so a module changed in between fails
+    /// with a 412 instead of being overwritten.
+    ///
+    /// Returns the ID of every listed module with its new keys or the error
+    /// rotating them, in the order the modules were listed. A failure doesn't
+    /// stop the other rotations. Modules using other authentication, like
+    /// X.509, have no keys to rotate and are skipped without a request, with
+    /// `ModuleOperationReason::NotSasAuthenticated` as their error.
+    pub fn rotate_all_module_keys(
+        &self,
+        max_concurrent: usize,
+    ) -> impl Future<Item = Vec<(String, Result<SymmetricKey, Error>)>, Error = Error> {
+        let client = self.clone();
+
+        self.list_modules().and_then(move |modules| {
+            stream::iter_ok(modules)
+                .map(move |module| {
+                    let module_id = module.module_id().unwrap_or_default().to_string();
+                    let keys = if module.authentication().and_then(AuthMechanism::type_)
+                        == Some(AuthType::Sas)
+                    {
+                        Either::A(client.rotate_module_keys(module_id.clone(), module.etag()))
+                    } else {
+                        Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                            module_id.clone(),
+                            ModuleOperationReason::NotSasAuthenticated,
+                        ))))
+                    };
+                    keys.then(move |result| Ok::<_, Error>((module_id, result)))
+                })
+                .buffered(max_concurrent.max(1))
+                .collect()
+        })
+    }
+
+    // Switches a module to SAS authentication with keys IoT Hub generates,
+    // conditionally on `etag` or according to the `EtagFallback` without one.
+    fn rotate_module_keys(
+        &self,
+        module_id: String,
+        etag: Option<&str>,
+    ) -> impl Future<Item = SymmetricKey, Error = Error> {
+        let condition = match self.condition(etag) {
+            Some(condition) => condition,
+            None => {
+                return Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                    module_id,
+                    ModuleOperationReason::MissingEtag,
+                ))))
+            }
+        };
+        let authentication = AuthMechanism::new()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(SymmetricKey::new());
+
+        let res = self
+            .upsert_module(module_id.clone(), Some(authentication), None, condition)
+            .and_then(|module| {
+                module
+                    .authentication()
+                    .and_then(AuthMechanism::symmetric_key)
+                    .filter(|key| key.primary_key().is_some())
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::UpsertModuleWithReason(
+                            module_id,
+                            ModuleOperationReason::MissingSymmetricKey,
+                        ))
+                    })
+            });
+
+        Either::A(res)
     }
 
     fn upsert_module(
@@ -63,13 +602,44 @@ where
         module_id: String,
         authentication: Option<AuthMechanism>,
         managed_by: Option<&str>,
-        add_if_match: bool,
+        condition: Condition,
     ) -> impl Future<Item = Module, Error = Error> {
+        self.upsert_module_with_headers(
+            module_id,
+            authentication,
+            managed_by,
+            HeaderMap::new(),
+            condition,
+        )
+        .map(|(module, _)| module)
+    }
+
+    fn upsert_module_with_headers(
+        &self,
+        module_id: String,
+        authentication: Option<AuthMechanism>,
+        managed_by: Option<&str>,
+        headers: HeaderMap,
+        condition: Condition,
+    ) -> impl Future<Item = (Module, HeaderMap), Error = Error> {
         if module_id.trim().is_empty() {
             Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
                 module_id,
                 ModuleOperationReason::EmptyModuleId,
             ))))
+        } else if let Err(err) = validate_module_id(&module_id) {
+            Either::B(future::err(err))
+        } else if module_id.starts_with(RESERVED_MODULE_ID_PREFIX) && !self.system_modules_allowed {
+            Either::B(future::err(Error::from(ErrorKind::ReservedModuleId(
+                module_id,
+            ))))
+        } else if let Err(err) = authentication
+            .as_ref()
+            .and_then(AuthMechanism::symmetric_key)
+            .map_or(Ok(()), SymmetricKey::validate)
+        {
+            // a key IoT Hub would reject fails before any request is made
+            Either::B(future::err(err))
         } else {
             let mut module = Module::default()
                 .with_device_id(self.device_id.clone())
@@ -83,33 +653,190 @@ where
                 module = module.with_managed_by(managed_by.to_string());
             }
 
+            // Serialize up front so that a module that can't be serialized
+            // fails before any request is made.
+            let body = match serialize_body(&module) {
+                Ok(body) => body,
+                Err(err) => return Either::B(future::err(err)),
+            };
+
+            let path = format!(
+                "/devices/{}/modules/{}",
+                url_encode(&self.device_id),
+                url_encode(&module_id)
+            );
+            let required_fields = self.required_fields;
             let res = self
                 .client
-                .request::<Module, Module>(
+                .request_with_meta::<Value, Module>(
                     Method::PUT,
-                    &format!(
-                        "/devices/{}/modules/{}",
-                        url_encode(&self.device_id),
-                        url_encode(&module_id)
-                    ),
+                    &path,
                     None,
-                    Some(module),
-                    add_if_match,
+                    headers,
+                    Some(body),
+                    condition,
                 )
-                .then(|module| {
-                    let module =
+                .then(move |module| {
+                    let (module, headers) =
                         module.with_context(|_| ErrorKind::UpsertModule(module_id.clone()))?;
+                    let module = module.map(|module| with_etag_header(module, &headers));
                     let module = module.ok_or_else(|| {
                         Error::from(ErrorKind::UpsertModuleWithReason(
                             module_id,
-                            ModuleOperationReason::ModuleNotFound,
+                            ModuleOperationReason::EmptyResponse {
+                                operation: "upsert_module",
+                                path,
+                            },
                         ))
                     })?;
-                    Ok(module)
+                    if required_fields {
+                        module.require_fields()?;
+                    }
+                    Ok((module, headers))
                 });
 
-            Either::A(res)
+            Either::A(self.invalidate_on_completion(res))
+        }
+    }
+
+    /// Disables `module`, recording `reason` as its status reason. The update
+    /// is conditional on the module's ETag, or follows the `EtagFallback` if
+    /// it has none.
+    pub fn disable_module(
+        &self,
+        module: Module,
+        reason: &str,
+    ) -> impl Future<Item = Module, Error = Error> {
+        let module_id = module.module_id().unwrap_or_default().to_string();
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                module_id,
+                ModuleOperationReason::EmptyModuleId,
+            ))));
         }
+
+        let condition = match self.condition(module.etag()) {
+            Some(condition) => condition,
+            None => {
+                return Either::B(future::err(Error::from(ErrorKind::UpsertModuleWithReason(
+                    module_id,
+                    ModuleOperationReason::MissingEtag,
+                ))))
+            }
+        };
+        let module = module
+            .with_status(STATUS_DISABLED.to_string())
+            .with_status_reason(reason.to_string());
+
+        let path = format!(
+            "/devices/{}/modules/{}",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let res = self
+            .client
+            .request_with_meta::<Module, Module>(
+                Method::PUT,
+                &path,
+                None,
+                HeaderMap::new(),
+                Some(module),
+                condition,
+            )
+            .then(|module| {
+                let (module, headers) =
+                    module.with_context(|_| ErrorKind::UpsertModule(module_id.clone()))?;
+                module
+                    .map(|module| with_etag_header(module, &headers))
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::UpsertModuleWithReason(
+                            module_id,
+                            ModuleOperationReason::EmptyResponse {
+                                operation: "disable_module",
+                                path,
+                            },
+                        ))
+                    })
+            });
+
+        Either::A(self.invalidate_on_completion(res))
+    }
+
+    /// Gets a module, or `None` if it doesn't exist.
+    pub fn find_module(
+        &self,
+        module_id: &str,
+    ) -> impl Future<Item = Option<Module>, Error = Error> {
+        self.get_module_by_id(module_id.to_string())
+            .then(|module| match module {
+                Ok(module) => Ok(Some(module)),
+                Err(err) => match err.kind() {
+                    ErrorKind::GetModuleWithReason(_, ModuleOperationReason::ModuleNotFound) => {
+                        Ok(None)
+                    }
+                    _ => Err(err),
+                },
+            })
+    }
+
+    /// Creates the module if it's missing, or updates it if its
+    /// authentication doesn't match `authentication`, writing to IoT Hub at
+    /// most once. An update is conditional on the ETag of the module that
+    /// was found, so it fails rather than overwrite a concurrent change.
+    pub fn ensure_module(
+        &self,
+        module_id: String,
+        authentication: AuthMechanism,
+    ) -> impl Future<Item = EnsureOutcome, Error = Error> {
+        let client = self.clone();
+
+        let existing = match self.module_id_matching {
+            ModuleIdMatching::Exact => Either::A(self.find_module(&module_id)),
+            matching @ ModuleIdMatching::CaseInsensitive => {
+                let wanted = module_id.clone();
+                Either::B(self.list_modules().map(move |modules| {
+                    modules.into_iter().find(|module| {
+                        module
+                            .module_id()
+                            .map_or(false, |module_id| matching.matches(module_id, &wanted))
+                    })
+                }))
+            }
+        };
+        existing.and_then(move |module| match module {
+            None => Either::A(
+                client
+                    .create_module(module_id, Some(authentication), None)
+                    .map(EnsureOutcome::Created),
+            ),
+            Some(ref module) if module.matches_spec(&authentication) => Either::B(Either::A(
+                future::ok(EnsureOutcome::Unchanged(module.clone())),
+            )),
+            Some(module) => {
+                let condition = match module.etag() {
+                    Some(etag) => Some(Condition::IfMatch(etag.to_string())),
+                    None => client.condition(None),
+                };
+                match condition {
+                    Some(condition) => Either::B(Either::B(Either::A(
+                        client
+                            .upsert_module(
+                                module_id,
+                                Some(authentication),
+                                module.managed_by(),
+                                condition,
+                            )
+                            .map(EnsureOutcome::Updated),
+                    ))),
+                    None => Either::B(Either::B(Either::B(future::err(Error::from(
+                        ErrorKind::UpsertModuleWithReason(
+                            module_id,
+                            ModuleOperationReason::MissingEtag,
+                        ),
+                    ))))),
+                }
+            }
+        })
     }
 
     pub fn get_module_by_id(&self, module_id: String) -> impl Future<Item = Module, Error = Error> {
@@ -118,10 +845,13 @@ where
                 module_id,
                 ModuleOperationReason::EmptyModuleId,
             ))))
+        } else if let Err(err) = validate_module_id(&module_id) {
+            Either::B(future::err(err))
         } else {
+            let required_fields = self.required_fields;
             let res = self
                 .client
-                .request::<(), Module>(
+                .request_with_meta::<(), Module>(
                     Method::GET,
                     &format!(
                         "/devices/{}/modules/{}",
@@ -129,21 +859,26 @@ where
                         url_encode(&module_id)
                     ),
                     None,
+                    HeaderMap::new(),
                     None,
-                    false,
+                    Condition::None,
                 )
-                .then(|module| match module {
-                    Ok(Some(module)) => Ok(module),
+                .then(move |module| match module {
+                    Ok((Some(module), headers)) => {
+                        module.validate()?;
+                        if required_fields {
+                            module.require_fields()?;
+                        }
+                        Ok(with_etag_header(module, &headers))
+                    }
 
-                    Ok(None) => Err(Error::from(ErrorKind::GetModuleWithReason(
+                    Ok((None, _)) => Err(Error::from(ErrorKind::GetModuleWithReason(
                         module_id,
                         ModuleOperationReason::ModuleNotFound,
                     ))),
 
                     Err(err) => Err({
-                        if let HttpErrorKind::HttpWithErrorResponse(StatusCode::NOT_FOUND, _) =
-                            err.kind()
-                        {
+                        if err.is_not_found() {
                             Error::from(ErrorKind::GetModuleWithReason(
                                 module_id,
                                 ModuleOperationReason::ModuleNotFound,
@@ -159,456 +894,4567 @@ where
     }
 
     pub fn list_modules(&self) -> impl Future<Item = Vec<Module>, Error = Error> {
-        self.client
-            .request::<(), Vec<Module>>(
-                Method::GET,
-                &format!("/devices/{}/modules", url_encode(&self.device_id)),
-                None,
-                None,
-                false,
-            )
+        if let Some(modules) = self.cache.as_ref().and_then(ModuleCache::get) {
+            return Either::B(future::ok(modules));
+        }
+
+        let cache = self.cache.clone().map(|cache| (cache.generation(), cache));
+        let path = format!("/devices/{}/modules", url_encode(&self.device_id));
+        let required_fields = self.required_fields;
+        let res = self
+            .client
+            .request::<(), Vec<Module>>(Method::GET, &path, None, None, Condition::None)
             .map_err(|err| Error::from(err.context(ErrorKind::ListModules)))
             .and_then(|modules| {
                 modules.ok_or_else(|| {
                     Error::from(ErrorKind::ListModulesWithReason(
-                        ModuleOperationReason::EmptyResponse,
+                        ModuleOperationReason::EmptyResponse {
+                            operation: "list_modules",
+                            path,
+                        },
                     ))
                 })
             })
-    }
+            .and_then(move |modules| {
+                if required_fields {
+                    for module in &modules {
+                        module.require_fields()?;
+                    }
+                }
+                Ok(modules)
+            })
+            .map(move |modules| {
+                if let Some((generation, cache)) = cache {
+                    cache.set(generation, modules.clone());
+                }
+                modules
+            });
 
-    pub fn delete_module(&self, module_id: &str) -> impl Future<Item = (), Error = Error> {
-        if module_id.trim().is_empty() {
-            Either::B(future::err(Error::from(ErrorKind::DeleteModuleWithReason(
-                module_id.to_string(),
-                ModuleOperationReason::EmptyModuleId,
-            ))))
-        } else {
-            let res = self
-                .client
-                .request::<(), ()>(
-                    Method::DELETE,
-                    &format!(
-                        "/devices/{}/modules/{}",
-                        url_encode(&self.device_id),
-                        url_encode(&module_id)
-                    ),
-                    None,
-                    None,
-                    true,
-                )
-                .map_err(|err| Error::from(err.context(ErrorKind::DeleteModule)))
-                .and_then(|_| Ok(()));
+        Either::A(res)
+    }
 
-            Either::A(res)
-        }
+    /// Lists the modules of the device like `list_modules`, but yields each
+    /// module as soon as it is parsed out of the response, so that a listing
+    /// of thousands of modules is never held in memory all at once. The
+    /// module cache isn't used.
+    pub fn stream_modules(&self) -> impl Stream<Item = Module, Error = Error> {
+        let path = format!("/devices/{}/modules", url_encode(&self.device_id));
+        self.client
+            .request_streaming::<()>(Method::GET, &path, None, None, Condition::None)
+            .map_err(|err| Error::from(err.context(ErrorKind::ListModules)))
+            .map(|resp| {
+                JsonArrayStream::new(resp.into_body())
+                    .map_err(|err| Error::from(err.context(ErrorKind::ListModules)))
+            })
+            .flatten_stream()
     }
-}
 
-impl<C, T> Clone for DeviceClient<C, T>
-where
-    C: ClientImpl,
-    T: TokenSource + Clone,
-{
-    fn clone(&self) -> Self {
-        DeviceClient {
-            client: self.client.clone(),
-            device_id: self.device_id.clone(),
-        }
+    /// Lists the modules of the device keyed by module ID, so that they can be
+    /// looked up and iterate in the same order whatever order IoT Hub lists
+    /// them in. Modules without an ID are left out.
+    pub fn modules_by_id(&self) -> impl Future<Item = BTreeMap<String, Module>, Error = Error> {
+        self.list_modules().map(|modules| {
+            modules
+                .into_iter()
+                .filter_map(|module| {
+                    module
+                        .module_id()
+                        .map(ToString::to_string)
+                        .map(|module_id| (module_id, module))
+                })
+                .collect()
+        })
     }
-}
 
-fn url_encode(value: &str) -> PercentEncode<'_, IOTHUB_ENCODE_SET> {
-    percent_encode(value.as_bytes(), IOTHUB_ENCODE_SET)
-}
+    /// Lists the modules of the device that are disabled. Modules listed
+    /// without a status, as older API versions list them, are enabled and
+    /// left out.
+    pub fn list_disabled_modules(&self) -> impl Future<Item = Vec<Module>, Error = Error> {
+        self.list_modules().map(|modules| {
+            modules
+                .into_iter()
+                .filter(|module| {
+                    module
+                        .status()
+                        .map_or(false, |status| status.eq_ignore_ascii_case(STATUS_DISABLED))
+                })
+                .collect()
+        })
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        AuthMechanism, Client, DeviceClient, Error, Future, Module, StatusCode, TokenSource,
-    };
+    /// Lists the modules of the device alongside their twins, fetching at most
+    /// `max_concurrent` twins at a time, in the order the modules were listed.
+    /// Failing to fetch a twin is reported for its module without stopping
+    /// the others; only failing to list the modules fails the whole operation.
+    pub fn list_modules_with_twins(
+        &self,
+        max_concurrent: usize,
+    ) -> impl Future<Item = Vec<(Module, Result<Twin, Error>)>, Error = Error> {
+        let client = self.clone();
 
+        self.list_modules().and_then(move |modules| {
+            stream::iter_ok(modules)
+                .map(move |module| {
+                    client
+                        .get_module_twin(module.module_id().unwrap_or_default())
+                        .then(move |result| Ok::<_, Error>((module, result)))
+                })
+                .buffered(max_concurrent.max(1))
+                .collect()
+        })
+    }
+
+    /// Lists the modules that have more than `threshold` cloud-to-device
+    /// messages queued.
+    pub fn modules_with_pending_messages(
+        &self,
+        threshold: usize,
+    ) -> impl Future<Item = Vec<Module>, Error = Error> {
+        self.list_modules().map(move |modules| {
+            modules
+                .into_iter()
+                .filter(|module| module.cloud_to_device_message_count() > threshold)
+                .collect()
+        })
+    }
+
+    /// Purges the cloud-to-device messages queued for a module and returns how
+    /// many were purged. A module or queue that doesn't exist has nothing to
+    /// purge, so it returns 0.
+    pub fn purge_module_messages(
+        &self,
+        module_id: &str,
+    ) -> impl Future<Item = usize, Error = Error> {
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(
+                ErrorKind::PurgeModuleMessagesWithReason(
+                    module_id.to_string(),
+                    ModuleOperationReason::EmptyModuleId,
+                ),
+            )));
+        }
+
+        let module_id = module_id.to_string();
+        let path = format!(
+            "/devices/{}/modules/{}/messages/deviceBound",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let res = self
+            .client
+            .request::<(), PurgeMessageQueueResult>(
+                Method::DELETE,
+                &path,
+                None,
+                None,
+                Condition::None,
+            )
+            .then(|result| match result {
+                Ok(Some(result)) => Ok(result.total_messages_purged()),
+                Ok(None) => Err(Error::from(ErrorKind::PurgeModuleMessagesWithReason(
+                    module_id,
+                    ModuleOperationReason::EmptyResponse {
+                        operation: "purge_module_messages",
+                        path,
+                    },
+                ))),
+                Err(ref err) if err.is_not_found() => Ok(0),
+                Err(err) => Err(Error::from(
+                    err.context(ErrorKind::PurgeModuleMessages(module_id)),
+                )),
+            });
+
+        Either::A(self.invalidate_on_completion(res))
+    }
+
+    /// Lists the modules whose twin or registry entry changed after `since`.
+    ///
+    /// From API version 2018-06-30 on this runs a module twin query for
+    /// modules whose desired or reported properties were updated, or that
+    /// were active, after `since`. Older API versions can't query module
+    /// twins, so every module is listed instead and filtered on its
+    /// `lastActivityTime`, which doesn't reflect twin updates.
+    ///
+    /// Without the `chrono` feature `since` must be an RFC 3339 timestamp in
+    /// UTC.
+    pub fn modules_changed_since(
+        &self,
+        since: Timestamp,
+    ) -> impl Future<Item = Vec<Module>, Error = Error> {
+        if self.client.api_version() >= MODULE_QUERY_API_VERSION {
+            let query = format!(
+                "SELECT * FROM devices.modules WHERE deviceId = {device_id} AND \
+                 (properties.desired.$metadata.$lastUpdated > {since} OR \
+                 properties.reported.$metadata.$lastUpdated > {since} OR \
+                 lastActivityTime > {since})",
+                device_id = quote_query_string(&self.device_id),
+                since = quote_query_string(&timestamp::to_rfc3339(&since)),
+            );
+
+            let res = Paginator::<_, _, Module>::new(
+                self.client.clone(),
+                Method::POST,
+                "/devices/query".to_string(),
+                || ErrorKind::ListModules,
+            )
+            .with_body(json!({ "query": query }))
+            .all()
+            .map_err(|err| err.into_parts().2);
+            Either::A(res)
+        } else {
+            Either::B(self.list_modules().map(move |modules| {
+                modules
+                    .into_iter()
+                    .filter(|module| {
+                        module
+                            .last_activity_time()
+                            .map_or(false, |last_activity_time| {
+                                timestamp::is_after(last_activity_time, &since)
+                            })
+                    })
+                    .collect()
+            }))
+        }
+    }
+
+    pub fn list_all_modules(
+        &self,
+    ) -> impl Future<Item = Vec<Module>, Error = PartialListError<Module>> {
+        self.list_all_modules_from(None)
+    }
+
+    /// Lists the modules a page at a time, starting at the page identified by
+    /// `continuation`. If fetching a page fails the error carries the modules
+    /// listed so far and the token to resume from. Each page is only
+    /// requested once the previous one arrived, so dropping the future
+    /// requests no further pages.
+    pub fn list_all_modules_from(
+        &self,
+        continuation: Option<String>,
+    ) -> impl Future<Item = Vec<Module>, Error = PartialListError<Module>> {
+        self.list_modules_paged().all_from(continuation)
+    }
+
+    /// Writes every module to `writer` as newline-delimited JSON, one page at
+    /// a time, and returns the number of modules written.
+    pub fn export_modules_to<W>(&self, writer: W) -> impl Future<Item = usize, Error = Error>
+    where
+        W: Write,
+    {
+        self.export_modules_from(writer, None)
+    }
+
+    /// Like `export_modules_to`, but starts at the page identified by
+    /// `continuation`. If a page can't be fetched the error is
+    /// `ErrorKind::ExportModules`, and if it can't be written it is
+    /// `ErrorKind::WriteModuleExport`. Both carry the continuation token of
+    /// that page to resume the export from.
+    pub fn export_modules_from<W>(
+        &self,
+        writer: W,
+        continuation: Option<String>,
+    ) -> impl Future<Item = usize, Error = Error>
+    where
+        W: Write,
+    {
+        let paginator = self.list_modules_paged();
+
+        future::loop_fn(
+            (writer, 0, continuation),
+            move |(mut writer, written, continuation): (W, usize, Option<String>)| {
+                paginator
+                    .page(continuation.as_ref().map(AsRef::as_ref))
+                    .then(move |page| {
+                        let page =
+                            page.with_context(|_| ErrorKind::ExportModules(continuation.clone()))?;
+                        write_modules(&mut writer, page.items())
+                            .with_context(|_| ErrorKind::WriteModuleExport(continuation))?;
+
+                        let written = written + page.items().len();
+                        Ok::<_, Error>(match page.continuation() {
+                            Some(next) => Loop::Continue((writer, written, Some(next.to_string()))),
+                            None => Loop::Break(written),
+                        })
+                    })
+            },
+        )
+    }
+
+    /// Estimates the size of the device's modules serialized as JSON from the
+    /// average size of those on the first page. If there are more pages the
+    /// modules are counted with a module query, which needs API version
+    /// 2018-06-30 or later.
+    pub fn estimate_modules_size(&self) -> impl Future<Item = SizeEstimate, Error = Error> {
+        let client = self.client.clone();
+        let query = format!(
+            "SELECT COUNT() AS numberOfModules FROM devices.modules WHERE deviceId = {}",
+            quote_query_string(&self.device_id)
+        );
+
+        self.list_modules_paged().page(None).and_then(move |page| {
+            let sample_size = page.items().len();
+            let sample_bytes = page
+                .items()
+                .iter()
+                .map(|module| serde_json::to_vec(module).map_or(0, |bytes| bytes.len()))
+                .sum::<usize>();
+
+            let module_count = match page.continuation() {
+                None => Either::A(future::ok(sample_size)),
+                Some(_) => Either::B(
+                    client
+                        .request::<Value, Vec<Value>>(
+                            Method::POST,
+                            "/devices/query",
+                            None,
+                            Some(json!({ "query": query })),
+                            Condition::None,
+                        )
+                        .map_err(|err| Error::from(err.context(ErrorKind::ListModules)))
+                        .map(move |counts| {
+                            counts
+                                .and_then(|counts| counts.first()?["numberOfModules"].as_u64())
+                                .and_then(|count| usize::try_from(count).ok())
+                                .unwrap_or(sample_size)
+                        }),
+                ),
+            };
+
+            module_count.map(move |module_count| SizeEstimate {
+                bytes: sample_bytes
+                    .checked_div(sample_size)
+                    .map_or(0, |average| average * module_count),
+                module_count,
+                sample_size,
+            })
+        })
+    }
+
+    pub fn list_modules_paged(&self) -> Paginator<C, T, Module> {
+        Paginator::new(
+            self.client.clone(),
+            Method::GET,
+            format!("/devices/{}/modules", url_encode(&self.device_id)),
+            || ErrorKind::ListModules,
+        )
+    }
+
+    /// Deletes every module of the device one at a time and returns the IDs
+    /// of the deleted modules. Modules whose IDs start with `$` are skipped
+    /// unless system modules are allowed. Each delete is only issued once the
+    /// previous one completed, so dropping the future issues no further
+    /// deletes. `deleted` records every module as soon as its delete
+    /// completes, which tells the caller what was deleted before the future
+    /// was dropped or failed. A delete that was in flight when the future
+    /// was dropped isn't recorded even if IoT Hub carried it out.
+    /// Every delete goes through the client's retry policy on its own.
+    pub fn delete_all_modules(
+        &self,
+        deleted: DeletedModules,
+    ) -> impl Future<Item = Vec<String>, Error = Error> {
+        let client = self.clone();
+
+        self.list_all_modules()
+            .map_err(|err| err.into_parts().2)
+            .and_then(move |modules| {
+                let module_ids: Vec<String> = modules
+                    .iter()
+                    .filter_map(|module| {
+                        module
+                            .module_id()
+                            .filter(|module_id| {
+                                client.system_modules_allowed
+                                    || !module_id.starts_with(RESERVED_MODULE_ID_PREFIX)
+                            })
+                            .map(ToString::to_string)
+                    })
+                    .collect();
+
+                future::loop_fn(
+                    (module_ids.into_iter(), Vec::<String>::new()),
+                    move |(mut remaining, mut deleted_ids)| {
+                        let deleted = deleted.clone();
+                        match remaining.next() {
+                            Some(module_id) => Either::A(
+                                client.delete_module_if_exists(&module_id).map(move |()| {
+                                    deleted.push(module_id.clone());
+                                    deleted_ids.push(module_id);
+                                    Loop::Continue((remaining, deleted_ids))
+                                }),
+                            ),
+                            None => Either::B(future::ok(Loop::Break(deleted_ids))),
+                        }
+                    },
+                )
+            })
+    }
+
+    pub fn delete_module(&self, module_id: &str) -> impl Future<Item = (), Error = Error> {
+        self.delete_module_inner(module_id, None, false)
+    }
+
+    pub fn delete_module_if_exists(
+        &self,
+        module_id: &str,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.delete_module_inner(module_id, None, true)
+    }
+
+    /// Deletes `module` only if it hasn't changed since it was read, using its
+    /// ETag as the `If-Match` condition. A module without an ETag is deleted
+    /// according to the client's `EtagFallback`.
+    pub fn delete(&self, module: &Module) -> impl Future<Item = (), Error = Error> {
+        self.delete_module_inner(module.module_id().unwrap_or_default(), module.etag(), false)
+    }
+
+    /// Reads a module then deletes it conditionally on the ETag it was read
+    /// with, returning the module as it was right before the delete. If the
+    /// module changed in between the delete fails with a 412 rather than
+    /// deleting the new incarnation, and a module read without an ETag isn't
+    /// deleted at all.
+    pub fn delete_module_returning(
+        &self,
+        module_id: &str,
+    ) -> impl Future<Item = Module, Error = Error> {
+        let client = self.clone();
+
+        self.get_module_by_id(module_id.to_string())
+            .and_then(move |module| {
+                if module.etag().is_none() {
+                    return Either::B(future::err(Error::from(ErrorKind::DeleteModuleWithReason(
+                        module.module_id().unwrap_or_default().to_string(),
+                        ModuleOperationReason::MissingEtag,
+                    ))));
+                }
+
+                Either::A(client.delete(&module).map(|()| module))
+            })
+    }
+
+    fn delete_module_inner(
+        &self,
+        module_id: &str,
+        etag: Option<&str>,
+        ignore_not_found: bool,
+    ) -> impl Future<Item = (), Error = Error> {
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(ErrorKind::DeleteModuleWithReason(
+                module_id.to_string(),
+                ModuleOperationReason::EmptyModuleId,
+            ))));
+        }
+        if let Err(err) = validate_module_id(module_id) {
+            return Either::B(future::err(err));
+        }
+
+        let condition = match self.condition(etag) {
+            Some(condition) => condition,
+            None => {
+                return Either::B(future::err(Error::from(ErrorKind::DeleteModuleWithReason(
+                    module_id.to_string(),
+                    ModuleOperationReason::MissingEtag,
+                ))))
+            }
+        };
+
+        let client = self.client.clone();
+        let path = format!(
+            "/devices/{}/modules/{}",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let delete = move || {
+            client
+                .request::<(), ()>(Method::DELETE, &path, None, None, condition)
+                .then(move |result| match result {
+                    Ok(_) => Ok(()),
+                    Err(ref err) if ignore_not_found && err.is_not_found() => Ok(()),
+                    Err(err) => Err(Error::from(err.context(ErrorKind::DeleteModule))),
+                })
+        };
+        let res = match self.deletes {
+            Some(ref deletes) => Either::A(deletes.coalesce(
+                (
+                    module_id.to_string(),
+                    etag.map(ToString::to_string),
+                    ignore_not_found,
+                ),
+                delete,
+            )),
+            None => Either::B(delete()),
+        };
+
+        Either::A(self.invalidate_on_completion(res))
+    }
+
+    pub fn apply_module_operations<I>(
+        &self,
+        operations: I,
+    ) -> impl Future<Item = BulkRegistryOperationResult, Error = Error>
+    where
+        I: IntoIterator<Item = ModuleOperation>,
+        I::IntoIter: 'static + Send,
+    {
+        // serialize the operations one at a time as the request body is
+        // streamed instead of building the whole JSON array upfront
+        let device_id = self.device_id.clone();
+        let operations = stream::iter_ok(operations.into_iter().enumerate()).and_then(
+            move |(i, operation)| -> Result<Vec<u8>, serde_json::Error> {
+                let mut chunk = if i == 0 { vec![] } else { b",".to_vec() };
+                serde_json::to_writer(&mut chunk, &operation.with_device_id(device_id.clone()))?;
+                Ok(chunk)
+            },
+        );
+        let body = stream::once(Ok(b"[".to_vec()))
+            .chain(operations)
+            .chain(stream::once(Ok(b"]".to_vec())));
+
+        let res = self
+            .client
+            .request_stream::<_, BulkRegistryOperationResult>(
+                Method::POST,
+                "/devices",
+                None,
+                body,
+                Condition::None,
+            )
+            .map_err(|err| Error::from(err.context(ErrorKind::ApplyModuleOperations)))
+            .and_then(|result| {
+                result.ok_or_else(|| {
+                    Error::from(ErrorKind::ApplyModuleOperationsWithReason(
+                        ModuleOperationReason::EmptyResponse {
+                            operation: "apply_module_operations",
+                            path: "/devices".to_string(),
+                        },
+                    ))
+                })
+            });
+
+        self.invalidate_on_completion(res)
+    }
+
+    /// Deletes `module_ids` with bulk registry operations rather than a
+    /// request per module, in requests of at most 100 modules sent one
+    /// after the other. Returns the ID of every module with the result of
+    /// its delete, in the order given. A module IoT Hub couldn't delete fails
+    /// with `ModuleOperationReason::RegistryOperationFailed`, and if a
+    /// request fails as a whole every module in it fails with its error.
+    pub fn delete_modules(
+        &self,
+        module_ids: &[String],
+    ) -> impl Future<Item = Vec<(String, Result<(), Error>)>, Error = Error> {
+        let client = self.clone();
+        let batches: Vec<Vec<String>> = module_ids
+            .chunks(MAX_BULK_OPERATIONS)
+            .map(<[String]>::to_vec)
+            .collect();
+
+        stream::iter_ok(batches)
+            .and_then(move |module_ids| {
+                let operations: Vec<ModuleOperation> = module_ids
+                    .iter()
+                    .map(|module_id| ModuleOperation::new(module_id.clone(), ImportMode::Delete))
+                    .collect();
+                client
+                    .apply_module_operations(operations)
+                    .then(move |result| Ok(bulk_delete_results(module_ids, result)))
+            })
+            .collect()
+            .map(|batches| batches.into_iter().flatten().collect())
+    }
+
+    pub fn get_device(&self) -> impl Future<Item = Device, Error = Error> {
+        let path = format!("/devices/{}", url_encode(&self.device_id));
+        self.client
+            .request::<(), Device>(Method::GET, &path, None, None, Condition::None)
+            .map_err(|err| Error::from(err.context(ErrorKind::GetDevice)))
+            .and_then(|device| {
+                device.ok_or_else(|| {
+                    Error::from(ErrorKind::GetDeviceWithReason(
+                        ModuleOperationReason::EmptyResponse {
+                            operation: "get_device",
+                            path,
+                        },
+                    ))
+                })
+            })
+    }
+
+    /// Deletes the device. The delete is made conditional according to the
+    /// client's `EtagFallback`. IoT Hub deletes the modules of the device
+    /// along with it; see `delete_device_cascade` to delete them explicitly
+    /// first.
+    pub fn delete_device(&self) -> impl Future<Item = (), Error = Error> {
+        let condition = match self.condition(None) {
+            Some(condition) => condition,
+            None => {
+                return Either::B(future::err(Error::from(ErrorKind::DeleteDeviceWithReason(
+                    ModuleOperationReason::MissingEtag,
+                ))))
+            }
+        };
+
+        Either::A(self.delete_device_with_condition(condition))
+    }
+
+    fn delete_device_with_condition(
+        &self,
+        condition: Condition,
+    ) -> impl Future<Item = (), Error = Error> {
+        let path = format!("/devices/{}", url_encode(&self.device_id));
+        let res = self
+            .client
+            .request::<(), ()>(Method::DELETE, &path, None, None, condition)
+            .then(|result| match result {
+                Ok(_) => Ok(()),
+                Err(err) => Err(Error::from(err.context(ErrorKind::DeleteDevice))),
+            });
+
+        self.invalidate_on_completion(res)
+    }
+
+    /// Deletes every module of the device with `delete_all_modules`, then the
+    /// device itself, and returns the IDs of the deleted modules. If a module
+    /// fails to be deleted the cascade stops there and the device is left in
+    /// place, failing with `ErrorKind::DeleteDevice`. `deleted` then tells
+    /// the caller which modules were deleted before the failure.
+    pub fn delete_device_cascade(
+        &self,
+        deleted: DeletedModules,
+    ) -> impl Future<Item = Vec<String>, Error = Error> {
+        let client = self.clone();
+
+        self.delete_all_modules(deleted)
+            .map_err(|err| Error::from(err.context(ErrorKind::DeleteDevice)))
+            .and_then(move |module_ids| client.delete_device().map(|()| module_ids))
+    }
+
+    pub fn get_module_twin(&self, module_id: &str) -> impl Future<Item = Twin, Error = Error> {
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(
+                ErrorKind::GetModuleTwinWithReason(
+                    module_id.to_string(),
+                    ModuleOperationReason::EmptyModuleId,
+                ),
+            )));
+        }
+
+        let module_id = module_id.to_string();
+        let path = format!(
+            "/twins/{}/modules/{}",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let res = self
+            .client
+            .request::<(), Twin>(Method::GET, &path, None, None, Condition::None)
+            .then(|twin| {
+                let twin = twin.with_context(|_| ErrorKind::GetModuleTwin(module_id.clone()))?;
+                twin.ok_or_else(|| {
+                    Error::from(ErrorKind::GetModuleTwinWithReason(
+                        module_id,
+                        ModuleOperationReason::EmptyResponse {
+                            operation: "get_module_twin",
+                            path,
+                        },
+                    ))
+                })
+            });
+
+        Either::A(res)
+    }
+
+    /// Like `get_module_twin`, but with `TwinMetadata::Minimal` the
+    /// `$metadata` of the twin's properties is dropped once the twin is
+    /// parsed, so that a twin kept around takes less memory. IoT Hub still
+    /// sends it.
+    pub fn get_module_twin_with_metadata(
+        &self,
+        module_id: &str,
+        metadata: TwinMetadata,
+    ) -> impl Future<Item = Twin, Error = Error> {
+        self.get_module_twin(module_id)
+            .map(move |twin| match metadata {
+                TwinMetadata::Full => twin,
+                TwinMetadata::Minimal => twin.without_metadata(),
+            })
+    }
+
+    /// Gets a module's twin unless it still has `last_etag`, in which case
+    /// IoT Hub answers the `If-None-Match` request with a 304 and no body and
+    /// this returns `None`. The ETag of the returned twin is the one to poll
+    /// with next. Without `last_etag` the twin is always returned.
+    pub fn poll_module_twin_if_changed(
+        &self,
+        module_id: &str,
+        last_etag: Option<&str>,
+    ) -> impl Future<Item = Option<Twin>, Error = Error> {
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(
+                ErrorKind::GetModuleTwinWithReason(
+                    module_id.to_string(),
+                    ModuleOperationReason::EmptyModuleId,
+                ),
+            )));
+        }
+
+        let module_id = module_id.to_string();
+        let condition = last_etag.map_or(Condition::None, |etag| {
+            Condition::IfNoneMatch(etag.to_string())
+        });
+        let path = format!(
+            "/twins/{}/modules/{}",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let res = self
+            .client
+            .request_with_meta::<(), Twin>(
+                Method::GET,
+                &path,
+                None,
+                HeaderMap::new(),
+                None,
+                condition,
+            )
+            .then(|twin| match twin {
+                Ok((Some(twin), headers)) => Ok(Some(with_twin_etag_header(twin, &headers))),
+                Ok((None, _)) => Err(Error::from(ErrorKind::GetModuleTwinWithReason(
+                    module_id,
+                    ModuleOperationReason::EmptyResponse {
+                        operation: "poll_module_twin_if_changed",
+                        path,
+                    },
+                ))),
+                Err(ref err) if err.status_code() == Some(StatusCode::NOT_MODIFIED) => Ok(None),
+                Err(err) => Err(Error::from(
+                    err.context(ErrorKind::GetModuleTwin(module_id)),
+                )),
+            });
+
+        Either::A(res)
+    }
+
+    /// Polls a module's twin every `poll_interval` until its reported
+    /// properties are at `desired_version` or later, and returns that twin.
+    /// Once `timeout` has elapsed the twin is polled one last time, and if
+    /// its reported properties still aren't there this fails with
+    /// `ErrorKind::Timeout`. Waits run on the client's timer if it has one.
+    pub fn wait_for_reported_version(
+        &self,
+        module_id: &str,
+        desired_version: i64,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> impl Future<Item = Twin, Error = Error> {
+        let is_reported = move |twin: &Twin| {
+            reported_version(twin).map_or(false, |version| version >= desired_version)
+        };
+
+        let client = self.clone();
+        let loop_module_id = module_id.to_string();
+        let polls = future::loop_fn((), move |()| {
+            let client = client.clone();
+            let module_id = loop_module_id.clone();
+            client.get_module_twin(&module_id).and_then(move |twin| {
+                if is_reported(&twin) {
+                    return Either::A(future::ok(Loop::Break(twin)));
+                }
+
+                Either::B(
+                    client
+                        .delay(Instant::now() + poll_interval)
+                        .map(|()| Loop::Continue(()))
+                        .map_err(|err| {
+                            Error::from(err.context(ErrorKind::GetModuleTwin(module_id)))
+                        }),
+                )
+            })
+        });
+
+        let client = self.clone();
+        let module_id = module_id.to_string();
+        let deadline = self.delay(Instant::now() + timeout);
+        polls.select2(deadline).then(move |res| match res {
+            Ok(Either::A((twin, _))) => Either::A(future::ok(twin)),
+            Err(Either::A((err, _))) => Either::A(future::err(err)),
+            Err(Either::B((err, _))) => Either::A(future::err(Error::from(
+                err.context(ErrorKind::GetModuleTwin(module_id)),
+            ))),
+            // the last poll, at the deadline
+            Ok(Either::B(((), _))) => {
+                Either::B(client.get_module_twin(&module_id).and_then(move |twin| {
+                    if is_reported(&twin) {
+                        Ok(twin)
+                    } else {
+                        Err(Error::from(ErrorKind::Timeout(module_id, desired_version)))
+                    }
+                }))
+            }
+        })
+    }
+
+    // A timer firing at `deadline`, on the client's timer if it has one and
+    // otherwise on the timer of the runtime it is polled on.
+    fn delay(&self, deadline: Instant) -> Delay {
+        match self.client.timer() {
+            Some(timer) => timer.delay(deadline),
+            None => Delay::new(deadline),
+        }
+    }
+
+    /// Replaces the tags and desired properties of a module's twin with those
+    /// of `twin`, unlike `update_module_twin` which merges a patch into them.
+    /// Properties left out of `twin` are removed, and so are those it sets to
+    /// `null`. With an `etag` the twin is only replaced if it hasn't changed
+    /// since that ETag was read, otherwise the client's `EtagFallback` applies.
+    pub fn replace_module_twin(
+        &self,
+        module_id: &str,
+        twin: Twin,
+        etag: Option<&str>,
+    ) -> impl Future<Item = Option<Twin>, Error = Error> {
+        let module_id = module_id.to_string();
+
+        let condition = match self.condition(etag) {
+            Some(condition) => condition,
+            None => {
+                return Either::B(future::err(Error::from(
+                    ErrorKind::ReplaceModuleTwinWithReason(
+                        module_id,
+                        ModuleOperationReason::MissingEtag,
+                    ),
+                )))
+            }
+        };
+
+        Either::A(self.replace_module_twin_with_condition(module_id, twin, condition))
+    }
+
+    fn replace_module_twin_with_condition(
+        &self,
+        module_id: String,
+        twin: Twin,
+        condition: Condition,
+    ) -> impl Future<Item = Option<Twin>, Error = Error> {
+        self.client
+            .request::<Twin, Twin>(
+                Method::PUT,
+                &format!(
+                    "/twins/{}/modules/{}",
+                    url_encode(&self.device_id),
+                    url_encode(&module_id)
+                ),
+                None,
+                Some(twin),
+                condition,
+            )
+            .map_err(|err| Error::from(err.context(ErrorKind::ReplaceModuleTwin(module_id))))
+    }
+
+    /// Patches the desired properties of a module's twin. If `expected_version`
+    /// is given it is the twin version the patch was computed against, and a
+    /// `VersionConflict` is returned if the twin ends up at any version other
+    /// than the one this update alone would produce.
+    ///
+    /// IoT Hub doesn't make twin patches conditional on the version, so this is
+    /// a best-effort check: the patch has already been applied when a conflict
+    /// is reported, and a write that lands after the patch but before the twin
+    /// is re-read is reported as a conflict too.
+    ///
+    /// A patch over IoT Hub's size limit fails with `TwinPatchTooLarge`,
+    /// unless the client was configured with `with_split_twin_patches`.
+    pub fn update_module_twin(
+        &self,
+        module_id: &str,
+        desired: Value,
+        expected_version: Option<i32>,
+    ) -> impl Future<Item = Twin, Error = Error> {
+        let client = self.clone();
+        let module_id = module_id.to_string();
+
+        let patches = match twin_patches(&module_id, desired, self.split_twin_patches) {
+            Ok(patches) => patches,
+            Err(err) => return Either::B(future::err(err)),
+        };
+        let expected_version = expected_version.map(|expected_version| {
+            expected_version.saturating_add(i32::try_from(patches.len()).unwrap_or(i32::MAX))
+        });
+
+        let res = future::loop_fn((patches.into_iter(), None), {
+            let client = client.clone();
+            let module_id = module_id.clone();
+            move |(mut patches, twin)| match patches.next() {
+                Some(patch) => Either::A(
+                    client
+                        .patch_module_twin(&module_id, patch)
+                        .map(|twin| Loop::Continue((patches, twin))),
+                ),
+                None => Either::B(future::ok(Loop::Break(twin))),
+            }
+        })
+        .and_then({
+            let module_id = module_id.clone();
+            move |twin| match twin {
+                Some(twin) => Either::A(future::ok(twin)),
+                None => Either::B(client.get_module_twin(&module_id)),
+            }
+        })
+        .and_then(move |twin| match expected_version {
+            Some(expected_version) if *twin.version() != expected_version => Err(Error::from(
+                ErrorKind::VersionConflict(module_id, expected_version, *twin.version()),
+            )),
+            _ => Ok(twin),
+        });
+
+        Either::A(res)
+    }
+
+    fn patch_module_twin(
+        &self,
+        module_id: &str,
+        patch: Value,
+    ) -> impl Future<Item = Option<Twin>, Error = Error> {
+        let module_id = module_id.to_string();
+
+        self.client
+            .request::<Value, Twin>(
+                Method::PATCH,
+                &format!(
+                    "/twins/{}/modules/{}",
+                    url_encode(&self.device_id),
+                    url_encode(&module_id)
+                ),
+                None,
+                Some(patch),
+                Condition::None,
+            )
+            .map_err(|err| Error::from(err.context(ErrorKind::UpdateModuleTwin(module_id))))
+    }
+
+    /// Invokes the direct method `method_name` on a module and returns what
+    /// the module responded with.
+    pub fn invoke_module_method(
+        &self,
+        module_id: &str,
+        method_name: &str,
+        payload: Value,
+        timeouts: MethodTimeouts,
+    ) -> impl Future<Item = MethodResult, Error = Error> {
+        let module_id = module_id.to_string();
+        let method_name = method_name.to_string();
+        if module_id.trim().is_empty() {
+            return Either::B(future::err(Error::from(
+                ErrorKind::InvokeModuleMethodWithReason(
+                    module_id,
+                    method_name,
+                    ModuleOperationReason::EmptyModuleId,
+                ),
+            )));
+        }
+
+        let mut method = json!({ "methodName": method_name });
+        method["payload"] = payload;
+        if let Some(timeout) = timeouts.response_timeout() {
+            method["responseTimeoutInSeconds"] = json!(timeout.as_secs());
+        }
+        if let Some(timeout) = timeouts.connect_timeout() {
+            method["connectTimeoutInSeconds"] = json!(timeout.as_secs());
+        }
+
+        let path = format!(
+            "/twins/{}/modules/{}/methods",
+            url_encode(&self.device_id),
+            url_encode(&module_id)
+        );
+        let res = self
+            .client
+            .request::<Value, MethodResult>(
+                Method::POST,
+                &path,
+                None,
+                Some(method),
+                Condition::None,
+            )
+            .then(|result| {
+                let result = result.with_context(|_| {
+                    ErrorKind::InvokeModuleMethod(module_id.clone(), method_name.clone())
+                })?;
+                result.ok_or_else(|| {
+                    Error::from(ErrorKind::InvokeModuleMethodWithReason(
+                        module_id,
+                        method_name,
+                        ModuleOperationReason::EmptyResponse {
+                            operation: "invoke_module_method",
+                            path,
+                        },
+                    ))
+                })
+            });
+
+        Either::A(res)
+    }
+
+    /// Invokes the direct method `method_name` on every module of the device,
+    /// with at most `max_concurrent` invocations in flight, and returns the
+    /// result of each in the order the modules were listed. A failed
+    /// invocation doesn't stop the others; only failing to list the modules
+    /// fails the whole operation.
+    pub fn invoke_method_all_modules(
+        &self,
+        method_name: &str,
+        payload: Value,
+        timeouts: MethodTimeouts,
+        max_concurrent: usize,
+    ) -> impl Future<Item = Vec<(String, Result<MethodResult, Error>)>, Error = Error> {
+        let client = self.clone();
+        let method_name = method_name.to_string();
+
+        self.list_modules().and_then(move |modules| {
+            let module_ids: Vec<String> = modules
+                .iter()
+                .filter_map(|module| module.module_id().map(ToString::to_string))
+                .collect();
+
+            stream::iter_ok(module_ids)
+                .map(move |module_id| {
+                    client
+                        .invoke_module_method(&module_id, &method_name, payload.clone(), timeouts)
+                        .then(move |result| Ok::<_, Error>((module_id, result)))
+                })
+                .buffered(max_concurrent.max(1))
+                .collect()
+        })
+    }
+
+    /// Captures the device's registry entry, its modules and their twins.
+    /// The twins are fetched concurrently once the modules are listed.
+    pub fn export_device(&self) -> impl Future<Item = DeviceExport, Error = Error> {
+        let client = self.clone();
+        self.get_device()
+            .join(self.list_modules())
+            .and_then(move |(device, modules)| {
+                let twins: Vec<_> = modules
+                    .iter()
+                    .filter_map(|module| {
+                        module
+                            .module_id()
+                            .map(|module_id| client.get_module_twin(module_id))
+                    })
+                    .collect();
+                future::join_all(twins).map(|twins| DeviceExport::new(device, modules, twins))
+            })
+            .map_err(|err| Error::from(err.context(ErrorKind::ExportDevice)))
+    }
+
+    /// Re-creates an exported device, its modules and their twins under this
+    /// client's device ID. The device and its non-system modules must not
+    /// already exist. The system modules IoT Hub creates along with an edge
+    /// device are replaced instead, and every twin is replaced whatever its
+    /// ETag. Modules and twins are imported one at a time, and if any of them
+    /// fails the device is deleted again and this fails with `ImportDevice`.
+    /// If it can't be deleted this fails with `ImportDevicePartial` instead,
+    /// and the device is left partly imported.
+    pub fn import_device(&self, export: &DeviceExport) -> impl Future<Item = (), Error = Error> {
+        // the export is trusted with the system modules it was taken with
+        let client = self.clone().with_system_modules_allowed(true);
+        let device = export
+            .device()
+            .clone()
+            .with_device_id(self.device_id.clone());
+        let modules = export.modules().to_vec();
+        let twins = export.twins().to_vec();
+
+        self.client
+            .request::<Device, Device>(
+                Method::PUT,
+                &format!("/devices/{}", url_encode(&self.device_id)),
+                None,
+                Some(device),
+                Condition::None,
+            )
+            .map_err(|err| Error::from(err.context(ErrorKind::UpsertDevice)))
+            .map_err(|err| Error::from(err.context(ErrorKind::ImportDevice)))
+            .and_then(move |_| {
+                let module_client = client.clone();
+                let twin_client = client.clone();
+
+                stream::iter_ok::<_, Error>(modules)
+                    .filter_map(|module| Some((module.module_id()?.to_string(), module)))
+                    .and_then(move |(module_id, module)| {
+                        let condition = if module_id.starts_with(RESERVED_MODULE_ID_PREFIX) {
+                            Condition::Any
+                        } else {
+                            Condition::None
+                        };
+                        module_client.upsert_module(
+                            module_id,
+                            module.authentication().cloned(),
+                            module.managed_by(),
+                            condition,
+                        )
+                    })
+                    .for_each(|_| Ok(()))
+                    .and_then(move |()| {
+                        stream::iter_ok::<_, Error>(twins)
+                            .filter_map(|twin| Some((twin.module_id()?.to_string(), twin)))
+                            .and_then(move |(module_id, twin)| {
+                                twin_client.replace_module_twin_with_condition(
+                                    module_id,
+                                    twin,
+                                    Condition::Any,
+                                )
+                            })
+                            .for_each(|_| Ok(()))
+                    })
+                    .then(move |result| match result {
+                        Ok(()) => Either::A(future::ok(())),
+                        Err(err) => {
+                            Either::B(client.delete_device_with_condition(Condition::Any).then(
+                                move |deleted| {
+                                    let kind = match deleted {
+                                        Ok(()) => ErrorKind::ImportDevice,
+                                        Err(_) => ErrorKind::ImportDevicePartial,
+                                    };
+                                    Err(Error::from(err.context(kind)))
+                                },
+                            ))
+                        }
+                    })
+            })
+    }
+}
+
+impl<C, T> Clone for DeviceClient<C, T>
+where
+    C: ClientImpl,
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        DeviceClient {
+            client: self.client.clone(),
+            device_id: self.device_id.clone(),
+            cache: self.cache.clone(),
+            etag_fallback: self.etag_fallback,
+            system_modules_allowed: self.system_modules_allowed,
+            split_twin_patches: self.split_twin_patches,
+            module_id_matching: self.module_id_matching,
+            deletes: self.deletes.clone(),
+            required_fields: self.required_fields,
+        }
+    }
+}
+
+// Builds the patches that set `desired` on a twin. Over the size limit,
+// `desired` is either rejected or, with `split`, its top-level properties are
+// packed into as few patches under the limit as they fit in, in key order.
+fn twin_patches(module_id: &str, desired: Value, split: bool) -> Result<Vec<Value>, Error> {
+    let patch = twin_patch(desired);
+    let size = patch_size(&patch);
+    if size <= MAX_TWIN_PATCH_SIZE {
+        return Ok(vec![patch]);
+    }
+
+    let properties = match patch["properties"]["desired"] {
+        Value::Object(ref properties) if split => properties.clone(),
+        _ => {
+            return Err(Error::from(ErrorKind::TwinPatchTooLarge(
+                module_id.to_string(),
+                size,
+            )))
+        }
+    };
+
+    let mut patches = vec![];
+    let mut chunk = serde_json::Map::new();
+    for (key, value) in properties {
+        chunk.insert(key.clone(), value.clone());
+        if patch_size(&twin_patch(Value::Object(chunk.clone()))) <= MAX_TWIN_PATCH_SIZE {
+            continue;
+        }
+
+        chunk.remove(&key);
+        if !chunk.is_empty() {
+            patches.push(twin_patch(Value::Object(chunk)));
+        }
+
+        chunk = serde_json::Map::new();
+        chunk.insert(key, value);
+        let size = patch_size(&twin_patch(Value::Object(chunk.clone())));
+        if size > MAX_TWIN_PATCH_SIZE {
+            return Err(Error::from(ErrorKind::TwinPatchTooLarge(
+                module_id.to_string(),
+                size,
+            )));
+        }
+    }
+    if !chunk.is_empty() {
+        patches.push(twin_patch(Value::Object(chunk)));
+    }
+
+    Ok(patches)
+}
+
+fn twin_patch(desired: Value) -> Value {
+    json!({ "properties": Properties::new(desired) })
+}
+
+fn patch_size(patch: &Value) -> usize {
+    serde_json::to_vec(patch).map_or(0, |bytes| bytes.len())
+}
+
+fn reported_version(twin: &Twin) -> Option<i64> {
+    twin.properties()
+        .reported()
+        .and_then(|reported| reported.get("$version"))
+        .and_then(Value::as_i64)
+}
+
+// Checks a module ID against IoT Hub's rules, which it would otherwise reject
+// with a 400 that doesn't say what is wrong.
+fn validate_module_id(module_id: &str) -> Result<(), Error> {
+    let reason = if module_id.len() > MAX_MODULE_ID_LENGTH {
+        "module ID is longer than 128 characters"
+    } else if module_id
+        .chars()
+        .any(|c| !c.is_ascii_alphanumeric() && !MODULE_ID_SYMBOLS.contains(c))
+    {
+        "module ID may only contain ASCII letters, digits and -.+%_#*?!(),:=@$'"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::from(ErrorKind::InvalidModuleId {
+        module_id: module_id.to_string(),
+        reason,
+    }))
+}
+
+fn serialize_body<B: Serialize>(body: &B) -> Result<Value, Error> {
+    serde_json::to_value(body)
+        .context(ErrorKind::Serialization)
+        .map_err(Error::from)
+}
+
+// Responses don't always repeat the ETag in the body, but do in the quoted
+// `ETag` header.
+fn with_etag_header(module: Module, headers: &HeaderMap) -> Module {
+    if module.etag().is_none() {
+        if let Some(etag) = headers.get(ETAG).and_then(|value| value.to_str().ok()) {
+            return module.with_etag(etag.trim_matches('"').to_string());
+        }
+    }
+    module
+}
+
+fn with_twin_etag_header(twin: Twin, headers: &HeaderMap) -> Twin {
+    if twin.etag().is_none() {
+        if let Some(etag) = headers.get(ETAG).and_then(|value| value.to_str().ok()) {
+            return twin.with_etag(etag.trim_matches('"').to_string());
+        }
+    }
+    twin
+}
+
+// The result of deleting each of `module_ids` with one bulk registry request.
+fn bulk_delete_results(
+    module_ids: Vec<String>,
+    result: Result<BulkRegistryOperationResult, Error>,
+) -> Vec<(String, Result<(), Error>)> {
+    match result {
+        Ok(result) => module_ids
+            .into_iter()
+            .map(|module_id| {
+                let failure = result
+                    .errors()
+                    .iter()
+                    .find(|error| error.module_id() == Some(module_id.as_str()));
+                let deleted = match failure {
+                    Some(error) => Err(Error::from(ErrorKind::DeleteModuleWithReason(
+                        module_id.clone(),
+                        ModuleOperationReason::RegistryOperationFailed {
+                            error_code: error.error_code().to_string(),
+                            error_status: error.error_status().to_string(),
+                        },
+                    ))),
+                    None => Ok(()),
+                };
+                (module_id, deleted)
+            })
+            .collect(),
+        Err(err) => {
+            let err = Arc::new(err);
+            module_ids
+                .into_iter()
+                .map(|module_id| (module_id, Err(shared_error(err.clone()))))
+                .collect()
+        }
+    }
+}
+
+fn write_modules<W>(writer: &mut W, modules: &[Module]) -> io::Result<()>
+where
+    W: Write,
+{
+    for module in modules {
+        serde_json::to_writer(&mut *writer, module)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+pub(crate) fn url_encode(value: &str) -> PercentEncode<'_, IOTHUB_ENCODE_SET> {
+    percent_encode(value.as_bytes(), IOTHUB_ENCODE_SET)
+}
+
+// Quotes `value` as a string literal of IoT Hub's query language. IDs may
+// contain apostrophes, which would otherwise end the literal early.
+pub(crate) fn quote_query_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if c == '\'' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        quote_query_string, serialize_body, AppMetadata, AuthMechanism,
+        BulkRegistryOperationResult, Client, Condition, DeletedModules, DeviceClient,
+        EnsureOutcome, Error, EtagFallback, Future, ImportMode, Module, ModuleIdMatching,
+        ModuleOperation, StatusCode, Timestamp, TokenSource,
+    };
+
+    use std::cmp;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(feature = "chrono")]
+    use chrono::TimeZone;
     use chrono::{DateTime, Utc};
+    use futures::future::{self, Either};
+    use futures::sync::oneshot;
     use futures::Stream;
     use hyper::{self, Body, Client as HyperClient, Method, Request, Response};
+    use serde_json::json;
     use typed_headers::{mime, ContentType, HeaderMapExt};
     use url::Url;
 
-    use crate::error::{ErrorKind, ModuleOperationReason};
-    use crate::model::{AuthType, SymmetricKey};
+    use edgelet_http::client::RetryPolicy;
+
+    use crate::error::{ErrorKind, ModuleOperationReason};
+    use crate::model::{
+        AuthType, Device, DeviceExport, MethodTimeouts, Properties, RegistryOperationError,
+        SymmetricKey, Twin, TwinMetadata,
+    };
+
+    struct NullTokenSource;
+
+    impl TokenSource for NullTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok("token".to_string())
+        }
+    }
+
+    impl Clone for NullTokenSource {
+        fn clone(&self) -> Self {
+            NullTokenSource {}
+        }
+    }
+
+    #[test]
+    fn device_client_create_empty_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        match DeviceClient::new(client, "".to_string()) {
+            Ok(_) => panic!("Excepted err got success"),
+            Err(err) => {
+                if let ErrorKind::DeleteModule = err.kind() {
+                    panic!("Wrong error kind. Expected `Http` found {:?}", err);
+                }
+            }
+        };
+    }
+
+    #[test]
+    fn device_client_create_white_space_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        match DeviceClient::new(client, "       ".to_string()) {
+            Ok(_) => panic!("Excepted err got success"),
+            Err(err) => {
+                if let ErrorKind::DeleteModule = err.kind() {
+                    panic!("Wrong error kind. Expected `Http` found {:?}", err);
+                }
+            }
+        };
+    }
+
+    #[test]
+    fn module_upsert_empty_module_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let name = "";
+
+        let task = device_client
+            .upsert_module(name.to_string(), None, None, Condition::None)
+            .then(|result| match result {
+                Ok(_) => panic!("Excepted err got success"),
+                Err(err) => match err.kind() {
+                    ErrorKind::UpsertModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
+                    _ => panic!("Wrong error kind. Expected `UpsertModuleWithReason(EmptyModuleId)` found {:?}", err),
+                }
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_upsert_white_space_module_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let name = "     ";
+
+        let task = device_client
+            .upsert_module(name.to_string(), None, None, Condition::None)
+            .then(|result| match result {
+                Ok(_) => panic!("Excepted err got success"),
+                Err(err) => match err.kind() {
+                    ErrorKind::UpsertModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
+                    _ => panic!("Wrong error kind. Expected `UpsertModuleWithReason(EmptyModuleId)` found {:?}", err),
+                }
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_upsert_reserved_module_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module("$edgeAgent".to_string(), None, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::ReservedModuleId("$edgeAgent".to_string()),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_upsert_reserved_module_id_allowed_for_system_modules() {
+        let handler = |req: Request<Body>| {
+            assert_eq!("/devices/d1/modules/$edgeHub", req.uri().path());
+            Ok(json_response(
+                &Module::default()
+                    .with_device_id("d1".to_string())
+                    .with_module_id("$edgeHub".to_string()),
+            ))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_system_modules_allowed(true);
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module("$edgeHub".to_string(), None, None))
+            .unwrap();
+        assert_eq!(Some("$edgeHub"), module.module_id());
+    }
+
+    #[test]
+    fn module_upsert_adds_module_body_without_if_match() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let auth = AuthMechanism::default()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(
+                SymmetricKey::default()
+                    .with_primary_key(PRIMARY_KEY.to_string())
+                    .with_secondary_key(SECONDARY_KEY.to_string()),
+            );
+        let module_request = Module::default()
+            .with_device_id("d1".to_string())
+            .with_module_id("m1".to_string())
+            .with_authentication(auth.clone())
+            .with_managed_by("iotedge".to_string());
+        let expected_response = module_request.clone().with_generation_id("g1".to_string());
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            let module_request_copy = module_request.clone();
+            req.into_body()
+                .concat2()
+                .and_then(|req_body| Ok(serde_json::from_slice::<Module>(&req_body).unwrap()))
+                .and_then(move |module| {
+                    assert_eq!(module, module_request_copy);
+
+                    let mut response = Response::new(
+                        serde_json::to_string(&module.with_generation_id("g1".to_string()))
+                            .unwrap()
+                            .into(),
+                    );
+                    response
+                        .headers_mut()
+                        .typed_insert(&ContentType(mime::APPLICATION_JSON));
+                    Ok(response)
+                })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client
+            .upsert_module(
+                "m1".to_string(),
+                Some(auth),
+                Some(&"iotedge".to_string()),
+                Condition::None,
+            )
+            .then(|result| {
+                assert_eq!(expected_response, result.unwrap());
+                Ok::<_, Error>(())
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_create_without_auth_returns_generated_keys() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+
+            req.into_body().concat2().map(|req_body| {
+                let module: serde_json::Value = serde_json::from_slice(&req_body).unwrap();
+                assert_eq!(None, module.get("authentication"));
+
+                json_response(&json!({
+                    "deviceId": "d1",
+                    "moduleId": "m1",
+                    "generationId": "g1",
+                    "authentication": {
+                        "type": "sas",
+                        "symmetricKey": {
+                            "primaryKey": "generated-pkey",
+                            "secondaryKey": "generated-skey"
+                        }
+                    }
+                }))
+            })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module("m1".to_string(), None, None))
+            .unwrap();
+
+        let auth = module.authentication().unwrap();
+        assert_eq!(Some(AuthType::Sas), auth.type_());
+        let key = auth.symmetric_key().unwrap();
+        assert_eq!(Some("generated-pkey"), key.primary_key());
+        assert_eq!(Some("generated-skey"), key.secondary_key());
+    }
+
+    #[test]
+    fn module_create_returns_location() {
+        let handler = |req: Request<Body>| {
+            req.into_body().concat2().map(|body| {
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header(
+                        hyper::header::LOCATION,
+                        "https://hub.azure-devices.net/devices/d1/modules/m1",
+                    )
+                    .body(Body::from(body))
+                    .unwrap()
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let (module, location) = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module_with_location("m1".to_string(), None, None))
+            .unwrap();
+        assert_eq!(Some("m1"), module.module_id());
+        assert_eq!(
+            Some("https://hub.azure-devices.net/devices/d1/modules/m1".to_string()),
+            location
+        );
+    }
+
+    #[test]
+    fn module_create_round_trips_app_metadata() {
+        let handler = |req: Request<Body>| {
+            let echoed = req.headers()["iothub-app-deployment-id"].clone();
+            req.into_body().concat2().map(move |body| {
+                Response::builder()
+                    .header("iothub-app-deployment-id", echoed)
+                    .body(Body::from(body))
+                    .unwrap()
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let metadata = AppMetadata::new()
+            .with_entry("deployment-id", "d-42")
+            .unwrap();
+        let (module, echoed) = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module_with_app_metadata(
+                "m1".to_string(),
+                None,
+                None,
+                &metadata,
+            ))
+            .unwrap();
+        assert_eq!(Some("m1"), module.module_id());
+        assert_eq!(metadata, echoed);
+    }
+
+    fn ensure_handler(
+        existing: Option<Module>,
+        writes: Arc<AtomicUsize>,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            assert_eq!("/devices/d1/modules/m1", req.uri().path());
+
+            if *req.method() == Method::GET {
+                return Ok(match existing {
+                    Some(ref module) => json_response(module),
+                    None => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap(),
+                });
+            }
+
+            assert_eq!(&Method::PUT, req.method());
+            writes.fetch_add(1, Ordering::SeqCst);
+            let body = req.into_body().concat2().wait().unwrap();
+            Ok(Response::new(Body::from(body)))
+        }
+    }
+
+    fn sas_auth(primary_key: &str) -> AuthMechanism {
+        AuthMechanism::default()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(
+                SymmetricKey::default()
+                    .with_primary_key(primary_key.to_string())
+                    .with_secondary_key(SECONDARY_KEY.to_string()),
+            )
+    }
+
+    fn ensure_module(
+        existing: Option<Module>,
+        authentication: AuthMechanism,
+    ) -> (EnsureOutcome, usize) {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let client = Client::new(
+            ensure_handler(existing, writes.clone()),
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let outcome = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.ensure_module("m1".to_string(), authentication))
+            .unwrap();
+        (outcome, writes.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn ensure_module_creates_missing_module() {
+        let (outcome, writes) = ensure_module(None, sas_auth(PRIMARY_KEY));
+
+        match outcome {
+            EnsureOutcome::Created(_) => (),
+            _ => panic!("Expected the module to be created, found {:?}", outcome),
+        }
+        assert_eq!(1, writes);
+    }
+
+    #[test]
+    fn ensure_module_updates_mismatched_auth() {
+        let existing = Module::default()
+            .with_module_id("m1".to_string())
+            .with_authentication(sas_auth("b2xkLXByaW1hcnkta2V5LW9mLW0x"));
+        let (outcome, writes) = ensure_module(Some(existing), sas_auth(PRIMARY_KEY));
+
+        match outcome {
+            EnsureOutcome::Updated(ref module) => {
+                assert!(module.matches_spec(&sas_auth(PRIMARY_KEY)));
+            }
+            _ => panic!("Expected the module to be updated, found {:?}", outcome),
+        }
+        assert_eq!(1, writes);
+    }
+
+    #[test]
+    fn ensure_module_updates_if_found_module_is_unchanged() {
+        let handler = |req: Request<Body>| {
+            if *req.method() == Method::GET {
+                return Ok(json_response(
+                    &Module::default()
+                        .with_module_id("m1".to_string())
+                        .with_etag("AAAA".to_string())
+                        .with_authentication(sas_auth("b2xkLXByaW1hcnkta2V5LW9mLW0x")),
+                ));
+            }
+
+            assert_eq!(&Method::PUT, req.method());
+            assert_eq!(
+                "\"AAAA\"",
+                req.headers().get(hyper::header::IF_MATCH).unwrap()
+            );
+            let body = req.into_body().concat2().wait().unwrap();
+            Ok(Response::new(Body::from(body)))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        // the found module's ETag is used whatever the fallback
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_etag_fallback(EtagFallback::Fail);
+
+        let outcome = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.ensure_module("m1".to_string(), sas_auth(PRIMARY_KEY)))
+            .unwrap();
+        match outcome {
+            EnsureOutcome::Updated(_) => (),
+            _ => panic!("Expected the module to be updated, found {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn ensure_module_leaves_matching_module_unchanged() {
+        let existing = Module::default()
+            .with_module_id("m1".to_string())
+            .with_authentication(sas_auth(PRIMARY_KEY));
+        let (outcome, writes) = ensure_module(Some(existing.clone()), sas_auth(PRIMARY_KEY));
+
+        assert_eq!(EnsureOutcome::Unchanged(existing), outcome);
+        assert_eq!(0, writes);
+    }
+
+    #[test]
+    fn ensure_module_matches_id_case_insensitively() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let handler_writes = writes.clone();
+        let handler = move |req: Request<Body>| {
+            if *req.method() != Method::GET {
+                handler_writes.fetch_add(1, Ordering::SeqCst);
+            }
+            assert_eq!("/devices/d1/modules", req.uri().path());
+            let modules = vec![Module::default()
+                .with_module_id("m1".to_string())
+                .with_authentication(sas_auth(PRIMARY_KEY))];
+            Ok(json_response(&modules))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_module_id_matching(ModuleIdMatching::CaseInsensitive);
+
+        let outcome = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.ensure_module("M1".to_string(), sas_auth(PRIMARY_KEY)))
+            .unwrap();
+        assert_eq!(Some("m1"), outcome.module().module_id());
+        match outcome {
+            EnsureOutcome::Unchanged(_) => (),
+            _ => panic!("Expected the module to be unchanged, found {:?}", outcome),
+        }
+        assert_eq!(0, writes.load(Ordering::SeqCst));
+
+        assert!(ModuleIdMatching::CaseInsensitive.matches("M1", "m1"));
+        assert!(!ModuleIdMatching::Exact.matches("M1", "m1"));
+    }
+
+    #[test]
+    fn module_upsert_empty_response_fails() {
+        let handler = |_req: Request<Body>| Ok(Response::new(Body::empty()));
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.update_module("m1".to_string(), None, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::UpsertModuleWithReason(
+                "m1".to_string(),
+                ModuleOperationReason::EmptyResponse {
+                    operation: "upsert_module",
+                    path: "/devices/d1/modules/m1".to_string(),
+                }
+            ),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_upsert_validates_supplied_keys() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let handler_requests = requests.clone();
+        let handler = move |req: Request<Body>| {
+            handler_requests.fetch_add(1, Ordering::SeqCst);
+            let body = req.into_body().concat2().wait().unwrap();
+            Ok(Response::new(Body::from(body)))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let mut create = |primary_key: &str| {
+            let auth = AuthMechanism::default()
+                .with_type(AuthType::Sas)
+                .with_symmetric_key(
+                    SymmetricKey::default()
+                        .with_primary_key(primary_key.to_string())
+                        .with_secondary_key(SECONDARY_KEY.to_string()),
+                );
+            runtime.block_on(device_client.create_module("m1".to_string(), Some(auth), None))
+        };
+
+        // base64 of 5 bytes
+        let err = create("c2hvcnQ=").unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidKey {
+                reason: "primary key must be 16 to 64 bytes"
+            },
+            err.kind()
+        );
+        let err = create("not base64!").unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidKey {
+                reason: "primary key is not base64"
+            },
+            err.kind()
+        );
+        assert_eq!(0, requests.load(Ordering::SeqCst));
+
+        let module = create(PRIMARY_KEY).unwrap();
+        assert_eq!(Some(PRIMARY_KEY), module.primary_symmetric_key());
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn module_upsert_adds_module_body_with_if_match() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let auth = AuthMechanism::default()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(
+                SymmetricKey::default()
+                    .with_primary_key(PRIMARY_KEY.to_string())
+                    .with_secondary_key(SECONDARY_KEY.to_string()),
+            );
+        let module_request = Module::default()
+            .with_device_id("d1".to_string())
+            .with_module_id("m1".to_string())
+            .with_authentication(auth.clone())
+            .with_managed_by("iotedge".to_string());
+        let expected_response = module_request.clone().with_generation_id("g1".to_string());
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            assert_eq!(req.headers().get(hyper::header::IF_MATCH).unwrap(), "*");
+
+            let module_request_copy = module_request.clone();
+            req.into_body()
+                .concat2()
+                .and_then(|req_body| Ok(serde_json::from_slice::<Module>(&req_body).unwrap()))
+                .and_then(move |module| {
+                    assert_eq!(module, module_request_copy);
+
+                    let mut response = Response::new(
+                        serde_json::to_string(&module.with_generation_id("g1".to_string()))
+                            .unwrap()
+                            .into(),
+                    );
+                    response
+                        .headers_mut()
+                        .typed_insert(&ContentType(mime::APPLICATION_JSON));
+                    Ok(response)
+                })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client
+            .upsert_module(
+                "m1".to_string(),
+                Some(auth),
+                Some(&"iotedge".to_string()),
+                Condition::Any,
+            )
+            .then(|result| {
+                assert_eq!(expected_response, result.unwrap());
+                Ok::<_, Error>(())
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_get_on_behalf_of_child() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/devices/child/modules/m1");
+            assert_eq!(
+                "child/$edgeHub",
+                req.headers()["x-ms-edge-moduleId"].to_str().unwrap()
+            );
+            Ok(json_response(
+                &Module::default().with_module_id("m1".to_string()),
+            ))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "child".to_string())
+            .unwrap()
+            .with_on_behalf_of("child/$edgeHub");
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.get_module_by_id("m1".to_string()))
+            .unwrap();
+        assert_eq!(Some("m1"), module.module_id());
+    }
+
+    fn module_update_if_match(etag_fallback: EtagFallback) -> Option<String> {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let if_match = Arc::new(Mutex::new(None));
+        let if_match_copy = if_match.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            *if_match_copy.lock().unwrap() = req
+                .headers()
+                .get(hyper::header::IF_MATCH)
+                .map(|value| value.to_str().unwrap().to_string());
+
+            let module = Module::default()
+                .with_device_id("d1".to_string())
+                .with_module_id("m1".to_string());
+            Ok(json_response(&module))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_etag_fallback(etag_fallback);
+
+        let task = device_client
+            .update_module("m1".to_string(), None, None)
+            .map(move |_| if_match.lock().unwrap().clone());
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap()
+    }
+
+    #[test]
+    fn module_update_matches_any_by_default() {
+        assert_eq!(
+            Some("*".to_string()),
+            module_update_if_match(EtagFallback::MatchAny)
+        );
+    }
+
+    #[test]
+    fn module_update_can_omit_if_match() {
+        assert_eq!(None, module_update_if_match(EtagFallback::Omit));
+    }
+
+    #[test]
+    fn module_update_without_etag_can_fail() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("A module should not be updated without an ETag")
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_etag_fallback(EtagFallback::Fail);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.update_module("m1".to_string(), None, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::UpsertModuleWithReason(
+                "m1".to_string(),
+                ModuleOperationReason::MissingEtag
+            ),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_rotate_to_hub_generated_returns_new_keys() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+
+            req.into_body().concat2().map(|body| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    json!({ "symmetricKey": {}, "type": "sas" }),
+                    body["authentication"]
+                );
+
+                let module = Module::default()
+                    .with_device_id("d1".to_string())
+                    .with_module_id("m1".to_string())
+                    .with_authentication(
+                        AuthMechanism::new()
+                            .with_type(AuthType::Sas)
+                            .with_symmetric_key(
+                                SymmetricKey::new()
+                                    .with_primary_key("pkey".to_string())
+                                    .with_secondary_key("skey".to_string()),
+                            ),
+                    );
+                json_response(&module)
+            })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let key = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.rotate_module_to_hub_generated("m1"))
+            .unwrap();
+        assert_eq!(Some("pkey"), key.primary_key());
+        assert_eq!(Some("skey"), key.secondary_key());
+    }
+
+    #[test]
+    fn failed_create_stops_fail_fast_creates() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let creates = Arc::new(AtomicUsize::new(0));
+
+        let handler_creates = creates.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(&Method::PUT, req.method());
+            handler_creates.fetch_add(1, Ordering::SeqCst);
+            if req.uri().path() == "/devices/d1/modules/m1" {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::CONFLICT;
+                return Ok(response);
+            }
+            Ok(json_response(
+                &Module::default().with_module_id("m2".to_string()),
+            ))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let modules: Vec<(String, Option<AuthMechanism>)> = vec![
+            ("m1".to_string(), None),
+            ("m2".to_string(), None),
+            ("m3".to_string(), None),
+        ];
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let err = runtime
+            .block_on(device_client.create_modules(modules.clone(), 1, true))
+            .unwrap_err();
+        assert!(err.is_conflict());
+        assert_eq!(1, creates.load(Ordering::SeqCst));
+
+        creates.store(0, Ordering::SeqCst);
+        let results = runtime
+            .block_on(device_client.create_modules(modules, 1, false))
+            .unwrap();
+        assert_eq!(3, results.len());
+        assert!(results[0].1.as_ref().unwrap_err().is_conflict());
+        assert!(results[1].1.is_ok() && results[2].1.is_ok());
+        assert_eq!(3, creates.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn module_keys_are_rotated_for_sas_modules_only() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let sas = || AuthMechanism::new().with_type(AuthType::Sas);
+        let modules = vec![
+            Module::default()
+                .with_module_id("m1".to_string())
+                .with_etag("e1".to_string())
+                .with_authentication(sas()),
+            Module::default()
+                .with_module_id("m2".to_string())
+                .with_etag("e2".to_string())
+                .with_authentication(AuthMechanism::new().with_type(AuthType::X509)),
+            Module::default()
+                .with_module_id("m3".to_string())
+                .with_etag("e3".to_string())
+                .with_authentication(sas()),
+        ];
+        let updates = Arc::new(Mutex::new(Vec::new()));
+
+        let handler_updates = updates.clone();
+        let handler = move |req: Request<Body>| {
+            if req.method() == Method::GET {
+                return Ok(json_response(&modules));
+            }
+
+            assert_eq!(&Method::PUT, req.method());
+            let if_match = req.headers()[hyper::header::IF_MATCH]
+                .to_str()
+                .unwrap()
+                .to_string();
+            handler_updates
+                .lock()
+                .unwrap()
+                .push((req.uri().path().to_string(), if_match));
+            if req.uri().path() == "/devices/d1/modules/m3" {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                return Ok(response);
+            }
+            Ok(json_response(
+                &Module::default()
+                    .with_module_id("m1".to_string())
+                    .with_authentication(
+                        sas().with_symmetric_key(
+                            SymmetricKey::new()
+                                .with_primary_key("pkey".to_string())
+                                .with_secondary_key("skey".to_string()),
+                        ),
+                    ),
+            ))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.rotate_all_module_keys(2))
+            .unwrap();
+
+        let module_ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["m1", "m2", "m3"], module_ids);
+        assert_eq!(Some("pkey"), results[0].1.as_ref().unwrap().primary_key());
+        assert_eq!(
+            &ErrorKind::UpsertModuleWithReason(
+                "m2".to_string(),
+                ModuleOperationReason::NotSasAuthenticated
+            ),
+            results[1].1.as_ref().unwrap_err().kind()
+        );
+        assert!(results[2].1.as_ref().unwrap_err().is_conflict());
+
+        let mut updates = updates.lock().unwrap().clone();
+        updates.sort();
+        assert_eq!(
+            vec![
+                ("/devices/d1/modules/m1".to_string(), "\"e1\"".to_string()),
+                ("/devices/d1/modules/m3".to_string(), "\"e3\"".to_string()),
+            ],
+            updates
+        );
+    }
+
+    #[test]
+    fn serialize_body_failure_is_reported() {
+        struct Unserializable;
+
+        impl serde::Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("unserializable"))
+            }
+        }
+
+        let err = serialize_body(&Unserializable).unwrap_err();
+        assert_eq!(&ErrorKind::Serialization, err.kind());
+    }
+
+    fn rejecting_client() -> DeviceClient<
+        impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync,
+        NullTokenSource,
+    > {
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("An invalid module ID should not be sent to IoT Hub")
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        DeviceClient::new(client, "d1".to_string()).unwrap()
+    }
+
+    #[test]
+    fn module_upsert_rejects_overlong_module_id() {
+        let module_id = "m".repeat(129);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(rejecting_client().create_module(module_id.clone(), None, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidModuleId {
+                module_id,
+                reason: "module ID is longer than 128 characters",
+            },
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_get_and_delete_reject_disallowed_characters() {
+        let device_client = rejecting_client();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let err = runtime
+            .block_on(device_client.get_module_by_id("m/1".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidModuleId {
+                module_id: "m/1".to_string(),
+                reason: "module ID may only contain ASCII letters, digits and -.+%_#*?!(),:=@$'",
+            },
+            err.kind()
+        );
+
+        let err = runtime
+            .block_on(device_client.delete_module("m 1"))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::InvalidModuleId { module_id, .. } => assert_eq!("m 1", module_id),
+            kind => panic!("Expected InvalidModuleId, found {:?}", kind),
+        }
+    }
+
+    fn delete_returning_handler(
+        deleted_if_match: Arc<Mutex<Option<String>>>,
+        changed: bool,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            assert_eq!("/devices/d1/modules/m1", req.uri().path());
+            if req.method() == Method::GET {
+                let module = Module::default()
+                    .with_module_id("m1".to_string())
+                    .with_status("enabled".to_string())
+                    .with_etag("AAAA".to_string());
+                return Ok(json_response(&module));
+            }
+
+            assert_eq!(&Method::DELETE, req.method());
+            *deleted_if_match.lock().unwrap() = req
+                .headers()
+                .get(hyper::header::IF_MATCH)
+                .map(|value| value.to_str().unwrap().to_string());
+            let mut response = Response::new(Body::empty());
+            if changed {
+                *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+            } else {
+                *response.status_mut() = StatusCode::NO_CONTENT;
+            }
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn module_delete_returning_returns_state_before_delete() {
+        let if_match = Arc::new(Mutex::new(None));
+        let client = Client::new(
+            delete_returning_handler(if_match.clone(), false),
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete_module_returning("m1"))
+            .unwrap();
+
+        assert_eq!(Some("m1"), module.module_id());
+        assert_eq!(Some("enabled"), module.status());
+        assert_eq!(Some("AAAA"), module.etag());
+        assert_eq!(Some("\"AAAA\"".to_string()), *if_match.lock().unwrap());
+    }
+
+    #[test]
+    fn module_delete_returning_fails_when_module_changed() {
+        let if_match = Arc::new(Mutex::new(None));
+        let client = Client::new(
+            delete_returning_handler(if_match.clone(), true),
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete_module_returning("m1"))
+            .unwrap_err();
+
+        assert_eq!(&ErrorKind::DeleteModule, err.kind());
+        assert!(err.is_conflict());
+        assert_eq!(Some("\"AAAA\"".to_string()), *if_match.lock().unwrap());
+    }
+
+    #[test]
+    fn module_delete_empty_module_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let name = "";
+
+        let task = device_client.delete_module(name).then(|result| match result {
+                Ok(_) => panic!("Excepted err got success"),
+                Err(err) => match err.kind() {
+                    ErrorKind::DeleteModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
+                    _ => panic!("Wrong error kind. Expected `DeleteModuleWithReason(EmptyModuleId)` found {:?}", err),
+                }
+        });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_white_space_module_id_fails() {
+        let hyper_client = HyperClient::new();
+        let client = Client::new(
+            hyper_client,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let name = "     ";
+
+        let task = device_client
+            .delete_module(name)
+            .then(|result| match result {
+                Ok(_) => panic!("Excepted err got success"),
+                Err(err) => match err.kind() {
+                    ErrorKind::DeleteModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
+                    _ => panic!("Wrong error kind. Expected `DeleteModuleWithReason(EmptyModuleId)` found {:?}", err),
+                }
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_get_reads_etag_header() {
+        let handler = |req: Request<Body>| {
+            assert_eq!("/devices/d1/modules/m1", req.uri().path());
+            let mut response = json_response(&Module::default().with_module_id("m1".to_string()));
+            response
+                .headers_mut()
+                .insert(hyper::header::ETAG, "\"AAAA\"".parse().unwrap());
+            Ok(response)
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.get_module_by_id("m1".to_string()))
+            .unwrap();
+        assert_eq!(Some("AAAA"), module.etag());
+    }
+
+    #[test]
+    fn module_get_rejects_contradictory_auth() {
+        let handler = |_req: Request<Body>| {
+            Ok(json_response(&json!({
+                "moduleId": "m1",
+                "authentication": {
+                    "symmetricKey": { "primaryKey": "pkey", "secondaryKey": null },
+                    "x509Thumbprint": { "primaryThumbprint": "thumb1", "secondaryThumbprint": null },
+                },
+            })))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.get_module_by_id("m1".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidAuth {
+                module_id: "m1".to_string(),
+                reason: "authentication has both symmetric keys and X.509 thumbprints",
+            },
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_disable_sends_status_reason() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::PUT, req.method());
+            assert_eq!("/devices/d1/modules/m1", req.uri().path());
+            assert_eq!(
+                "\"AAAA\"",
+                req.headers().get(hyper::header::IF_MATCH).unwrap()
+            );
+
+            req.into_body().concat2().map(|body| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!("disabled", body["status"]);
+                assert_eq!("compromised credentials", body["statusReason"]);
+
+                let mut module = body;
+                module["statusUpdatedTime"] = json!("2019-06-01T12:00:00Z");
+                json_response(&module)
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_etag("AAAA".to_string());
+
+        let module = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.disable_module(module, "compromised credentials"))
+            .unwrap();
+        assert_eq!(Some("disabled"), module.status());
+        assert_eq!(Some("compromised credentials"), module.status_reason());
+        #[cfg(feature = "chrono")]
+        assert_eq!(
+            Some(&Utc.ymd(2019, 6, 1).and_hms(12, 0, 0)),
+            module.status_updated_time()
+        );
+    }
+
+    #[test]
+    fn module_delete_request() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::DELETE);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            assert_eq!(req.headers().get(hyper::header::IF_MATCH).unwrap(), "*");
+
+            Ok(Response::new(Body::empty()))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client
+            .delete_module("m1")
+            .then(|result: Result<(), _>| result);
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn gateway_host_receives_requests_scoped_to_hub() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("https://MyHub.azure-devices.net").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.uri().host(), Some("parent-edge.local"));
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            assert!(req.headers().get(hyper::header::HOST).is_none());
+
+            Ok(Response::new(Body::empty()))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_gateway_host(Url::parse("https://parent-edge.local").unwrap());
+        assert_eq!(
+            "myhub.azure-devices.net%2Fdevices%2Fd1",
+            device_client.resource_uri()
+        );
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete_module("m1"))
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_by_value_uses_etag() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::DELETE);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+            assert_eq!(
+                req.headers().get(hyper::header::IF_MATCH).unwrap(),
+                "\"AAAAAAAAAAE=\""
+            );
+
+            Ok(Response::new(Body::empty()))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_etag("AAAAAAAAAAE=".to_string());
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete(&module))
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_by_value_without_etag_matches_any() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.headers().get(hyper::header::IF_MATCH).unwrap(), "*");
+
+            Ok(Response::new(Body::empty()))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let module = Module::default().with_module_id("m1".to_string());
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete(&module))
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_by_value_without_etag_can_fail() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("A module without an ETag should not be deleted")
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_etag_fallback(EtagFallback::Fail);
+        let module = Module::default().with_module_id("m1".to_string());
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete(&module))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::DeleteModuleWithReason(
+                "m1".to_string(),
+                ModuleOperationReason::MissingEtag
+            ),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_delete_not_found_fails() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client.delete_module("m1").then(|result| {
+            assert_eq!(&ErrorKind::DeleteModule, result.unwrap_err().kind());
+            Ok::<_, Error>(())
+        });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_if_exists_not_found_succeeds() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::DELETE);
+            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
+
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client.delete_module_if_exists("m1");
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn module_delete_if_exists_propagates_other_failures() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |_req: Request<Body>| {
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client.delete_module_if_exists("m1").then(|result| {
+            assert_eq!(&ErrorKind::DeleteModule, result.unwrap_err().kind());
+            Ok::<_, Error>(())
+        });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn concurrent_identical_deletes_share_one_request() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let deletes = Arc::new(AtomicUsize::new(0));
+
+        let handler_deletes = deletes.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::DELETE);
+            handler_deletes.fetch_add(1, Ordering::SeqCst);
+
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("could not build hyper::Response");
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_delete_coalescing(true);
+        let task = device_client
+            .delete_module("m1")
+            .then(Ok::<_, Error>)
+            .join(device_client.delete_module("m1").then(Ok));
+
+        let (first, second) = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+
+        assert_eq!(1, deletes.load(Ordering::SeqCst));
+        for result in &[first, second] {
+            let err = result.as_ref().unwrap_err();
+            assert_eq!(&ErrorKind::DeleteModule, err.kind());
+            assert!(err.is_not_found());
+        }
+
+        // the delete is sent again once the shared one has completed
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete_module("m1"))
+            .unwrap_err();
+        assert_eq!(2, deletes.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn modules_with_pending_messages_filters_listing() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| {
+            Ok(json_response(&json!([
+                { "moduleId": "m1", "cloudToDeviceMessageCount": 10 },
+                { "moduleId": "m2", "cloudToDeviceMessageCount": 3 },
+                { "moduleId": "m3" },
+                { "moduleId": "m4", "cloudToDeviceMessageCount": 4 },
+            ])))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.modules_with_pending_messages(3))
+            .unwrap();
+
+        let ids: Vec<_> = modules.iter().filter_map(Module::module_id).collect();
+        assert_eq!(vec!["m1", "m4"], ids);
+        assert_eq!(10, modules[0].cloud_to_device_message_count());
+    }
+
+    #[test]
+    fn module_purge_messages_returns_purged_count() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::DELETE, req.method());
+            assert_eq!(
+                "/devices/d1/modules/m1/messages/deviceBound",
+                req.uri().path()
+            );
+            Ok(json_response(&json!({
+                "deviceId": "d1",
+                "moduleId": "m1",
+                "totalMessagesPurged": 7,
+            })))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let purged = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.purge_module_messages("m1"))
+            .unwrap();
+        assert_eq!(7, purged);
+    }
+
+    #[test]
+    fn module_purge_messages_not_found_purges_nothing() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| {
+            Ok(hyper::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let purged = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.purge_module_messages("m1"))
+            .unwrap();
+        assert_eq!(0, purged);
+    }
+
+    #[test]
+    fn modules_list_with_twins_pairs_results() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::GET, req.method());
+            match req.uri().path() {
+                "/devices/d1/modules" => Ok(json_response(&json!([
+                    { "moduleId": "m1" },
+                    { "moduleId": "m2" },
+                ]))),
+                "/twins/d1/modules/m1" => Ok(json_response(&module_twin("m1"))),
+                "/twins/d1/modules/m2" => Ok(hyper::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap()),
+                path => panic!("unexpected path {}", path),
+            }
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.list_modules_with_twins(2))
+            .unwrap();
+
+        assert_eq!(2, results.len());
+        assert_eq!(Some("m1"), results[0].0.module_id());
+        assert_eq!(&module_twin("m1"), results[0].1.as_ref().unwrap());
+        assert_eq!(Some("m2"), results[1].0.module_id());
+        assert_eq!(
+            &ErrorKind::GetModuleTwin("m2".to_string()),
+            results[1].1.as_ref().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn invoke_method_all_modules_collects_every_result() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            if req.method() == Method::GET {
+                return Ok(json_response(&json!([
+                    { "moduleId": "m1" },
+                    { "moduleId": "m2" },
+                ])));
+            }
+
+            assert_eq!(&Method::POST, req.method());
+            let path = req.uri().path().to_string();
+            let body = req.into_body().concat2().wait().unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                json!({
+                    "methodName": "ping",
+                    "payload": { "verbose": true },
+                    "responseTimeoutInSeconds": 30,
+                }),
+                body
+            );
+
+            match path.as_ref() {
+                "/twins/d1/modules/m1/methods" => Ok(json_response(
+                    &json!({ "status": 200, "payload": { "pong": true } }),
+                )),
+                "/twins/d1/modules/m2/methods" => Ok(hyper::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(r#"{"Message":"ErrorCode:DeviceNotOnline;Timed out"}"#.into())
+                    .unwrap()),
+                path => panic!("unexpected path {}", path),
+            }
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.invoke_method_all_modules(
+                "ping",
+                json!({ "verbose": true }),
+                MethodTimeouts::new().with_response_timeout(Duration::from_secs(30)),
+                2,
+            ))
+            .unwrap();
+
+        assert_eq!(2, results.len());
+        assert_eq!("m1", results[0].0);
+        let result = results[0].1.as_ref().unwrap();
+        assert_eq!(200, result.status());
+        assert_eq!(Some(&json!({ "pong": true })), result.payload());
+        assert_eq!("m2", results[1].0);
+        assert_eq!(
+            &ErrorKind::InvokeModuleMethod("m2".to_string(), "ping".to_string()),
+            results[1].1.as_ref().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn modules_changed_since_queries_twins() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::POST, req.method());
+            assert_eq!("/devices/query", req.uri().path());
+
+            req.into_body().concat2().map(|body| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    json!({
+                        "query": "SELECT * FROM devices.modules WHERE deviceId = 'd1' AND \
+                                  (properties.desired.$metadata.$lastUpdated > '2019-06-01T12:00:00.000Z' OR \
+                                  properties.reported.$metadata.$lastUpdated > '2019-06-01T12:00:00.000Z' OR \
+                                  lastActivityTime > '2019-06-01T12:00:00.000Z')"
+                    }),
+                    body
+                );
+                json_response(&json!([{ "deviceId": "d1", "moduleId": "m1" }]))
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-06-30".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.modules_changed_since(since()))
+            .unwrap();
+        assert_eq!(
+            vec![Some("m1")],
+            modules.iter().map(Module::module_id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn modules_changed_since_quotes_device_id() {
+        let handler = |req: Request<Body>| {
+            req.into_body().concat2().map(|body| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let query = body["query"].as_str().unwrap();
+                assert!(query
+                    .starts_with(r"SELECT * FROM devices.modules WHERE deviceId = 'd\'1' AND "));
+                json_response(&json!([]))
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-06-30".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d'1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.modules_changed_since(since()))
+            .unwrap();
+        assert!(modules.is_empty());
+    }
+
+    #[test]
+    fn query_strings_are_quoted() {
+        assert_eq!("'d1'", quote_query_string("d1"));
+        assert_eq!(r"'it\'s'", quote_query_string("it's"));
+        assert_eq!(r"'a\\b'", quote_query_string(r"a\b"));
+    }
+
+    #[test]
+    fn modules_changed_since_filters_listing_on_older_api_versions() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::GET, req.method());
+            assert_eq!("/devices/d1/modules", req.uri().path());
+            Ok(json_response(&json!([
+                { "moduleId": "m1", "lastActivityTime": "2019-06-01T11:00:00Z" },
+                { "moduleId": "m2", "lastActivityTime": "2019-06-01T13:00:00Z" },
+                { "moduleId": "m3" },
+            ])))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.modules_changed_since(since()))
+            .unwrap();
+        assert_eq!(
+            vec![Some("m2")],
+            modules.iter().map(Module::module_id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn modules_list_empty_response_names_operation() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| Ok(Response::new(Body::empty()));
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.list_modules())
+            .unwrap_err();
+
+        assert_eq!(
+            &ErrorKind::ListModulesWithReason(ModuleOperationReason::EmptyResponse {
+                operation: "list_modules",
+                path: "/devices/d1/modules".to_string(),
+            }),
+            err.kind()
+        );
+        assert!(err
+            .to_string()
+            .contains("list_modules (/devices/d1/modules)"));
+    }
+
+    #[test]
+    fn modules_list_request() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let auth = AuthMechanism::default()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(
+                SymmetricKey::default()
+                    .with_primary_key("pkey".to_string())
+                    .with_secondary_key("skey".to_string()),
+            );
+        let modules = vec![
+            Module::default()
+                .with_device_id("d1".to_string())
+                .with_module_id("m1".to_string())
+                .with_generation_id("g1".to_string())
+                .with_managed_by("iotedge".to_string())
+                .with_authentication(auth.clone()),
+            Module::default()
+                .with_device_id("d1".to_string())
+                .with_module_id("m2".to_string())
+                .with_generation_id("g2".to_string())
+                .with_managed_by("iotedge".to_string())
+                .with_authentication(auth),
+        ];
+        let expected_modules = modules.clone();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::GET);
+            assert_eq!(req.uri().path(), "/devices/d1/modules");
+            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+
+            let mut response = Response::new(serde_json::to_string(&modules).unwrap().into());
+            response
+                .headers_mut()
+                .typed_insert(&ContentType(mime::APPLICATION_JSON));
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client.list_modules().then(|modules| {
+            let modules = modules.unwrap();
+            assert_eq!(expected_modules.len(), modules.len());
+            for i in 0..modules.len() {
+                assert_eq!(expected_modules[i], modules[i])
+            }
+            Ok::<_, Error>(())
+        });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn modules_by_id_are_ordered_by_module_id() {
+        let handler = |_req: Request<Body>| {
+            let modules = vec![
+                Module::default().with_module_id("m2".to_string()),
+                Module::default().with_module_id("m3".to_string()),
+                Module::default().with_module_id("m1".to_string()),
+            ];
+            Ok(json_response(&modules))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.modules_by_id())
+            .unwrap();
+
+        assert_eq!(Some("m3"), modules["m3"].module_id());
+        assert!(modules.get("m4").is_none());
+        assert_eq!(
+            vec!["m1", "m2", "m3"],
+            modules.keys().map(AsRef::as_ref).collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn disabled_modules_are_listed_alone() {
+        let handler = |_req: Request<Body>| {
+            let modules = vec![
+                Module::default()
+                    .with_module_id("m1".to_string())
+                    .with_status("enabled".to_string()),
+                Module::default()
+                    .with_module_id("m2".to_string())
+                    .with_status("disabled".to_string()),
+                Module::default().with_module_id("m3".to_string()),
+                Module::default()
+                    .with_module_id("m4".to_string())
+                    .with_status("Disabled".to_string()),
+            ];
+            Ok(json_response(&modules))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.list_disabled_modules())
+            .unwrap();
+        assert_eq!(
+            vec![Some("m2"), Some("m4")],
+            modules.iter().map(Module::module_id).collect::<Vec<_>>()
+        );
+    }
+
+    // base64 of keys within the 16 to 64 bytes IoT Hub accepts
+    const PRIMARY_KEY: &str = "cHJpbWFyeS1rZXktb2YtbTE=";
+    const SECONDARY_KEY: &str = "c2Vjb25kYXJ5LWtleS1vZi1tMQ==";
+
+    #[cfg(feature = "chrono")]
+    fn since() -> Timestamp {
+        Utc.ymd(2019, 6, 1).and_hms(12, 0, 0)
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn since() -> Timestamp {
+        "2019-06-01T12:00:00.000Z".to_string()
+    }
+
+    fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+        let mut response = Response::new(serde_json::to_string(value).unwrap().into());
+        response
+            .headers_mut()
+            .typed_insert(&ContentType(mime::APPLICATION_JSON));
+        response
+    }
+
+    fn module_twin(module_id: &str) -> Twin {
+        Twin::new(
+            "d1",
+            3,
+            AuthType::Sas,
+            Properties::new(json!({ "module": module_id })),
+        )
+        .with_module_id(module_id.to_string())
+    }
+
+    #[test]
+    fn device_export_gathers_modules_and_twins() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::GET);
+
+            let response = match req.uri().path() {
+                "/devices/d1" => json_response(
+                    &Device::default()
+                        .with_device_id("d1".to_string())
+                        .with_status("enabled".to_string()),
+                ),
+                "/devices/d1/modules" => json_response(&vec![
+                    Module::default()
+                        .with_device_id("d1".to_string())
+                        .with_module_id("m1".to_string()),
+                    Module::default()
+                        .with_device_id("d1".to_string())
+                        .with_module_id("m2".to_string()),
+                ]),
+                "/twins/d1/modules/m1" => json_response(&module_twin("m1")),
+                "/twins/d1/modules/m2" => json_response(&module_twin("m2")),
+                path => panic!("unexpected request path {}", path),
+            };
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let export = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.export_device())
+            .unwrap();
+
+        assert_eq!(Some("d1"), export.device().device_id());
+        assert_eq!(Some("enabled"), export.device().status());
+        let module_ids: Vec<_> = export
+            .modules()
+            .iter()
+            .filter_map(Module::module_id)
+            .collect();
+        assert_eq!(vec!["m1", "m2"], module_ids);
+        assert_eq!(&[module_twin("m1"), module_twin("m2")], export.twins());
+
+        let serialized = serde_json::to_value(&export).unwrap();
+        assert_eq!("d1", serialized["device"]["deviceId"]);
+        assert_eq!(2, serialized["modules"].as_array().unwrap().len());
+        assert_eq!(
+            "m2",
+            serialized["twins"][1]["properties"]["desired"]["module"]
+        );
+    }
+
+    #[test]
+    fn device_import_reapplies_export() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let requests_copy = requests.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PUT);
+
+            let path = req.uri().path().to_string();
+            requests_copy.lock().unwrap().push(path.clone());
+            let response = if path.starts_with("/twins/") {
+                json_response(&module_twin("m1"))
+            } else if path.contains("/modules/") {
+                json_response(&Module::default().with_module_id("m1".to_string()))
+            } else {
+                json_response(&Device::default().with_device_id("d1".to_string()))
+            };
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let export = DeviceExport::new(
+            Device::default().with_device_id("d1".to_string()),
+            vec![
+                Module::default().with_module_id("m1".to_string()),
+                Module::default().with_module_id("m2".to_string()),
+            ],
+            vec![module_twin("m1"), module_twin("m2")],
+        );
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.import_device(&export))
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            vec![
+                "/devices/d1",
+                "/devices/d1/modules/m1",
+                "/devices/d1/modules/m2",
+                "/twins/d1/modules/m1",
+                "/twins/d1/modules/m2",
+            ],
+            *requests
+        );
+    }
+
+    #[test]
+    fn exported_edge_device_is_imported_under_another_id() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let writes = Arc::new(Mutex::new(vec![]));
+
+        let writes_copy = writes.clone();
+        let handler = move |req: Request<Body>| {
+            let path = req.uri().path().to_string();
+            let response = match (req.method(), path.as_str()) {
+                (&Method::GET, "/devices/d1") => {
+                    json_response(&Device::default().with_device_id("d1".to_string()))
+                }
+                (&Method::GET, "/devices/d1/modules") => json_response(
+                    &["$edgeAgent", "$edgeHub", "m1"]
+                        .iter()
+                        .map(|module_id| {
+                            Module::default()
+                                .with_device_id("d1".to_string())
+                                .with_module_id((*module_id).to_string())
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                (&Method::GET, path) if path.starts_with("/twins/d1/modules/") => {
+                    json_response(&module_twin(&path["/twins/d1/modules/".len()..]))
+                }
+                (&Method::PUT, path) => {
+                    let if_match = req
+                        .headers()
+                        .get(hyper::header::IF_MATCH)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    writes_copy
+                        .lock()
+                        .unwrap()
+                        .push((path.to_string(), if_match));
+                    if path.starts_with("/twins/") {
+                        json_response(&module_twin("m1"))
+                    } else if path.contains("/modules/") {
+                        json_response(&Module::default().with_module_id("m1".to_string()))
+                    } else {
+                        json_response(&Device::default().with_device_id("d2".to_string()))
+                    }
+                }
+                (method, path) => panic!("unexpected request {} {}", method, path),
+            };
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let source = DeviceClient::new(client.clone(), "d1".to_string()).unwrap();
+        // ETags of the exported twins don't apply to the imported ones
+        let target = DeviceClient::new(client, "d2".to_string())
+            .unwrap()
+            .with_etag_fallback(EtagFallback::Fail);
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let export = runtime.block_on(source.export_device()).unwrap();
+        runtime.block_on(target.import_device(&export)).unwrap();
+
+        let any = || Some("*".to_string());
+        assert_eq!(
+            vec![
+                ("/devices/d2".to_string(), None),
+                ("/devices/d2/modules/$edgeAgent".to_string(), any()),
+                ("/devices/d2/modules/$edgeHub".to_string(), any()),
+                ("/devices/d2/modules/m1".to_string(), None),
+                ("/twins/d2/modules/$edgeAgent".to_string(), any()),
+                ("/twins/d2/modules/$edgeHub".to_string(), any()),
+                ("/twins/d2/modules/m1".to_string(), any()),
+            ],
+            *writes.lock().unwrap()
+        );
+        // the import doesn't leave the target able to manage system modules
+        assert!(!target.system_modules_allowed());
+    }
+
+    #[test]
+    fn failed_device_import_deletes_device() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let requests_copy = requests.clone();
+        let handler = move |req: Request<Body>| {
+            let path = req.uri().path().to_string();
+            requests_copy
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", req.method(), path));
+            let response = if *req.method() == Method::DELETE {
+                assert_eq!("*", req.headers()[hyper::header::IF_MATCH]);
+                hyper::Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            } else if path == "/devices/d1/modules/m2" {
+                hyper::Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::empty())
+                    .unwrap()
+            } else if path.contains("/modules/") {
+                json_response(&Module::default().with_module_id("m1".to_string()))
+            } else {
+                json_response(&Device::default().with_device_id("d1".to_string()))
+            };
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_etag_fallback(EtagFallback::Fail);
+
+        let export = DeviceExport::new(
+            Device::default().with_device_id("d1".to_string()),
+            vec![
+                Module::default().with_module_id("m1".to_string()),
+                Module::default().with_module_id("m2".to_string()),
+                Module::default().with_module_id("m3".to_string()),
+            ],
+            vec![module_twin("m1"), module_twin("m2"), module_twin("m3")],
+        );
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.import_device(&export))
+            .unwrap_err();
+
+        assert_eq!(&ErrorKind::ImportDevice, err.kind());
+        assert_eq!(
+            vec![
+                "PUT /devices/d1",
+                "PUT /devices/d1/modules/m1",
+                "PUT /devices/d1/modules/m2",
+                "DELETE /devices/d1",
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn module_twin_replace_sends_whole_twin_with_etag() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let twin = module_twin("m1")
+            .with_tags(json!({ "site": "plant-1", "owner": null }))
+            .with_properties(Properties::new(json!({ "interval": 5, "threshold": null })));
+        let expected = serde_json::to_value(&twin).unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(&Method::PUT, req.method());
+            assert_eq!("/twins/d1/modules/m1", req.uri().path());
+            assert_eq!(
+                "\"AAAA\"",
+                req.headers().get(hyper::header::IF_MATCH).unwrap()
+            );
+
+            let body = req.into_body().concat2().wait().unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            // the body is the whole twin, explicit nulls included, rather
+            // than a patch of what changed
+            assert_eq!(expected, body);
+            assert_eq!(json!(null), body["tags"]["owner"]);
+            assert_eq!(json!(null), body["properties"]["desired"]["threshold"]);
+
+            Ok(json_response(&body))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let replaced = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.replace_module_twin("m1", twin.clone(), Some("AAAA")))
+            .unwrap();
+        assert_eq!(Some(twin), replaced);
+    }
+
+    #[test]
+    fn module_twin_update_checks_version() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PATCH);
+            assert_eq!(req.uri().path(), "/twins/d1/modules/m1");
+
+            req.into_body().concat2().map(|body| {
+                let patch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(json!({ "properties": { "desired": { "a": 1 } } }), patch);
+
+                json_response(&module_twin("m1").with_version(4))
+            })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let twin = runtime
+            .block_on(device_client.update_module_twin("m1", json!({ "a": 1 }), Some(3)))
+            .unwrap();
+        assert_eq!(4, *twin.version());
+
+        let twin = runtime
+            .block_on(device_client.update_module_twin("m1", json!({ "a": 1 }), None))
+            .unwrap();
+        assert_eq!(4, *twin.version());
+    }
+
+    #[test]
+    fn module_twin_update_detects_version_conflict() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler =
+            move |_req: Request<Body>| Ok(json_response(&module_twin("m1").with_version(7)));
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.update_module_twin("m1", json!({ "a": 1 }), Some(3)))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::VersionConflict("m1".to_string(), 4, 7),
+            err.kind()
+        );
+    }
+
+    fn create_with_twin_handler(
+        requests: Arc<Mutex<Vec<(Method, String)>>>,
+        patch_status: StatusCode,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            requests
+                .lock()
+                .unwrap()
+                .push((req.method().clone(), req.uri().path().to_string()));
+
+            let response = match *req.method() {
+                Method::PUT => json_response(
+                    &Module::default()
+                        .with_module_id("m1".to_string())
+                        .with_etag("AAAA".to_string()),
+                ),
+                Method::PATCH if patch_status.is_success() => {
+                    json_response(&module_twin("m1").with_version(2))
+                }
+                Method::DELETE => {
+                    assert_eq!(
+                        "\"AAAA\"",
+                        req.headers().get(hyper::header::IF_MATCH).unwrap()
+                    );
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NO_CONTENT;
+                    response
+                }
+                _ => {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = patch_status;
+                    response
+                }
+            };
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn module_create_with_twin_patches_desired_properties() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new(
+            create_with_twin_handler(requests.clone(), StatusCode::OK),
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let (module, twin) = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module_with_twin(
+                "m1".to_string(),
+                None,
+                json!({ "interval": 5 }),
+            ))
+            .unwrap();
+
+        assert_eq!(Some("m1"), module.module_id());
+        assert_eq!(2, *twin.version());
+        assert_eq!(
+            vec![
+                (Method::PUT, "/devices/d1/modules/m1".to_string()),
+                (Method::PATCH, "/twins/d1/modules/m1".to_string()),
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn module_create_with_twin_rolls_back_on_failed_patch() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new(
+            create_with_twin_handler(requests.clone(), StatusCode::BAD_REQUEST),
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.create_module_with_twin(
+                "m1".to_string(),
+                None,
+                json!({ "interval": 5 }),
+            ))
+            .unwrap_err();
+
+        assert_eq!(
+            &ErrorKind::CreateModuleWithTwin("m1".to_string()),
+            err.kind()
+        );
+        assert_eq!(
+            Some(StatusCode::BAD_REQUEST),
+            err.http_error().and_then(edgelet_http::Error::status_code)
+        );
+        assert_eq!(
+            vec![
+                (Method::PUT, "/devices/d1/modules/m1".to_string()),
+                (Method::PATCH, "/twins/d1/modules/m1".to_string()),
+                (Method::DELETE, "/devices/d1/modules/m1".to_string()),
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    fn reporting_twin(reported_version: i64) -> Twin {
+        Twin::new(
+            "d1",
+            3,
+            AuthType::Sas,
+            Properties::new(json!({ "$version": 5 }))
+                .with_reported(json!({ "$version": reported_version })),
+        )
+        .with_module_id("m1".to_string())
+    }
+
+    #[test]
+    fn module_twin_wait_polls_until_reported_version() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        let handler_polls = polls.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::GET);
+            assert_eq!(req.uri().path(), "/twins/d1/modules/m1");
+
+            let reported_version = if handler_polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                4
+            } else {
+                5
+            };
+            Ok(json_response(&reporting_twin(reported_version)))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let twin = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.wait_for_reported_version(
+                "m1",
+                5,
+                Duration::from_secs(5),
+                Duration::from_millis(10),
+            ))
+            .unwrap();
+        assert_eq!(reporting_twin(5), twin);
+        assert_eq!(2, polls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn module_twin_wait_times_out() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| Ok(json_response(&reporting_twin(4)));
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.wait_for_reported_version(
+                "m1",
+                5,
+                Duration::from_millis(50),
+                Duration::from_millis(10),
+            ))
+            .unwrap_err();
+        assert_eq!(&ErrorKind::Timeout("m1".to_string(), 5), err.kind());
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn module_twin_wait_polls_once_more_at_deadline() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        let handler_polls = polls.clone();
+        let handler = move |_req: Request<Body>| {
+            let reported_version = if handler_polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                4
+            } else {
+                5
+            };
+            Ok(json_response(&reporting_twin(reported_version)))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        // the next poll would only be due long after the deadline
+        let twin = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.wait_for_reported_version(
+                "m1",
+                5,
+                Duration::from_millis(50),
+                Duration::from_secs(3600),
+            ))
+            .unwrap();
+        assert_eq!(reporting_twin(5), twin);
+        assert_eq!(2, polls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn module_twin_minimal_read_drops_metadata() {
+        let handler = |_req: Request<Body>| {
+            let twin = module_twin("m1").with_properties(Properties::new(json!({
+                "a": 1,
+                "$metadata": { "$lastUpdated": "2026-10-15T10:00:00Z" },
+            })));
+            Ok(json_response(&twin))
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let full = runtime
+            .block_on(device_client.get_module_twin_with_metadata("m1", TwinMetadata::Full))
+            .unwrap();
+        assert!(full.properties().desired().get("$metadata").is_some());
+
+        let minimal = runtime
+            .block_on(device_client.get_module_twin_with_metadata("m1", TwinMetadata::Minimal))
+            .unwrap();
+        assert_eq!(&json!({ "a": 1 }), minimal.properties().desired());
+    }
+
+    #[test]
+    fn module_twin_poll_skips_unchanged_twin() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/twins/d1/modules/m1");
+
+            let response = match req.headers().get(hyper::header::IF_NONE_MATCH) {
+                Some(etag) if etag == "\"AAAA\"" => Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .unwrap(),
+                _ => Response::builder()
+                    .header(hyper::header::ETAG, "\"BBBB\"")
+                    .body(serde_json::to_string(&module_twin("m1")).unwrap().into())
+                    .unwrap(),
+            };
+            Ok(response)
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let unchanged = runtime
+            .block_on(device_client.poll_module_twin_if_changed("m1", Some("AAAA")))
+            .unwrap();
+        assert_eq!(None, unchanged);
+
+        let changed = runtime
+            .block_on(device_client.poll_module_twin_if_changed("m1", Some("ZZZZ")))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("BBBB"), changed.etag());
+    }
+
+    #[test]
+    fn module_twin_update_rejects_oversized_patch() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+            panic!("An oversized twin patch should not be sent")
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let desired = json!({ "a": "x".repeat(40 * 1024) });
+        let size = serde_json::to_vec(&json!({ "properties": { "desired": desired } }))
+            .unwrap()
+            .len();
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.update_module_twin("m1", desired, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::TwinPatchTooLarge("m1".to_string(), size),
+            err.kind()
+        );
+    }
+
+    #[test]
+    fn module_twin_update_splits_oversized_patch() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let patches = Arc::new(Mutex::new(vec![]));
+        let patches_copy = patches.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::PATCH);
+
+            let patches = patches_copy.clone();
+            req.into_body().concat2().map(move |body| {
+                assert!(body.len() <= 32 * 1024);
+                let patch: serde_json::Value = serde_json::from_slice(&body).unwrap();
 
-    struct NullTokenSource;
+                let mut patches = patches.lock().unwrap();
+                patches.push(patch);
+                let version = 3 + i32::try_from(patches.len()).unwrap();
+                json_response(&module_twin("m1").with_version(version))
+            })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_split_twin_patches(true);
 
-    impl TokenSource for NullTokenSource {
-        type Error = Error;
-        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
-            Ok("token".to_string())
+        let big = "x".repeat(20 * 1024);
+        let twin = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.update_module_twin(
+                "m1",
+                json!({ "a": big, "b": big, "c": 1 }),
+                Some(3),
+            ))
+            .unwrap();
+        assert_eq!(5, *twin.version());
+
+        let patches = patches.lock().unwrap();
+        assert_eq!(
+            *patches,
+            vec![
+                json!({ "properties": { "desired": { "a": big } } }),
+                json!({ "properties": { "desired": { "b": big, "c": 1 } } }),
+            ]
+        );
+    }
+
+    fn paged_list_handler(
+        fail_second_page: bool,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/devices/d1/modules");
+
+            let continuation = req
+                .headers()
+                .get("x-ms-continuation")
+                .map(|value| value.to_str().unwrap().to_string());
+            let response = match continuation.as_ref().map(AsRef::as_ref) {
+                None => {
+                    let page = vec![Module::default().with_module_id("m1".to_string())];
+                    hyper::Response::builder()
+                        .header("x-ms-continuation", "page2")
+                        .body(serde_json::to_string(&page).unwrap().into())
+                        .unwrap()
+                }
+                Some("page2") if fail_second_page => hyper::Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap(),
+                Some("page2") => {
+                    let page = vec![Module::default().with_module_id("m2".to_string())];
+                    hyper::Response::builder()
+                        .body(serde_json::to_string(&page).unwrap().into())
+                        .unwrap()
+                }
+                Some(continuation) => panic!("unexpected continuation token {}", continuation),
+            };
+            Ok(response)
         }
     }
 
-    impl Clone for NullTokenSource {
-        fn clone(&self) -> Self {
-            NullTokenSource {}
+    // Fails the first request for the second page and every first delete of
+    // a module with a 503, as a service recovering from an outage would.
+    fn transiently_failing_handler(
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        let failed = Mutex::new(Vec::new());
+        let list = paged_list_handler(false);
+        move |req: Request<Body>| {
+            let key = format!(
+                "{} {} {:?}",
+                req.method(),
+                req.uri().path(),
+                req.headers().get("x-ms-continuation")
+            );
+            let first_page =
+                req.method() == Method::GET && req.headers().get("x-ms-continuation").is_none();
+            if !first_page && !failed.lock().unwrap().contains(&key) {
+                failed.lock().unwrap().push(key);
+                return Ok(hyper::Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+
+            if req.method() == Method::DELETE {
+                Ok(Response::new(Body::empty()))
+            } else {
+                list(req)
+            }
         }
     }
 
-    #[test]
-    fn device_client_create_empty_id_fails() {
-        let hyper_client = HyperClient::new();
+    fn retrying_device_client() -> DeviceClient<
+        impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync,
+        NullTokenSource,
+    > {
         let client = Client::new(
-            hyper_client,
+            transiently_failing_handler(),
             Some(NullTokenSource),
-            "2018-04-11".to_string(),
+            "2018-04-10".to_string(),
             Url::parse("http://localhost").unwrap(),
         )
-        .unwrap();
-        match DeviceClient::new(client, "".to_string()) {
-            Ok(_) => panic!("Excepted err got success"),
-            Err(err) => {
-                if let ErrorKind::DeleteModule = err.kind() {
-                    panic!("Wrong error kind. Expected `Http` found {:?}", err);
-                }
+        .unwrap()
+        .with_retry_policy(RetryPolicy::new().with_request_retries(1));
+        DeviceClient::new(client, "d1".to_string()).unwrap()
+    }
+
+    #[test]
+    fn modules_list_all_retries_failed_page() {
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(retrying_device_client().list_all_modules())
+            .unwrap();
+
+        let ids: Vec<_> = modules.iter().filter_map(Module::module_id).collect();
+        assert_eq!(vec!["m1", "m2"], ids);
+    }
+
+    #[test]
+    fn modules_stream_retries_failed_page() {
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(
+                retrying_device_client()
+                    .list_modules_paged()
+                    .stream()
+                    .collect(),
+            )
+            .unwrap();
+
+        let ids: Vec<_> = modules.iter().filter_map(Module::module_id).collect();
+        assert_eq!(vec!["m1", "m2"], ids);
+    }
+
+    #[test]
+    fn modules_delete_all_retries_failed_deletes() {
+        let deleted = DeletedModules::default();
+        let module_ids = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(retrying_device_client().delete_all_modules(deleted.clone()))
+            .unwrap();
+
+        assert_eq!(vec!["m1".to_string(), "m2".to_string()], module_ids);
+        assert_eq!(module_ids, deleted.module_ids());
+    }
+
+    #[test]
+    fn modules_size_is_estimated_from_first_page() {
+        let api_version = "2018-06-30".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let page = vec![
+            Module::default().with_module_id("m1".to_string()),
+            Module::default()
+                .with_module_id("m2".to_string())
+                .with_managed_by("iotedge".to_string()),
+        ];
+        let sample_bytes = page
+            .iter()
+            .map(|module| serde_json::to_vec(module).unwrap().len())
+            .sum::<usize>();
+
+        let handler = move |req: Request<Body>| match req.uri().path() {
+            "/devices/d1/modules" => {
+                assert_eq!(None, req.headers().get("x-ms-continuation"));
+                Ok(hyper::Response::builder()
+                    .header("x-ms-continuation", "page2")
+                    .body(serde_json::to_string(&page).unwrap().into())
+                    .unwrap())
+            }
+            "/devices/query" => {
+                let body = req.into_body().concat2().wait().unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    json!({
+                        "query": "SELECT COUNT() AS numberOfModules FROM devices.modules \
+                                  WHERE deviceId = 'd1'"
+                    }),
+                    body
+                );
+                Ok(json_response(&json!([{ "numberOfModules": 10 }])))
             }
+            path => panic!("unexpected path {}", path),
         };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let estimate = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.estimate_modules_size())
+            .unwrap();
+
+        assert_eq!(10, estimate.module_count());
+        assert_eq!(2, estimate.sample_size());
+        assert_eq!(sample_bytes / 2 * 10, estimate.bytes());
+        assert!(!estimate.is_exact());
     }
 
     #[test]
-    fn device_client_create_white_space_id_fails() {
-        let hyper_client = HyperClient::new();
-        let client = Client::new(
-            hyper_client,
-            Some(NullTokenSource),
-            "2018-04-11".to_string(),
-            Url::parse("http://localhost").unwrap(),
-        )
-        .unwrap();
-        match DeviceClient::new(client, "       ".to_string()) {
-            Ok(_) => panic!("Excepted err got success"),
-            Err(err) => {
-                if let ErrorKind::DeleteModule = err.kind() {
-                    panic!("Wrong error kind. Expected `Http` found {:?}", err);
-                }
+    fn modules_size_count_query_quotes_device_id() {
+        let api_version = "2018-06-30".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |req: Request<Body>| {
+            if req.uri().path() == "/devices/query" {
+                let body = req.into_body().concat2().wait().unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    json!({
+                        "query": r"SELECT COUNT() AS numberOfModules FROM devices.modules WHERE deviceId = 'd\'1'"
+                    }),
+                    body
+                );
+                Ok(json_response(&json!([{ "numberOfModules": 10 }])))
+            } else {
+                Ok(hyper::Response::builder()
+                    .header("x-ms-continuation", "page2")
+                    .body(r#"[{"moduleId":"m1"}]"#.into())
+                    .unwrap())
             }
         };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d'1".to_string()).unwrap();
+        let estimate = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.estimate_modules_size())
+            .unwrap();
+
+        assert_eq!(10, estimate.module_count());
     }
 
     #[test]
-    fn module_upsert_empty_module_id_fails() {
-        let hyper_client = HyperClient::new();
-        let client = Client::new(
-            hyper_client,
-            Some(NullTokenSource),
-            "2018-04-11".to_string(),
-            Url::parse("http://localhost").unwrap(),
-        )
-        .unwrap();
+    fn modules_size_of_single_page_is_exact() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let module = Module::default().with_module_id("m1".to_string());
+        let bytes = serde_json::to_vec(&module).unwrap().len();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.uri().path(), "/devices/d1/modules");
+            Ok(json_response(&vec![module.clone()]))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
         let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let name = "";
+        let estimate = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.estimate_modules_size())
+            .unwrap();
 
-        let task = device_client
-            .upsert_module(name.to_string(), None, None, false)
-            .then(|result| match result {
-                Ok(_) => panic!("Excepted err got success"),
-                Err(err) => match err.kind() {
-                    ErrorKind::UpsertModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
-                    _ => panic!("Wrong error kind. Expected `UpsertModuleWithReason(EmptyModuleId)` found {:?}", err),
-                }
-            });
+        assert_eq!(1, estimate.module_count());
+        assert_eq!(bytes, estimate.bytes());
+        assert!(estimate.is_exact());
+    }
 
-        tokio::runtime::current_thread::Runtime::new()
+    #[test]
+    fn modules_export_writes_json_lines() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = paged_list_handler(false);
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let mut buffer = Vec::new();
+        let written = tokio::runtime::current_thread::Runtime::new()
             .unwrap()
-            .block_on(task)
+            .block_on(device_client.export_modules_to(&mut buffer))
             .unwrap();
+
+        assert_eq!(2, written);
+        let lines: Vec<Module> = std::str::from_utf8(&buffer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                Module::default().with_module_id("m1".to_string()),
+                Module::default().with_module_id("m2".to_string()),
+            ],
+            lines
+        );
     }
 
     #[test]
-    fn module_upsert_white_space_module_id_fails() {
-        let hyper_client = HyperClient::new();
-        let client = Client::new(
-            hyper_client,
-            Some(NullTokenSource),
-            "2018-04-11".to_string(),
-            Url::parse("http://localhost").unwrap(),
-        )
-        .unwrap();
+    fn modules_export_failure_names_page_to_resume_from() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = paged_list_handler(true);
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
         let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let name = "     ";
 
-        let task = device_client
-            .upsert_module(name.to_string(), None, None, false)
-            .then(|result| match result {
-                Ok(_) => panic!("Excepted err got success"),
-                Err(err) => match err.kind() {
-                    ErrorKind::UpsertModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
-                    _ => panic!("Wrong error kind. Expected `UpsertModuleWithReason(EmptyModuleId)` found {:?}", err),
-                }
-            });
+        let mut buffer = Vec::new();
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.export_modules_to(&mut buffer))
+            .unwrap_err();
 
-        tokio::runtime::current_thread::Runtime::new()
+        assert_eq!(
+            &ErrorKind::ExportModules(Some("page2".to_string())),
+            err.kind()
+        );
+        assert_eq!(1, std::str::from_utf8(&buffer).unwrap().lines().count());
+    }
+
+    #[test]
+    fn modules_list_all_follows_continuation() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = paged_list_handler(false);
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
             .unwrap()
-            .block_on(task)
+            .block_on(device_client.list_all_modules())
             .unwrap();
+
+        let ids: Vec<_> = modules.iter().filter_map(Module::module_id).collect();
+        assert_eq!(vec!["m1", "m2"], ids);
     }
 
     #[test]
-    fn module_upsert_adds_module_body_without_if_match() {
+    fn modules_list_all_returns_partial_results_on_failure() {
         let api_version = "2018-04-10".to_string();
         let host_name = Url::parse("http://localhost").unwrap();
-        let auth = AuthMechanism::default()
-            .with_type(AuthType::Sas)
-            .with_symmetric_key(
-                SymmetricKey::default()
-                    .with_primary_key("pkey".to_string())
-                    .with_secondary_key("skey".to_string()),
-            );
-        let module_request = Module::default()
-            .with_device_id("d1".to_string())
-            .with_module_id("m1".to_string())
-            .with_authentication(auth.clone())
-            .with_managed_by("iotedge".to_string());
-        let expected_response = module_request.clone().with_generation_id("g1".to_string());
 
-        let handler = move |req: Request<Body>| {
-            assert_eq!(req.method(), &Method::PUT);
-            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
-            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
+        let handler = paged_list_handler(true);
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
 
-            let module_request_copy = module_request.clone();
-            req.into_body()
-                .concat2()
-                .and_then(|req_body| Ok(serde_json::from_slice::<Module>(&req_body).unwrap()))
-                .and_then(move |module| {
-                    assert_eq!(module, module_request_copy);
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.list_all_modules())
+            .unwrap_err();
 
-                    let mut response = Response::new(
-                        serde_json::to_string(&module.with_generation_id("g1".to_string()))
-                            .unwrap()
-                            .into(),
-                    );
-                    response
-                        .headers_mut()
-                        .typed_insert(&ContentType(mime::APPLICATION_JSON));
-                    Ok(response)
-                })
-        };
+        assert_eq!(1, err.items().len());
+        assert_eq!(Some("m1"), err.items()[0].module_id());
+        assert_eq!(Some("page2"), err.continuation());
+        assert_eq!(&ErrorKind::ListModules, err.error().kind());
+    }
+
+    #[test]
+    fn modules_list_all_resumes_from_continuation() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = paged_list_handler(false);
         let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
 
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.list_all_modules_from(Some("page2".to_string())))
+            .unwrap();
+
+        let ids: Vec<_> = modules.iter().filter_map(Module::module_id).collect();
+        assert_eq!(vec!["m2"], ids);
+    }
+
+    #[test]
+    fn modules_delete_all_stops_when_dropped() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        // the delete of m2 never completes and signals that it was issued
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let (m2_issued, on_m2_issued) = oneshot::channel();
+        let m2_issued = Mutex::new(Some(m2_issued));
+        let handler = {
+            let requested = requested.clone();
+            move |req: Request<Body>| {
+                if req.method() == Method::GET {
+                    let modules: Vec<Module> = ["m1", "m2", "m3"]
+                        .iter()
+                        .map(|module_id| Module::default().with_module_id((*module_id).to_string()))
+                        .collect();
+                    return Either::A(future::ok::<_, hyper::Error>(json_response(&modules)));
+                }
+
+                assert_eq!(&Method::DELETE, req.method());
+                let module_id = req.uri().path().rsplit('/').next().unwrap().to_string();
+                requested.lock().unwrap().push(module_id.clone());
+                if module_id == "m2" {
+                    m2_issued.lock().unwrap().take().unwrap().send(()).unwrap();
+                    Either::B(future::empty::<_, hyper::Error>())
+                } else {
+                    Either::A(future::ok(Response::new(Body::empty())))
+                }
+            }
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
         let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let task = device_client
-            .upsert_module(
-                "m1".to_string(),
-                Some(auth),
-                Some(&"iotedge".to_string()),
-                false,
-            )
-            .then(|result| {
-                assert_eq!(expected_response, result.unwrap());
-                Ok::<_, Error>(())
-            });
 
-        tokio::runtime::current_thread::Runtime::new()
-            .unwrap()
-            .block_on(task)
-            .unwrap();
+        let deleted = DeletedModules::default();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        match runtime.block_on(
+            device_client
+                .delete_all_modules(deleted.clone())
+                .select2(on_m2_issued),
+        ) {
+            Ok(Either::B((_, delete_all))) => drop(delete_all),
+            _ => panic!("delete_all_modules should still be waiting on m2"),
+        }
+        runtime.run().unwrap();
+
+        assert_eq!(vec!["m1", "m2"], *requested.lock().unwrap());
+        assert_eq!(vec!["m1".to_string()], deleted.module_ids());
+    }
+
+    fn cascade_handler(
+        requests: Arc<Mutex<Vec<String>>>,
+        failing_module_id: Option<&'static str>,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            requests
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", req.method(), req.uri().path()));
+
+            if req.method() == Method::GET {
+                let modules: Vec<Module> = ["m1", "m2"]
+                    .iter()
+                    .map(|module_id| Module::default().with_module_id((*module_id).to_string()))
+                    .collect();
+                return Ok(json_response(&modules));
+            }
+
+            let module_id = req.uri().path().rsplit('/').next().unwrap();
+            let mut response = Response::new(Body::empty());
+            if Some(module_id) == failing_module_id {
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            Ok(response)
+        }
     }
 
     #[test]
-    fn module_upsert_adds_module_body_with_if_match() {
+    fn device_delete_cascade_deletes_modules_first() {
         let api_version = "2018-04-10".to_string();
         let host_name = Url::parse("http://localhost").unwrap();
-        let auth = AuthMechanism::default()
-            .with_type(AuthType::Sas)
-            .with_symmetric_key(
-                SymmetricKey::default()
-                    .with_primary_key("pkey".to_string())
-                    .with_secondary_key("skey".to_string()),
-            );
-        let module_request = Module::default()
-            .with_device_id("d1".to_string())
-            .with_module_id("m1".to_string())
-            .with_authentication(auth.clone())
-            .with_managed_by("iotedge".to_string());
-        let expected_response = module_request.clone().with_generation_id("g1".to_string());
-
-        let handler = move |req: Request<Body>| {
-            assert_eq!(req.method(), &Method::PUT);
-            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
-            assert_eq!(req.headers().get(hyper::header::IF_MATCH).unwrap(), "*");
-
-            let module_request_copy = module_request.clone();
-            req.into_body()
-                .concat2()
-                .and_then(|req_body| Ok(serde_json::from_slice::<Module>(&req_body).unwrap()))
-                .and_then(move |module| {
-                    assert_eq!(module, module_request_copy);
 
-                    let mut response = Response::new(
-                        serde_json::to_string(&module.with_generation_id("g1".to_string()))
-                            .unwrap()
-                            .into(),
-                    );
-                    response
-                        .headers_mut()
-                        .typed_insert(&ContentType(mime::APPLICATION_JSON));
-                    Ok(response)
-                })
-        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = cascade_handler(requests.clone(), None);
         let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
-
         let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let task = device_client
-            .upsert_module(
-                "m1".to_string(),
-                Some(auth),
-                Some(&"iotedge".to_string()),
-                true,
-            )
-            .then(|result| {
-                assert_eq!(expected_response, result.unwrap());
-                Ok::<_, Error>(())
-            });
 
-        tokio::runtime::current_thread::Runtime::new()
+        let module_ids = tokio::runtime::current_thread::Runtime::new()
             .unwrap()
-            .block_on(task)
+            .block_on(device_client.delete_device_cascade(DeletedModules::default()))
             .unwrap();
+
+        assert_eq!(vec!["m1".to_string(), "m2".to_string()], module_ids);
+        assert_eq!(
+            vec![
+                "GET /devices/d1/modules",
+                "DELETE /devices/d1/modules/m1",
+                "DELETE /devices/d1/modules/m2",
+                "DELETE /devices/d1",
+            ],
+            *requests.lock().unwrap()
+        );
     }
 
     #[test]
-    fn module_delete_empty_module_id_fails() {
-        let hyper_client = HyperClient::new();
-        let client = Client::new(
-            hyper_client,
-            Some(NullTokenSource),
-            "2018-04-11".to_string(),
-            Url::parse("http://localhost").unwrap(),
-        )
-        .unwrap();
-        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let name = "";
+    fn device_delete_cascade_stops_at_failed_module() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
 
-        let task = device_client.delete_module(name).then(|result| match result {
-                Ok(_) => panic!("Excepted err got success"),
-                Err(err) => match err.kind() {
-                    ErrorKind::DeleteModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
-                    _ => panic!("Wrong error kind. Expected `DeleteModuleWithReason(EmptyModuleId)` found {:?}", err),
-                }
-        });
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = cascade_handler(requests.clone(), Some("m2"));
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
 
-        tokio::runtime::current_thread::Runtime::new()
+        let deleted = DeletedModules::default();
+        let err = tokio::runtime::current_thread::Runtime::new()
             .unwrap()
-            .block_on(task)
-            .unwrap();
+            .block_on(device_client.delete_device_cascade(deleted.clone()))
+            .unwrap_err();
+
+        assert_eq!(&ErrorKind::DeleteDevice, err.kind());
+        assert_eq!(vec!["m1".to_string()], deleted.module_ids());
+        assert_eq!(
+            vec![
+                "GET /devices/d1/modules",
+                "DELETE /devices/d1/modules/m1",
+                "DELETE /devices/d1/modules/m2",
+            ],
+            *requests.lock().unwrap()
+        );
     }
 
-    #[test]
-    fn module_delete_white_space_module_id_fails() {
-        let hyper_client = HyperClient::new();
-        let client = Client::new(
-            hyper_client,
-            Some(NullTokenSource),
-            "2018-04-11".to_string(),
-            Url::parse("http://localhost").unwrap(),
-        )
-        .unwrap();
-        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let name = "     ";
+    fn counting_list_handler(
+        hits: Arc<AtomicUsize>,
+    ) -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        move |req: Request<Body>| {
+            if req.method() != Method::GET {
+                return Ok(Response::new(Body::empty()));
+            }
 
-        let task = device_client
-            .delete_module(name)
-            .then(|result| match result {
-                Ok(_) => panic!("Excepted err got success"),
-                Err(err) => match err.kind() {
-                    ErrorKind::DeleteModuleWithReason(s, ModuleOperationReason::EmptyModuleId) if s == name => Ok::<_, Error>(()),
-                    _ => panic!("Wrong error kind. Expected `DeleteModuleWithReason(EmptyModuleId)` found {:?}", err),
-                }
-            });
+            hits.fetch_add(1, Ordering::SeqCst);
+            let modules = vec![Module::default()
+                .with_device_id("d1".to_string())
+                .with_module_id("m1".to_string())];
 
-        tokio::runtime::current_thread::Runtime::new()
-            .unwrap()
-            .block_on(task)
-            .unwrap();
+            let mut response = Response::new(serde_json::to_string(&modules).unwrap().into());
+            response
+                .headers_mut()
+                .typed_insert(&ContentType(mime::APPLICATION_JSON));
+            Ok(response)
+        }
     }
 
     #[test]
-    fn module_delete_request() {
+    fn modules_list_is_cached_within_ttl() {
         let api_version = "2018-04-10".to_string();
         let host_name = Url::parse("http://localhost").unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
 
-        let handler = move |req: Request<Body>| {
-            assert_eq!(req.method(), &Method::DELETE);
-            assert_eq!(req.uri().path(), "/devices/d1/modules/m1");
-            assert_eq!(req.headers().get(hyper::header::IF_MATCH).unwrap(), "*");
+        let handler = counting_list_handler(hits.clone());
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_module_cache(Duration::from_secs(3600));
 
-            Ok(Response::new(Body::empty()))
-        };
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let first = runtime.block_on(device_client.list_modules()).unwrap();
+        let second = runtime.block_on(device_client.list_modules()).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn modules_list_is_refreshed_after_ttl() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+
+        let handler = counting_list_handler(hits.clone());
         let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
+            .unwrap()
+            .with_module_cache(Duration::from_millis(10));
 
-        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let task = device_client
-            .delete_module("m1")
-            .then(|result: Result<(), _>| result);
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.block_on(device_client.list_modules()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        runtime.block_on(device_client.list_modules()).unwrap();
 
-        tokio::runtime::current_thread::Runtime::new()
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn modules_list_cache_is_invalidated() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+
+        let handler = counting_list_handler(hits.clone());
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string())
             .unwrap()
-            .block_on(task)
-            .unwrap();
+            .with_module_cache(Duration::from_secs(3600));
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.block_on(device_client.list_modules()).unwrap();
+        runtime.block_on(device_client.delete_module("m1")).unwrap();
+        runtime.block_on(device_client.list_modules()).unwrap();
+        device_client.invalidate();
+        runtime.block_on(device_client.list_modules()).unwrap();
+
+        assert_eq!(3, hits.load(Ordering::SeqCst));
     }
 
     #[test]
-    fn modules_list_request() {
+    fn modules_are_streamed_from_listing() {
         let api_version = "2018-04-10".to_string();
         let host_name = Url::parse("http://localhost").unwrap();
-        let auth = AuthMechanism::default()
-            .with_type(AuthType::Sas)
-            .with_symmetric_key(
-                SymmetricKey::default()
-                    .with_primary_key("pkey".to_string())
-                    .with_secondary_key("skey".to_string()),
-            );
         let modules = vec![
-            Module::default()
-                .with_device_id("d1".to_string())
-                .with_module_id("m1".to_string())
-                .with_generation_id("g1".to_string())
-                .with_managed_by("iotedge".to_string())
-                .with_authentication(auth.clone()),
-            Module::default()
-                .with_device_id("d1".to_string())
-                .with_module_id("m2".to_string())
-                .with_generation_id("g2".to_string())
-                .with_managed_by("iotedge".to_string())
-                .with_authentication(auth),
+            Module::default().with_module_id("m1".to_string()),
+            Module::default().with_module_id("m2".to_string()),
         ];
-        let expected_modules = modules.clone();
 
+        let response = modules.clone();
         let handler = move |req: Request<Body>| {
             assert_eq!(req.method(), &Method::GET);
             assert_eq!(req.uri().path(), "/devices/d1/modules");
-            assert_eq!(None, req.headers().get(hyper::header::IF_MATCH));
-
-            let mut response = Response::new(serde_json::to_string(&modules).unwrap().into());
-            response
-                .headers_mut()
-                .typed_insert(&ContentType(mime::APPLICATION_JSON));
-            Ok(response)
+            Ok(json_response(&response))
         };
         let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
-
         let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
-        let task = device_client.list_modules().then(|modules| {
-            let modules = modules.unwrap();
-            assert_eq!(expected_modules.len(), modules.len());
-            for i in 0..modules.len() {
-                assert_eq!(expected_modules[i], modules[i])
-            }
-            Ok::<_, Error>(())
-        });
 
-        tokio::runtime::current_thread::Runtime::new()
+        let streamed = tokio::runtime::current_thread::Runtime::new()
             .unwrap()
-            .block_on(task)
+            .block_on(device_client.stream_modules().collect())
             .unwrap();
+        assert_eq!(modules, streamed);
     }
 
     #[test]
@@ -658,6 +5504,48 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn module_missing_generation_id_fails_when_required() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+
+        let handler = move |_req: Request<Body>| {
+            Ok(json_response(
+                &Module::default()
+                    .with_device_id("d1".to_string())
+                    .with_module_id("m1".to_string()),
+            ))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let module = runtime
+            .block_on(device_client.get_module_by_id("m1".to_string()))
+            .unwrap();
+        assert_eq!(None, module.generation_id());
+
+        let device_client = device_client.with_required_fields(true);
+        let err = runtime
+            .block_on(device_client.get_module_by_id("m1".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::MissingField {
+                field: "generationId"
+            },
+            err.kind()
+        );
+        let err = runtime
+            .block_on(device_client.create_module("m1".to_string(), None, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::MissingField {
+                field: "generationId"
+            },
+            err.kind()
+        );
+    }
+
     #[test]
     fn modules_get_request_with_encoding() {
         let api_version = "2018-04-10".to_string();
@@ -742,4 +5630,128 @@ mod tests {
             .block_on(task)
             .unwrap();
     }
+
+    #[test]
+    fn module_operations_are_streamed() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let operations: Vec<ModuleOperation> = (0..1000)
+            .map(|i| ModuleOperation::new(format!("m{}", i), ImportMode::CreateOrUpdate))
+            .collect();
+        let expected_operations: Vec<ModuleOperation> = operations
+            .iter()
+            .cloned()
+            .map(|operation| operation.with_device_id("d1".to_string()))
+            .collect();
+
+        let handler = move |req: Request<Body>| {
+            assert_eq!(req.method(), &Method::POST);
+            assert_eq!(req.uri().path(), "/devices");
+            assert_eq!(None, req.headers().get(hyper::header::CONTENT_LENGTH));
+
+            let expected_operations = expected_operations.clone();
+            req.into_body()
+                .fold((0, 0, Vec::new()), |(count, largest, mut body), chunk| {
+                    body.extend_from_slice(&chunk);
+                    Ok::<_, hyper::Error>((count + 1, cmp::max(largest, chunk.len()), body))
+                })
+                .and_then(move |(count, largest, body)| {
+                    // one chunk per operation plus the opening and closing brackets
+                    assert_eq!(expected_operations.len() + 2, count);
+                    assert!(largest * 100 < body.len());
+
+                    let operations = serde_json::from_slice::<Vec<ModuleOperation>>(&body).unwrap();
+                    assert_eq!(expected_operations, operations);
+
+                    let mut response = Response::new(
+                        serde_json::to_string(&BulkRegistryOperationResult::new(true))
+                            .unwrap()
+                            .into(),
+                    );
+                    response
+                        .headers_mut()
+                        .typed_insert(&ContentType(mime::APPLICATION_JSON));
+                    Ok(response)
+                })
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+        let task = device_client
+            .apply_module_operations(operations)
+            .then(|result| {
+                assert!(result.unwrap().is_successful());
+                Ok::<_, Error>(())
+            });
+
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(task)
+            .unwrap();
+    }
+
+    #[test]
+    fn modules_are_deleted_in_batches() {
+        let api_version = "2018-04-10".to_string();
+        let host_name = Url::parse("http://localhost").unwrap();
+        let module_ids: Vec<String> = (0..250).map(|i| format!("m{}", i)).collect();
+        let batches = Arc::new(Mutex::new(Vec::new()));
+
+        let handler_batches = batches.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(&Method::POST, req.method());
+            assert_eq!("/devices", req.uri().path());
+            let body = req.into_body().concat2().wait().unwrap();
+            let operations = serde_json::from_slice::<Vec<ModuleOperation>>(&body).unwrap();
+            assert!(operations
+                .iter()
+                .all(|operation| operation.import_mode() == ImportMode::Delete
+                    && operation.device_id() == Some("d1")));
+            handler_batches.lock().unwrap().push(operations.len());
+
+            let result = if operations
+                .iter()
+                .any(|operation| operation.module_id() == "m150")
+            {
+                BulkRegistryOperationResult::new(false).with_errors(vec![
+                    RegistryOperationError::new(
+                        "d1".to_string(),
+                        "404010".to_string(),
+                        "Module not found".to_string(),
+                    )
+                    .with_module_id("m150".to_string()),
+                ])
+            } else {
+                BulkRegistryOperationResult::new(true)
+            };
+            Ok(json_response(&result))
+        };
+        let client = Client::new(handler, Some(NullTokenSource), api_version, host_name).unwrap();
+        let device_client = DeviceClient::new(client, "d1".to_string()).unwrap();
+
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(device_client.delete_modules(&module_ids))
+            .unwrap();
+
+        assert_eq!(vec![100, 100, 50], *batches.lock().unwrap());
+        let deleted_ids: Vec<String> = results.iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(module_ids, deleted_ids);
+        for (module_id, deleted) in &results {
+            if module_id == "m150" {
+                assert_eq!(
+                    &ErrorKind::DeleteModuleWithReason(
+                        "m150".to_string(),
+                        ModuleOperationReason::RegistryOperationFailed {
+                            error_code: "404010".to_string(),
+                            error_status: "Module not found".to_string(),
+                        }
+                    ),
+                    deleted.as_ref().unwrap_err().kind()
+                );
+            } else {
+                assert!(deleted.is_ok());
+            }
+        }
+    }
 }
@@ -0,0 +1,466 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use failure::Fail;
+use futures::future::{self, Either};
+use futures::Future;
+use serde_json::Value;
+
+use edgelet_http::client::{ClientImpl, TokenSource};
+
+use crate::device::DeviceClient;
+use crate::error::{Error, ErrorKind};
+use crate::model::{Module, Twin};
+
+const EDGE_AGENT: &str = "$edgeAgent";
+const EDGE_HUB: &str = "$edgeHub";
+const DESIRED_PROPERTIES: &str = "properties.desired";
+const ROUTES: &str = "routes";
+
+/// Manages an IoT Edge device, whose deployment lives in the twins of its
+/// `$edgeAgent` and `$edgeHub` system modules.
+pub struct EdgeDeviceClient<C, T> {
+    device: DeviceClient<C, T>,
+    route_validation: bool,
+}
+
+impl<C, T> EdgeDeviceClient<C, T>
+where
+    C: ClientImpl,
+    T: 'static + TokenSource + Clone,
+    <T as TokenSource>::Error: Fail,
+{
+    pub fn new(device: DeviceClient<C, T>) -> Self {
+        EdgeDeviceClient {
+            device: device.with_system_modules_allowed(true),
+            route_validation: false,
+        }
+    }
+
+    /// Checks the syntax of the `routes` in `$edgeHub` desired properties
+    /// before sending them, instead of leaving a malformed route for the
+    /// runtime to reject at deploy time.
+    pub fn with_route_validation(mut self, route_validation: bool) -> Self {
+        self.route_validation = route_validation;
+        self
+    }
+
+    pub fn device(&self) -> &DeviceClient<C, T> {
+        &self.device
+    }
+
+    pub fn route_validation(&self) -> bool {
+        self.route_validation
+    }
+
+    /// Creates whichever of `$edgeAgent` and `$edgeHub` is missing, and
+    /// returns both.
+    pub fn ensure_system_modules(&self) -> impl Future<Item = Vec<Module>, Error = Error> {
+        let device = self.device.clone();
+        let matching = self.device.module_id_matching();
+
+        self.device.list_modules().and_then(move |modules| {
+            future::join_all([EDGE_AGENT, EDGE_HUB].iter().map(move |module_id| {
+                match modules.iter().find(|module| {
+                    module
+                        .module_id()
+                        .map_or(false, |id| matching.matches(id, module_id))
+                }) {
+                    Some(module) => Either::A(future::ok(module.clone())),
+                    None => Either::B(device.create_module((*module_id).to_string(), None, None)),
+                }
+            }))
+        })
+    }
+
+    /// Applies a deployment in the shape of a deployment manifest's
+    /// `modulesContent`, patching the desired properties of every module it
+    /// names with the `properties.desired` given for it. With route validation
+    /// on, the routes given for `$edgeHub` are checked first.
+    pub fn set_deployment(
+        &self,
+        twin_patch: &Value,
+    ) -> impl Future<Item = Vec<Twin>, Error = Error> {
+        let patches = match self.deployment_patches(twin_patch) {
+            Ok(patches) => patches,
+            Err(err) => return Either::B(future::err(err)),
+        };
+
+        let device = self.device.clone();
+        let res =
+            future::join_all(patches.into_iter().map(move |(module_id, desired)| {
+                device.update_module_twin(&module_id, desired, None)
+            }))
+            .map_err(|err| Error::from(err.context(ErrorKind::SetDeployment)));
+
+        Either::A(res)
+    }
+
+    fn deployment_patches(&self, twin_patch: &Value) -> Result<Vec<(String, Value)>, Error> {
+        let patches = desired_properties(twin_patch)?;
+        if self.route_validation {
+            if let Some((_, desired)) = patches.iter().find(|(module_id, _)| module_id == EDGE_HUB)
+            {
+                validate_routes(desired)?;
+            }
+        }
+        Ok(patches)
+    }
+
+    /// Patches the desired properties of `$edgeHub`, validating its routes
+    /// first if route validation is on.
+    pub fn update_edge_hub_twin(
+        &self,
+        desired: Value,
+        expected_version: Option<i32>,
+    ) -> impl Future<Item = Twin, Error = Error> {
+        if self.route_validation {
+            if let Err(err) = validate_routes(&desired) {
+                return Either::B(future::err(err));
+            }
+        }
+
+        Either::A(
+            self.device
+                .update_module_twin(EDGE_HUB, desired, expected_version),
+        )
+    }
+}
+
+impl<C, T> Clone for EdgeDeviceClient<C, T>
+where
+    C: ClientImpl,
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        EdgeDeviceClient {
+            device: self.device.clone(),
+            route_validation: self.route_validation,
+        }
+    }
+}
+
+fn desired_properties(twin_patch: &Value) -> Result<Vec<(String, Value)>, Error> {
+    twin_patch
+        .as_object()
+        .ok_or(ErrorKind::InvalidDeployment)?
+        .iter()
+        .map(|(module_id, content)| {
+            content
+                .get(DESIRED_PROPERTIES)
+                .map(|desired| (module_id.clone(), desired.clone()))
+                .ok_or_else(|| Error::from(ErrorKind::InvalidDeployment))
+        })
+        .collect()
+}
+
+// Routes are given either as a route string or, since schema 1.1, as an
+// object with the route string in `route`.
+fn validate_routes(desired: &Value) -> Result<(), Error> {
+    let routes = match desired.get(ROUTES) {
+        Some(routes) => routes,
+        None => return Ok(()),
+    };
+    let routes = routes.as_object().ok_or_else(|| ErrorKind::InvalidRoute {
+        name: ROUTES.to_string(),
+        reason: "routes must be an object of named routes",
+    })?;
+
+    for (name, route) in routes {
+        route
+            .as_str()
+            .or_else(|| route.get("route").and_then(Value::as_str))
+            .ok_or("route must be a string or an object with a `route` string")
+            .and_then(check_route)
+            .map_err(|reason| ErrorKind::InvalidRoute {
+                name: name.clone(),
+                reason,
+            })?;
+    }
+    Ok(())
+}
+
+// Checks a route has the shape `FROM <source> [WHERE <condition>] INTO <sink>`.
+// The condition itself isn't parsed beyond balancing its quotes and
+// parentheses.
+fn check_route(route: &str) -> Result<(), &'static str> {
+    let route = route.trim();
+    let keywords = keywords(route)?;
+    let find = |keyword: &str| {
+        keywords.iter().find_map(|(index, word)| {
+            if word.eq_ignore_ascii_case(keyword) {
+                Some(*index)
+            } else {
+                None
+            }
+        })
+    };
+
+    if find("FROM") != Some(0) {
+        return Err("route must start with FROM");
+    }
+    let into = find("INTO").ok_or("route has no INTO clause")?;
+    let where_ = find("WHERE");
+
+    let source_end = match where_ {
+        Some(where_) if where_ > into => return Err("WHERE must come before INTO"),
+        Some(where_) => {
+            if route[where_ + "WHERE".len()..into].trim().is_empty() {
+                return Err("WHERE has no condition");
+            }
+            where_
+        }
+        None => into,
+    };
+
+    if !route["FROM".len()..source_end].trim().starts_with('/') {
+        return Err("source must be a message path like /messages/*");
+    }
+
+    let sink = route[into + "INTO".len()..].trim();
+    let is_endpoint = sink.len() > "BrokeredEndpoint()".len()
+        && sink
+            .get(.."BrokeredEndpoint(".len())
+            .map_or(false, |prefix| {
+                prefix.eq_ignore_ascii_case("BrokeredEndpoint(")
+            })
+        && sink.ends_with(')');
+    if !sink.eq_ignore_ascii_case("$upstream") && !is_endpoint {
+        return Err("sink must be $upstream or BrokeredEndpoint(\"...\")");
+    }
+
+    Ok(())
+}
+
+// Splits out the words of a route that are outside quotes and parentheses,
+// which is where its keywords are, along with their byte offsets.
+fn keywords(route: &str) -> Result<Vec<(usize, &str)>, &'static str> {
+    let mut keywords = vec![];
+    let mut quote = None;
+    let mut depth = 0_usize;
+    let mut word_start = None;
+
+    for (index, c) in route.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth = depth.checked_sub(1).ok_or("unbalanced parentheses")?,
+                _ => (),
+            },
+        }
+
+        let in_word = quote.is_none() && depth == 0 && c.is_ascii_alphabetic();
+        match (word_start, in_word) {
+            (None, true) if route[..index].ends_with(char::is_whitespace) || index == 0 => {
+                word_start = Some(index);
+            }
+            (Some(start), false) => {
+                if c.is_whitespace() {
+                    keywords.push((start, &route[start..index]));
+                }
+                word_start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(start) = word_start {
+        keywords.push((start, &route[start..]));
+    }
+
+    if quote.is_some() {
+        Err("unbalanced quotes")
+    } else if depth > 0 {
+        Err("unbalanced parentheses")
+    } else {
+        Ok(keywords)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EdgeDeviceClient;
+
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{DateTime, Utc};
+    use futures::{future, Future, Stream};
+    use hyper::{Body, Method, Request, Response};
+    use serde_json::json;
+    use url::Url;
+
+    use edgelet_http::client::{Client, TokenSource};
+
+    use crate::device::DeviceClient;
+    use crate::error::{Error, ErrorKind};
+    use crate::model::{AuthType, Module, Properties, Twin};
+
+    struct NullTokenSource;
+
+    impl TokenSource for NullTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok("token".to_string())
+        }
+    }
+
+    impl Clone for NullTokenSource {
+        fn clone(&self) -> Self {
+            NullTokenSource
+        }
+    }
+
+    #[test]
+    fn ensure_system_modules_creates_missing_modules() {
+        let created = Arc::new(Mutex::new(Vec::new()));
+
+        let created_copy = created.clone();
+        let handler = move |req: Request<Body>| {
+            if *req.method() == Method::GET {
+                assert_eq!("/devices/d1/modules", req.uri().path());
+                return Box::new(future::ok(Response::new(Body::from("[]"))))
+                    as Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+            }
+
+            assert_eq!(&Method::PUT, req.method());
+            created_copy
+                .lock()
+                .unwrap()
+                .push(req.uri().path().to_string());
+            Box::new(
+                req.into_body()
+                    .concat2()
+                    .map(|body| Response::new(Body::from(body))),
+            )
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let edge_client =
+            EdgeDeviceClient::new(DeviceClient::new(client, "d1".to_string()).unwrap());
+
+        let modules = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(edge_client.ensure_system_modules())
+            .unwrap();
+
+        assert_eq!(
+            vec![Some("$edgeAgent"), Some("$edgeHub")],
+            modules.iter().map(Module::module_id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                "/devices/d1/modules/$edgeAgent",
+                "/devices/d1/modules/$edgeHub"
+            ],
+            *created.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn set_deployment_requires_desired_properties() {
+        let client = Client::new(
+            |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+                panic!("An invalid deployment should not reach IoT Hub")
+            },
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let edge_client =
+            EdgeDeviceClient::new(DeviceClient::new(client, "d1".to_string()).unwrap());
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(
+                edge_client.set_deployment(&json!({ "$edgeAgent": { "schemaVersion": "1.0" } })),
+            )
+            .unwrap_err();
+        assert_eq!(&ErrorKind::InvalidDeployment, err.kind());
+    }
+
+    #[test]
+    fn update_edge_hub_twin_sends_valid_routes() {
+        let desired = json!({
+            "routes": {
+                "upstream": "FROM /messages/* WHERE $body.level = 'high' INTO $upstream",
+                "filter": {
+                    "route": "FROM /messages/modules/a/outputs/* INTO BrokeredEndpoint(\"/modules/b/inputs/in1\")",
+                    "priority": 1,
+                },
+            },
+        });
+
+        let expected = desired.clone();
+        let handler = move |req: Request<Body>| {
+            assert_eq!(&Method::PATCH, req.method());
+            assert_eq!("/twins/d1/modules/$edgeHub", req.uri().path());
+
+            let expected = expected.clone();
+            req.into_body().concat2().map(move |body| {
+                let patch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(expected, patch["properties"]["desired"]);
+
+                let twin = Twin::new("d1", 2, AuthType::Sas, Properties::new(expected))
+                    .with_module_id("$edgeHub".to_string());
+                Response::new(Body::from(serde_json::to_string(&twin).unwrap()))
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let edge_client =
+            EdgeDeviceClient::new(DeviceClient::new(client, "d1".to_string()).unwrap())
+                .with_route_validation(true);
+
+        let twin = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(edge_client.update_edge_hub_twin(desired, None))
+            .unwrap();
+        assert_eq!(Some("$edgeHub"), twin.module_id());
+    }
+
+    #[test]
+    fn update_edge_hub_twin_rejects_invalid_route() {
+        let client = Client::new(
+            |_req: Request<Body>| -> Result<Response<Body>, hyper::Error> {
+                panic!("An invalid route should not reach IoT Hub")
+            },
+            Some(NullTokenSource),
+            "2018-04-11".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let edge_client =
+            EdgeDeviceClient::new(DeviceClient::new(client, "d1".to_string()).unwrap())
+                .with_route_validation(true);
+
+        let desired = json!({
+            "routes": {
+                "upstream": "FROM /messages/* INTO $upstream",
+                "broken": "FROM /messages/* WHERE $body.level = 'high' $upstream",
+            },
+        });
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(edge_client.update_edge_hub_twin(desired, None))
+            .unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidRoute {
+                name: "broken".to_string(),
+                reason: "route has no INTO clause",
+            },
+            err.kind()
+        );
+    }
+}
@@ -2,10 +2,13 @@
 
 use std::fmt;
 use std::fmt::Display;
+use std::time::Duration;
 
 use failure::{Backtrace, Context, Fail};
 use hyper::StatusCode;
 
+use edgelet_http::Error as HttpError;
+
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
@@ -13,35 +16,191 @@ pub struct Error {
 
 #[derive(Debug, Fail, PartialEq)]
 pub enum ErrorKind {
+    #[fail(display = "Could not apply module operations")]
+    ApplyModuleOperations,
+
+    #[fail(display = "Could not apply module operations: {}", _0)]
+    ApplyModuleOperationsWithReason(ModuleOperationReason),
+
+    #[fail(
+        display = "Could not set the initial twin of module {}, so it was deleted again",
+        _0
+    )]
+    CreateModuleWithTwin(String),
+
+    #[fail(
+        display = "Could not set the initial twin of module {}, which was created but could not be deleted again",
+        _0
+    )]
+    CreateModuleWithTwinPartial(String),
+
+    #[fail(display = "Could not delete device")]
+    DeleteDevice,
+
+    #[fail(display = "Could not delete device: {}", _0)]
+    DeleteDeviceWithReason(ModuleOperationReason),
+
     #[fail(display = "Could not delete module")]
     DeleteModule,
 
     #[fail(display = "Could not delete module {}: {}", _0, _1)]
     DeleteModuleWithReason(String, ModuleOperationReason),
 
+    #[fail(display = "Could not export device")]
+    ExportDevice,
+
+    #[fail(display = "Could not export modules")]
+    ExportModules(Option<String>),
+
+    #[fail(display = "Could not get device")]
+    GetDevice,
+
+    #[fail(display = "Could not get device: {}", _0)]
+    GetDeviceWithReason(ModuleOperationReason),
+
     #[fail(display = "Could not get module {}", _0)]
     GetModule(String),
 
     #[fail(display = "Could not get module {}: {}", _0, _1)]
     GetModuleWithReason(String, ModuleOperationReason),
 
+    #[fail(display = "Could not get twin of module {}", _0)]
+    GetModuleTwin(String),
+
+    #[fail(display = "Could not get twin of module {}: {}", _0, _1)]
+    GetModuleTwinWithReason(String, ModuleOperationReason),
+
     #[fail(display = "IoT Hub service error: [{}] {}", _0, _1)]
     HubService(StatusCode, String),
 
+    #[fail(display = "Could not import device")]
+    ImportDevice,
+
+    #[fail(display = "Could not import device, which was created but could not be deleted again")]
+    ImportDevicePartial,
+
+    #[fail(display = "Invalid app metadata {:?}: {}", key, reason)]
+    InvalidAppMetadata { key: String, reason: &'static str },
+
+    #[fail(display = "Invalid authentication of module {}: {}", module_id, reason)]
+    InvalidAuth {
+        module_id: String,
+        reason: &'static str,
+    },
+
+    #[fail(display = "Invalid connection string: {}", reason)]
+    InvalidConnectionString { reason: &'static str },
+
+    #[fail(display = "Invalid continuation token {:?}: {}", continuation, reason)]
+    InvalidContinuation {
+        continuation: String,
+        reason: &'static str,
+    },
+
+    #[fail(display = "Deployment must give the `properties.desired` of every module it names")]
+    InvalidDeployment,
+
+    #[fail(display = "Invalid listing: {}", reason)]
+    InvalidListing { reason: &'static str },
+
+    #[fail(display = "Could not invoke method {} on module {}", _1, _0)]
+    InvokeModuleMethod(String, String),
+
+    #[fail(display = "Could not invoke method {} on module {}: {}", _1, _0, _2)]
+    InvokeModuleMethodWithReason(String, String, ModuleOperationReason),
+
     #[fail(display = "Invalid device ID {:?}", _0)]
     InvalidDeviceId(String),
 
+    #[fail(display = "Invalid symmetric key: {}", reason)]
+    InvalidKey { reason: &'static str },
+
+    #[fail(display = "Invalid module ID {:?}: {}", module_id, reason)]
+    InvalidModuleId {
+        module_id: String,
+        reason: &'static str,
+    },
+
+    #[fail(display = "Invalid route {}: {}", name, reason)]
+    InvalidRoute { name: String, reason: &'static str },
+
+    #[fail(display = "Could not list devices")]
+    ListDevices,
+
     #[fail(display = "Could not list modules")]
     ListModules,
 
     #[fail(display = "Could not list modules: {}", _0)]
     ListModulesWithReason(ModuleOperationReason),
 
+    #[fail(display = "Device {} has no device scope", _0)]
+    MissingDeviceScope(String),
+
+    #[fail(display = "IoT Hub response is missing the required field {}", field)]
+    MissingField { field: &'static str },
+
+    #[fail(display = "Could not purge cloud-to-device messages of module {}", _0)]
+    PurgeModuleMessages(String),
+
+    #[fail(
+        display = "Could not purge cloud-to-device messages of module {}: {}",
+        _0, _1
+    )]
+    PurgeModuleMessagesWithReason(String, ModuleOperationReason),
+
+    #[fail(display = "Could not run query")]
+    Query,
+
+    #[fail(display = "Could not deserialize query result row")]
+    QueryRow,
+
+    #[fail(display = "Could not replace twin of module {}", _0)]
+    ReplaceModuleTwin(String),
+
+    #[fail(display = "Could not replace twin of module {}: {}", _0, _1)]
+    ReplaceModuleTwinWithReason(String, ModuleOperationReason),
+
+    #[fail(display = "Module ID {} is reserved for IoT Edge system modules", _0)]
+    ReservedModuleId(String),
+
+    #[fail(display = "Could not serialize request body")]
+    Serialization,
+
+    #[fail(display = "Could not set deployment")]
+    SetDeployment,
+
+    #[fail(
+        display = "Timed out waiting for module {} to report version {}",
+        _0, _1
+    )]
+    Timeout(String, i64),
+
+    #[fail(
+        display = "Twin patch of module {} is {} bytes, over IoT Hub's limit",
+        _0, _1
+    )]
+    TwinPatchTooLarge(String, usize),
+
+    #[fail(display = "Could not update twin of module {}", _0)]
+    UpdateModuleTwin(String),
+
+    #[fail(display = "Could not upsert device")]
+    UpsertDevice,
+
     #[fail(display = "Could not upsert module {}", _0)]
     UpsertModule(String),
 
     #[fail(display = "Could not upsert module {}: {}", _0, _1)]
     UpsertModuleWithReason(String, ModuleOperationReason),
+
+    #[fail(
+        display = "Twin of module {} was updated concurrently: expected version {} but found {}",
+        _0, _1, _2
+    )]
+    VersionConflict(String, i32, i32),
+
+    #[fail(display = "Could not write module export")]
+    WriteModuleExport(Option<String>),
 }
 
 impl Fail for Error {
@@ -64,6 +223,50 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
     }
+
+    /// The error of the request to IoT Hub this error was caused by, if any.
+    pub fn http_error(&self) -> Option<&HttpError> {
+        Fail::iter_chain(self).find_map(|cause| cause.downcast_ref::<HttpError>())
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        match self.kind() {
+            ErrorKind::DeleteModuleWithReason(_, ModuleOperationReason::ModuleNotFound)
+            | ErrorKind::GetDeviceWithReason(ModuleOperationReason::ModuleNotFound)
+            | ErrorKind::GetModuleWithReason(_, ModuleOperationReason::ModuleNotFound)
+            | ErrorKind::GetModuleTwinWithReason(_, ModuleOperationReason::ModuleNotFound) => true,
+            _ => self.http_error().map_or(false, HttpError::is_not_found),
+        }
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.http_error().map_or(false, HttpError::is_throttled)
+    }
+
+    /// Whether the operation conflicted with the current state in IoT Hub,
+    /// like a module that already exists or an ETag that no longer matches.
+    pub fn is_conflict(&self) -> bool {
+        match self.kind() {
+            ErrorKind::VersionConflict(..) => true,
+            _ => self.http_error().map_or(false, HttpError::is_conflict),
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Timeout(..) => true,
+            _ => self.http_error().map_or(false, HttpError::is_timeout),
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.http_error().and_then(HttpError::retry_after)
+    }
+
+    /// The ID IoT Hub gave the failed request, to quote to Azure support.
+    pub fn request_id(&self) -> Option<&str> {
+        self.http_error().and_then(HttpError::request_id)
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -80,22 +283,175 @@ impl From<Context<ErrorKind>> for Error {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// The error of a paginated listing that failed part way through. It holds
+/// the items fetched before the failure and the continuation token of the page
+/// that failed, which can be passed back in to resume the listing.
+#[derive(Debug)]
+pub struct PartialListError<T> {
+    items: Vec<T>,
+    continuation: Option<String>,
+    error: Error,
+}
+
+impl<T> PartialListError<T> {
+    pub fn new(items: Vec<T>, continuation: Option<String>, error: Error) -> Self {
+        PartialListError {
+            items,
+            continuation,
+            error,
+        }
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    pub fn continuation(&self) -> Option<&str> {
+        self.continuation.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    pub fn into_parts(self) -> (Vec<T>, Option<String>, Error) {
+        (self.items, self.continuation, self.error)
+    }
+}
+
+impl<T> Fail for PartialListError<T>
+where
+    T: fmt::Debug + Send + Sync + 'static,
+{
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(&self.error)
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.error.backtrace()
+    }
+}
+
+impl<T> Display for PartialListError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Listing failed after {} item(s): {}",
+            self.items.len(),
+            self.error
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ModuleOperationReason {
     EmptyModuleId,
-    EmptyResponse,
+    EmptyResponse {
+        operation: &'static str,
+        path: String,
+    },
+    MissingEtag,
+    MissingSymmetricKey,
     ModuleNotFound,
+    NotSasAuthenticated,
+    RegistryOperationFailed {
+        error_code: String,
+        error_status: String,
+    },
 }
 
 impl Display for ModuleOperationReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ModuleOperationReason::EmptyModuleId => write!(f, "Module ID is empty"),
-            ModuleOperationReason::EmptyResponse => write!(
+            ModuleOperationReason::EmptyResponse { operation, path } => write!(
                 f,
-                "IoT Hub returned an empty response when a value was expected"
+                "IoT Hub returned an empty response to {} ({}) when a value was expected",
+                operation, path
             ),
+            ModuleOperationReason::MissingEtag => {
+                write!(f, "Module has no ETag to make the operation conditional on")
+            }
+            ModuleOperationReason::MissingSymmetricKey => {
+                write!(f, "IoT Hub returned the module without its symmetric keys")
+            }
             ModuleOperationReason::ModuleNotFound => write!(f, "Module not found"),
+            ModuleOperationReason::NotSasAuthenticated => write!(
+                f,
+                "Module doesn't use SAS authentication, so it has no keys to rotate"
+            ),
+            ModuleOperationReason::RegistryOperationFailed {
+                error_code,
+                error_status,
+            } => write!(
+                f,
+                "IoT Hub failed the registry operation with error {}: {}",
+                error_code, error_status
+            ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use failure::Fail;
+    use hyper::StatusCode;
+
+    use edgelet_http::{Error as HttpError, ErrorKind as HttpErrorKind};
+
+    use super::{Error, ErrorKind, ModuleOperationReason};
+
+    fn service_error(status_code: StatusCode) -> Error {
+        Error::from(
+            HttpError::http_with_error_response(status_code, &b""[..])
+                .context(ErrorKind::GetModule("m1".to_string())),
+        )
+    }
+
+    #[test]
+    fn not_found_is_recognized() {
+        assert!(service_error(StatusCode::NOT_FOUND).is_not_found());
+        assert!(Error::from(ErrorKind::GetModuleWithReason(
+            "m1".to_string(),
+            ModuleOperationReason::ModuleNotFound
+        ))
+        .is_not_found());
+        assert!(!service_error(StatusCode::BAD_REQUEST).is_not_found());
+    }
+
+    #[test]
+    fn throttled_is_recognized() {
+        assert!(service_error(StatusCode::TOO_MANY_REQUESTS).is_throttled());
+        assert!(!service_error(StatusCode::SERVICE_UNAVAILABLE).is_throttled());
+    }
+
+    #[test]
+    fn conflict_is_recognized() {
+        assert!(service_error(StatusCode::CONFLICT).is_conflict());
+        assert!(service_error(StatusCode::PRECONDITION_FAILED).is_conflict());
+        assert!(Error::from(ErrorKind::VersionConflict("m1".to_string(), 4, 7)).is_conflict());
+        assert!(!service_error(StatusCode::NOT_FOUND).is_conflict());
+    }
+
+    #[test]
+    fn timeout_is_recognized() {
+        assert!(service_error(StatusCode::GATEWAY_TIMEOUT).is_timeout());
+        assert!(!service_error(StatusCode::BAD_GATEWAY).is_timeout());
+        assert!(Error::from(ErrorKind::Timeout("m1".to_string(), 4)).is_timeout());
+    }
+
+    #[test]
+    fn errors_without_response_match_no_predicate() {
+        let err = Error::from(HttpError::from(HttpErrorKind::Http).context(ErrorKind::ListModules));
+        assert!(err.http_error().is_some());
+        assert!(!err.is_not_found());
+        assert!(!err.is_throttled());
+        assert!(!err.is_conflict());
+        assert!(!err.is_timeout());
+        assert_eq!(None::<Duration>, err.retry_after());
+
+        assert!(Error::from(ErrorKind::ListModules).http_error().is_none());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::marker::PhantomData;
+
+use failure::{Fail, ResultExt};
+use futures::{Async, Poll, Stream};
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // before the `[` opening the array
+    Start,
+    // inside the array, between or within elements
+    Elements,
+    // past the `]` closing the array
+    Done,
+}
+
+/// Parses the elements of a JSON array out of a body as its chunks arrive,
+/// yielding each as soon as it is complete. Only the element being parsed
+/// and the chunk being scanned are held in memory, rather than the whole
+/// array.
+pub(crate) struct JsonArrayStream<S, T> {
+    body: S,
+    chunk: Vec<u8>,
+    pos: usize,
+    element: Vec<u8>,
+    state: State,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    item: PhantomData<T>,
+}
+
+impl<S, T> JsonArrayStream<S, T>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    S::Error: Fail,
+    T: DeserializeOwned,
+{
+    pub(crate) fn new(body: S) -> Self {
+        JsonArrayStream {
+            body,
+            chunk: Vec::new(),
+            pos: 0,
+            element: Vec::new(),
+            state: State::Start,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            item: PhantomData,
+        }
+    }
+
+    // Scans the rest of the current chunk up to the end of the next element,
+    // and returns the element if there is one.
+    fn scan(&mut self) -> Result<Option<T>, Error> {
+        while self.pos < self.chunk.len() {
+            let b = self.chunk[self.pos];
+            self.pos += 1;
+
+            match self.state {
+                State::Start if b.is_ascii_whitespace() => (),
+                State::Start if b == b'[' => self.state = State::Elements,
+                State::Start => return Err(invalid("body is not a JSON array")),
+                State::Done if b.is_ascii_whitespace() => (),
+                State::Done => return Err(invalid("array is followed by trailing data")),
+                State::Elements => {
+                    if let Some(element) = self.scan_element(b)? {
+                        return Ok(Some(element));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan_element(&mut self, b: u8) -> Result<Option<T>, Error> {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if b == b'\\' {
+                self.escaped = true;
+            } else if b == b'"' {
+                self.in_string = false;
+            }
+            self.element.push(b);
+            return Ok(None);
+        }
+
+        match b {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' if self.depth > 0 => self.depth -= 1,
+            b']' => {
+                self.state = State::Done;
+                return self.take_element(true);
+            }
+            b',' if self.depth == 0 => return self.take_element(false),
+            _ => (),
+        }
+        self.element.push(b);
+        Ok(None)
+    }
+
+    // Parses the element read so far. Only the last element of an array, or
+    // the only element of an empty one, may be missing.
+    fn take_element(&mut self, last: bool) -> Result<Option<T>, Error> {
+        if self.element.iter().all(u8::is_ascii_whitespace) {
+            self.element.clear();
+            return if last {
+                Ok(None)
+            } else {
+                Err(invalid("array has an empty element"))
+            };
+        }
+
+        let element = serde_json::from_slice(&self.element).context(ErrorKind::InvalidListing {
+            reason: "element could not be deserialized",
+        })?;
+        self.element.clear();
+        Ok(Some(element))
+    }
+}
+
+impl<S, T> Stream for JsonArrayStream<S, T>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    S::Error: Fail,
+    T: DeserializeOwned,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        loop {
+            if let Some(element) = self.scan()? {
+                return Ok(Async::Ready(Some(element)));
+            }
+
+            let chunk = match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => chunk,
+                Ok(Async::Ready(None)) if self.state == State::Done => {
+                    return Ok(Async::Ready(None))
+                }
+                Ok(Async::Ready(None)) => return Err(invalid("array is truncated")),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    return Err(Error::from(err.context(ErrorKind::InvalidListing {
+                        reason: "body could not be read",
+                    })))
+                }
+            };
+            self.chunk.clear();
+            self.chunk.extend_from_slice(chunk.as_ref());
+            self.pos = 0;
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> Error {
+    Error::from(ErrorKind::InvalidListing { reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonArrayStream;
+
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::sync::mpsc;
+    use futures::{stream, Future, Sink, Stream};
+    use serde_json::{json, Value};
+
+    use crate::error::{Error, ErrorKind};
+
+    fn parse(chunks: Vec<&'static str>) -> Result<Vec<Value>, Error> {
+        JsonArrayStream::new(stream::iter_ok::<_, io::Error>(chunks))
+            .collect()
+            .wait()
+    }
+
+    #[test]
+    fn elements_split_across_chunks_are_parsed() {
+        assert_eq!(
+            vec![
+                json!({ "moduleId": "m,1", "tags": ["]", "\"{"] }),
+                json!({ "moduleId": "m2" }),
+                json!(3),
+            ],
+            parse(vec![
+                " [ {\"moduleId\": \"m,",
+                "1\", \"tags\": [\"]\", \"\\\"{\"]}",
+                ", {\"moduleId\"",
+                ": \"m2\"},3 ] ",
+            ])
+            .unwrap()
+        );
+        assert!(parse(vec!["[", " ]"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_arrays_fail() {
+        assert_eq!(
+            &ErrorKind::InvalidListing {
+                reason: "body is not a JSON array"
+            },
+            parse(vec!["{}"]).unwrap_err().kind()
+        );
+        assert_eq!(
+            &ErrorKind::InvalidListing {
+                reason: "array is truncated"
+            },
+            parse(vec!["[{\"a\": 1}, {"]).unwrap_err().kind()
+        );
+        assert_eq!(
+            &ErrorKind::InvalidListing {
+                reason: "array has an empty element"
+            },
+            parse(vec!["[1,,2]"]).unwrap_err().kind()
+        );
+        assert_eq!(
+            &ErrorKind::InvalidListing {
+                reason: "array is followed by trailing data"
+            },
+            parse(vec!["[1, 2] ", " ,3]"]).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn large_array_is_parsed_incrementally() {
+        const MODULES: usize = 1000;
+
+        // the body arrives through a channel holding a single chunk, so the
+        // sender is only a few chunks ahead of what has been parsed
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>(1);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let chunks = {
+            let sent = sent.clone();
+            (0..=MODULES).map(move |i| {
+                sent.fetch_add(1, Ordering::SeqCst);
+                let chunk = match i {
+                    0 => "[".to_string(),
+                    i if i == MODULES => format!("{{\"moduleId\": \"m{}\"}}]", i),
+                    i => format!("{{\"moduleId\": \"m{}\"}},", i),
+                };
+                Ok::<_, ()>(chunk.into_bytes())
+            })
+        };
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.spawn(
+            sender
+                .sink_map_err(|_| ())
+                .send_all(stream::iter_result(chunks))
+                .map(|_| ()),
+        );
+
+        let modules = JsonArrayStream::<_, Value>::new(
+            receiver.map_err(|()| io::Error::from(io::ErrorKind::UnexpectedEof)),
+        );
+        let (first, rest) = runtime
+            .block_on(modules.into_future().map_err(|(err, _)| err))
+            .unwrap();
+        assert_eq!(Some(json!({ "moduleId": "m1" })), first);
+        assert!(sent.load(Ordering::SeqCst) < 10);
+
+        let rest = runtime.block_on(rest.collect()).unwrap();
+        assert_eq!(MODULES - 1, rest.len());
+        assert_eq!(json!({ "moduleId": "m1000" }), rest[MODULES - 2]);
+        assert_eq!(MODULES + 1, sent.load(Ordering::SeqCst));
+    }
+}
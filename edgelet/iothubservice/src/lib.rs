@@ -3,6 +3,7 @@
 #![deny(rust_2018_idioms, warnings)]
 #![deny(clippy::all, clippy::pedantic)]
 #![allow(
+    clippy::doc_markdown, // clippy wants the "IoT" of "IoT Hub" in a code fence
     clippy::missing_errors_doc,
     clippy::module_name_repetitions,
     clippy::must_use_candidate,
@@ -10,12 +11,30 @@
     clippy::use_self
 )]
 
+mod cache;
+mod coalesce;
 mod device;
+mod edge;
 pub mod error;
+mod json_stream;
+mod metadata;
 mod model;
+mod paginator;
+mod registry;
+mod sas;
 
-pub use crate::device::DeviceClient;
-pub use crate::error::{Error, ErrorKind, ModuleOperationReason};
+pub use crate::device::{
+    DeletedModules, DeviceClient, EnsureOutcome, EtagFallback, ModuleIdMatching, SizeEstimate,
+};
+pub use crate::edge::EdgeDeviceClient;
+pub use crate::error::{Error, ErrorKind, ModuleOperationReason, PartialListError};
+pub use crate::metadata::AppMetadata;
 pub use crate::model::{
-    AuthMechanism, AuthType, Module, Properties, SymmetricKey, Twin, X509Thumbprint,
+    AuthMechanism, AuthType, BulkRegistryOperationResult, Device, DeviceExport, ImportMode,
+    MethodResult, MethodTimeouts, Module, ModuleOperation, Properties, PurgeMessageQueueResult,
+    RegistryOperationError, SymmetricKey, Timestamp, Twin, TwinChange, TwinDiff, TwinMetadata,
+    X509Thumbprint,
 };
+pub use crate::paginator::{Page, Paginator};
+pub use crate::registry::RegistryClient;
+pub use crate::sas::resource_uri;
@@ -0,0 +1,159 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::collections::BTreeMap;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::error::{Error, ErrorKind};
+
+const APP_METADATA_HEADER_PREFIX: &str = "iothub-app-";
+const MAX_APP_METADATA_KEY_LENGTH: usize = 64;
+const MAX_APP_METADATA_VALUE_LENGTH: usize = 1024;
+
+/// Application metadata sent with a module operation as `iothub-app-<key>`
+/// headers, which IoT Hub echoes back in its response. Keys are ASCII
+/// letters, digits, `-` and `_`, and are case-insensitive like the headers
+/// carrying them, so they are kept lowercased.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AppMetadata {
+    entries: BTreeMap<String, String>,
+}
+
+impl AppMetadata {
+    pub fn new() -> Self {
+        AppMetadata::default()
+    }
+
+    /// Adds an entry, replacing any with the same key. Fails with
+    /// `ErrorKind::InvalidAppMetadata` if the key is empty, longer than 64
+    /// characters or has other characters than those allowed, or if the
+    /// value is longer than 1024 bytes or isn't printable ASCII.
+    pub fn with_entry(mut self, key: &str, value: &str) -> Result<Self, Error> {
+        validate_entry(key, value)?;
+        self.entries
+            .insert(key.to_ascii_lowercase(), value.to_string());
+        Ok(self)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .get(&key.to_ascii_lowercase())
+            .map(AsRef::as_ref)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn to_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (key, value) in &self.entries {
+            // entries are validated as they are added, so they always make
+            // valid headers
+            let name =
+                HeaderName::from_bytes(format!("{}{}", APP_METADATA_HEADER_PREFIX, key).as_bytes())
+                    .expect("app metadata key is a valid header name");
+            let value = HeaderValue::from_str(value).expect("app metadata value is a valid header");
+            headers.insert(name, value);
+        }
+        headers
+    }
+
+    /// The metadata in the `iothub-app-*` headers of a response. Headers
+    /// that don't make valid entries are skipped.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let entries = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix(APP_METADATA_HEADER_PREFIX)?;
+                let value = value.to_str().ok()?;
+                validate_entry(key, value).ok()?;
+                Some((key.to_string(), value.to_string()))
+            })
+            .collect();
+        AppMetadata { entries }
+    }
+}
+
+fn validate_entry(key: &str, value: &str) -> Result<(), Error> {
+    let reason = if key.is_empty() {
+        "key is empty"
+    } else if key.len() > MAX_APP_METADATA_KEY_LENGTH {
+        "key is longer than 64 characters"
+    } else if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        "key may only contain ASCII letters, digits, - and _"
+    } else if value.len() > MAX_APP_METADATA_VALUE_LENGTH {
+        "value is longer than 1024 bytes"
+    } else if value.chars().any(|c| c != ' ' && !c.is_ascii_graphic()) {
+        "value may only contain printable ASCII characters"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::from(ErrorKind::InvalidAppMetadata {
+        key: key.to_string(),
+        reason,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AppMetadata;
+
+    use hyper::header::{HeaderMap, HeaderValue};
+
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn entries_round_trip_through_headers() {
+        let metadata = AppMetadata::new()
+            .with_entry("Deployment-Id", "d-42")
+            .unwrap();
+        let headers = metadata.to_headers();
+        assert_eq!("d-42", headers["iothub-app-deployment-id"]);
+
+        let mut headers = headers;
+        headers.insert("etag", HeaderValue::from_static("\"AAAA\""));
+        let echoed = AppMetadata::from_headers(&headers);
+        assert_eq!(metadata, echoed);
+        assert_eq!(Some("d-42"), echoed.get("deployment-id"));
+    }
+
+    #[test]
+    fn invalid_entries_fail() {
+        let long_key = "k".repeat(65);
+        let long_value = "v".repeat(1025);
+        for (key, value) in &[
+            ("", "v"),
+            (long_key.as_str(), "v"),
+            ("a.b", "v"),
+            ("k", long_value.as_str()),
+            ("k", "line\nbreak"),
+        ] {
+            let err = AppMetadata::new().with_entry(key, value).unwrap_err();
+            match err.kind() {
+                ErrorKind::InvalidAppMetadata { key: invalid, .. } => {
+                    assert_eq!(*key, invalid.as_str())
+                }
+                kind => panic!("unexpected error kind {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_headers_are_skipped() {
+        let mut headers = HeaderMap::new();
+        headers.insert("iothub-app-a.b", HeaderValue::from_static("v"));
+        headers.insert("iothub-app-", HeaderValue::from_static("v"));
+        assert!(AppMetadata::from_headers(&headers).is_empty());
+    }
+}
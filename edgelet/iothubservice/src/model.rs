@@ -1,10 +1,36 @@
 // Copyright (c) Microsoft. All rights reserved.
 
+use std::collections::BTreeSet;
 use std::default::Default;
+use std::fmt;
+use std::time::Duration;
 
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::error::{Error, ErrorKind};
+
+// Twin properties IoT Hub maintains itself, which `Twin::diff` leaves out.
+const TWIN_METADATA_KEYS: &[&str] = &["$metadata", "$version"];
+
+/// A timestamp reported by IoT Hub.
+#[cfg(feature = "chrono")]
+pub type Timestamp = DateTime<Utc>;
+
+/// A timestamp reported by IoT Hub, kept as the string the hub sent since the
+/// `chrono` feature is off.
+#[cfg(not(feature = "chrono"))]
+pub type Timestamp = String;
+
+// The property recording when the properties beside it were last updated.
+const METADATA_PROPERTY: &str = "$metadata";
+
+// The number of bytes IoT Hub accepts in a symmetric key once decoded.
+const MIN_SYMMETRIC_KEY_LENGTH: usize = 16;
+const MAX_SYMMETRIC_KEY_LENGTH: usize = 64;
+
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum AuthType {
@@ -13,14 +39,29 @@ pub enum AuthType {
     X509,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+impl fmt::Display for AuthType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let auth_type = match self {
+            AuthType::None => "none",
+            AuthType::Sas => "sas",
+            AuthType::X509 => "x509",
+        };
+        write!(f, "{}", auth_type)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Twin {
     device_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     module_id: Option<String>,
+    #[serde(alias = "eTag", skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
     version: i32,
     authentication_type: AuthType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Value>,
     properties: Properties,
 }
 
@@ -34,8 +75,10 @@ impl Twin {
         Twin {
             device_id: device_id.to_string(),
             module_id: None,
+            etag: None,
             version,
             authentication_type,
+            tags: None,
             properties,
         }
     }
@@ -50,6 +93,11 @@ impl Twin {
         self
     }
 
+    pub fn with_etag(mut self, etag: String) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
     pub fn with_version(mut self, version: i32) -> Self {
         self.version = version;
         self
@@ -60,6 +108,11 @@ impl Twin {
         self
     }
 
+    pub fn with_tags(mut self, tags: Value) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
     pub fn with_properties(mut self, properties: Properties) -> Self {
         self.properties = properties;
         self
@@ -73,6 +126,10 @@ impl Twin {
         self.module_id.as_ref().map(AsRef::as_ref)
     }
 
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(AsRef::as_ref)
+    }
+
     pub fn version(&self) -> &i32 {
         &self.version
     }
@@ -81,27 +138,191 @@ impl Twin {
         &self.authentication_type
     }
 
+    pub fn tags(&self) -> Option<&Value> {
+        self.tags.as_ref()
+    }
+
     pub fn properties(&self) -> &Properties {
         &self.properties
     }
+
+    /// Compares this twin with a later snapshot of it, walking its tags and
+    /// desired and reported properties. Changes are keyed by the dotted path
+    /// of the property, like `properties.desired.routes.upstream`, and only
+    /// objects are walked into, so a changed array is a single change.
+    pub fn diff(&self, other: &Twin) -> TwinDiff {
+        let mut changes = vec![];
+        diff_values("tags", self.tags(), other.tags(), &mut changes);
+        diff_values(
+            "properties.desired",
+            Some(self.properties.desired()),
+            Some(other.properties.desired()),
+            &mut changes,
+        );
+        diff_values(
+            "properties.reported",
+            self.properties.reported(),
+            other.properties.reported(),
+            &mut changes,
+        );
+        TwinDiff { changes }
+    }
+
+    /// The twin without the `$metadata` IoT Hub adds to its desired and
+    /// reported properties, recording when each property was last updated.
+    pub fn without_metadata(mut self) -> Self {
+        remove_metadata(&mut self.properties.desired);
+        if let Some(ref mut reported) = self.properties.reported {
+            remove_metadata(reported);
+        }
+        self
+    }
+}
+
+/// How much of a twin `DeviceClient::get_module_twin_with_metadata` keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TwinMetadata {
+    Full,
+    /// Leaves out the `$metadata` of the twin's properties.
+    Minimal,
+}
+
+fn remove_metadata(properties: &mut Value) {
+    if let Value::Object(properties) = properties {
+        properties.remove(METADATA_PROPERTY);
+        properties.values_mut().for_each(remove_metadata);
+    }
+}
+
+/// A one line summary of the twin, leaving out its properties.
+impl fmt::Display for Twin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.device_id)?;
+        if let Some(ref module_id) = self.module_id {
+            write!(f, "/{}", module_id)?;
+        }
+        write!(
+            f,
+            " version={} auth={}",
+            self.version, self.authentication_type
+        )
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Properties {
     desired: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reported: Option<Value>,
 }
 
 impl Properties {
     pub fn new(desired: Value) -> Properties {
-        Properties { desired }
+        Properties {
+            desired,
+            reported: None,
+        }
+    }
+
+    pub fn with_reported(mut self, reported: Value) -> Self {
+        self.reported = Some(reported);
+        self
     }
 
     pub fn desired(&self) -> &Value {
         &self.desired
     }
+
+    pub fn reported(&self) -> Option<&Value> {
+        self.reported.as_ref()
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+/// The differences between two snapshots of a twin, as found by
+/// `Twin::diff`, ordered by path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TwinDiff {
+    changes: Vec<TwinChange>,
+}
+
+impl TwinDiff {
+    pub fn changes(&self) -> &[TwinChange] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TwinChange {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl TwinChange {
+    pub fn path(&self) -> &str {
+        match self {
+            TwinChange::Added { path, .. }
+            | TwinChange::Removed { path, .. }
+            | TwinChange::Changed { path, .. } => path,
+        }
+    }
+}
+
+fn diff_values(
+    path: &str,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    changes: &mut Vec<TwinChange>,
+) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old
+                .keys()
+                .chain(new.keys())
+                .filter(|key| !TWIN_METADATA_KEYS.contains(&key.as_str()))
+                .collect();
+            for key in keys {
+                diff_values(
+                    &format!("{}.{}", path, key),
+                    old.get(key),
+                    new.get(key),
+                    changes,
+                );
+            }
+        }
+        (Some(old), Some(new)) if old == new => (),
+        (Some(old), Some(new)) => changes.push(TwinChange::Changed {
+            path: path.to_string(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        (None, Some(new)) => changes.push(TwinChange::Added {
+            path: path.to_string(),
+            value: new.clone(),
+        }),
+        (Some(old), None) => changes.push(TwinChange::Removed {
+            path: path.to_string(),
+            value: old.clone(),
+        }),
+        (None, None) => (),
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct X509Thumbprint {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -143,7 +364,16 @@ impl Default for X509Thumbprint {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+impl fmt::Debug for X509Thumbprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("X509Thumbprint")
+            .field("primary_thumbprint", &redact(&self.primary_thumbprint))
+            .field("secondary_thumbprint", &redact(&self.secondary_thumbprint))
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SymmetricKey {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -177,6 +407,39 @@ impl SymmetricKey {
     pub fn secondary_key(&self) -> Option<&str> {
         self.secondary_key.as_ref().map(AsRef::as_ref)
     }
+
+    /// Checks that the keys given are base64 of 16 to 64 bytes, as IoT Hub
+    /// requires, failing with `ErrorKind::InvalidKey` otherwise.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        validate_key(
+            self.primary_key(),
+            "primary key is not base64",
+            "primary key must be 16 to 64 bytes",
+        )?;
+        validate_key(
+            self.secondary_key(),
+            "secondary key is not base64",
+            "secondary key must be 16 to 64 bytes",
+        )
+    }
+}
+
+fn validate_key(
+    key: Option<&str>,
+    not_base64: &'static str,
+    wrong_length: &'static str,
+) -> Result<(), Error> {
+    let reason = match key.map(base64::decode) {
+        None => return Ok(()),
+        Some(Ok(ref bytes))
+            if (MIN_SYMMETRIC_KEY_LENGTH..=MAX_SYMMETRIC_KEY_LENGTH).contains(&bytes.len()) =>
+        {
+            return Ok(())
+        }
+        Some(Ok(_)) => wrong_length,
+        Some(Err(_)) => not_base64,
+    };
+    Err(Error::from(ErrorKind::InvalidKey { reason }))
 }
 
 impl Default for SymmetricKey {
@@ -185,6 +448,41 @@ impl Default for SymmetricKey {
     }
 }
 
+impl fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("primary_key", &redact(&self.primary_key))
+            .field("secondary_key", &redact(&self.secondary_key))
+            .finish()
+    }
+}
+
+// Keys and thumbprints are credentials, so only whether they are set is
+// shown when they're formatted for logs.
+fn redact(value: &Option<String>) -> Option<&'static str> {
+    value.as_ref().map(|_| "***")
+}
+
+// Shows a field missing from a model summary as `-`.
+struct OrDash<'a, T: ?Sized>(Option<&'a T>);
+
+impl<'a, T> fmt::Display for OrDash<'a, T>
+where
+    T: fmt::Display + ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) => fmt::Display::fmt(value, f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+// Only the authentication type is shown, never the keys or thumbprints.
+fn auth_type(authentication: Option<&AuthMechanism>) -> Option<AuthType> {
+    authentication.and_then(AuthMechanism::type_)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthMechanism {
@@ -207,6 +505,36 @@ impl AuthMechanism {
         }
     }
 
+    /// SAS authentication with the `SharedAccessKey` of a device or module
+    /// connection string as its primary key. The other parts of the
+    /// connection string are ignored. Fails with
+    /// `ErrorKind::InvalidConnectionString` if a part isn't a `name=value`
+    /// pair, or if the key is missing, empty or not base64.
+    pub fn from_connection_string(connection_string: &str) -> Result<Self, Error> {
+        let mut key = None;
+        for part in connection_string.split(';').filter(|part| !part.is_empty()) {
+            // base64 keys can end with `=` padding, so only split on the first
+            let mut pair = part.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some("SharedAccessKey"), Some(value)) => key = Some(value),
+                (Some(_), Some(_)) => (),
+                _ => return Err(invalid_connection_string("part is not a name=value pair")),
+            }
+        }
+
+        let key = match key {
+            None => return Err(invalid_connection_string("SharedAccessKey is missing")),
+            Some("") => return Err(invalid_connection_string("SharedAccessKey is empty")),
+            Some(key) => key,
+        };
+        base64::decode(key)
+            .map_err(|_| invalid_connection_string("SharedAccessKey is not base64"))?;
+
+        Ok(AuthMechanism::new()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(SymmetricKey::new().with_primary_key(key.to_string())))
+    }
+
     pub fn with_symmetric_key(mut self, symmetric_key: SymmetricKey) -> Self {
         self.symmetric_key = Some(symmetric_key);
         self
@@ -233,6 +561,33 @@ impl AuthMechanism {
     pub fn type_(&self) -> Option<AuthType> {
         self.type_
     }
+
+    // IoT Hub sends both a symmetric key and X.509 thumbprints whatever the
+    // authentication type, with only those the type uses populated.
+    fn inconsistency(&self) -> Option<&'static str> {
+        let has_keys = self.symmetric_key.as_ref().map_or(false, |key| {
+            key.primary_key.is_some() || key.secondary_key.is_some()
+        });
+        let has_thumbprints = self.x509_thumbprint.as_ref().map_or(false, |thumbprint| {
+            thumbprint.primary_thumbprint.is_some() || thumbprint.secondary_thumbprint.is_some()
+        });
+
+        match self.type_ {
+            Some(AuthType::None) if has_keys || has_thumbprints => {
+                Some("authentication type is none but it has key material")
+            }
+            Some(AuthType::Sas) if has_thumbprints => {
+                Some("authentication type is sas but it has X.509 thumbprints")
+            }
+            Some(AuthType::X509) if has_keys => {
+                Some("authentication type is x509 but it has symmetric keys")
+            }
+            None if has_keys && has_thumbprints => {
+                Some("authentication has both symmetric keys and X.509 thumbprints")
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Default for AuthMechanism {
@@ -241,8 +596,13 @@ impl Default for AuthMechanism {
     }
 }
 
+fn invalid_connection_string(reason: &'static str) -> Error {
+    // the connection string holds a key, so it isn't kept in the error
+    Error::from(ErrorKind::InvalidConnectionString { reason })
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", from = "ModuleRepr")]
 pub struct Module {
     #[serde(skip_serializing_if = "Option::is_none")]
     module_id: Option<String>,
@@ -253,6 +613,22 @@ pub struct Module {
     #[serde(skip_serializing_if = "Option::is_none")]
     generation_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_state: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp")]
+    connection_state_updated_time: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cloud_to_device_message_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp")]
+    last_activity_time: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp")]
+    status_updated_time: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     authentication: Option<AuthMechanism>,
 }
 
@@ -263,6 +639,14 @@ impl Module {
             managed_by: None,
             device_id: None,
             generation_id: None,
+            etag: None,
+            connection_state: None,
+            connection_state_updated_time: None,
+            cloud_to_device_message_count: None,
+            last_activity_time: None,
+            status: None,
+            status_reason: None,
+            status_updated_time: None,
             authentication: None,
         }
     }
@@ -303,6 +687,83 @@ impl Module {
         self.generation_id.as_ref().map(AsRef::as_ref)
     }
 
+    pub fn with_etag(mut self, etag: String) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_connection_state(mut self, connection_state: String) -> Self {
+        self.connection_state = Some(connection_state);
+        self
+    }
+
+    pub fn connection_state(&self) -> Option<&str> {
+        self.connection_state.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_connection_state_updated_time(
+        mut self,
+        connection_state_updated_time: Timestamp,
+    ) -> Self {
+        self.connection_state_updated_time = Some(connection_state_updated_time);
+        self
+    }
+
+    pub fn connection_state_updated_time(&self) -> Option<&Timestamp> {
+        self.connection_state_updated_time.as_ref()
+    }
+
+    pub fn with_cloud_to_device_message_count(mut self, count: usize) -> Self {
+        self.cloud_to_device_message_count = Some(count);
+        self
+    }
+
+    /// The number of cloud-to-device messages queued for the module, which is
+    /// zero if the hub didn't report it.
+    pub fn cloud_to_device_message_count(&self) -> usize {
+        self.cloud_to_device_message_count.unwrap_or_default()
+    }
+
+    pub fn with_last_activity_time(mut self, last_activity_time: Timestamp) -> Self {
+        self.last_activity_time = Some(last_activity_time);
+        self
+    }
+
+    pub fn last_activity_time(&self) -> Option<&Timestamp> {
+        self.last_activity_time.as_ref()
+    }
+
+    pub fn with_status(mut self, status: String) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_status_reason(mut self, status_reason: String) -> Self {
+        self.status_reason = Some(status_reason);
+        self
+    }
+
+    pub fn status_reason(&self) -> Option<&str> {
+        self.status_reason.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_status_updated_time(mut self, status_updated_time: Timestamp) -> Self {
+        self.status_updated_time = Some(status_updated_time);
+        self
+    }
+
+    pub fn status_updated_time(&self) -> Option<&Timestamp> {
+        self.status_updated_time.as_ref()
+    }
+
     pub fn with_authentication(mut self, authentication: AuthMechanism) -> Self {
         self.authentication = Some(authentication);
         self
@@ -311,6 +772,98 @@ impl Module {
     pub fn authentication(&self) -> Option<&AuthMechanism> {
         self.authentication.as_ref()
     }
+
+    /// The primary key of a module authenticated with SAS, or `None` if it
+    /// uses another authentication type or has no primary key.
+    pub fn primary_symmetric_key(&self) -> Option<&str> {
+        self.authentication
+            .as_ref()
+            .filter(|authentication| authentication.type_() == Some(AuthType::Sas))
+            .and_then(AuthMechanism::symmetric_key)
+            .and_then(SymmetricKey::primary_key)
+    }
+
+    /// The primary and secondary thumbprints of a module authenticated with
+    /// X.509 certificates, or `None` if it uses another authentication type
+    /// or lacks either thumbprint.
+    pub fn x509_thumbprints(&self) -> Option<(&str, &str)> {
+        let thumbprint = self
+            .authentication
+            .as_ref()
+            .filter(|authentication| authentication.type_() == Some(AuthType::X509))
+            .and_then(AuthMechanism::x509_thumbprint)?;
+        Some((
+            thumbprint.primary_thumbprint()?,
+            thumbprint.secondary_thumbprint()?,
+        ))
+    }
+
+    /// Whether the module's authentication satisfies `authentication`. Only
+    /// what `authentication` specifies is compared, so a spec without keys
+    /// matches a module whose keys IoT Hub generated.
+    pub fn matches_spec(&self, authentication: &AuthMechanism) -> bool {
+        let actual = self.authentication();
+
+        authentication.type_().map_or(true, |type_| {
+            actual.and_then(AuthMechanism::type_) == Some(type_)
+        }) && authentication.symmetric_key().map_or(true, |key| {
+            actual.and_then(AuthMechanism::symmetric_key) == Some(key)
+        }) && authentication.x509_thumbprint().map_or(true, |thumbprint| {
+            actual.and_then(AuthMechanism::x509_thumbprint) == Some(thumbprint)
+        })
+    }
+
+    /// Checks that the key material of the module's authentication is what
+    /// its authentication type uses, failing with `ErrorKind::InvalidAuth` if
+    /// it is ambiguous.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self
+            .authentication
+            .as_ref()
+            .and_then(AuthMechanism::inconsistency)
+        {
+            Some(reason) => Err(Error::from(ErrorKind::InvalidAuth {
+                module_id: self.module_id().unwrap_or_default().to_string(),
+                reason,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the module has the fields IoT Hub always returns a module
+    /// with, its device ID, module ID and generation ID, failing with
+    /// `ErrorKind::MissingField` naming the first one that is missing or
+    /// empty.
+    pub fn require_fields(&self) -> Result<(), Error> {
+        let fields = [
+            ("deviceId", self.device_id()),
+            ("moduleId", self.module_id()),
+            ("generationId", self.generation_id()),
+        ];
+        match fields
+            .iter()
+            .find(|(_, value)| value.map_or(true, str::is_empty))
+        {
+            Some(&(field, _)) => Err(Error::from(ErrorKind::MissingField { field })),
+            None => Ok(()),
+        }
+    }
+
+    /// A copy of the module to create on the device `new_device_id`, without
+    /// the fields IoT Hub assigns: the generation ID, ETag, connection state,
+    /// message count and activity and status update times. The module ID,
+    /// manager, status and authentication, keys included, are kept.
+    pub fn clone_for_new_device(&self, new_device_id: &str) -> Module {
+        Module {
+            module_id: self.module_id.clone(),
+            managed_by: self.managed_by.clone(),
+            device_id: Some(new_device_id.to_string()),
+            status: self.status.clone(),
+            status_reason: self.status_reason.clone(),
+            authentication: self.authentication.clone(),
+            ..Module::new()
+        }
+    }
 }
 
 impl Default for Module {
@@ -318,3 +871,1346 @@ impl Default for Module {
         Module::new()
     }
 }
+
+// A module as IoT Hub sends it, which depends on the API version. The ETag
+// is spelled `etag` or `eTag`, and the authentication type is either the
+// `type` of `authentication` or a top-level `authenticationType`, which is
+// moved into `authentication`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleRepr {
+    module_id: Option<String>,
+    managed_by: Option<String>,
+    device_id: Option<String>,
+    generation_id: Option<String>,
+    #[serde(alias = "eTag")]
+    etag: Option<String>,
+    connection_state: Option<String>,
+    #[serde(default, with = "timestamp")]
+    connection_state_updated_time: Option<Timestamp>,
+    cloud_to_device_message_count: Option<usize>,
+    #[serde(default, with = "timestamp")]
+    last_activity_time: Option<Timestamp>,
+    status: Option<String>,
+    status_reason: Option<String>,
+    #[serde(default, with = "timestamp")]
+    status_updated_time: Option<Timestamp>,
+    authentication: Option<AuthMechanism>,
+    authentication_type: Option<AuthType>,
+}
+
+impl From<ModuleRepr> for Module {
+    fn from(repr: ModuleRepr) -> Self {
+        // the type in `authentication` wins if both are given
+        let authentication = match (repr.authentication, repr.authentication_type) {
+            (Some(authentication), Some(type_)) if authentication.type_().is_none() => {
+                Some(authentication.with_type(type_))
+            }
+            (None, Some(type_)) => Some(AuthMechanism::new().with_type(type_)),
+            (authentication, _) => authentication,
+        };
+
+        Module {
+            module_id: repr.module_id,
+            managed_by: repr.managed_by,
+            device_id: repr.device_id,
+            generation_id: repr.generation_id,
+            etag: repr.etag,
+            connection_state: repr.connection_state,
+            connection_state_updated_time: repr.connection_state_updated_time,
+            cloud_to_device_message_count: repr.cloud_to_device_message_count,
+            last_activity_time: repr.last_activity_time,
+            status: repr.status,
+            status_reason: repr.status_reason,
+            status_updated_time: repr.status_updated_time,
+            authentication,
+        }
+    }
+}
+
+/// A one line summary of the module for terminal output, like
+/// `m1 status=enabled auth=sas connection=Connected`.
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} status={} auth={} connection={}",
+            OrDash(self.module_id()),
+            OrDash(self.status()),
+            OrDash(auth_type(self.authentication()).as_ref()),
+            OrDash(self.connection_state()),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp")]
+    status_updated_time: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_state: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp")]
+    connection_state_updated_time: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authentication: Option<AuthMechanism>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_scopes: Option<Vec<String>>,
+}
+
+impl Device {
+    pub fn new() -> Self {
+        Device {
+            device_id: None,
+            generation_id: None,
+            status: None,
+            status_reason: None,
+            status_updated_time: None,
+            connection_state: None,
+            connection_state_updated_time: None,
+            authentication: None,
+            device_scope: None,
+            parent_scopes: None,
+        }
+    }
+
+    pub fn with_device_id(mut self, device_id: String) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_generation_id(mut self, generation_id: String) -> Self {
+        self.generation_id = Some(generation_id);
+        self
+    }
+
+    pub fn generation_id(&self) -> Option<&str> {
+        self.generation_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_status(mut self, status: String) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_status_reason(mut self, status_reason: String) -> Self {
+        self.status_reason = Some(status_reason);
+        self
+    }
+
+    pub fn status_reason(&self) -> Option<&str> {
+        self.status_reason.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_status_updated_time(mut self, status_updated_time: Timestamp) -> Self {
+        self.status_updated_time = Some(status_updated_time);
+        self
+    }
+
+    pub fn status_updated_time(&self) -> Option<&Timestamp> {
+        self.status_updated_time.as_ref()
+    }
+
+    pub fn with_connection_state(mut self, connection_state: String) -> Self {
+        self.connection_state = Some(connection_state);
+        self
+    }
+
+    pub fn connection_state(&self) -> Option<&str> {
+        self.connection_state.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_connection_state_updated_time(
+        mut self,
+        connection_state_updated_time: Timestamp,
+    ) -> Self {
+        self.connection_state_updated_time = Some(connection_state_updated_time);
+        self
+    }
+
+    pub fn connection_state_updated_time(&self) -> Option<&Timestamp> {
+        self.connection_state_updated_time.as_ref()
+    }
+
+    pub fn with_authentication(mut self, authentication: AuthMechanism) -> Self {
+        self.authentication = Some(authentication);
+        self
+    }
+
+    pub fn authentication(&self) -> Option<&AuthMechanism> {
+        self.authentication.as_ref()
+    }
+
+    /// The scope of an edge device in a nested hierarchy, which its children
+    /// list among their `parent_scopes`.
+    pub fn with_device_scope(mut self, device_scope: String) -> Self {
+        self.device_scope = Some(device_scope);
+        self
+    }
+
+    pub fn device_scope(&self) -> Option<&str> {
+        self.device_scope.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_parent_scopes(mut self, parent_scopes: Vec<String>) -> Self {
+        self.parent_scopes = Some(parent_scopes);
+        self
+    }
+
+    pub fn parent_scopes(&self) -> Option<&[String]> {
+        self.parent_scopes.as_ref().map(AsRef::as_ref)
+    }
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device::new()
+    }
+}
+
+/// A one line summary of the device in the same form as `Module`'s.
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} status={} auth={} connection={}",
+            OrDash(self.device_id()),
+            OrDash(self.status()),
+            OrDash(auth_type(self.authentication()).as_ref()),
+            OrDash(self.connection_state()),
+        )
+    }
+}
+
+/// A device's registry entry together with its modules and their twins, as
+/// captured by `DeviceClient::export_device`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceExport {
+    device: Device,
+    #[serde(default)]
+    modules: Vec<Module>,
+    #[serde(default)]
+    twins: Vec<Twin>,
+}
+
+impl DeviceExport {
+    pub fn new(device: Device, modules: Vec<Module>, twins: Vec<Twin>) -> Self {
+        DeviceExport {
+            device,
+            modules,
+            twins,
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    pub fn twins(&self) -> &[Twin] {
+        &self.twins
+    }
+}
+
+/// What a `ModuleOperation` does to its module. The `IfMatchETag` modes only
+/// apply if the module still has the ETag given with `with_etag`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportMode {
+    Create,
+    Update,
+    UpdateIfMatchETag,
+    Delete,
+    DeleteIfMatchETag,
+    CreateOrUpdate,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleOperation {
+    #[serde(rename = "id", skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    module_id: String,
+    import_mode: ImportMode,
+    #[serde(rename = "eTag", skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authentication: Option<AuthMechanism>,
+}
+
+impl ModuleOperation {
+    pub fn new(module_id: String, import_mode: ImportMode) -> Self {
+        ModuleOperation {
+            device_id: None,
+            module_id,
+            import_mode,
+            etag: None,
+            authentication: None,
+        }
+    }
+
+    pub fn with_device_id(mut self, device_id: String) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn module_id(&self) -> &str {
+        &self.module_id
+    }
+
+    pub fn import_mode(&self) -> ImportMode {
+        self.import_mode
+    }
+
+    pub fn with_etag(mut self, etag: String) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_authentication(mut self, authentication: AuthMechanism) -> Self {
+        self.authentication = Some(authentication);
+        self
+    }
+
+    pub fn authentication(&self) -> Option<&AuthMechanism> {
+        self.authentication.as_ref()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkRegistryOperationResult {
+    is_successful: bool,
+    #[serde(default)]
+    errors: Vec<RegistryOperationError>,
+}
+
+impl BulkRegistryOperationResult {
+    pub fn new(is_successful: bool) -> Self {
+        BulkRegistryOperationResult {
+            is_successful,
+            errors: vec![],
+        }
+    }
+
+    pub fn with_errors(mut self, errors: Vec<RegistryOperationError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn is_successful(&self) -> bool {
+        self.is_successful
+    }
+
+    pub fn errors(&self) -> &[RegistryOperationError] {
+        &self.errors
+    }
+}
+
+/// How long IoT Hub waits for a module to connect, and then to respond, when
+/// invoking a direct method on it. IoT Hub's defaults apply to the timeouts
+/// that aren't set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MethodTimeouts {
+    connect_timeout: Option<Duration>,
+    response_timeout: Option<Duration>,
+}
+
+impl MethodTimeouts {
+    pub fn new() -> Self {
+        MethodTimeouts::default()
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    pub fn with_response_timeout(mut self, response_timeout: Duration) -> Self {
+        self.response_timeout = Some(response_timeout);
+        self
+    }
+
+    pub fn response_timeout(&self) -> Option<Duration> {
+        self.response_timeout
+    }
+}
+
+/// The status and payload a module responded to a direct method with.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MethodResult {
+    status: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<Value>,
+}
+
+impl MethodResult {
+    pub fn new(status: i32) -> Self {
+        MethodResult {
+            status,
+            payload: None,
+        }
+    }
+
+    pub fn with_payload(mut self, payload: Value) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    pub fn payload(&self) -> Option<&Value> {
+        self.payload.as_ref()
+    }
+}
+
+/// The result of purging the cloud-to-device message queue of a device or
+/// module.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeMessageQueueResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module_id: Option<String>,
+    total_messages_purged: usize,
+}
+
+impl PurgeMessageQueueResult {
+    pub fn new(total_messages_purged: usize) -> Self {
+        PurgeMessageQueueResult {
+            device_id: None,
+            module_id: None,
+            total_messages_purged,
+        }
+    }
+
+    pub fn with_device_id(mut self, device_id: String) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn with_module_id(mut self, module_id: String) -> Self {
+        self.module_id = Some(module_id);
+        self
+    }
+
+    pub fn module_id(&self) -> Option<&str> {
+        self.module_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn total_messages_purged(&self) -> usize {
+        self.total_messages_purged
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryOperationError {
+    device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module_id: Option<String>,
+    error_code: String,
+    error_status: String,
+}
+
+impl RegistryOperationError {
+    pub fn new(device_id: String, error_code: String, error_status: String) -> Self {
+        RegistryOperationError {
+            device_id,
+            module_id: None,
+            error_code,
+            error_status,
+        }
+    }
+
+    pub fn with_module_id(mut self, module_id: String) -> Self {
+        self.module_id = Some(module_id);
+        self
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn module_id(&self) -> Option<&str> {
+        self.module_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn error_code(&self) -> &str {
+        &self.error_code
+    }
+
+    pub fn error_status(&self) -> &str {
+        &self.error_status
+    }
+}
+
+// IoT Hub's timestamps are ISO-8601, with up to seven fractional digits and
+// sometimes without an offset, and it reports times that never happened, like
+// the last activity of a module that never connected, as
+// `0001-01-01T00:00:00Z`. Those are read as `None`.
+#[cfg(feature = "chrono")]
+pub(crate) mod timestamp {
+    use super::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match time {
+            Some(time) => {
+                serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(time) => parse(&time)
+                .map(|time| Some(time).filter(|time| *time > never()))
+                .ok_or_else(|| D::Error::custom(format!("invalid timestamp {:?}", time))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn to_rfc3339(time: &DateTime<Utc>) -> String {
+        time.to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    pub fn is_after(time: &DateTime<Utc>, since: &DateTime<Utc>) -> bool {
+        time > since
+    }
+
+    fn parse(time: &str) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| time.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|time| Utc.from_utc_datetime(&time))
+            })
+            .ok()
+    }
+
+    fn never() -> DateTime<Utc> {
+        Utc.ymd(1, 1, 1).and_hms(0, 0, 0)
+    }
+}
+
+// Without chrono timestamps are passed through as they are, except for the
+// `0001-01-01T00:00:00Z` that IoT Hub reports for times that never happened.
+#[cfg(not(feature = "chrono"))]
+pub(crate) mod timestamp {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    const NEVER: &str = "0001-01-01T00:00:00";
+
+    pub fn serialize<S>(time: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        time.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<String>::deserialize(deserializer)?.filter(|time| !time.starts_with(NEVER)))
+    }
+
+    pub fn to_rfc3339(time: &str) -> String {
+        time.to_string()
+    }
+
+    pub fn is_after(time: &str, since: &str) -> bool {
+        sort_key(time) > sort_key(since)
+    }
+
+    // Splits a UTC timestamp into its seconds and its fractional digits,
+    // padded to nanoseconds, so that timestamps with a different number of
+    // fractional digits, or without the `Z`, still order by time.
+    fn sort_key(time: &str) -> (&str, String) {
+        let seconds = time.get(..NEVER.len()).unwrap_or(time);
+        let fraction: String = time[seconds.len()..]
+            .trim_start_matches('.')
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        (seconds, format!("{:0<9}", fraction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AuthMechanism, AuthType, BulkRegistryOperationResult, Device, DeviceExport, ImportMode,
+        MethodResult, Module, ModuleOperation, Properties, PurgeMessageQueueResult,
+        RegistryOperationError, SymmetricKey, Timestamp, Twin, TwinChange, X509Thumbprint,
+    };
+
+    use std::fmt::Debug;
+
+    #[cfg(feature = "chrono")]
+    use chrono::{TimeZone, Utc};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+
+    use crate::error::ErrorKind;
+
+    // Serializes `value`, checks it deserializes back into an equal value and
+    // returns the JSON to check the wire names in.
+    fn round_trip<T>(value: &T) -> Value
+    where
+        T: Debug + DeserializeOwned + PartialEq + Serialize,
+    {
+        let json = serde_json::to_value(value).unwrap();
+        let parsed: T = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(value, &parsed);
+        json
+    }
+
+    #[cfg(feature = "chrono")]
+    fn timestamp() -> Timestamp {
+        Utc.ymd(2026, 10, 15).and_hms(10, 0, 0)
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn timestamp() -> Timestamp {
+        "2026-10-15T10:00:00Z".to_string()
+    }
+
+    fn keys(json: &Value) -> Vec<&str> {
+        let mut keys: Vec<_> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(AsRef::as_ref)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    fn sas_auth() -> AuthMechanism {
+        AuthMechanism::default()
+            .with_type(AuthType::Sas)
+            .with_symmetric_key(
+                SymmetricKey::default()
+                    .with_primary_key("pkey".to_string())
+                    .with_secondary_key("skey".to_string()),
+            )
+    }
+
+    fn x509_auth() -> AuthMechanism {
+        AuthMechanism::default()
+            .with_type(AuthType::X509)
+            .with_x509_thumbprint(
+                X509Thumbprint::default()
+                    .with_primary_thumbprint("pthumb".to_string())
+                    .with_secondary_thumbprint("sthumb".to_string()),
+            )
+    }
+
+    #[test]
+    fn auth_is_parsed_from_connection_string() {
+        let auth = AuthMechanism::from_connection_string(
+            "HostName=hub.azure-devices.net;DeviceId=d1;ModuleId=m1;SharedAccessKey=a2V5MQ==",
+        )
+        .unwrap();
+        assert_eq!(Some(AuthType::Sas), auth.type_());
+        let key = auth.symmetric_key().unwrap();
+        assert_eq!(Some("a2V5MQ=="), key.primary_key());
+        assert_eq!(None, key.secondary_key());
+    }
+
+    #[test]
+    fn malformed_connection_string_fails() {
+        for (connection_string, reason) in &[
+            ("HostName=hub;DeviceId=d1", "SharedAccessKey is missing"),
+            ("HostName=hub;SharedAccessKey=", "SharedAccessKey is empty"),
+            (
+                "SharedAccessKey=not base64!",
+                "SharedAccessKey is not base64",
+            ),
+            (
+                "HostName;SharedAccessKey=a2V5MQ==",
+                "part is not a name=value pair",
+            ),
+        ] {
+            let err = AuthMechanism::from_connection_string(connection_string).unwrap_err();
+            assert_eq!(
+                &ErrorKind::InvalidConnectionString { reason: *reason },
+                err.kind()
+            );
+        }
+    }
+
+    #[test]
+    fn module_key_material_follows_auth_type() {
+        let sas = Module::default().with_authentication(sas_auth());
+        assert_eq!(Some("pkey"), sas.primary_symmetric_key());
+        assert_eq!(None, sas.x509_thumbprints());
+
+        let x509 = Module::default().with_authentication(x509_auth());
+        assert_eq!(Some(("pthumb", "sthumb")), x509.x509_thumbprints());
+        assert_eq!(None, x509.primary_symmetric_key());
+
+        // keys left over from a previous authentication type are ignored
+        let mismatched = Module::default().with_authentication(
+            sas_auth()
+                .with_type(AuthType::X509)
+                .with_x509_thumbprint(X509Thumbprint::default()),
+        );
+        assert_eq!(None, mismatched.primary_symmetric_key());
+        assert_eq!(None, mismatched.x509_thumbprints());
+        assert_eq!(None, Module::default().primary_symmetric_key());
+    }
+
+    #[test]
+    fn module_etag_accepts_either_casing() {
+        for body in &[
+            r#"{"moduleId":"m1","etag":"AAAA"}"#,
+            r#"{"moduleId":"m1","eTag":"AAAA"}"#,
+        ] {
+            let module: Module = serde_json::from_str(body).unwrap();
+            assert_eq!(Some("AAAA"), module.etag());
+        }
+    }
+
+    #[test]
+    fn module_auth_type_accepts_either_representation() {
+        for body in &[
+            r#"{"moduleId":"m1","authentication":{"type":"sas","symmetricKey":{"primaryKey":"pkey"}}}"#,
+            r#"{"moduleId":"m1","authenticationType":"sas","authentication":{"symmetricKey":{"primaryKey":"pkey"}}}"#,
+        ] {
+            let module: Module = serde_json::from_str(body).unwrap();
+            let authentication = module.authentication().unwrap();
+            assert_eq!(Some(AuthType::Sas), authentication.type_());
+            assert_eq!(Some("pkey"), module.primary_symmetric_key());
+        }
+
+        let module: Module =
+            serde_json::from_str(r#"{"moduleId":"m1","authenticationType":"x509"}"#).unwrap();
+        assert_eq!(
+            Some(AuthType::X509),
+            module.authentication().and_then(AuthMechanism::type_)
+        );
+
+        // the nested type wins over the top-level one
+        let module: Module = serde_json::from_str(
+            r#"{"moduleId":"m1","authenticationType":"x509","authentication":{"type":"sas"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(AuthType::Sas),
+            module.authentication().and_then(AuthMechanism::type_)
+        );
+    }
+
+    #[test]
+    fn device_scopes_are_parsed() {
+        let device: Device = serde_json::from_str(
+            r#"{
+                "deviceId": "child",
+                "deviceScope": "ms-azure-iot-edge://child-1",
+                "parentScopes": ["ms-azure-iot-edge://parent-2"]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(Some("ms-azure-iot-edge://child-1"), device.device_scope());
+        assert_eq!(
+            Some(&["ms-azure-iot-edge://parent-2".to_string()][..]),
+            device.parent_scopes()
+        );
+
+        let device: Device = serde_json::from_str(r#"{"deviceId":"leaf"}"#).unwrap();
+        assert_eq!(None, device.device_scope());
+        assert_eq!(None, device.parent_scopes());
+    }
+
+    #[test]
+    fn symmetric_key_debug_is_redacted() {
+        let key = SymmetricKey::default()
+            .with_primary_key("pkey-secret".to_string())
+            .with_secondary_key("skey-secret".to_string());
+
+        assert_eq!(
+            r#"SymmetricKey { primary_key: Some("***"), secondary_key: Some("***") }"#,
+            format!("{:?}", key)
+        );
+    }
+
+    #[test]
+    fn module_debug_is_redacted() {
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_authentication(
+                AuthMechanism::default()
+                    .with_type(AuthType::Sas)
+                    .with_symmetric_key(
+                        SymmetricKey::default()
+                            .with_primary_key("pkey-secret".to_string())
+                            .with_secondary_key("skey-secret".to_string()),
+                    )
+                    .with_x509_thumbprint(
+                        X509Thumbprint::default()
+                            .with_primary_thumbprint("pthumb-secret".to_string())
+                            .with_secondary_thumbprint("sthumb-secret".to_string()),
+                    ),
+            );
+
+        let debug = format!("{:?}", module);
+        assert!(debug.contains("m1"));
+        assert!(!debug.contains("secret"));
+        let debug = format!("{:#?}", module);
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn module_display_is_one_redacted_line() {
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_device_id("d1".to_string())
+            .with_status("enabled".to_string())
+            .with_connection_state("Connected".to_string())
+            .with_authentication(
+                AuthMechanism::default()
+                    .with_type(AuthType::Sas)
+                    .with_symmetric_key(
+                        SymmetricKey::default().with_primary_key("pkey-secret".to_string()),
+                    ),
+            );
+
+        assert_eq!(
+            "m1 status=enabled auth=sas connection=Connected",
+            module.to_string()
+        );
+    }
+
+    #[test]
+    fn module_display_marks_missing_fields() {
+        let module = Module::default().with_module_id("m1".to_string());
+
+        assert_eq!("m1 status=- auth=- connection=-", module.to_string());
+    }
+
+    #[test]
+    fn device_and_twin_display() {
+        let device = Device::default()
+            .with_device_id("d1".to_string())
+            .with_status("disabled".to_string())
+            .with_authentication(AuthMechanism::default().with_type(AuthType::X509));
+        assert_eq!(
+            "d1 status=disabled auth=x509 connection=-",
+            device.to_string()
+        );
+
+        let twin = Twin::new("d1", 3, AuthType::Sas, Properties::new(json!({ "a": 1 })))
+            .with_module_id("m1".to_string());
+        assert_eq!("d1/m1 version=3 auth=sas", twin.to_string());
+    }
+
+    fn twin(desired: serde_json::Value, reported: serde_json::Value) -> Twin {
+        Twin::new(
+            "d1",
+            1,
+            AuthType::Sas,
+            Properties::new(desired).with_reported(reported),
+        )
+        .with_module_id("m1".to_string())
+    }
+
+    #[test]
+    fn twin_diff_finds_nested_additions_and_changed_leaves() {
+        let before = twin(
+            json!({ "a": { "b": 1 }, "$version": 1 }),
+            json!({ "temperature": 20 }),
+        )
+        .with_tags(json!({ "env": "prod" }));
+        let after = twin(
+            json!({ "a": { "b": 1, "c": { "d": 2 } }, "$version": 2 }),
+            json!({ "temperature": 21 }),
+        )
+        .with_tags(json!({}));
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            &[
+                TwinChange::Removed {
+                    path: "tags.env".to_string(),
+                    value: json!("prod"),
+                },
+                TwinChange::Added {
+                    path: "properties.desired.a.c".to_string(),
+                    value: json!({ "d": 2 }),
+                },
+                TwinChange::Changed {
+                    path: "properties.reported.temperature".to_string(),
+                    old: json!(20),
+                    new: json!(21),
+                },
+            ],
+            diff.changes()
+        );
+    }
+
+    #[test]
+    fn twin_diff_of_identical_twins_is_empty() {
+        let twin = twin(json!({ "a": [1, 2] }), json!({ "b": "c" }));
+
+        assert!(twin.diff(&twin.clone()).is_empty());
+    }
+
+    #[test]
+    fn module_clone_for_new_device_clears_server_fields() {
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_managed_by("iotedge".to_string())
+            .with_device_id("d1".to_string())
+            .with_generation_id("g1".to_string())
+            .with_etag("AAAA".to_string())
+            .with_connection_state("Connected".to_string())
+            .with_connection_state_updated_time(timestamp())
+            .with_cloud_to_device_message_count(3)
+            .with_last_activity_time(timestamp())
+            .with_status("disabled".to_string())
+            .with_status_reason("reason".to_string())
+            .with_status_updated_time(timestamp())
+            .with_authentication(sas_auth());
+
+        assert_eq!(
+            Module::default()
+                .with_module_id("m1".to_string())
+                .with_managed_by("iotedge".to_string())
+                .with_device_id("d2".to_string())
+                .with_status("disabled".to_string())
+                .with_status_reason("reason".to_string())
+                .with_authentication(sas_auth()),
+            module.clone_for_new_device("d2")
+        );
+    }
+
+    #[test]
+    fn module_round_trips_with_wire_names() {
+        let module = Module::default()
+            .with_module_id("m1".to_string())
+            .with_managed_by("iotedge".to_string())
+            .with_device_id("d1".to_string())
+            .with_generation_id("g1".to_string())
+            .with_etag("AAAA".to_string())
+            .with_connection_state("Connected".to_string())
+            .with_connection_state_updated_time(timestamp())
+            .with_cloud_to_device_message_count(3)
+            .with_last_activity_time(timestamp())
+            .with_status("enabled".to_string())
+            .with_status_reason("reason".to_string())
+            .with_status_updated_time(timestamp())
+            .with_authentication(sas_auth());
+
+        let json = round_trip(&module);
+        assert_eq!(
+            vec![
+                "authentication",
+                "cloudToDeviceMessageCount",
+                "connectionState",
+                "connectionStateUpdatedTime",
+                "deviceId",
+                "etag",
+                "generationId",
+                "lastActivityTime",
+                "managedBy",
+                "moduleId",
+                "status",
+                "statusReason",
+                "statusUpdatedTime",
+            ],
+            keys(&json)
+        );
+        assert_eq!(json!("2026-10-15T10:00:00Z"), json["lastActivityTime"]);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn module_parses_iothub_timestamps() {
+        let module: Module = serde_json::from_value(json!({
+            "moduleId": "m1",
+            "connectionStateUpdatedTime": "2019-03-04T05:06:07.1234567Z",
+            "lastActivityTime": "2019-03-04T05:06:07",
+            "statusUpdatedTime": "0001-01-01T00:00:00Z",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Some(&Utc.ymd(2019, 3, 4).and_hms_nano(5, 6, 7, 123_456_700)),
+            module.connection_state_updated_time()
+        );
+        assert_eq!(
+            Some(&Utc.ymd(2019, 3, 4).and_hms(5, 6, 7)),
+            module.last_activity_time()
+        );
+        assert_eq!(None, module.status_updated_time());
+
+        let json = round_trip(&module);
+        assert_eq!(
+            json!("2019-03-04T05:06:07.123456700Z"),
+            json["connectionStateUpdatedTime"]
+        );
+        assert_eq!(None, json.get("statusUpdatedTime"));
+    }
+
+    #[test]
+    fn module_validates_consistent_auth() {
+        let module: Module = serde_json::from_value(json!({
+            "moduleId": "m1",
+            "authentication": {
+                "symmetricKey": { "primaryKey": "pkey", "secondaryKey": "skey" },
+                "x509Thumbprint": { "primaryThumbprint": null, "secondaryThumbprint": null },
+                "type": "sas",
+            },
+        }))
+        .unwrap();
+        module.validate().unwrap();
+    }
+
+    #[test]
+    fn module_rejects_contradictory_auth() {
+        let module: Module = serde_json::from_value(json!({
+            "moduleId": "m1",
+            "authentication": {
+                "symmetricKey": { "primaryKey": "pkey", "secondaryKey": "skey" },
+                "x509Thumbprint": { "primaryThumbprint": "thumb1", "secondaryThumbprint": null },
+                "type": "sas",
+            },
+        }))
+        .unwrap();
+
+        let err = module.validate().unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidAuth {
+                module_id: "m1".to_string(),
+                reason: "authentication type is sas but it has X.509 thumbprints",
+            },
+            err.kind()
+        );
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    #[test]
+    fn module_keeps_iothub_timestamps_as_sent() {
+        let module: Module = serde_json::from_value(json!({
+            "moduleId": "m1",
+            "lastActivityTime": "2019-03-04T05:06:07.1234567Z",
+            "statusUpdatedTime": "0001-01-01T00:00:00Z",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Some("2019-03-04T05:06:07.1234567Z"),
+            module.last_activity_time().map(String::as_str)
+        );
+        assert_eq!(None, module.status_updated_time());
+
+        let json = round_trip(&module);
+        assert_eq!(
+            json!("2019-03-04T05:06:07.1234567Z"),
+            json["lastActivityTime"]
+        );
+        assert_eq!(None, json.get("statusUpdatedTime"));
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    #[test]
+    fn timestamps_as_sent_order_by_time() {
+        use super::timestamp::is_after;
+
+        assert!(is_after("2019-03-04T05:06:07.1Z", "2019-03-04T05:06:07Z"));
+        assert!(is_after(
+            "2019-03-04T05:06:07.1234567Z",
+            "2019-03-04T05:06:07.123Z"
+        ));
+        assert!(is_after("2019-03-04T05:06:08", "2019-03-04T05:06:07.999Z"));
+        assert!(!is_after(
+            "2019-03-04T05:06:07.100Z",
+            "2019-03-04T05:06:07.1"
+        ));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn module_rejects_invalid_timestamp() {
+        let module = serde_json::from_value::<Module>(json!({ "lastActivityTime": "yesterday" }));
+        assert!(module.is_err());
+    }
+
+    #[test]
+    fn module_without_optional_fields_omits_them() {
+        assert_eq!(json!({}), round_trip(&Module::default()));
+
+        let module = Module::default().with_module_id("m1".to_string());
+        assert_eq!(json!({ "moduleId": "m1" }), round_trip(&module));
+    }
+
+    #[test]
+    fn auth_mechanism_round_trips_with_wire_names() {
+        let auth = sas_auth().with_x509_thumbprint(
+            X509Thumbprint::default()
+                .with_primary_thumbprint("pthumb".to_string())
+                .with_secondary_thumbprint("sthumb".to_string()),
+        );
+
+        assert_eq!(
+            json!({
+                "symmetricKey": { "primaryKey": "pkey", "secondaryKey": "skey" },
+                "x509Thumbprint": { "primaryThumbprint": "pthumb", "secondaryThumbprint": "sthumb" },
+                "type": "sas",
+            }),
+            round_trip(&auth)
+        );
+        assert_eq!(json!({}), round_trip(&AuthMechanism::default()));
+    }
+
+    #[test]
+    fn symmetric_key_round_trips_with_absent_keys() {
+        assert_eq!(json!({}), round_trip(&SymmetricKey::default()));
+
+        let key = SymmetricKey::default().with_secondary_key("skey".to_string());
+        assert_eq!(json!({ "secondaryKey": "skey" }), round_trip(&key));
+    }
+
+    #[test]
+    fn auth_types_use_wire_names() {
+        assert_eq!(json!("none"), round_trip(&AuthType::None));
+        assert_eq!(json!("sas"), round_trip(&AuthType::Sas));
+        assert_eq!(json!("x509"), round_trip(&AuthType::X509));
+    }
+
+    #[test]
+    fn twin_without_metadata_keeps_values() {
+        let twin = Twin::new(
+            "d1",
+            2,
+            AuthType::Sas,
+            Properties::new(json!({
+                "a": { "b": 1 },
+                "$metadata": { "$lastUpdated": "2026-10-15T10:00:00Z", "a": { "b": {} } },
+                "$version": 4,
+            }))
+            .with_reported(json!({
+                "c": 2,
+                "$metadata": { "$lastUpdated": "2026-10-15T10:00:00Z" },
+            })),
+        );
+
+        let twin = twin.without_metadata();
+        assert_eq!(
+            &json!({ "a": { "b": 1 }, "$version": 4 }),
+            twin.properties().desired()
+        );
+        assert_eq!(Some(&json!({ "c": 2 })), twin.properties().reported());
+    }
+
+    #[test]
+    fn twin_round_trips_with_wire_names() {
+        let twin = Twin::new(
+            "d1",
+            2,
+            AuthType::Sas,
+            Properties::new(json!({ "a": 1 })).with_reported(json!({ "b": 2 })),
+        )
+        .with_module_id("m1".to_string())
+        .with_etag("AAAA".to_string())
+        .with_tags(json!({ "site": "plant-1" }));
+
+        let json = round_trip(&twin);
+        assert_eq!(
+            vec![
+                "authenticationType",
+                "deviceId",
+                "etag",
+                "moduleId",
+                "properties",
+                "tags",
+                "version"
+            ],
+            keys(&json)
+        );
+        assert_eq!(vec!["desired", "reported"], keys(&json["properties"]));
+
+        let twin = Twin::new("d1", 2, AuthType::None, Properties::new(json!({})));
+        assert_eq!(
+            vec!["authenticationType", "deviceId", "properties", "version"],
+            keys(&round_trip(&twin))
+        );
+    }
+
+    #[test]
+    fn device_round_trips_with_wire_names() {
+        let device = Device::default()
+            .with_device_id("d1".to_string())
+            .with_generation_id("g1".to_string())
+            .with_status("enabled".to_string())
+            .with_status_reason("reason".to_string())
+            .with_status_updated_time(timestamp())
+            .with_connection_state("Disconnected".to_string())
+            .with_connection_state_updated_time(timestamp())
+            .with_authentication(sas_auth());
+
+        assert_eq!(
+            vec![
+                "authentication",
+                "connectionState",
+                "connectionStateUpdatedTime",
+                "deviceId",
+                "generationId",
+                "status",
+                "statusReason",
+                "statusUpdatedTime",
+            ],
+            keys(&round_trip(&device))
+        );
+        assert_eq!(json!({}), round_trip(&Device::default()));
+
+        let export = DeviceExport::new(
+            device,
+            vec![Module::default().with_module_id("m1".to_string())],
+            vec![],
+        );
+        assert_eq!(
+            vec!["device", "modules", "twins"],
+            keys(&round_trip(&export))
+        );
+    }
+
+    #[test]
+    fn import_modes_serialize_to_documented_names() {
+        let modes = [
+            (ImportMode::Create, "create"),
+            (ImportMode::Update, "update"),
+            (ImportMode::UpdateIfMatchETag, "updateIfMatchETag"),
+            (ImportMode::Delete, "delete"),
+            (ImportMode::DeleteIfMatchETag, "deleteIfMatchETag"),
+            (ImportMode::CreateOrUpdate, "createOrUpdate"),
+        ];
+        for (mode, name) in &modes {
+            assert_eq!(json!(name), round_trip(mode));
+        }
+    }
+
+    #[test]
+    fn module_operations_mix_import_modes() {
+        let operations = vec![
+            ModuleOperation::new("m1".to_string(), ImportMode::Create),
+            ModuleOperation::new("m2".to_string(), ImportMode::DeleteIfMatchETag)
+                .with_etag("AAAA".to_string()),
+            ModuleOperation::new("m3".to_string(), ImportMode::CreateOrUpdate),
+        ];
+
+        assert_eq!(
+            json!([
+                { "moduleId": "m1", "importMode": "create" },
+                { "moduleId": "m2", "importMode": "deleteIfMatchETag", "eTag": "AAAA" },
+                { "moduleId": "m3", "importMode": "createOrUpdate" },
+            ]),
+            round_trip(&operations)
+        );
+    }
+
+    #[test]
+    fn registry_operations_round_trip_with_wire_names() {
+        let operation = ModuleOperation::new("m1".to_string(), ImportMode::UpdateIfMatchETag)
+            .with_device_id("d1".to_string())
+            .with_etag("AAAA".to_string())
+            .with_authentication(sas_auth());
+        assert_eq!(
+            vec!["authentication", "eTag", "id", "importMode", "moduleId"],
+            keys(&round_trip(&operation))
+        );
+
+        let result =
+            BulkRegistryOperationResult::new(false).with_errors(vec![RegistryOperationError::new(
+                "d1".to_string(),
+                "ModuleAlreadyExists".to_string(),
+                "conflict".to_string(),
+            )
+            .with_module_id("m1".to_string())]);
+        let json = round_trip(&result);
+        assert_eq!(vec!["errors", "isSuccessful"], keys(&json));
+        assert_eq!(
+            vec!["deviceId", "errorCode", "errorStatus", "moduleId"],
+            keys(&json["errors"][0])
+        );
+
+        let result: BulkRegistryOperationResult =
+            serde_json::from_value(json!({ "isSuccessful": true })).unwrap();
+        assert!(result.errors().is_empty());
+    }
+
+    #[test]
+    fn method_and_purge_results_round_trip_with_wire_names() {
+        let result = MethodResult::new(200).with_payload(json!({ "pong": true }));
+        assert_eq!(
+            json!({ "status": 200, "payload": { "pong": true } }),
+            round_trip(&result)
+        );
+        assert_eq!(
+            json!({ "status": 404 }),
+            round_trip(&MethodResult::new(404))
+        );
+
+        let purged = PurgeMessageQueueResult::new(7)
+            .with_device_id("d1".to_string())
+            .with_module_id("m1".to_string());
+        assert_eq!(
+            json!({ "deviceId": "d1", "moduleId": "m1", "totalMessagesPurged": 7 }),
+            round_trip(&purged)
+        );
+    }
+}
@@ -0,0 +1,448 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::marker::PhantomData;
+
+use failure::Fail;
+use futures::future::{self, Either, Loop};
+use futures::{stream, Future, Stream};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::Method;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use edgelet_http::client::{Client, ClientImpl, Condition, TokenSource};
+
+use crate::error::{Error, ErrorKind, PartialListError};
+
+const CONTINUATION_HEADER: &str = "x-ms-continuation";
+const MAX_ITEM_COUNT_HEADER: &str = "x-ms-max-item-count";
+const MAX_CONTINUATION_LENGTH: usize = 4096;
+const TOTAL_COUNT_HEADER: &str = "x-ms-total-count";
+
+/// Fetches a paginated IoT Hub listing. Every page is requested with the
+/// continuation token returned alongside the previous one, and the listing
+/// ends with the first page that comes back without a token. Each page is a
+/// request of its own, so the client's retry policy retries a page that
+/// failed without fetching the pages before it again.
+pub struct Paginator<C, T, I> {
+    client: Client<C, T>,
+    method: Method,
+    path: String,
+    body: Option<Value>,
+    page_size: Option<usize>,
+    error_kind: fn() -> ErrorKind,
+    item: PhantomData<fn() -> I>,
+}
+
+impl<C, T, I> Paginator<C, T, I>
+where
+    C: ClientImpl,
+    T: 'static + TokenSource + Clone,
+    T::Error: Fail,
+    I: 'static + DeserializeOwned,
+{
+    pub(crate) fn new(
+        client: Client<C, T>,
+        method: Method,
+        path: String,
+        error_kind: fn() -> ErrorKind,
+    ) -> Self {
+        Paginator {
+            client,
+            method,
+            path,
+            body: None,
+            page_size: None,
+            error_kind,
+            item: PhantomData,
+        }
+    }
+
+    pub(crate) fn with_body(mut self, body: Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    pub fn page_size(&self) -> Option<usize> {
+        self.page_size
+    }
+
+    /// Fetches the page identified by `continuation`, or the first page if
+    /// it is `None`. A token that couldn't be sent back to IoT Hub, whether
+    /// given or returned with the page, fails with
+    /// `ErrorKind::InvalidContinuation`.
+    pub fn page(&self, continuation: Option<&str>) -> impl Future<Item = Page<I>, Error = Error> {
+        let error_kind = self.error_kind;
+
+        let mut headers = HeaderMap::new();
+        if let Some(page_size) = self.page_size {
+            headers.insert(MAX_ITEM_COUNT_HEADER, HeaderValue::from(page_size));
+        }
+        if let Some(continuation) = continuation {
+            match continuation_value(continuation.as_bytes()) {
+                Ok(value) => {
+                    headers.insert(CONTINUATION_HEADER, value);
+                }
+                Err(err) => return Either::B(future::err(err)),
+            }
+        }
+
+        let res = self
+            .client
+            .request_with_meta::<Value, Vec<I>>(
+                self.method.clone(),
+                &self.path,
+                None,
+                headers,
+                self.body.clone(),
+                Condition::None,
+            )
+            .map_err(move |err| Error::from(err.context(error_kind())))
+            .and_then(|(items, headers)| {
+                let continuation = match headers.get(CONTINUATION_HEADER) {
+                    Some(value) => Some(
+                        continuation_value(value.as_bytes())?
+                            .to_str()
+                            .expect("continuation token is visible ASCII")
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+                let total_count = headers
+                    .get(TOTAL_COUNT_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok());
+                Ok(Page {
+                    items: items.unwrap_or_else(Vec::new),
+                    continuation,
+                    total_count,
+                })
+            });
+
+        Either::A(res)
+    }
+
+    /// Streams the items of every page, fetching each page only once the
+    /// items of the previous one have been consumed. The stream ends with the
+    /// first page that fails.
+    pub fn stream(&self) -> impl Stream<Item = I, Error = Error> {
+        let paginator = self.clone();
+
+        stream::unfold(Cursor::First, move |cursor| {
+            let continuation = match cursor {
+                Cursor::First => None,
+                Cursor::Next(continuation) => Some(continuation),
+                Cursor::Done => return None,
+            };
+            Some(
+                paginator
+                    .page(continuation.as_ref().map(AsRef::as_ref))
+                    .map(|page| {
+                        let next = page.continuation.map_or(Cursor::Done, Cursor::Next);
+                        (page.items, next)
+                    }),
+            )
+        })
+        .map(stream::iter_ok::<_, Error>)
+        .flatten()
+    }
+
+    pub fn all(&self) -> impl Future<Item = Vec<I>, Error = PartialListError<I>> {
+        self.all_from(None)
+    }
+
+    /// Fetches every page starting at the one identified by `continuation`.
+    /// If a page fails the error carries the items fetched so far and the
+    /// token to resume from.
+    pub fn all_from(
+        &self,
+        continuation: Option<String>,
+    ) -> impl Future<Item = Vec<I>, Error = PartialListError<I>> {
+        let paginator = self.clone();
+
+        future::loop_fn(
+            (Vec::new(), continuation),
+            move |(mut items, continuation): (Vec<I>, Option<String>)| {
+                paginator
+                    .page(continuation.as_ref().map(AsRef::as_ref))
+                    .then(move |page| match page {
+                        Ok(page) => {
+                            items.extend(page.items);
+                            match page.continuation {
+                                Some(next) => Ok(Loop::Continue((items, Some(next)))),
+                                None => Ok(Loop::Break(items)),
+                            }
+                        }
+                        Err(err) => Err(PartialListError::new(items, continuation, err)),
+                    })
+            },
+        )
+    }
+}
+
+impl<C, T, I> Clone for Paginator<C, T, I>
+where
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        Paginator {
+            client: self.client.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            body: self.body.clone(),
+            page_size: self.page_size,
+            error_kind: self.error_kind,
+            item: PhantomData,
+        }
+    }
+}
+
+// Where `Paginator::stream` is in the listing: at the first page, at the page
+// a continuation token points at, or past the last page.
+enum Cursor {
+    First,
+    Next(String),
+    Done,
+}
+
+// Checks a continuation token is one IoT Hub could have issued, so that a
+// garbled one fails here instead of being sent back for a confusing 400.
+fn continuation_value(continuation: &[u8]) -> Result<HeaderValue, Error> {
+    let reason = if continuation.iter().all(u8::is_ascii_whitespace) {
+        "continuation token is empty"
+    } else if continuation.len() > MAX_CONTINUATION_LENGTH {
+        "continuation token is longer than 4096 bytes"
+    } else if continuation
+        .iter()
+        .any(|b| *b != b' ' && !b.is_ascii_graphic())
+    {
+        "continuation token may only contain printable ASCII characters"
+    } else {
+        return Ok(
+            HeaderValue::from_bytes(continuation).expect("continuation token is visible ASCII")
+        );
+    };
+
+    Err(Error::from(ErrorKind::InvalidContinuation {
+        continuation: String::from_utf8_lossy(continuation).into_owned(),
+        reason,
+    }))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<I> {
+    items: Vec<I>,
+    continuation: Option<String>,
+    total_count: Option<u64>,
+}
+
+impl<I> Page<I> {
+    pub fn items(&self) -> &[I] {
+        &self.items
+    }
+
+    /// The token to fetch the next page with, or `None` on the last page.
+    pub fn continuation(&self) -> Option<&str> {
+        self.continuation.as_ref().map(AsRef::as_ref)
+    }
+
+    /// The number of items in the whole listing, from the
+    /// `x-ms-total-count` header of the page, for reporting progress through
+    /// it. `None` if IoT Hub didn't send the header, as the count is then
+    /// unknown.
+    pub fn total_count(&self) -> Option<u64> {
+        self.total_count
+    }
+
+    pub fn into_items(self) -> Vec<I> {
+        self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Paginator;
+
+    use chrono::{DateTime, Utc};
+    use futures::{Future, Stream};
+    use hyper::header::HeaderValue;
+    use hyper::{Body, Method, Request, Response};
+    use serde_json::json;
+    use url::Url;
+
+    use edgelet_http::client::{Client, TokenSource};
+
+    use crate::error::{Error, ErrorKind};
+
+    struct NullTokenSource;
+
+    impl TokenSource for NullTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok("token".to_string())
+        }
+    }
+
+    impl Clone for NullTokenSource {
+        fn clone(&self) -> Self {
+            NullTokenSource
+        }
+    }
+
+    fn two_pages() -> impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync {
+        |req: Request<Body>| {
+            assert_eq!("/items", req.uri().path());
+            assert_eq!(
+                "2",
+                req.headers()
+                    .get("x-ms-max-item-count")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            );
+
+            let continuation = req
+                .headers()
+                .get("x-ms-continuation")
+                .map(|value| value.to_str().unwrap().to_string());
+            let response = match continuation.as_ref().map(AsRef::as_ref) {
+                None => Response::builder()
+                    .header("x-ms-continuation", "page2")
+                    .body(r#"["a","b"]"#.into())
+                    .unwrap(),
+                Some("page2") => Response::new(r#"["c"]"#.into()),
+                Some(continuation) => panic!("unexpected continuation token {}", continuation),
+            };
+            Ok(response)
+        }
+    }
+
+    fn paginator<F>(method: Method, handler: F) -> Paginator<F, NullTokenSource, String>
+    where
+        F: Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync,
+    {
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        Paginator::new(client, method, "/items".to_string(), || {
+            ErrorKind::ListModules
+        })
+        .with_page_size(2)
+    }
+
+    #[test]
+    fn page_forwards_continuation() {
+        let paginator = paginator(Method::GET, two_pages());
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let first = runtime.block_on(paginator.page(None)).unwrap();
+        assert_eq!(&["a".to_string(), "b".to_string()], first.items());
+        assert_eq!(Some("page2"), first.continuation());
+
+        let last = runtime
+            .block_on(paginator.page(first.continuation()))
+            .unwrap();
+        assert_eq!(&["c".to_string()], last.items());
+        assert_eq!(None, last.continuation());
+    }
+
+    #[test]
+    fn page_captures_total_count() {
+        let handler = |req: Request<Body>| {
+            let response = match req.headers().get("x-ms-continuation") {
+                None => Response::builder()
+                    .header("x-ms-continuation", "page2")
+                    .header("x-ms-total-count", "3")
+                    .body(r#"["a","b"]"#.into())
+                    .unwrap(),
+                Some(_) => Response::new(r#"["c"]"#.into()),
+            };
+            Ok(response)
+        };
+        let paginator = paginator(Method::GET, handler);
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let first = runtime.block_on(paginator.page(None)).unwrap();
+        assert_eq!(Some(3), first.total_count());
+
+        let last = runtime
+            .block_on(paginator.page(first.continuation()))
+            .unwrap();
+        assert_eq!(None, last.total_count());
+    }
+
+    #[test]
+    fn all_collects_every_page() {
+        let paginator = paginator(Method::GET, two_pages());
+
+        let items = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(paginator.all())
+            .unwrap();
+        assert_eq!(vec!["a", "b", "c"], items);
+    }
+
+    #[test]
+    fn invalid_continuation_fails() {
+        for continuation in &[&b""[..], &b"page\xff2"[..], &[b'a'; 4097][..]] {
+            let paginator = paginator(Method::GET, move |_req: Request<Body>| {
+                let response = Response::builder()
+                    .header(
+                        "x-ms-continuation",
+                        HeaderValue::from_bytes(continuation).unwrap(),
+                    )
+                    .body(r#"["a","b"]"#.into())
+                    .unwrap();
+                Ok(response)
+            });
+
+            let err = tokio::runtime::current_thread::Runtime::new()
+                .unwrap()
+                .block_on(paginator.page(None))
+                .unwrap_err();
+            match err.kind() {
+                ErrorKind::InvalidContinuation { .. } => (),
+                kind => panic!("unexpected error kind {:?}", kind),
+            }
+        }
+
+        let err = paginator(Method::GET, two_pages())
+            .page(Some("page\n2"))
+            .wait()
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::InvalidContinuation { reason, .. } => assert_eq!(
+                "continuation token may only contain printable ASCII characters",
+                *reason
+            ),
+            kind => panic!("unexpected error kind {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn page_sends_body() {
+        let paginator = paginator(Method::POST, |req: Request<Body>| {
+            assert_eq!(&Method::POST, req.method());
+            let body = req.into_body().concat2().wait().unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json!({ "query": "SELECT * FROM devices" }), body);
+            Ok(Response::new(r#"["a"]"#.into()))
+        })
+        .with_body(json!({ "query": "SELECT * FROM devices" }));
+
+        let page = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(paginator.page(None))
+            .unwrap();
+        assert_eq!(&["a".to_string()], page.items());
+    }
+}
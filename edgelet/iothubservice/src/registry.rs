@@ -0,0 +1,385 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use failure::{Fail, ResultExt};
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use hyper::Method;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use edgelet_http::client::{Client, ClientImpl, Condition, TokenSource};
+
+use crate::device::{quote_query_string, url_encode};
+use crate::error::{Error, ErrorKind, ModuleOperationReason};
+use crate::model::Device;
+use crate::paginator::Paginator;
+
+/// Operations on the device registry of an IoT Hub as a whole, as opposed to
+/// those on a single device that `DeviceClient` provides.
+pub struct RegistryClient<C, T> {
+    client: Client<C, T>,
+    fail_on_invalid_row: bool,
+}
+
+impl<C, T> RegistryClient<C, T>
+where
+    C: ClientImpl,
+    T: 'static + TokenSource + Clone,
+    <T as TokenSource>::Error: Fail,
+{
+    pub fn new(client: Client<C, T>) -> Self {
+        RegistryClient {
+            client,
+            fail_on_invalid_row: false,
+        }
+    }
+
+    /// Makes `query_typed` end its stream with the first row that can't be
+    /// deserialized, instead of yielding an error for that row and going on.
+    pub fn with_fail_on_invalid_row(mut self, fail_on_invalid_row: bool) -> Self {
+        self.fail_on_invalid_row = fail_on_invalid_row;
+        self
+    }
+
+    pub fn client(&self) -> &Client<C, T> {
+        &self.client
+    }
+
+    pub fn fail_on_invalid_row(&self) -> bool {
+        self.fail_on_invalid_row
+    }
+
+    pub fn list_devices_paged(&self) -> Paginator<C, T, Device> {
+        Paginator::new(
+            self.client.clone(),
+            Method::GET,
+            "/devices".to_string(),
+            || ErrorKind::ListDevices,
+        )
+    }
+
+    /// Lists the devices directly below `device_id` in a nested hierarchy,
+    /// which are those with its device scope among their parent scopes.
+    /// Fails with `MissingDeviceScope` if the device has no device scope.
+    pub fn children_of(&self, device_id: &str) -> impl Future<Item = Vec<Device>, Error = Error> {
+        let client = self.client.clone();
+        let device_id = device_id.to_string();
+        let path = format!("/devices/{}", url_encode(&device_id));
+
+        self.client
+            .request::<(), Device>(Method::GET, &path, None, None, Condition::None)
+            .map_err(|err| Error::from(err.context(ErrorKind::GetDevice)))
+            .and_then(move |device| {
+                let device = match device {
+                    Some(device) => device,
+                    None => {
+                        return Either::B(future::err(Error::from(ErrorKind::GetDeviceWithReason(
+                            ModuleOperationReason::EmptyResponse {
+                                operation: "children_of",
+                                path,
+                            },
+                        ))))
+                    }
+                };
+                match device.device_scope() {
+                    Some(scope) => Either::A(
+                        Paginator::<_, _, Device>::new(
+                            client,
+                            Method::POST,
+                            "/devices/query".to_string(),
+                            || ErrorKind::ListDevices,
+                        )
+                        .with_body(json!({ "query": children_query(scope) }))
+                        .all()
+                        .map_err(|err| err.into_parts().2),
+                    ),
+                    None => Either::B(future::err(Error::from(ErrorKind::MissingDeviceScope(
+                        device_id,
+                    )))),
+                }
+            })
+    }
+
+    /// Runs an IoT Hub query such as `SELECT * FROM devices.modules`. The
+    /// results are returned as raw JSON since their shape depends on the
+    /// query.
+    pub fn query(&self, query: &str) -> Paginator<C, T, Value> {
+        Paginator::new(
+            self.client.clone(),
+            Method::POST,
+            "/devices/query".to_string(),
+            || ErrorKind::Query,
+        )
+        .with_body(json!({ "query": query }))
+    }
+
+    /// Streams the results of a query deserialized into `Q`, fetching
+    /// `page_size` rows at a time. A row that doesn't deserialize is yielded
+    /// as an error unless `with_fail_on_invalid_row` is set, in which case it
+    /// ends the stream.
+    pub fn query_typed<Q>(
+        &self,
+        query: &str,
+        page_size: usize,
+    ) -> impl Stream<Item = Result<Q, Error>, Error = Error>
+    where
+        Q: DeserializeOwned,
+    {
+        let fail_on_invalid_row = self.fail_on_invalid_row;
+
+        self.query(query)
+            .with_page_size(page_size)
+            .stream()
+            .and_then(move |row| {
+                let row = serde_json::from_value(row)
+                    .context(ErrorKind::QueryRow)
+                    .map_err(Error::from);
+                match row {
+                    Err(err) if fail_on_invalid_row => Err(err),
+                    row => Ok(row),
+                }
+            })
+    }
+}
+
+impl<C, T> Clone for RegistryClient<C, T>
+where
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        RegistryClient {
+            client: self.client.clone(),
+            fail_on_invalid_row: self.fail_on_invalid_row,
+        }
+    }
+}
+
+// The query for the devices with `scope` among their parent scopes.
+fn children_query(scope: &str) -> String {
+    format!(
+        "SELECT * FROM devices WHERE ARRAY_CONTAINS(parentScopes, {})",
+        quote_query_string(scope)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{children_query, RegistryClient};
+
+    use chrono::{DateTime, Utc};
+    use futures::{Future, Stream};
+    use hyper::{Body, Method, Request, Response};
+    use serde_derive::Deserialize;
+    use serde_json::json;
+    use url::Url;
+
+    use edgelet_http::client::{Client, TokenSource};
+
+    use crate::error::{Error, ErrorKind};
+    use crate::model::Device;
+
+    struct NullTokenSource;
+
+    impl TokenSource for NullTokenSource {
+        type Error = Error;
+        fn get(&self, _expiry: &DateTime<Utc>) -> Result<String, Error> {
+            Ok("token".to_string())
+        }
+    }
+
+    impl Clone for NullTokenSource {
+        fn clone(&self) -> Self {
+            NullTokenSource
+        }
+    }
+
+    #[test]
+    fn query_follows_continuation() {
+        let handler = |req: Request<Body>| {
+            assert_eq!(&Method::POST, req.method());
+            assert_eq!("/devices/query", req.uri().path());
+
+            let continuation = req
+                .headers()
+                .get("x-ms-continuation")
+                .map(|value| value.to_str().unwrap().to_string());
+            req.into_body().concat2().map(move |body| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(json!({ "query": "SELECT * FROM devices.modules" }), body);
+
+                match continuation.as_ref().map(AsRef::as_ref) {
+                    None => Response::builder()
+                        .header("x-ms-continuation", "page2")
+                        .body(r#"[{"moduleId":"m1"}]"#.into())
+                        .unwrap(),
+                    Some("page2") => Response::new(r#"[{"moduleId":"m2"}]"#.into()),
+                    Some(continuation) => panic!("unexpected continuation token {}", continuation),
+                }
+            })
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let registry = RegistryClient::new(client);
+
+        let results = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(registry.query("SELECT * FROM devices.modules").all())
+            .unwrap();
+        assert_eq!(
+            vec![json!({ "moduleId": "m1" }), json!({ "moduleId": "m2" })],
+            results
+        );
+    }
+
+    #[test]
+    fn children_query_matches_parent_scopes() {
+        assert_eq!(
+            "SELECT * FROM devices WHERE ARRAY_CONTAINS(parentScopes, 'ms-azure-iot-edge://p-1')",
+            children_query("ms-azure-iot-edge://p-1")
+        );
+    }
+
+    #[test]
+    fn children_query_quotes_scope() {
+        assert_eq!(
+            r"SELECT * FROM devices WHERE ARRAY_CONTAINS(parentScopes, 'ms-azure-iot-edge://p\'1')",
+            children_query("ms-azure-iot-edge://p'1")
+        );
+    }
+
+    #[test]
+    fn children_are_queried_by_device_scope() {
+        let handler = |req: Request<Body>| {
+            let res = match (req.method(), req.uri().path()) {
+                (&Method::GET, "/devices/parent") => Response::new(
+                    r#"{"deviceId":"parent","deviceScope":"ms-azure-iot-edge://parent-1"}"#.into(),
+                ),
+                (&Method::GET, "/devices/leaf") => Response::new(r#"{"deviceId":"leaf"}"#.into()),
+                (&Method::POST, "/devices/query") => {
+                    let body = req.into_body().concat2().wait().unwrap();
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    assert_eq!(
+                        json!({ "query": children_query("ms-azure-iot-edge://parent-1") }),
+                        body
+                    );
+                    Response::new(
+                        r#"[{"deviceId":"child","parentScopes":["ms-azure-iot-edge://parent-1"]}]"#
+                            .into(),
+                    )
+                }
+                (method, path) => panic!("unexpected request {} {}", method, path),
+            };
+            Ok(res)
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        let registry = RegistryClient::new(client);
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let children = runtime.block_on(registry.children_of("parent")).unwrap();
+        let child_ids: Vec<_> = children.iter().filter_map(Device::device_id).collect();
+        assert_eq!(vec!["child"], child_ids);
+
+        let err = runtime.block_on(registry.children_of("leaf")).unwrap_err();
+        assert_eq!(
+            &ErrorKind::MissingDeviceScope("leaf".to_string()),
+            err.kind()
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct ModuleRow {
+        #[serde(rename = "moduleId")]
+        module_id: String,
+    }
+
+    fn typed_query_registry() -> RegistryClient<
+        impl Fn(Request<Body>) -> Result<Response<Body>, hyper::Error> + Send + Sync,
+        NullTokenSource,
+    > {
+        let handler = |req: Request<Body>| {
+            assert_eq!(
+                "1",
+                req.headers()
+                    .get("x-ms-max-item-count")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            );
+            let res = match req.headers().get("x-ms-continuation") {
+                None => Response::builder()
+                    .header("x-ms-continuation", "page2")
+                    .body(r#"[{"moduleId":"m1"},{"moduleId":2}]"#.into())
+                    .unwrap(),
+                Some(continuation) => {
+                    assert_eq!("page2", continuation.to_str().unwrap());
+                    Response::new(r#"[{"moduleId":"m3"}]"#.into())
+                }
+            };
+            Ok(res)
+        };
+        let client = Client::new(
+            handler,
+            Some(NullTokenSource),
+            "2018-04-10".to_string(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap();
+        RegistryClient::new(client)
+    }
+
+    #[test]
+    fn query_typed_streams_rows_across_pages() {
+        let registry = typed_query_registry();
+
+        let rows = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(
+                registry
+                    .query_typed::<ModuleRow>("SELECT moduleId FROM devices.modules", 1)
+                    .collect(),
+            )
+            .unwrap();
+
+        assert_eq!(3, rows.len());
+        assert_eq!(
+            &ModuleRow {
+                module_id: "m1".to_string()
+            },
+            rows[0].as_ref().unwrap()
+        );
+        assert_eq!(&ErrorKind::QueryRow, rows[1].as_ref().unwrap_err().kind());
+        assert_eq!(
+            &ModuleRow {
+                module_id: "m3".to_string()
+            },
+            rows[2].as_ref().unwrap()
+        );
+    }
+
+    #[test]
+    fn query_typed_can_fail_on_invalid_row() {
+        let registry = typed_query_registry().with_fail_on_invalid_row(true);
+
+        let err = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(
+                registry
+                    .query_typed::<ModuleRow>("SELECT moduleId FROM devices.modules", 1)
+                    .collect(),
+            )
+            .unwrap_err();
+
+        assert_eq!(&ErrorKind::QueryRow, err.kind());
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use percent_encoding::percent_encode;
+
+use crate::device::IOTHUB_ENCODE_SET;
+
+/// Builds the resource URI a SAS token is scoped to. The token is scoped to
+/// the hub when no device is given, and a module ID is only meaningful along
+/// with the device it belongs to.
+pub fn resource_uri(host: &str, device_id: Option<&str>, module_id: Option<&str>) -> String {
+    let mut resource = host.to_string();
+
+    if let Some(device_id) = device_id {
+        resource.push_str("/devices/");
+        resource.push_str(device_id);
+
+        if let Some(module_id) = module_id {
+            resource.push_str("/modules/");
+            resource.push_str(module_id);
+        }
+    }
+
+    percent_encode(resource.to_lowercase().as_bytes(), IOTHUB_ENCODE_SET).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resource_uri;
+
+    #[test]
+    fn hub_scope() {
+        assert_eq!(
+            "myhub.azure-devices.net",
+            resource_uri("MyHub.azure-devices.net", None, None)
+        );
+    }
+
+    #[test]
+    fn hub_scope_ignores_module() {
+        assert_eq!(
+            "myhub.azure-devices.net",
+            resource_uri("MyHub.azure-devices.net", None, Some("m1"))
+        );
+    }
+
+    #[test]
+    fn device_scope() {
+        assert_eq!(
+            "myhub.azure-devices.net%2Fdevices%2Fn@m.et%23st",
+            resource_uri("MyHub.azure-devices.net", Some("N@m.et#st"), None)
+        );
+    }
+
+    #[test]
+    fn module_scope() {
+        assert_eq!(
+            "myhub.azure-devices.net%2Fdevices%2Fd1%2Fmodules%2F$edgeagent",
+            resource_uri("MyHub.azure-devices.net", Some("D1"), Some("$edgeAgent"))
+        );
+    }
+}